@@ -2,7 +2,7 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use clap::Parser;
-use openai_server::{start_http_server, ContributorMode, ServerMode};
+use openai_server::{start_http_server, ContributorMode, ModelManifests, ResourceSource, ServerMode};
 use std::env;
 use utils::random_node_id;
 
@@ -45,7 +45,19 @@ async fn main() {
             // let window = app.get_window("main").unwrap();
             // window.open_devtools();
             tauri::async_runtime::spawn(async move {
-                start_http_server(args.http_bind, &args.registry_server, &node_id, &args.stun_server, ServerMode::Contributor(ContributorMode {})).await;
+                start_http_server(
+                    args.http_bind,
+                    &args.registry_server,
+                    &node_id,
+                    &args.stun_server,
+                    ResourceSource::default(),
+                    ModelManifests::builtin(),
+                    ServerMode::Contributor(ContributorMode {
+                        kv_cache_mb: None,
+                        kv_cache_int8: false,
+                    }),
+                )
+                .await;
             });
             Ok(())
         })
@@ -179,6 +179,7 @@ const SwarmHealthTables = ({
                     <TableHead>Output Tokens</TableHead>
                     <TableHead>Network Out</TableHead>
                     <TableHead>Network In</TableHead>
+                    <TableHead>KV Cache</TableHead>
                   </TableRow>
                 </TableHeader>
                 <TableBody>
@@ -201,6 +202,10 @@ const SwarmHealthTables = ({
                         <TableCell>{node.info.stats.token_out_sum}</TableCell>
                         <TableCell>{formatBytes(node.info.stats.network_out_bytes)} ({formatBits(node.info.stats.network_out_bps)})</TableCell>
                         <TableCell>{formatBytes(node.info.stats.network_in_bytes)} ({formatBits(node.info.stats.network_in_bps)})</TableCell>
+                        <TableCell>
+                          {formatBytes(node.info.stats.kv_used_bytes)}
+                          {node.info.stats.kv_max_bytes > 0 && <> / {formatBytes(node.info.stats.kv_max_bytes)}</>} ({node.info.stats.kv_sessions} sessions)
+                        </TableCell>
                       </TableRow>
                     ))
                   ) : (
@@ -17,6 +17,10 @@ export interface SwarmHealth {
         network_out_bps: number;
         token_in_tps: number;
         token_out_tps: number;
+        kv_used_bytes: number;
+        kv_max_bytes: number;
+        kv_sessions: number;
+        kv_evictions: number;
       }
     };
   }[];
@@ -1,14 +1,15 @@
-use candle_core::{DType, Device, Tensor};
+use candle_core::{Device, Tensor};
 use clap::Parser;
 use contract::{
     aptos_sdk::{rest_client::AptosBaseUrl, types::LocalAccount},
     OnChainService,
 };
-use models::{fake, get_device, llama, phi3, ModelLayersWorker};
-use utils::random_node_id;
-use std::{net::ToSocketAddrs, sync::Arc};
+use models::{get_device, model_layers, new_layers_worker, KvBudgetConfig, KvQuantization, ModelLayersWorker, ResourceSource};
+use protocol::ModelManifests;
+use std::{net::ToSocketAddrs, path::PathBuf, sync::Arc};
 use tokio::signal;
 use usage_service::WorkerUsageService;
+use utils::random_node_id;
 use worker::WorkerRunner;
 
 /// OpenAI Server for decentralized LLM
@@ -39,6 +40,34 @@ struct Args {
     #[arg(env, long)]
     layers_to: u32,
 
+    /// experts of a mixture-of-experts model held by this node, the others run on their owners. Default to all experts
+    #[arg(env, long)]
+    experts_from: Option<u32>,
+
+    /// experts of a mixture-of-experts model held by this node, exclusive
+    #[arg(env, long)]
+    experts_to: Option<u32>,
+
+    /// load model files from this local directory instead of hf-hub
+    #[arg(env, long)]
+    model_dir: Option<PathBuf>,
+
+    /// never download model files, only use the local directory or the hf-hub cache
+    #[arg(env, long)]
+    offline: bool,
+
+    /// json file with the model manifests, default to the builtin models
+    #[arg(env, long)]
+    manifest: Option<PathBuf>,
+
+    /// max MB of kv caches, the least recently used idle sessions are evicted or new ones refused above it
+    #[arg(env, long)]
+    kv_cache_mb: Option<u64>,
+
+    /// store the kv caches in int8, about 2x (f16 models) to 4x (f32 models) more sessions for a small loss of accuracy
+    #[arg(env, long)]
+    kv_cache_int8: bool,
+
     /// Wallet private key
     #[arg(env, long, default_value = "0x69d91353993001d80ef74f7a27fcb15456d4d6298c755a5316a0a0d87b6b39b9")]
     private_key: String,
@@ -66,110 +95,50 @@ async fn main() {
     onchain_service.init().await;
     let usage_service = Arc::new(onchain_service);
     let node_id = args.node_id.unwrap_or_else(random_node_id);
+    let source = ResourceSource {
+        local_dir: args.model_dir.clone(),
+        offline: args.offline,
+    };
 
-    match args.model.as_str() {
-        "phi3" => {
-            let layers_worker = phi3::Phi3LayersWorker::new(false, args.layers_from..args.layers_to, &device).await.unwrap();
-            run::<_, 32>(
-                &args.registry_server,
-                device,
-                layers_worker,
-                &args.model,
-                &node_id,
-                args.layers_from,
-                args.layers_to,
-                &args.stun_server,
-                usage_service,
-            )
-            .await;
-        }
-        "llama32-1b" => {
-            let resource = llama::ModelResource {
-                repo: "unsloth/Llama-3.2-1B-Instruct".to_string(),
-                model: "model.safetensors".to_string(),
-                config: "config.json".to_string(),
-                tokenizer: "tokenizer.json".to_string(),
-            };
-            let layers_worker = llama::new_layers(&resource, DType::F16, device.clone(), false, args.layers_from..args.layers_to).await;
-            run::<_, 16>(
-                &args.registry_server,
-                device,
-                layers_worker,
-                &args.model,
-                &node_id,
-                args.layers_from,
-                args.layers_to,
-                &args.stun_server,
-                usage_service,
-            )
-            .await;
-        }
-        "llama32-3b" => {
-            let resource = llama::ModelResource {
-                repo: "unsloth/Llama-3.2-3B-Instruct".to_string(),
-                model: "model.safetensors".to_string(),
-                config: "config.json".to_string(),
-                tokenizer: "tokenizer.json".to_string(),
-            };
-            let layers_worker = llama::new_layers(&resource, DType::F16, device.clone(), false, args.layers_from..args.layers_to).await;
-            run::<_, 28>(
-                &args.registry_server,
-                device,
-                layers_worker,
-                &args.model,
-                &node_id,
-                args.layers_from,
-                args.layers_to,
-                &args.stun_server,
-                usage_service,
-            )
-            .await;
-        }
-        "llama32-vision-11b" => {
-            let resource = llama::ModelResource {
-                repo: "unsloth/Llama-3.2-11B-Vision-Instruct".to_string(),
-                model: "model.safetensors.index.json".to_string(),
-                config: "config.json".to_string(),
-                tokenizer: "tokenizer.json".to_string(),
-            };
-            let layers_worker = llama::new_layers(&resource, DType::F16, device.clone(), false, args.layers_from..args.layers_to).await;
-            run::<_, 40>(
-                &args.registry_server,
-                device,
-                layers_worker,
-                &args.model,
-                &node_id,
-                args.layers_from,
-                args.layers_to,
-                &args.stun_server,
-                usage_service,
-            )
-            .await;
-        }
-        "fake" => {
-            let layers_worker = fake::FakeLayersWorker::new(args.layers_from..args.layers_to);
-            run::<_, 16>(
-                &args.registry_server,
-                device,
-                layers_worker,
-                &args.model,
-                &node_id,
-                args.layers_from,
-                args.layers_to,
-                &args.stun_server,
-                usage_service,
-            )
-            .await;
-        }
-        _ => panic!("unsupported"),
-    }
+    let manifests = ModelManifests::load(args.manifest.as_deref()).expect("Should load model manifests");
+    let manifest = manifests.get(&args.model).unwrap_or_else(|| panic!("model {} not found in manifests", args.model));
+    let model_layers = model_layers(manifest, source.clone()).await.unwrap();
+    let kv_budget = KvBudgetConfig {
+        max_bytes: args.kv_cache_mb.map(|mb| mb * 1024 * 1024),
+        quantization: if args.kv_cache_int8 {
+            KvQuantization::Int8
+        } else {
+            KvQuantization::None
+        },
+        ..Default::default()
+    };
+    let experts = match (args.experts_from, args.experts_to) {
+        (Some(from), Some(to)) => Some(from..to),
+        (None, None) => None,
+        _ => panic!("experts-from and experts-to must be set together"),
+    };
+    let layers_worker = new_layers_worker(manifest, source, &device, args.layers_from..args.layers_to, experts, kv_budget).await.unwrap();
+    run(
+        &args.registry_server,
+        device,
+        layers_worker,
+        &args.model,
+        model_layers,
+        &node_id,
+        args.layers_from,
+        args.layers_to,
+        &args.stun_server,
+        usage_service,
+    )
+    .await;
 }
 
-async fn run<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_LAYERS: usize>(
+async fn run<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static>(
     registry_server: &str,
     device: Device,
     layers_worker: LW,
     model: &str,
+    model_layers: u32,
     node_id: &str,
     from: u32,
     to: u32,
@@ -177,7 +146,7 @@ async fn run<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const
     usage_service: Arc<dyn WorkerUsageService>,
 ) {
     let stun_servers = stun_server.to_socket_addrs().unwrap().collect();
-    let (mut worker, _virtual_layers) = WorkerRunner::<MODEL_LAYERS>::new(registry_server, model, node_id, from..to, layers_worker, device, stun_servers, usage_service).await;
+    let (mut worker, _virtual_layers) = WorkerRunner::new(registry_server, model, model_layers, node_id, from..to, layers_worker, device, stun_servers, usage_service).await;
 
     loop {
         tokio::select! {
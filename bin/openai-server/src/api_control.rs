@@ -4,6 +4,7 @@ use contract::{
     aptos_sdk::{rest_client::AptosBaseUrl, types::LocalAccount},
     OnChainService,
 };
+use models::{KvBudgetConfig, ResourceSource};
 use openai_http::ModelStore;
 use poem::{
     handler,
@@ -11,6 +12,7 @@ use poem::{
     web::{Data, Json, Query},
     Body, Response,
 };
+use protocol::ModelManifests;
 use registry::client::{get_layers_distribution, select_layers, LayerSelectionRes};
 use serde::{Deserialize, Serialize};
 use tokio::sync::{
@@ -33,8 +35,11 @@ pub struct P2pState {
     pub registry_server: String,
     pub node_id: String,
     pub stun_server: String,
+    pub source: ResourceSource,
+    pub manifests: ModelManifests,
     pub store: ModelStore,
     pub models: Arc<Mutex<HashMap<String, ModelState>>>,
+    pub kv_budget: KvBudgetConfig,
 }
 
 #[derive(Debug, Serialize)]
@@ -116,11 +121,14 @@ pub async fn p2p_start(body: Json<P2pStart>, data: Data<&P2pState>) -> Response
     if models.contains_key(&body.model) {
         return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from_string("Model already started".to_string()));
     }
+    let manifest = match data.manifests.get(&body.model) {
+        Some(manifest) => manifest.clone(),
+        None => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from_string("Model not supported".to_string())),
+    };
 
     let registry_server = data.registry_server.clone();
     let node_id = data.node_id.clone();
     let stun_server = data.stun_server.clone();
-    let model = body.model.clone();
     let range = body.from_layer..body.to_layer;
     let (query_tx, query_rx) = channel(10);
     let account = LocalAccount::from_private_key(&body.private_key, 0).expect("Invalid private key");
@@ -130,8 +138,10 @@ pub async fn p2p_start(body: Json<P2pStart>, data: Data<&P2pState>) -> Response
     let usage_service = Arc::new(onchain_service);
     let wallet = usage_service.clone();
     let store = data.store.clone();
+    let source = data.source.clone();
+    let kv_budget = data.kv_budget.clone();
     tokio::spawn(async move {
-        run_model_worker(&registry_server, &model, &node_id, range, &stun_server, query_rx, usage_service, store).await;
+        run_model_worker(&registry_server, manifest, &node_id, range, &stun_server, source, kv_budget, query_rx, usage_service, store).await;
     });
     let model_state = ModelState {
         model: body.model.clone(),
@@ -6,6 +6,7 @@ use contract::{
     aptos_sdk::{rest_client::AptosBaseUrl, types::LocalAccount},
     OnChainService,
 };
+use models::{KvBudgetConfig, KvQuantization};
 use openai_http::ModelStore;
 use poem::{
     listener::TcpListener,
@@ -15,6 +16,9 @@ use poem::{
 use tokio::sync::{mpsc::channel, Mutex};
 use worker::run_model_worker;
 
+pub use models::ResourceSource;
+pub use protocol::ModelManifests;
+
 mod api_control;
 mod worker;
 
@@ -25,7 +29,15 @@ pub enum ServerMode {
 }
 
 #[derive(Debug, Parser)]
-pub struct ContributorMode {}
+pub struct ContributorMode {
+    /// max MB of kv caches, the least recently used idle sessions are evicted or new ones refused above it
+    #[arg(env, long)]
+    pub kv_cache_mb: Option<u64>,
+
+    /// store the kv caches in int8, about 2x (f16 models) to 4x (f32 models) more sessions for a small loss of accuracy
+    #[arg(env, long)]
+    pub kv_cache_int8: bool,
+}
 
 #[derive(Debug, Parser)]
 pub struct GatewayMode {
@@ -33,15 +45,16 @@ pub struct GatewayMode {
     #[arg(env, long)]
     private_key: String,
 
-    /// Models: phi3, llama32-1b, llama32-3b, llama32-vision-11b
+    /// Model ids from the manifests, e.g. phi3, llama32-1b, llama32-3b
     #[arg(env, long)]
     models: Vec<String>,
 }
 
-pub async fn start_http_server(http_bind: SocketAddr, registry_server: &str, node_id: &str, stun_server: &str, mode: ServerMode) {
+/// `source.local_dir` is the root directory holding one sub-directory per model
+pub async fn start_http_server(http_bind: SocketAddr, registry_server: &str, node_id: &str, stun_server: &str, source: ResourceSource, manifests: ModelManifests, mode: ServerMode) {
     let store = ModelStore::default();
     match mode {
-        ServerMode::Contributor(_) => {
+        ServerMode::Contributor(contributor) => {
             let p2p_app = Route::new()
                 .at("/v1/status", poem::get(p2p_status))
                 .at("/v1/suggest_layers", poem::get(p2p_suggest_layers))
@@ -52,7 +65,18 @@ pub async fn start_http_server(http_bind: SocketAddr, registry_server: &str, nod
                     node_id: node_id.to_string(),
                     store: store.clone(),
                     stun_server: stun_server.to_string(),
+                    source: source.clone(),
+                    manifests: manifests.clone(),
                     models: Default::default(),
+                    kv_budget: KvBudgetConfig {
+                        max_bytes: contributor.kv_cache_mb.map(|mb| mb * 1024 * 1024),
+                        quantization: if contributor.kv_cache_int8 {
+                            KvQuantization::Int8
+                        } else {
+                            KvQuantization::None
+                        },
+                        ..Default::default()
+                    },
                 });
 
             let (chat_tx, mut chat_rx) = channel(10);
@@ -72,6 +96,7 @@ pub async fn start_http_server(http_bind: SocketAddr, registry_server: &str, nod
             let model_ids = gateway.models;
             let models: Arc<Mutex<HashMap<String, ModelState>>> = Default::default();
             for model_id in model_ids {
+                let manifest = manifests.get(&model_id).unwrap_or_else(|| panic!("model {model_id} not found in manifests")).clone();
                 let range = 0..0;
                 let (control_tx, control_rx) = channel(10);
                 controls.insert(model_id.to_string(), control_tx.clone());
@@ -80,9 +105,9 @@ pub async fn start_http_server(http_bind: SocketAddr, registry_server: &str, nod
                 onchain_service.init().await;
                 let usage_service = Arc::new(onchain_service);
                 let registry_server = registry_server.to_owned();
-                let model = model_id.to_owned();
                 let node_id = node_id.to_owned();
                 let stun_server = stun_server.to_owned();
+                let source = source.clone();
                 let store = store.clone();
                 let model_state = ModelState {
                     model: model_id.to_string(),
@@ -92,7 +117,21 @@ pub async fn start_http_server(http_bind: SocketAddr, registry_server: &str, nod
                     wallet: usage_service.clone(),
                 };
                 models.lock().await.insert(model_id.to_string(), model_state);
-                tokio::spawn(async move { run_model_worker(&registry_server, &model, &node_id, range, &stun_server, control_rx, usage_service, store.clone()).await });
+                tokio::spawn(async move {
+                    run_model_worker(
+                        &registry_server,
+                        manifest,
+                        &node_id,
+                        range,
+                        &stun_server,
+                        source,
+                        KvBudgetConfig::default(),
+                        control_rx,
+                        usage_service,
+                        store.clone(),
+                    )
+                    .await
+                });
             }
 
             let (chat_tx, mut chat_rx) = channel(10);
@@ -102,7 +141,10 @@ pub async fn start_http_server(http_bind: SocketAddr, registry_server: &str, nod
                 node_id: node_id.to_string(),
                 store: store.clone(),
                 stun_server: stun_server.to_string(),
+                source,
+                manifests,
                 models,
+                kv_budget: KvBudgetConfig::default(),
             });
             let app = Route::new().nest("/p2p", p2p_app).nest("/", openai_app).with(Cors::new()).with(Tracing::default());
 
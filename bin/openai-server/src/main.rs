@@ -1,6 +1,6 @@
 use clap::Parser;
-use openai_server::{start_http_server, ServerMode};
-use std::net::SocketAddr;
+use openai_server::{start_http_server, ModelManifests, ResourceSource, ServerMode};
+use std::{net::SocketAddr, path::PathBuf};
 use utils::random_node_id;
 
 /// OpenAI Server for decentralized LLM
@@ -23,6 +23,18 @@ struct Args {
     #[arg(env, long)]
     node_id: Option<String>,
 
+    /// load model files from `<models_dir>/<model>` instead of hf-hub
+    #[arg(env, long)]
+    models_dir: Option<PathBuf>,
+
+    /// never download model files, only use the local directory or the hf-hub cache
+    #[arg(env, long)]
+    offline: bool,
+
+    /// json file with the model manifests, default to the builtin models
+    #[arg(env, long)]
+    manifest: Option<PathBuf>,
+
     #[command(subcommand)]
     mode: ServerMode,
 }
@@ -39,5 +51,10 @@ async fn main() {
 
     let node_id = args.node_id.unwrap_or_else(random_node_id);
     tracing_subscriber::registry().with(fmt::layer()).with(EnvFilter::from_default_env()).init();
-    start_http_server(args.http_bind, &args.registry_server, &node_id, &args.stun_server, args.mode).await;
+    let source = ResourceSource {
+        local_dir: args.models_dir,
+        offline: args.offline,
+    };
+    let manifests = ModelManifests::load(args.manifest.as_deref()).expect("Should load model manifests");
+    start_http_server(args.http_bind, &args.registry_server, &node_id, &args.stun_server, source, manifests, args.mode).await;
 }
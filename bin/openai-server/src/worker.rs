@@ -1,110 +1,60 @@
-use std::{net::ToSocketAddrs, ops::Range, sync::Arc};
+use std::{net::ToSocketAddrs, ops::Range, sync::Arc, time::Duration};
 
-use candle_core::DType;
-
-use models::{fake, get_device, llama, phi3, ChatModel};
+use models::{get_device, model_layers, new_chat_model, new_layers_worker, BatchConfig, BatchScheduler, ChatModel, KvBudgetConfig, ResourceSource};
 use openai_http::ModelStore;
 
-use protocol::Model;
+use protocol::{ChatEvent, FinishReason, Model, ModelManifest};
 use tokio::sync::{mpsc::Receiver, oneshot};
 use usage_service::WorkerUsageService;
 use worker::WorkerRunner;
 
+/// Conversations idle for longer are finished, dropping their kv caches on every hop
+const CONVERSATION_IDLE_TTL: Duration = Duration::from_secs(300);
+
 pub async fn run_model_worker(
     registry_server: &str,
-    model: &str,
+    manifest: ModelManifest,
     node_id: &str,
     layers: Range<u32>,
     stun_server: &str,
+    source: ResourceSource,
+    kv_budget: KvBudgetConfig,
     control_rx: Receiver<WorkerControl>,
     usage_service: Arc<dyn WorkerUsageService>,
     store: ModelStore,
 ) {
     let stun_servers = stun_server.to_socket_addrs().unwrap().collect::<Vec<_>>();
-    log::info!("[OpenAIServer] start with model {} and stun server {}", model, stun_server);
+    log::info!("[OpenAIServer] start with model {} and stun server {}", manifest.id, stun_server);
     let device = get_device(false).unwrap();
+    let source = ResourceSource {
+        local_dir: source.local_dir.map(|dir| dir.join(&manifest.id)),
+        offline: source.offline,
+    };
 
-    match model {
-        "phi3" => {
-            let layers_worker = phi3::Phi3LayersWorker::new(false, layers.clone(), &device).await.unwrap();
-            let (mut worker, virtual_model_layers) = WorkerRunner::<32>::new(registry_server, model, node_id, layers.clone(), layers_worker, device.clone(), stun_servers, usage_service).await;
-            let model_exe = phi3::Phi3Model::new(device.clone(), virtual_model_layers).await;
-            let model = Model {
-                id: "phi3".to_owned(),
-                object: "model".to_owned(),
-                created: 0,
-                owned_by: "Microsoft".to_owned(),
-            };
-            run_model_worker_internal(&mut worker, model, Arc::new(model_exe), store, control_rx).await;
-        }
-        "llama32-1b" => {
-            let resource = llama::ModelResource {
-                repo: "unsloth/Llama-3.2-1B-Instruct".to_string(),
-                model: "model.safetensors".to_string(),
-                config: "config.json".to_string(),
-                tokenizer: "tokenizer.json".to_string(),
-            };
-            let layers_worker = llama::new_layers(&resource, DType::F16, device.clone(), false, layers.clone()).await;
-            let (mut worker, virtual_model_layers) = WorkerRunner::<16>::new(registry_server, model, node_id, layers.clone(), layers_worker, device.clone(), stun_servers, usage_service).await;
-            let model_exe = llama::LlamaModel::new(&resource, device.clone(), DType::F16, virtual_model_layers, false).await;
-            let model = Model {
-                id: "llama32-1b".to_owned(),
-                object: "model".to_owned(),
-                created: 0,
-                owned_by: "unsloth".to_owned(),
-            };
-            run_model_worker_internal(&mut worker, model, Arc::new(model_exe), store, control_rx).await;
-        }
-        "llama32-3b" => {
-            let resource = llama::ModelResource {
-                repo: "unsloth/Llama-3.2-3B-Instruct".to_string(),
-                model: "model.safetensors".to_string(),
-                config: "config.json".to_string(),
-                tokenizer: "tokenizer.json".to_string(),
-            };
-            let layers_worker = llama::new_layers(&resource, DType::F16, device.clone(), false, layers.clone()).await;
-            let (mut worker, virtual_model_layers) = WorkerRunner::<28>::new(registry_server, model, node_id, layers.clone(), layers_worker, device.clone(), stun_servers, usage_service).await;
-            let model_exe = llama::LlamaModel::new(&resource, device.clone(), DType::F16, virtual_model_layers, false).await;
-            let model = Model {
-                id: "llama32-3b".to_owned(),
-                object: "model".to_owned(),
-                created: 0,
-                owned_by: "unsloth".to_owned(),
-            };
-            run_model_worker_internal(&mut worker, model, Arc::new(model_exe), store, control_rx).await;
-        }
-        "llama32-vision-11b" => {
-            let resource = llama::ModelResource {
-                repo: "unsloth/Llama-3.2-11B-Vision-Instruct".to_string(),
-                model: "model.safetensors.index.json".to_string(),
-                config: "config.json".to_string(),
-                tokenizer: "tokenizer.json".to_string(),
-            };
-            let layers_worker = llama::new_layers(&resource, DType::F16, device.clone(), false, layers.clone()).await;
-            let (mut worker, virtual_model_layers) = WorkerRunner::<40>::new(registry_server, model, node_id, layers.clone(), layers_worker, device.clone(), stun_servers, usage_service).await;
-            let model_exe = llama::LlamaModel::new(&resource, device.clone(), DType::F16, virtual_model_layers, false).await;
-            let model = Model {
-                id: "llama32-11b".to_owned(),
-                object: "model".to_owned(),
-                created: 0,
-                owned_by: "unsloth".to_owned(),
-            };
-            run_model_worker_internal(&mut worker, model, Arc::new(model_exe), store, control_rx).await;
-        }
-        "fake" => {
-            let layers_worker = fake::FakeLayersWorker::new(layers.clone());
-            let (mut worker, virtual_model_layers) = WorkerRunner::<16>::new(registry_server, model, node_id, layers.clone(), layers_worker, device.clone(), stun_servers, usage_service).await;
-            let model_exe = fake::FakeModel::new(device.clone(), virtual_model_layers).await;
-            let model = Model {
-                id: "fake".to_owned(),
-                object: "model".to_owned(),
-                created: 0,
-                owned_by: "fake".to_owned(),
-            };
-            run_model_worker_internal(&mut worker, model, Arc::new(model_exe), store, control_rx).await;
-        }
-        _ => panic!("unsupported"),
-    }
+    let model_layers = model_layers(&manifest, source.clone()).await.unwrap();
+    let layers_worker = new_layers_worker(&manifest, source.clone(), &device, layers.clone(), None, kv_budget).await.unwrap();
+    let (mut worker, virtual_model_layers) = WorkerRunner::new(
+        registry_server,
+        &manifest.id,
+        model_layers,
+        node_id,
+        layers.clone(),
+        layers_worker,
+        device.clone(),
+        stun_servers,
+        usage_service,
+    )
+    .await;
+    // decode steps of concurrent chats go through the layers chain as one batched request per hop
+    let virtual_model_layers = BatchScheduler::new(virtual_model_layers, BatchConfig::default());
+    let model_exe = new_chat_model(&manifest, source, &device, virtual_model_layers).await.unwrap();
+    let model = Model {
+        id: manifest.id.clone(),
+        object: "model".to_owned(),
+        created: 0,
+        owned_by: manifest.owned_by.clone(),
+    };
+    run_model_worker_internal(&mut worker, model, model_exe, store, control_rx).await;
 }
 
 #[derive(Debug)]
@@ -119,16 +69,15 @@ pub enum WorkerControl {
     Stop(oneshot::Sender<()>),
 }
 
-async fn run_model_worker_internal<const MODEL_LAYERS: usize>(
-    worker: &mut WorkerRunner<MODEL_LAYERS>,
-    model: Model,
-    model_exe: Arc<dyn ChatModel>,
-    store: ModelStore,
-    mut control_rx: Receiver<WorkerControl>,
-) {
+async fn run_model_worker_internal(worker: &mut WorkerRunner, model: Model, model_exe: Arc<dyn ChatModel>, store: ModelStore, mut control_rx: Receiver<WorkerControl>) {
     let mut chat_rx = store.add_model(model.clone());
+    let mut expire_ticker = tokio::time::interval(Duration::from_secs(10));
     loop {
         tokio::select! {
+            _ = expire_ticker.tick() => {
+                let model_exe = model_exe.clone();
+                tokio::spawn(async move { model_exe.expire_conversations(CONVERSATION_IDLE_TTL).await });
+            },
             e = worker.recv() => match e {
                 Some(_e) => {},
                 None => {
@@ -160,9 +109,24 @@ async fn run_model_worker_internal<const MODEL_LAYERS: usize>(
                     let prompt = model_exe.build_prompt(&req.req);
                     let model_exe = model_exe.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = model_exe.chat(req.session, req.cfg, &prompt, req.answer_tx).await {
-                            log::error!("[OpenAIServer] run session error {e:?}");
+                        if let Err(e) = model_exe.check_prompt(&req.cfg, &prompt) {
+                            log::warn!("[OpenAIServer] rejected prompt: {e}");
+                            let _ = req.accept_tx.send(Err(e.to_string()));
+                            return;
                         }
+                        let _ = req.accept_tx.send(Ok(()));
+                        let res = match &req.conversation_id {
+                            Some(conversation_id) => model_exe.chat_turn(conversation_id, req.cfg, &prompt, req.answer_tx.clone()).await,
+                            None => model_exe.chat(req.session, req.cfg, &prompt, req.answer_tx.clone()).await,
+                        };
+                        let finish_reason = match res {
+                            Ok(finish_reason) => finish_reason,
+                            Err(e) => {
+                                log::error!("[OpenAIServer] run session error {e:?}");
+                                FinishReason::Error
+                            }
+                        };
+                        let _ = req.answer_tx.send(ChatEvent::Finish(finish_reason)).await;
                     });
                 },
                 None => {
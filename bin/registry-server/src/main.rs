@@ -1,6 +1,7 @@
 use clap::Parser;
-use registry::server::RegistryServer;
-use std::net::SocketAddr;
+use protocol::ModelManifests;
+use registry::{server::RegistryServer, supported_models};
+use std::{net::SocketAddr, path::PathBuf};
 
 /// Registry server for decentralized LLM
 #[derive(Parser, Debug)]
@@ -9,6 +10,10 @@ struct Args {
     /// address to bind websocket server
     #[arg(env, short, long, default_value = "0.0.0.0:3000")]
     ws_bind: SocketAddr,
+
+    /// json file with the model manifests, default to the builtin models
+    #[arg(env, long)]
+    manifest: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -16,6 +21,8 @@ async fn main() {
     let args = Args::parse();
     env_logger::builder().format_timestamp_millis().init();
 
-    let mut registry = RegistryServer::new(args.ws_bind);
+    let manifests = ModelManifests::load(args.manifest.as_deref()).expect("Should load model manifests");
+    let models = supported_models(&manifests).expect("Should list supported models");
+    let mut registry = RegistryServer::new(args.ws_bind, models);
     while let Some(_) = registry.recv().await {}
 }
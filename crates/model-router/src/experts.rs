@@ -0,0 +1,157 @@
+use std::{collections::HashMap, fmt::Debug, hash::Hash, ops::Range};
+
+use crate::{table::ROUTE_TIMEOUT_MS, LayerRemoteInfo};
+
+/// The experts `experts` of the mixture-of-experts layers `layers` held by a node
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpertShard {
+    pub layers: Range<u32>,
+    pub experts: Range<u32>,
+}
+
+impl ExpertShard {
+    pub fn contains(&self, layer: u32, expert: u32) -> bool {
+        self.layers.contains(&layer) && self.experts.contains(&expert)
+    }
+}
+
+/// Experts of a layer which go to the same node, `node` is None for the local node
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpertPath<Node> {
+    pub node: Option<Node>,
+    pub experts: Vec<u32>,
+}
+
+/// Owners of the experts of every layer, each node announces its own shard.
+/// Unlike the layers, the experts are not chained: the hop running a layer dispatches each token to the owners of its top-k experts
+#[derive(Debug, Clone)]
+pub struct ExpertTable<Node> {
+    num_experts: u32,
+    local: Option<ExpertShard>,
+    remotes: HashMap<Node, (ExpertShard, LayerRemoteInfo)>,
+}
+
+impl<Node> Default for ExpertTable<Node> {
+    fn default() -> Self {
+        Self {
+            num_experts: 0,
+            local: None,
+            remotes: HashMap::new(),
+        }
+    }
+}
+
+impl<Node: Clone + Debug + Eq + Hash> ExpertTable<Node> {
+    pub fn new(num_experts: u32, local: ExpertShard) -> Self {
+        Self {
+            num_experts,
+            local: Some(local),
+            remotes: HashMap::new(),
+        }
+    }
+
+    pub fn local(&self) -> Option<&ExpertShard> {
+        self.local.as_ref()
+    }
+
+    pub fn on_tick(&mut self, now_ms: u64) {
+        let pre = self.remotes.len();
+        self.remotes.retain(|_node, (_, info)| info.last_updated + ROUTE_TIMEOUT_MS > now_ms);
+        if self.remotes.len() != pre {
+            log::info!("experts remove {} timeout remotes", pre - self.remotes.len());
+        }
+    }
+
+    pub fn on_disconnected(&mut self, node: &Node) {
+        self.remotes.remove(node);
+    }
+
+    pub fn apply_sync(&mut self, from: Node, rtt: u32, experts: Option<(ExpertShard, LayerRemoteInfo)>) {
+        match experts {
+            Some((shard, mut info)) => {
+                info.cost += rtt;
+                self.remotes.insert(from, (shard, info));
+            }
+            None => {
+                self.remotes.remove(&from);
+            }
+        }
+    }
+
+    /// Every expert of the `layers` has an owner
+    pub fn complete(&self, layers: Range<u32>) -> bool {
+        layers.into_iter().all(|layer| (0..self.num_experts).all(|expert| self.owner(layer, expert).is_some()))
+    }
+
+    /// Group the `experts` of the `layer` by their cheapest owner, the local node first.
+    /// None when an expert has no owner
+    pub fn select(&self, layer: u32, experts: &[u32]) -> Option<Vec<ExpertPath<Node>>> {
+        let mut paths: Vec<ExpertPath<Node>> = vec![];
+        for expert in experts {
+            let node = self.owner(layer, *expert)?;
+            match paths.iter_mut().find(|path| path.node == node) {
+                Some(path) => path.experts.push(*expert),
+                None => paths.push(ExpertPath { node, experts: vec![*expert] }),
+            }
+        }
+        paths.sort_by_key(|path| path.node.is_some());
+        Some(paths)
+    }
+
+    fn owner(&self, layer: u32, expert: u32) -> Option<Option<Node>> {
+        if self.local.as_ref().is_some_and(|local| local.contains(layer, expert)) {
+            return Some(None);
+        }
+        self.remotes
+            .iter()
+            .filter(|(_, (shard, _))| shard.contains(layer, expert))
+            .min_by_key(|(_, (_, info))| info.cost)
+            .map(|(node, _)| Some(node.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+
+    use crate::{table::ROUTE_TIMEOUT_MS, ExpertPath, ExpertShard, LayerRemoteInfo, RouteSync, RouteTable};
+
+    const MODEL_LAYERS: u32 = 2;
+    const NUM_EXPERTS: u32 = 4;
+
+    fn sync(experts: Range<u32>, cost: u32) -> RouteSync {
+        RouteSync {
+            layers: vec![Some(LayerRemoteInfo { cost, last_updated: 100 }); MODEL_LAYERS as usize],
+            experts: Some((ExpertShard { layers: 0..MODEL_LAYERS, experts }, LayerRemoteInfo { cost, last_updated: 100 })),
+        }
+    }
+
+    #[test]
+    fn dispatch_experts_to_owners() {
+        let mut table = RouteTable::<u8>::new(MODEL_LAYERS, 0..MODEL_LAYERS).with_experts(NUM_EXPERTS, 0..2);
+        assert!(!table.ready());
+        assert_eq!(table.select_experts(0, &[1, 3]), None);
+
+        table.apply_sync(2, 10, sync(2..4, 0));
+        table.apply_sync(3, 30, sync(3..4, 0));
+        assert!(table.ready());
+        assert_eq!(
+            table.select_experts(1, &[3, 0, 2, 1]),
+            Some(vec![ExpertPath { node: None, experts: vec![0, 1] }, ExpertPath { node: Some(2), experts: vec![3, 2] }])
+        );
+
+        // the cheapest owner of an expert goes away
+        table.on_disconnected(2);
+        assert!(!table.ready());
+        assert_eq!(table.select_experts(1, &[3]), Some(vec![ExpertPath { node: Some(3), experts: vec![3] }]));
+        table.on_tick(100 + ROUTE_TIMEOUT_MS);
+        assert_eq!(table.select_experts(1, &[3]), None);
+    }
+
+    #[test]
+    fn dense_model_without_experts() {
+        let table = RouteTable::<u8>::new(MODEL_LAYERS, 0..MODEL_LAYERS);
+        assert!(table.ready());
+        assert_eq!(table.create_sync(100).experts, None);
+    }
+}
@@ -1,2 +1,4 @@
+mod experts;
 mod table;
-pub use table::*;
\ No newline at end of file
+pub use experts::*;
+pub use table::*;
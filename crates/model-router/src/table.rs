@@ -1,6 +1,8 @@
 use std::{collections::HashMap, fmt::Debug, hash::Hash, ops::Range};
 
-const ROUTE_TIMEOUT_MS: u64 = 5_000; // a route will be removed after 5 seconds no update
+use crate::{ExpertPath, ExpertShard, ExpertTable};
+
+pub(crate) const ROUTE_TIMEOUT_MS: u64 = 5_000; // a route will be removed after 5 seconds no update
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct RoutePath<Node> {
@@ -45,6 +47,8 @@ impl LayerRemoteInfo {
 #[derive(Debug, PartialEq)]
 pub struct RouteSync {
     pub layers: Vec<Option<LayerRemoteInfo>>,
+    /// experts held by the node itself, for the mixture-of-experts models
+    pub experts: Option<(ExpertShard, LayerRemoteInfo)>,
 }
 
 impl RouteSync {
@@ -53,28 +57,50 @@ impl RouteSync {
         for (i, layer) in self.layers.iter().enumerate() {
             log::info!("layer {i}: {:?}", layer);
         }
+        log::info!("experts: {:?}", self.experts);
         log::info!("==========end dump RouteSync==========");
     }
 }
 
-pub struct RouteTable<Node, const MODEL_LAYERS: usize> {
-    remote_layers: [LayerRemotePaths<Node>; MODEL_LAYERS],
+pub struct RouteTable<Node> {
+    remote_layers: Vec<LayerRemotePaths<Node>>,
     local_layers: Range<u32>,
+    model_layers: u32,
+    experts: ExpertTable<Node>,
 }
 
-impl<Node: Clone + Debug + Eq + Hash, const MODEL_LAYERS: usize> RouteTable<Node, MODEL_LAYERS> {
-    pub fn new(local_layers: Range<u32>) -> Self {
+impl<Node: Clone + Debug + Eq + Hash> RouteTable<Node> {
+    pub fn new(model_layers: u32, local_layers: Range<u32>) -> Self {
         Self {
-            remote_layers: std::array::from_fn(|_| LayerRemotePaths {
-                remotes: Default::default(),
-                next: None,
-            }),
+            remote_layers: (0..model_layers)
+                .map(|_| LayerRemotePaths {
+                    remotes: Default::default(),
+                    next: None,
+                })
+                .collect(),
             local_layers,
+            model_layers,
+            experts: ExpertTable::default(),
         }
     }
 
+    /// The layers are mixture-of-experts layers of `num_experts` experts, of which only `experts` are local
+    pub fn with_experts(mut self, num_experts: u32, experts: Range<u32>) -> Self {
+        let local = ExpertShard {
+            layers: self.local_layers.clone(),
+            experts,
+        };
+        self.experts = ExpertTable::new(num_experts, local);
+        self
+    }
+
+    pub fn model_layers(&self) -> u32 {
+        self.model_layers
+    }
+
+    /// The chain reaches the last layer and the local layers have an owner for each of their experts
     pub fn ready(&self) -> bool {
-        self.select_next(0).is_some()
+        self.select_next(0).is_some() && self.experts.complete(self.local_layers.clone())
     }
 
     pub fn on_tick(&mut self, now_ms: u64) {
@@ -86,6 +112,7 @@ impl<Node: Clone + Debug + Eq + Hash, const MODEL_LAYERS: usize> RouteTable<Node
                 route.update_best();
             }
         }
+        self.experts.on_tick(now_ms);
     }
 
     pub fn on_disconnected(&mut self, node: Node) {
@@ -94,25 +121,27 @@ impl<Node: Clone + Debug + Eq + Hash, const MODEL_LAYERS: usize> RouteTable<Node
                 route.update_best();
             }
         }
+        self.experts.on_disconnected(&node);
     }
 
     pub fn create_sync(&self, now_ms: u64) -> RouteSync {
         // log::info!("create sync");
-        let mut layers = vec![None; MODEL_LAYERS];
-        for layer in 0..MODEL_LAYERS {
+        let mut layers = vec![None; self.model_layers as usize];
+        for layer in 0..self.model_layers as usize {
             layers[layer] = self.select_next(layer as u32).map(|n: RoutePath<Node>| LayerRemoteInfo {
                 cost: n.cost(),
                 last_updated: n.last_updated().unwrap_or(now_ms),
             });
         }
-        RouteSync { layers }
+        let experts = self.experts.local().map(|shard| (shard.clone(), LayerRemoteInfo::new(0, now_ms)));
+        RouteSync { layers, experts }
     }
 
     /// When we received a sync message from other node => we find if local layers can contribute to it
     /// We only care about
     pub fn apply_sync(&mut self, from: Node, rtt: u32, sync: RouteSync) {
         // log::info!("apply sync from {from:?} with rtt {rtt}");
-        for layer in 0..MODEL_LAYERS {
+        for layer in 0..self.model_layers as usize {
             if let Some(Some(info)) = sync.layers.get(layer) {
                 let mut info = info.clone();
                 info.cost += rtt;
@@ -122,43 +151,49 @@ impl<Node: Clone + Debug + Eq + Hash, const MODEL_LAYERS: usize> RouteTable<Node
             }
             self.remote_layers[layer].update_best();
         }
+        self.experts.apply_sync(from, rtt, sync.experts);
     }
 
     pub fn select_next(&self, next_layer: u32) -> Option<RoutePath<Node>> {
         if self.local_layers.contains(&next_layer) {
             // if we can process some in local
-            if self.local_layers.end == MODEL_LAYERS as u32 {
+            if self.local_layers.end == self.model_layers {
                 // if this is last
                 Some(RoutePath {
-                    local: Some(next_layer..MODEL_LAYERS as u32),
+                    local: Some(next_layer..self.model_layers),
                     remote: None,
                 })
             } else {
                 // if we need the help from other node
                 self.remote_layers[self.local_layers.end as usize].next.as_ref().map(|(dest, info)| RoutePath {
                     local: Some(next_layer..self.local_layers.end),
-                    remote: Some((dest.clone(), self.local_layers.end..MODEL_LAYERS as u32, info.cost, info.last_updated)),
+                    remote: Some((dest.clone(), self.local_layers.end..self.model_layers, info.cost, info.last_updated)),
                 })
             }
         } else {
             self.remote_layers[next_layer as usize].next.as_ref().map(|(dest, info)| RoutePath {
                 local: None,
-                remote: Some((dest.clone(), next_layer..MODEL_LAYERS as u32, info.cost, info.last_updated)),
+                remote: Some((dest.clone(), next_layer..self.model_layers, info.cost, info.last_updated)),
             })
         }
     }
 
+    /// Group the `experts` of the `layer` by the node which runs them, None when an expert has no owner
+    pub fn select_experts(&self, layer: u32, experts: &[u32]) -> Option<Vec<ExpertPath<Node>>> {
+        self.experts.select(layer, experts)
+    }
+
     fn dump(&self) {
         log::info!("==========start dump==========");
         log::info!("local layers: {:?}", self.local_layers);
         log::info!("start remote layers");
-        for layer in 0..MODEL_LAYERS {
+        for layer in 0..self.model_layers as usize {
             println!("layer {layer}: {:?}", self.remote_layers[layer].next);
         }
         log::info!("end remote layers");
         log::info!("start select next");
-        for layer in 0..MODEL_LAYERS {
-            let next = self.select_next(layer as u32);
+        for layer in 0..self.model_layers {
+            let next = self.select_next(layer);
             println!("layer {layer}: {next:?}");
         }
         log::info!("end select next");
@@ -177,15 +212,18 @@ mod tests {
     use crate::{table::ROUTE_TIMEOUT_MS, LayerRemoteInfo, RouteSync};
 
     type RoutePath = super::RoutePath<u8>;
-    type RouteTable = super::RouteTable<u8, 3>;
+    type RouteTable = super::RouteTable<u8>;
+
+    const MODEL_LAYERS: u32 = 3;
 
     #[test]
     fn full_table() {
-        let table = RouteTable::new(0..3);
+        let table = RouteTable::new(MODEL_LAYERS, 0..3);
         assert_eq!(
             table.create_sync(100),
             RouteSync {
-                layers: vec![Some(LayerRemoteInfo::new(0, 100)), Some(LayerRemoteInfo::new(0, 100)), Some(LayerRemoteInfo::new(0, 100))]
+                layers: vec![Some(LayerRemoteInfo::new(0, 100)), Some(LayerRemoteInfo::new(0, 100)), Some(LayerRemoteInfo::new(0, 100))],
+                experts: None,
             }
         );
 
@@ -198,11 +236,12 @@ mod tests {
 
     #[test]
     fn imcomplete_right() {
-        let table = RouteTable::new(1..3);
+        let table = RouteTable::new(MODEL_LAYERS, 1..3);
         assert_eq!(
             table.create_sync(100),
             RouteSync {
-                layers: vec![None, Some(LayerRemoteInfo::new(0, 100)), Some(LayerRemoteInfo::new(0, 100))]
+                layers: vec![None, Some(LayerRemoteInfo::new(0, 100)), Some(LayerRemoteInfo::new(0, 100))],
+                experts: None,
             }
         );
 
@@ -215,8 +254,14 @@ mod tests {
 
     #[test]
     fn imcomplete_left() {
-        let table = RouteTable::new(0..2);
-        assert_eq!(table.create_sync(100), RouteSync { layers: vec![None, None, None] });
+        let table = RouteTable::new(MODEL_LAYERS, 0..2);
+        assert_eq!(
+            table.create_sync(100),
+            RouteSync {
+                layers: vec![None, None, None],
+                experts: None
+            }
+        );
 
         assert_eq!(table.select_next(0), None);
         assert_eq!(table.select_next(1), None);
@@ -225,7 +270,7 @@ mod tests {
 
     #[test]
     fn imcomplete_right_sync() {
-        let mut table = RouteTable::new(1..3);
+        let mut table = RouteTable::new(MODEL_LAYERS, 1..3);
 
         const REMOTE_NODE: u8 = 2;
         const RTT: u32 = 10;
@@ -235,6 +280,7 @@ mod tests {
             RTT,
             RouteSync {
                 layers: vec![Some(LayerRemoteInfo::new(10, 100)), Some(LayerRemoteInfo::new(10, 100)), Some(LayerRemoteInfo::new(10, 100))],
+                experts: None,
             },
         );
 
@@ -253,7 +299,7 @@ mod tests {
 
     #[test]
     fn imcomplete_left_sync() {
-        let mut table = RouteTable::new(0..1);
+        let mut table = RouteTable::new(MODEL_LAYERS, 0..1);
 
         const REMOTE_NODE: u8 = 2;
         const RTT: u32 = 10;
@@ -263,6 +309,7 @@ mod tests {
             RTT,
             RouteSync {
                 layers: vec![None, Some(LayerRemoteInfo::new(0, 100)), Some(LayerRemoteInfo::new(0, 100))],
+                experts: None,
             },
         );
 
@@ -293,7 +340,7 @@ mod tests {
 
     #[test]
     fn remote_timeout() {
-        let mut table = RouteTable::new(0..1);
+        let mut table = RouteTable::new(MODEL_LAYERS, 0..1);
 
         const REMOTE_NODE: u8 = 2;
         const RTT: u32 = 10;
@@ -303,6 +350,7 @@ mod tests {
             RTT,
             RouteSync {
                 layers: vec![None, Some(LayerRemoteInfo::new(0, 100)), Some(LayerRemoteInfo::new(0, 100))],
+                experts: None,
             },
         );
 
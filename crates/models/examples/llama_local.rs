@@ -3,22 +3,27 @@ use models::{
     get_device,
     llama::{new_layers, LlamaLayersWorker, LlamaModel, ModelResource},
     remote::TensorBuf,
-    ChatModel, ModelLayersWorker,
+    ChatModel, KvBudgetConfig, ModelLayersWorker, ResourceSource,
 };
-use protocol::{ChatCfg, Session};
+use protocol::{ChatCfg, ChatEvent, ModelArchitecture, PromptTemplate, Session};
 use tokio::time::Instant;
 
 #[tokio::main]
 async fn main() {
     let device = get_device(false).unwrap();
     let resource = ModelResource {
+        architecture: ModelArchitecture::Llama,
         repo: "unsloth/Llama-3.2-1B-Instruct".to_string(),
+        tokenizer_repo: "unsloth/Llama-3.2-1B-Instruct".to_string(),
         model: "model.safetensors".to_string(),
         config: "config.json".to_string(),
         tokenizer: "tokenizer.json".to_string(),
+        tokenizer_config: Some("tokenizer_config.json".to_string()),
+        prompt_template: PromptTemplate::Llama3,
+        source: ResourceSource::default(),
     };
     let layers_worker = VirtualRemoteLayersWorker::new(&resource, device.clone()).await;
-    let llama = LlamaModel::new(&resource, device, DType::F16, layers_worker, false).await;
+    let llama = LlamaModel::new(&resource, device, DType::F16, layers_worker, false).await.unwrap();
     let (tx, mut rx) = tokio::sync::mpsc::channel(100);
     tokio::spawn(async move {
         llama.chat(Session::new(), ChatCfg::default(), "hello", tx).await.unwrap();
@@ -26,9 +31,11 @@ async fn main() {
 
     let begin = Instant::now();
     let mut count = 0;
-    while let Some(text) = rx.recv().await {
-        print!("{text}");
-        count += 1;
+    while let Some(event) = rx.recv().await {
+        if let ChatEvent::Delta { text, .. } = event {
+            print!("{text}");
+            count += 1;
+        }
     }
     println!(
         "\n{count} tokens in {:2} seconds => speed {:2}/s",
@@ -44,7 +51,7 @@ struct VirtualRemoteLayersWorker {
 
 impl VirtualRemoteLayersWorker {
     async fn new(resource: &ModelResource, device: Device) -> Self {
-        let layers_worker = new_layers(resource, DType::F16, device.clone(), false, 0..16).await;
+        let layers_worker = new_layers(resource, DType::F16, device.clone(), false, 0..16, KvBudgetConfig::default()).await.unwrap();
         Self { layers_worker, device }
     }
 }
@@ -1,18 +1,19 @@
 use candle_core::{Device, Result, Tensor};
 use models::{
     get_device,
-    phi3::{Phi3LayersWorker, Phi3Model},
+    phi3::{Phi3LayersWorker, Phi3Model, Phi3Resource},
     remote::TensorBuf,
-    ChatModel, ModelLayersWorker,
+    ChatModel, KvBudgetConfig, ModelLayersWorker,
 };
-use protocol::{ChatCfg, Session};
+use protocol::{ChatCfg, ChatEvent, Session};
 use tokio::time::Instant;
 
 #[tokio::main]
 async fn main() {
     let device = get_device(false).unwrap();
-    let layers_worker = VirtualRemoteLayersWorker::new(&device).await;
-    let phi3 = Phi3Model::new(device, layers_worker).await;
+    let resource = Phi3Resource::default();
+    let layers_worker = VirtualRemoteLayersWorker::new(&resource, &device).await;
+    let phi3 = Phi3Model::new(&resource, device, layers_worker).await.unwrap();
     let (tx, mut rx) = tokio::sync::mpsc::channel(100);
     tokio::spawn(async move {
         phi3.chat(Session::new(), ChatCfg::default(), "Write function max(x1, x2) in Rust", tx).await.unwrap();
@@ -20,9 +21,11 @@ async fn main() {
 
     let begin = Instant::now();
     let mut count = 0;
-    while let Some(text) = rx.recv().await {
-        print!("{text}");
-        count += 1;
+    while let Some(event) = rx.recv().await {
+        if let ChatEvent::Delta { text, .. } = event {
+            print!("{text}");
+            count += 1;
+        }
     }
     println!(
         "\n{count} tokens in {:2} seconds => speed {:2}/s",
@@ -37,8 +40,9 @@ struct VirtualRemoteLayersWorker {
 }
 
 impl VirtualRemoteLayersWorker {
-    async fn new(device: &Device) -> Self {
-        let layers_worker = Phi3LayersWorker::new(false, 0..31, &device).await.unwrap();
+    async fn new(resource: &Phi3Resource, device: &Device) -> Self {
+        let layers = resource.num_layers().await.unwrap();
+        let layers_worker = Phi3LayersWorker::new(resource, false, 0..layers, &device, KvBudgetConfig::default()).await.unwrap();
         Self {
             layers_worker,
             device: device.clone(),
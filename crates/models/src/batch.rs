@@ -0,0 +1,391 @@
+use std::{
+    collections::HashSet,
+    future::Future,
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use candle_core::{bail, Error, Result, Tensor};
+use protocol::{llm::PrefixKey, ChatCfg, Session};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+
+use crate::{CrossAttentionStates, KvUsage, ModelLayersWorker, RemoteExperts};
+
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    /// max decode steps forwarded together
+    pub max_batch_size: usize,
+    /// how long the first step of a batch waits for the steps of other sessions
+    pub window: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 16,
+            window: Duration::from_millis(2),
+        }
+    }
+}
+
+struct BatchItem {
+    session: Session,
+    step: u32,
+    embedding: (Tensor, u32),
+    index_pos: u32,
+    res: oneshot::Sender<Result<(Tensor, u32)>>,
+}
+
+/// Gather the decode steps of concurrent sessions and run them as one `forward_batch` of the inner worker.
+///
+/// Prefill chunks are bigger than one token, they are forwarded directly.
+pub struct BatchScheduler<LW> {
+    inner: Arc<LW>,
+    active: Arc<AtomicUsize>,
+    tx: mpsc::Sender<BatchItem>,
+}
+
+impl<LW: ModelLayersWorker<(Tensor, u32)>> BatchScheduler<LW> {
+    pub fn new(inner: LW, cfg: BatchConfig) -> Self {
+        let inner = Arc::new(inner);
+        let active = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = mpsc::channel(cfg.max_batch_size.max(1) * 4);
+        tokio::spawn(run_batches(inner.clone(), active.clone(), rx, cfg));
+        Self { inner, active, tx }
+    }
+}
+
+#[async_trait::async_trait]
+impl<LW: ModelLayersWorker<(Tensor, u32)>> ModelLayersWorker<(Tensor, u32)> for BatchScheduler<LW> {
+    async fn start(&self, session: Session, config: ChatCfg) -> Result<()> {
+        self.inner.start(session, config).await?;
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn start_with_prefix(&self, session: Session, config: ChatCfg, prefixes: Vec<PrefixKey>) -> Result<u32> {
+        let cached = self.inner.start_with_prefix(session, config, prefixes).await?;
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Ok(cached)
+    }
+
+    async fn forward(&self, session: Session, step: u32, embedding: (Tensor, u32), index_pos: u32) -> Result<(Tensor, u32)> {
+        if embedding.1 != 1 {
+            return self.inner.forward(session, step, embedding, index_pos).await;
+        }
+        let (res, rx) = oneshot::channel();
+        let item = BatchItem {
+            session,
+            step,
+            embedding,
+            index_pos,
+            res,
+        };
+        if self.tx.send(item).await.is_err() {
+            bail!("batch scheduler stopped")
+        }
+        rx.await.map_err(Error::wrap)?
+    }
+
+    async fn forward_batch(&self, batch: Vec<(Session, u32, (Tensor, u32), u32)>) -> Vec<Result<(Tensor, u32)>> {
+        self.inner.forward_batch(batch).await
+    }
+
+    async fn rollback(&self, session: Session, count: u32) -> Result<()> {
+        self.inner.rollback(session, count).await
+    }
+
+    async fn fork(&self, session: Session, forked: Session) -> Result<()> {
+        self.inner.fork(session, forked).await?;
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn finish(&self, session: Session) {
+        self.inner.finish(session).await;
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn kv_usage(&self) -> KvUsage {
+        self.inner.kv_usage()
+    }
+
+    fn local_experts(&self) -> Option<(u32, Range<u32>)> {
+        self.inner.local_experts()
+    }
+
+    fn set_remote_experts(&self, remote: Arc<dyn RemoteExperts>) {
+        self.inner.set_remote_experts(remote)
+    }
+
+    async fn forward_experts(&self, layer: u32, inputs: Vec<(u32, Tensor)>) -> Result<Vec<Tensor>> {
+        self.inner.forward_experts(layer, inputs).await
+    }
+
+    async fn set_cross_attention(&self, session: Session, states: CrossAttentionStates) -> Result<()> {
+        self.inner.set_cross_attention(session, states).await
+    }
+
+    fn cross_attention_layers(&self) -> &[u32] {
+        self.inner.cross_attention_layers()
+    }
+}
+
+async fn run_batches<LW: ModelLayersWorker<(Tensor, u32)>>(inner: Arc<LW>, active: Arc<AtomicUsize>, mut rx: mpsc::Receiver<BatchItem>, cfg: BatchConfig) {
+    // a session appears once per batch, a second step of it waits for the next batch
+    let mut deferred: Vec<BatchItem> = vec![];
+    loop {
+        let mut batch: Vec<BatchItem> = vec![];
+        let mut sessions = HashSet::new();
+        for item in std::mem::take(&mut deferred) {
+            if sessions.insert(item.session) {
+                batch.push(item);
+            } else {
+                deferred.push(item);
+            }
+        }
+        if batch.is_empty() {
+            match rx.recv().await {
+                Some(item) => {
+                    sessions.insert(item.session);
+                    batch.push(item);
+                }
+                None => break,
+            }
+        }
+
+        // no need to wait when every active session is already in the batch
+        let deadline = Instant::now() + cfg.window;
+        while batch.len() < cfg.max_batch_size.min(active.load(Ordering::Relaxed)) {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(item)) if sessions.insert(item.session) => batch.push(item),
+                Ok(Some(item)) => deferred.push(item),
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        log::debug!("[BatchScheduler] forward batch of {} sessions", batch.len());
+        let (items, senders): (Vec<_>, Vec<_>) = batch.into_iter().map(|i| ((i.session, i.step, i.embedding, i.index_pos), i.res)).unzip();
+        for (res, output) in senders.into_iter().zip(inner.forward_batch(items).await) {
+            let _ = res.send(output);
+        }
+    }
+}
+
+/// Outputs of the items of a batch, None until the item is forwarded
+type Outputs = Vec<Option<Result<(Tensor, u32)>>>;
+
+/// Items of a batch which passed their checks, in the order of the batch
+struct Stacked<T> {
+    /// index of each item in the batch
+    idxs: Vec<usize>,
+    prepared: Vec<T>,
+    embeddings: Vec<Tensor>,
+    seq_len: u32,
+}
+
+/// Run `forward(prepared, embeddings, seq_len)` on the stacked embeddings of a batch and split the output back per item.
+///
+/// Each item is checked and `prepare(session, index_pos, seq_len)` is run for it first, an item which fails only fails itself
+/// and the others are forwarded without it.
+pub(crate) fn forward_stacked<T, P, F>(batch: Vec<(Session, u32, (Tensor, u32), u32)>, prepare: P, forward: F) -> Vec<Result<(Tensor, u32)>>
+where
+    P: FnMut(Session, u32, u32) -> Result<T>,
+    F: FnOnce(Vec<T>, Tensor, u32) -> Result<Tensor>,
+{
+    let (mut outputs, stacked) = stack_batch(batch, prepare);
+    if !stacked.idxs.is_empty() {
+        let ys = Tensor::cat(&stacked.embeddings, 0).and_then(|xs| forward(stacked.prepared, xs, stacked.seq_len));
+        unstack_outputs(&mut outputs, &stacked.idxs, ys, stacked.seq_len);
+    }
+    outputs.into_iter().map(|output| output.expect("Should have the output of every item")).collect()
+}
+
+/// Same as `forward_stacked` for the workers which wait for other nodes in the middle of their layers
+pub(crate) async fn forward_stacked_async<T, P, F, Fut>(batch: Vec<(Session, u32, (Tensor, u32), u32)>, prepare: P, forward: F) -> Vec<Result<(Tensor, u32)>>
+where
+    P: FnMut(Session, u32, u32) -> Result<T>,
+    F: FnOnce(Vec<T>, Tensor, u32) -> Fut,
+    Fut: Future<Output = Result<Tensor>>,
+{
+    let (mut outputs, stacked) = stack_batch(batch, prepare);
+    if !stacked.idxs.is_empty() {
+        let ys = match Tensor::cat(&stacked.embeddings, 0) {
+            Ok(xs) => forward(stacked.prepared, xs, stacked.seq_len).await,
+            Err(e) => Err(e),
+        };
+        unstack_outputs(&mut outputs, &stacked.idxs, ys, stacked.seq_len);
+    }
+    outputs.into_iter().map(|output| output.expect("Should have the output of every item")).collect()
+}
+
+/// Split the stacked output of the items at `idxs` of the batch, or give each of them the error of the forward
+fn unstack_outputs(outputs: &mut Outputs, idxs: &[usize], ys: Result<Tensor>, seq_len: u32) {
+    match ys.and_then(|ys| (0..idxs.len()).map(|i| ys.narrow(0, i, 1)).collect::<Result<Vec<_>>>()) {
+        Ok(ys) => {
+            for (idx, ys) in idxs.iter().zip(ys) {
+                outputs[*idx] = Some(Ok((ys, seq_len)));
+            }
+        }
+        Err(e) => {
+            log::error!("[BatchScheduler] forward batch error {e}");
+            for idx in idxs {
+                outputs[*idx] = Some(Err(Error::Msg(e.to_string())));
+            }
+        }
+    }
+}
+
+/// Check the items of a batch can run together and prepare them, the items which fail get their error in the outputs
+fn stack_batch<T>(batch: Vec<(Session, u32, (Tensor, u32), u32)>, mut prepare: impl FnMut(Session, u32, u32) -> Result<T>) -> (Outputs, Stacked<T>) {
+    let seq_len = batch.first().map_or(0, |(_, _, (_, seq_len), _)| *seq_len);
+    let mut outputs = Vec::with_capacity(batch.len());
+    let mut stacked = Stacked {
+        idxs: vec![],
+        prepared: vec![],
+        embeddings: vec![],
+        seq_len,
+    };
+    let mut sessions = HashSet::new();
+    for (idx, (session, _step, (embedding, len), pos)) in batch.into_iter().enumerate() {
+        let prepared = if len != seq_len {
+            Err(Error::Msg(format!("batch mixes seq_len {seq_len} and {len}")))
+        } else if !sessions.insert(session) {
+            Err(Error::Msg(format!("session {session} twice in a batch")))
+        } else {
+            prepare(session, pos, seq_len)
+        };
+        match prepared {
+            Ok(prepared) => {
+                stacked.idxs.push(idx);
+                stacked.prepared.push(prepared);
+                stacked.embeddings.push(embedding);
+                outputs.push(None);
+            }
+            Err(e) => {
+                log::warn!("[BatchScheduler] session {session} left out of the batch: {e}");
+                outputs.push(Some(Err(e)));
+            }
+        }
+    }
+    (outputs, stacked)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use candle_core::{Device, Result, Tensor};
+    use protocol::{ChatCfg, Session};
+    use spin::Mutex;
+
+    use super::{forward_stacked, BatchConfig, BatchScheduler};
+    use crate::ModelLayersWorker;
+
+    #[derive(Default)]
+    struct RecordWorker {
+        batches: Mutex<Vec<usize>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ModelLayersWorker<(Tensor, u32)> for RecordWorker {
+        async fn start(&self, _session: Session, _config: ChatCfg) -> Result<()> {
+            Ok(())
+        }
+
+        async fn forward(&self, _session: Session, _step: u32, embedding: (Tensor, u32), _index_pos: u32) -> Result<(Tensor, u32)> {
+            Ok(embedding)
+        }
+
+        async fn forward_batch(&self, batch: Vec<(Session, u32, (Tensor, u32), u32)>) -> Vec<Result<(Tensor, u32)>> {
+            self.batches.lock().push(batch.len());
+            forward_stacked(batch, |_, _, _| Ok(()), |_, xs, _| xs * 2.)
+        }
+
+        async fn fork(&self, _session: Session, _forked: Session) -> Result<()> {
+            Ok(())
+        }
+
+        async fn finish(&self, _session: Session) {}
+    }
+
+    #[tokio::test]
+    async fn batch_concurrent_sessions() {
+        let cfg = BatchConfig {
+            max_batch_size: 4,
+            window: Duration::from_millis(500),
+        };
+        let scheduler = BatchScheduler::new(RecordWorker::default(), cfg);
+        let sessions = (0..3).map(|_| Session::new()).collect::<Vec<_>>();
+        for session in &sessions {
+            scheduler.start(*session, ChatCfg::default()).await.unwrap();
+        }
+
+        let forwards = sessions.iter().enumerate().map(|(i, session)| {
+            let xs = Tensor::new(&[[[i as f32]]], &Device::Cpu).unwrap();
+            scheduler.forward(*session, 1, (xs, 1), 5)
+        });
+        let outputs = futures_util::future::join_all(forwards).await;
+        let outputs = outputs.into_iter().map(|o| o.unwrap().0.flatten_all().unwrap().to_vec1::<f32>().unwrap()).collect::<Vec<_>>();
+        assert_eq!(outputs, vec![vec![0.], vec![2.], vec![4.]]);
+        // the batch is full once every active session is in, without waiting the whole window
+        assert_eq!(*scheduler.inner.batches.lock(), vec![3]);
+    }
+
+    #[test]
+    fn failing_items_leave_the_batch() {
+        let xs = |x: f32, len: usize| (Tensor::full(x, (1, len, 1), &Device::Cpu).unwrap(), len as u32);
+        let (a, b, c) = (Session::new(), Session::new(), Session::new());
+        // b is twice in the batch, c has another seq_len and the session at 9 fails its prepare
+        let failing = Session::new();
+        let batch = vec![(a, 1, xs(1., 1), 3), (b, 1, xs(2., 1), 3), (b, 1, xs(3., 1), 4), (c, 1, xs(4., 2), 3), (failing, 1, xs(5., 1), 9)];
+        let outputs = forward_stacked(
+            batch,
+            |session, pos, _| {
+                if pos == 9 {
+                    candle_core::bail!("session {session} was evicted")
+                } else {
+                    Ok(session)
+                }
+            },
+            |sessions, xs, _| {
+                assert_eq!(sessions, vec![a, b]);
+                xs * 2.
+            },
+        );
+        let outputs = outputs
+            .into_iter()
+            .map(|o| o.ok().map(|(ys, _)| ys.flatten_all().unwrap().to_vec1::<f32>().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(outputs, vec![Some(vec![2.]), Some(vec![4.]), None, None, None]);
+    }
+
+    #[tokio::test]
+    async fn forked_sessions_are_active() {
+        let cfg = BatchConfig {
+            max_batch_size: 4,
+            window: Duration::from_secs(30),
+        };
+        let scheduler = BatchScheduler::new(RecordWorker::default(), cfg);
+        let (session, forked) = (Session::new(), Session::new());
+        scheduler.start(session, ChatCfg::default()).await.unwrap();
+        scheduler.fork(session, forked).await.unwrap();
+        scheduler.finish(forked).await;
+        scheduler.finish(session).await;
+
+        // a lone session does not wait for the window
+        let lone = Session::new();
+        scheduler.start(lone, ChatCfg::default()).await.unwrap();
+        let xs = Tensor::new(&[[[1f32]]], &Device::Cpu).unwrap();
+        let forward = scheduler.forward(lone, 1, (xs, 1), 0);
+        tokio::time::timeout(Duration::from_secs(5), forward).await.expect("lone session waited for the window").unwrap();
+        assert_eq!(*scheduler.inner.batches.lock(), vec![1]);
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::HashSet;
+
+use candle_core::{bail, Result, Tensor, D};
+use futures_util::future::join_all;
+use protocol::{BeamSearch, ChatCfg, ChatEvent, FinishReason, Session};
+use tokio::sync::mpsc::Sender;
+
+use crate::{chat_output::ChatOutput, conversation::ChatSteps, utils::apply_penalties};
+
+struct Beam {
+    /// None while the beam is the prompt session, which is forked but never forwarded
+    session: Option<Session>,
+    tokens: Vec<u32>,
+    /// sum of the logprobs of the tokens
+    logprob: f32,
+    logits: Tensor,
+}
+
+/// An answer which ended with an EOS token, or at `max_len`
+struct Hypothesis {
+    tokens: Vec<u32>,
+    score: f32,
+    stopped: bool,
+}
+
+fn score(logprob: f32, len: usize, length_penalty: f32) -> f32 {
+    logprob / (len.max(1) as f32).powf(length_penalty)
+}
+
+/// Answer with the most likely of the `width` beams kept at every step.
+///
+/// Every beam runs in its own session forked from the prompt session, whose kv caches keep only the prompt.
+/// The decode steps of the beams run concurrently, so the layers workers batch them
+pub(crate) async fn beam_search<M: ChatSteps>(model: &M, session: Session, cfg: ChatCfg, beam: BeamSearch, tokens: &[u32], prefilled: (Tensor, u32), tx: Sender<ChatEvent>) -> Result<FinishReason> {
+    if cfg.constraint.is_some() || cfg.logprobs.is_some() {
+        bail!("beam search does not support constraints and logprobs")
+    }
+    let mut beams = vec![Beam {
+        session: None,
+        tokens: vec![],
+        logprob: 0.,
+        logits: model.logits(session, prefilled).await?,
+    }];
+    let res = search(model, session, &cfg, beam, tokens, &mut beams).await;
+    for session in beams.iter().filter_map(|beam| beam.session) {
+        model.finish(session).await;
+    }
+    let best = res?;
+
+    let mut output = ChatOutput::new(model.tokenizer().clone(), &cfg, tx);
+    let mut finish_reason = if best.stopped {
+        FinishReason::Stop
+    } else {
+        FinishReason::Length
+    };
+    for token in best.tokens {
+        if let Some(reason) = output.push_token(token, None).await? {
+            finish_reason = reason;
+            break;
+        }
+    }
+    if let Some(reason) = output.finish().await? {
+        finish_reason = reason;
+    }
+    Ok(finish_reason)
+}
+
+async fn search<M: ChatSteps>(model: &M, session: Session, cfg: &ChatCfg, beam: BeamSearch, prompt: &[u32], beams: &mut Vec<Beam>) -> Result<Hypothesis> {
+    let width = beam.width.max(1) as usize;
+    let eos_tokens = model.eos_tokens().into_iter().chain(cfg.stop_token_ids.iter().copied()).collect::<HashSet<_>>();
+    let mut finished: Vec<Hypothesis> = vec![];
+
+    for step in 0..cfg.max_len {
+        // the best continuations of every beam, enough of them are not EOS to fill the next beams
+        let mut candidates = vec![];
+        for (idx, beam) in beams.iter().enumerate() {
+            let context = [prompt, &beam.tokens].concat();
+            let logits = apply_penalties(beam.logits.clone(), cfg, &context, &beam.tokens)?;
+            let logprobs = candle_nn::ops::log_softmax(&logits, D::Minus1)?.to_vec1::<f32>()?;
+            let mut tokens = (0..logprobs.len() as u32).collect::<Vec<_>>();
+            let top = (2 * width).min(tokens.len());
+            if top > 0 {
+                tokens.select_nth_unstable_by(top - 1, |a, b| logprobs[*b as usize].total_cmp(&logprobs[*a as usize]));
+            }
+            candidates.extend(tokens[..top].iter().map(|token| (beam.logprob + logprobs[*token as usize], idx, *token)));
+        }
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let mut next = vec![];
+        for (rank, (logprob, idx, token)) in candidates.into_iter().enumerate() {
+            let parent = &beams[idx];
+            if eos_tokens.contains(&token) {
+                // like an EOS ranked after the kept beams, an answer worse than them is not kept
+                if rank < width {
+                    finished.push(Hypothesis {
+                        tokens: parent.tokens.clone(),
+                        score: score(logprob, parent.tokens.len() + 1, beam.length_penalty),
+                        stopped: true,
+                    });
+                }
+            } else {
+                next.push((logprob, idx, token));
+            }
+            if next.len() == width {
+                break;
+            }
+        }
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(width);
+
+        // done when no beam can become better than the worst kept answer
+        let len = beams[0].tokens.len() + 1;
+        let best_next = next.first().map(|(logprob, ..)| score(*logprob, len, beam.length_penalty));
+        let done = match (best_next, finished.last()) {
+            (None, _) => true,
+            (Some(best), Some(worst)) => finished.len() == width && best <= worst.score,
+            (Some(_), None) => false,
+        };
+        if done || step + 1 == cfg.max_len {
+            if !done {
+                finished.extend(next.iter().map(|(logprob, idx, token)| Hypothesis {
+                    tokens: [beams[*idx].tokens.as_slice(), &[*token]].concat(),
+                    score: score(*logprob, len, beam.length_penalty),
+                    stopped: false,
+                }));
+            }
+            break;
+        }
+
+        // the first child of a beam continues its session, the others fork it
+        let mut taken = vec![false; beams.len()];
+        let mut children: Vec<(Session, Vec<u32>, f32)> = Vec::with_capacity(next.len());
+        for (logprob, idx, token) in next {
+            let parent = &beams[idx];
+            let child_session = match parent.session {
+                Some(session) if !taken[idx] => session,
+                parent_session => {
+                    let forked = Session::new();
+                    if let Err(e) = model.fork(parent_session.unwrap_or(session), forked).await {
+                        model.finish(forked).await;
+                        // the parents are finished by the caller, the forks made at this step are not
+                        for (child, ..) in &children {
+                            if !beams.iter().any(|beam| beam.session == Some(*child)) {
+                                model.finish(*child).await;
+                            }
+                        }
+                        return Err(e);
+                    }
+                    forked
+                }
+            };
+            taken[idx] = true;
+            children.push((child_session, [parent.tokens.as_slice(), &[token]].concat(), logprob));
+        }
+        for (beam, taken) in beams.iter().zip(taken) {
+            match beam.session {
+                Some(session) if !taken => model.finish(session).await,
+                _ => {}
+            }
+        }
+        beams.clear();
+
+        let decodes = children.iter().map(|(session, tokens, _)| {
+            let index_pos = prompt.len() + tokens.len() - 1;
+            model.decode(*session, step + 1, tokens[tokens.len() - 1], index_pos as u32)
+        });
+        let logits = join_all(decodes).await;
+        let mut error = None;
+        for ((session, tokens, logprob), logits) in children.into_iter().zip(logits) {
+            match logits {
+                Ok(logits) => beams.push(Beam {
+                    session: Some(session),
+                    tokens,
+                    logprob,
+                    logits,
+                }),
+                Err(e) => {
+                    model.finish(session).await;
+                    error = Some(e);
+                }
+            }
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+    }
+
+    finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+    match finished.into_iter().next() {
+        Some(best) => Ok(best),
+        None => Ok(Hypothesis {
+            tokens: vec![],
+            score: 0.,
+            stopped: false,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{Device, Result, Tensor};
+    use protocol::{BeamSearch, ChatCfg, Session};
+
+    use super::{search, Beam};
+    use crate::conversation::{tests::MockSteps, ChatSteps};
+
+    const EOS: u32 = 0;
+
+    /// The probabilities of the next token only depend on the last token in the kv caches of the session.
+    /// After 1 every token is as likely, after 2 EOS is likely, so the greedy answer is not the most likely one
+    fn next_probs(last: u32) -> [f32; 4] {
+        match last {
+            1 => [0.01, 0.33, 0.33, 0.33],
+            2 => [0.9, 0.04, 0.03, 0.03],
+            _ => [0.01, 0.5, 0.4, 0.09],
+        }
+    }
+
+    fn logits(last: u32) -> Result<Tensor> {
+        Tensor::new(next_probs(last).map(f32::ln).as_slice(), &Device::Cpu)
+    }
+
+    #[tokio::test]
+    async fn beam_search_finds_most_likely_answer() {
+        let steps = MockSteps {
+            eos_tokens: vec![EOS],
+            next_logits: logits,
+            ..Default::default()
+        };
+        let session = Session::new();
+        let prompt = [5, 6];
+        steps.kv.lock().insert(session, prompt.to_vec());
+        let cfg = ChatCfg {
+            max_len: 4,
+            repeat_penalty: 1.,
+            ..Default::default()
+        };
+        let mut beams = vec![Beam {
+            session: None,
+            tokens: vec![],
+            logprob: 0.,
+            logits: logits(6).unwrap(),
+        }];
+
+        // the greedy answer starts with 1, which is less likely than 2 then EOS
+        let beam = BeamSearch { width: 2, length_penalty: 1. };
+        let best = search(&steps, session, &cfg, beam, &prompt, &mut beams).await.unwrap();
+        assert_eq!(best.tokens, vec![2]);
+        assert!(best.stopped);
+        assert!((best.score - (0.4f32 * 0.9).ln() / 2.).abs() < 1e-4);
+
+        // the prompt session keeps only the prompt, the beams left are finished by the caller
+        for session in beams.iter().filter_map(|beam| beam.session) {
+            steps.finish(session).await;
+        }
+        assert_eq!(steps.kv.lock().clone(), HashMap::from([(session, prompt.to_vec())]));
+        assert!(!steps.finished.lock().contains(&session));
+    }
+}
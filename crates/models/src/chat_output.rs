@@ -0,0 +1,130 @@
+use candle_core::Result;
+use protocol::{ChatCfg, ChatEvent, FinishReason, TokenLogprob, TopLogprob};
+use tokio::sync::mpsc::Sender;
+
+use crate::{logits_processor::Logprobs, stop_matcher::StopMatch, stop_matcher::StopMatcher, token_output_stream::TokenOutputStream};
+
+/// Stream generated tokens to the client, honouring the stop strings and stop token ids of the chat config.
+///
+/// Token logprobs are sent with the first text chunk which includes the token, because the text of a token
+/// can be held back by the decoder or the stop matcher.
+pub struct ChatOutput {
+    tos: TokenOutputStream,
+    stop_matcher: StopMatcher,
+    stop_token_ids: Vec<u32>,
+    tx: Sender<ChatEvent>,
+    pending_logprobs: Vec<TokenLogprob>,
+    stopped: bool,
+}
+
+impl ChatOutput {
+    pub fn new(tokenizer: tokenizers::Tokenizer, cfg: &ChatCfg, tx: Sender<ChatEvent>) -> Self {
+        Self {
+            tos: TokenOutputStream::new(tokenizer),
+            stop_matcher: StopMatcher::new(&cfg.stop),
+            stop_token_ids: cfg.stop_token_ids.clone(),
+            tx,
+            pending_logprobs: vec![],
+            stopped: false,
+        }
+    }
+
+    pub fn tokenizer(&self) -> &tokenizers::Tokenizer {
+        self.tos.tokenizer()
+    }
+
+    /// Emit a generated token, returning the finish reason when the generation must stop
+    pub async fn push_token(&mut self, token: u32, logprobs: Option<Logprobs>) -> Result<Option<FinishReason>> {
+        if self.stop_token_ids.contains(&token) {
+            return Ok(Some(FinishReason::Stop));
+        }
+        if let Some(logprobs) = logprobs {
+            let logprob = self.token_logprob(&logprobs)?;
+            self.pending_logprobs.push(logprob);
+        }
+        match self.tos.next_token(token)? {
+            Some(text) => Ok(self.push_text(&text).await),
+            None => Ok(None),
+        }
+    }
+
+    /// Emit the rest of the text when the generation ends, the rest can still complete a stop string
+    pub async fn finish(&mut self) -> Result<Option<FinishReason>> {
+        if self.stopped {
+            return Ok(None);
+        }
+        if let Some(rest) = self.tos.decode_rest()? {
+            if let Some(reason) = self.push_text(&rest).await {
+                return Ok(Some(reason));
+            }
+        }
+        let rest = self.stop_matcher.flush();
+        if rest.is_empty() && !self.pending_logprobs.is_empty() {
+            // the last tokens have no text, e.g. special tokens
+            let logprobs = std::mem::take(&mut self.pending_logprobs);
+            self.send_event(ChatEvent::Delta { text: rest, logprobs }).await;
+        } else {
+            self.send(rest).await;
+        }
+        Ok(None)
+    }
+
+    async fn push_text(&mut self, text: &str) -> Option<FinishReason> {
+        match self.stop_matcher.push(text) {
+            StopMatch::Continue(text) => {
+                if self.send(text).await {
+                    None
+                } else {
+                    Some(FinishReason::Error)
+                }
+            }
+            StopMatch::Stop(text) => {
+                self.stopped = true;
+                self.send(text).await;
+                Some(FinishReason::Stop)
+            }
+        }
+    }
+
+    async fn send(&mut self, text: String) -> bool {
+        if text.is_empty() {
+            return true;
+        }
+        let logprobs = std::mem::take(&mut self.pending_logprobs);
+        self.send_event(ChatEvent::Delta { text, logprobs }).await
+    }
+
+    async fn send_event(&self, event: ChatEvent) -> bool {
+        if let Err(e) = self.tx.send(event).await {
+            log::error!("error sending message: {}", e);
+            return false;
+        }
+        true
+    }
+
+    fn token_logprob(&self, logprobs: &Logprobs) -> Result<TokenLogprob> {
+        let token = self.decode_token(logprobs.token)?;
+        let top_logprobs = logprobs
+            .top
+            .iter()
+            .map(|(id, logprob)| {
+                let token = self.decode_token(*id)?;
+                Ok(TopLogprob {
+                    bytes: token.as_bytes().to_vec(),
+                    token,
+                    logprob: *logprob,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(TokenLogprob {
+            bytes: token.as_bytes().to_vec(),
+            token,
+            logprob: logprobs.logprob,
+            top_logprobs,
+        })
+    }
+
+    fn decode_token(&self, token: u32) -> Result<String> {
+        self.tokenizer().decode(&[token], false).map_err(candle_core::Error::msg)
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::HashMap;
+
+use candle_core::{bail, Result};
+
+/// A position in a grammar: rule, alternative and element index
+type Pos = (u32, u32, u32);
+/// Positions from the outer rule to the current one, the top is the next element to match
+type Stack = Vec<Pos>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Element {
+    Chars { ranges: Vec<(char, char)>, negated: bool },
+    Rule(u32),
+}
+
+/// A GBNF grammar, with the syntax of llama.cpp grammars.
+///
+/// Matching keeps every possible parse stack, like the llama.cpp sampler, so grammars must not be left recursive.
+pub struct Grammar {
+    rules: Vec<Vec<Vec<Element>>>,
+    root: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GrammarState {
+    stacks: Vec<Stack>,
+    /// bytes of an incomplete utf-8 char
+    partial: Vec<u8>,
+}
+
+impl Grammar {
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: src.chars().collect(),
+            pos: 0,
+            names: HashMap::new(),
+            rules: vec![],
+        };
+        parser.skip_space(true);
+        while parser.peek().is_some() {
+            let name = parser.name()?;
+            parser.skip_space(false);
+            parser.expect("::=")?;
+            parser.skip_space(true);
+            let alternatives = parser.alternatives(false)?;
+            if !matches!(parser.peek(), None | Some('\n')) {
+                bail!("grammar: unexpected {:?} in rule {name}", parser.peek().unwrap_or_default())
+            }
+            let id = parser.rule_id(&name);
+            if parser.rules[id as usize].is_some() {
+                bail!("grammar: rule {name} is defined twice")
+            }
+            parser.rules[id as usize] = Some(alternatives);
+            parser.skip_space(true);
+        }
+
+        let Some(root) = parser.names.get("root").copied() else { bail!("grammar: no root rule") };
+        let mut rules = Vec::with_capacity(parser.rules.len());
+        for (id, rule) in parser.rules.into_iter().enumerate() {
+            match rule {
+                Some(rule) => rules.push(rule),
+                None => bail!("grammar: rule {} is not defined", rule_name(&parser.names, id as u32)),
+            }
+        }
+        let grammar = Self { rules, root };
+        grammar.check_left_recursion(&parser.names)?;
+        Ok(grammar)
+    }
+
+    pub fn start(&self) -> GrammarState {
+        let mut stacks = vec![];
+        for alt in 0..self.rules[self.root as usize].len() {
+            self.expand(vec![(self.root, alt as u32, 0)], &mut stacks);
+        }
+        GrammarState::new(stacks, vec![])
+    }
+
+    pub fn step(&self, state: &GrammarState, byte: u8) -> Option<GrammarState> {
+        let mut partial = state.partial.clone();
+        partial.push(byte);
+        match std::str::from_utf8(&partial) {
+            Ok(c) => {
+                let c = c.chars().next()?;
+                let stacks = self.step_char(&state.stacks, c);
+                if stacks.is_empty() {
+                    None
+                } else {
+                    Some(GrammarState::new(stacks, vec![]))
+                }
+            }
+            // the char is not complete yet
+            Err(e) if e.error_len().is_none() => Some(GrammarState::new(state.stacks.clone(), partial)),
+            Err(_) => None,
+        }
+    }
+
+    pub fn is_accepting(&self, state: &GrammarState) -> bool {
+        state.partial.is_empty() && state.stacks.iter().any(|s| s.is_empty())
+    }
+
+    fn step_char(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut out = vec![];
+        for stack in stacks {
+            let Some(&(rule, alt, idx)) = stack.last() else {
+                continue;
+            };
+            if let Element::Chars { ranges, negated } = &self.rules[rule as usize][alt as usize][idx as usize] {
+                if ranges.iter().any(|(lo, hi)| *lo <= c && c <= *hi) != *negated {
+                    let mut stack = stack.clone();
+                    if let Some(top) = stack.last_mut() {
+                        top.2 += 1;
+                    }
+                    self.expand(stack, &mut out);
+                }
+            }
+        }
+        out
+    }
+
+    /// Resolve rule references until the top of the stack is a char element, or the stack is empty when the root rule is done
+    fn expand(&self, mut stack: Stack, out: &mut Vec<Stack>) {
+        loop {
+            let Some(&(rule, alt, idx)) = stack.last() else {
+                out.push(stack);
+                return;
+            };
+            let elements = &self.rules[rule as usize][alt as usize];
+            match elements.get(idx as usize) {
+                None => {
+                    stack.pop();
+                }
+                Some(Element::Chars { .. }) => {
+                    out.push(stack);
+                    return;
+                }
+                Some(Element::Rule(child)) => {
+                    // continue after the reference once the child is done, a finished position is dropped so right recursion does not grow the stack
+                    stack.pop();
+                    if idx as usize + 1 < elements.len() {
+                        stack.push((rule, alt, idx + 1));
+                    }
+                    for child_alt in 0..self.rules[*child as usize].len() {
+                        let mut stack = stack.clone();
+                        stack.push((*child, child_alt as u32, 0));
+                        self.expand(stack, out);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn check_left_recursion(&self, names: &HashMap<String, u32>) -> Result<()> {
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (id, rule) in self.rules.iter().enumerate() {
+                if !nullable[id] && rule.iter().any(|alt| alt.iter().all(|e| matches!(e, Element::Rule(r) if nullable[*r as usize]))) {
+                    nullable[id] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        // rules which can be referenced before any char is matched
+        let left_refs = self
+            .rules
+            .iter()
+            .map(|rule| {
+                let mut refs = vec![];
+                for alt in rule {
+                    for element in alt {
+                        match element {
+                            Element::Rule(r) => {
+                                refs.push(*r);
+                                if !nullable[*r as usize] {
+                                    break;
+                                }
+                            }
+                            Element::Chars { .. } => break,
+                        }
+                    }
+                }
+                refs
+            })
+            .collect::<Vec<_>>();
+
+        for start in 0..self.rules.len() as u32 {
+            let mut visited = vec![false; self.rules.len()];
+            let mut pending = left_refs[start as usize].clone();
+            while let Some(rule) = pending.pop() {
+                if rule == start {
+                    bail!("grammar: rule {} is left recursive", rule_name(names, start))
+                }
+                if !visited[rule as usize] {
+                    visited[rule as usize] = true;
+                    pending.extend_from_slice(&left_refs[rule as usize]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GrammarState {
+    fn new(mut stacks: Vec<Stack>, partial: Vec<u8>) -> Self {
+        stacks.sort_unstable();
+        stacks.dedup();
+        Self { stacks, partial }
+    }
+}
+
+fn rule_name(names: &HashMap<String, u32>, id: u32) -> String {
+    names.iter().find(|(_, v)| **v == id).map(|(name, _)| name.clone()).unwrap_or_else(|| format!("#{id}"))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    names: HashMap<String, u32>,
+    /// generated rules for groups and repetitions have no name
+    rules: Vec<Option<Vec<Vec<Element>>>>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        match self.peek() {
+            Some(c) => {
+                self.pos += 1;
+                Ok(c)
+            }
+            None => bail!("grammar: unexpected end"),
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<()> {
+        for expected in s.chars() {
+            if self.peek() != Some(expected) {
+                bail!("grammar: expected {s} at char {}", self.pos)
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    /// Skip spaces and comments, newlines end a rule unless inside parentheses
+    fn skip_space(&mut self, newline_ok: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => self.pos += 1,
+                '\n' if newline_ok => self.pos += 1,
+                '#' => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            bail!("grammar: expected a rule name at char {}", self.pos)
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn rule_id(&mut self, name: &str) -> u32 {
+        if let Some(id) = self.names.get(name) {
+            return *id;
+        }
+        self.rules.push(None);
+        let id = self.rules.len() as u32 - 1;
+        self.names.insert(name.to_string(), id);
+        id
+    }
+
+    fn new_rule(&mut self, alternatives: Vec<Vec<Element>>) -> u32 {
+        self.rules.push(Some(alternatives));
+        self.rules.len() as u32 - 1
+    }
+
+    fn alternatives(&mut self, nested: bool) -> Result<Vec<Vec<Element>>> {
+        let mut alternatives = vec![self.sequence(nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alternatives.push(self.sequence(nested)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self, nested: bool) -> Result<Vec<Element>> {
+        let mut sequence = vec![];
+        loop {
+            let item = match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    let mut item = vec![];
+                    while self.peek() != Some('"') {
+                        let c = self.char()?;
+                        item.push(Element::Chars { ranges: vec![(c, c)], negated: false });
+                    }
+                    self.pos += 1;
+                    item
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = vec![];
+                    while self.peek() != Some(']') {
+                        let lo = self.char()?;
+                        let hi = if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                            self.pos += 1;
+                            self.char()?
+                        } else {
+                            lo
+                        };
+                        ranges.push((lo, hi));
+                    }
+                    self.pos += 1;
+                    vec![Element::Chars { ranges, negated }]
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    vec![Element::Chars { ranges: vec![], negated: true }]
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alternatives = self.alternatives(true)?;
+                    self.expect(")")?;
+                    vec![Element::Rule(self.new_rule(alternatives))]
+                }
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_' => {
+                    let name = self.name()?;
+                    vec![Element::Rule(self.rule_id(&name))]
+                }
+                _ => return Ok(sequence),
+            };
+            self.skip_space(nested);
+            let item = match self.peek() {
+                Some('*') => self.repeat(item, 0, None),
+                Some('+') => self.repeat(item, 1, None),
+                Some('?') => self.repeat(item, 0, Some(1)),
+                Some('{') => {
+                    self.pos += 1;
+                    let min = self.number()?;
+                    let max = if self.peek() == Some(',') {
+                        self.pos += 1;
+                        if self.peek() == Some('}') {
+                            None
+                        } else {
+                            Some(self.number()?)
+                        }
+                    } else {
+                        Some(min)
+                    };
+                    if self.peek() != Some('}') || max.is_some_and(|max| max < min) {
+                        bail!("grammar: invalid repetition at char {}", self.pos)
+                    }
+                    self.repeat(item, min, max)
+                }
+                _ => {
+                    sequence.extend(item);
+                    continue;
+                }
+            };
+            self.pos += 1;
+            self.skip_space(nested);
+            sequence.extend(item);
+        }
+    }
+
+    fn number(&mut self) -> Result<usize> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits = self.chars[start..self.pos].iter().collect::<String>();
+        digits.parse().map_err(|_| candle_core::Error::Msg(format!("grammar: expected a number at char {start}")))
+    }
+
+    /// Repeat an item, the optional repetitions are nested rules `x (x (x)?)?` and unbounded ones a right recursive rule
+    fn repeat(&mut self, item: Vec<Element>, min: usize, max: Option<usize>) -> Vec<Element> {
+        let element = match <[Element; 1]>::try_from(item) {
+            Ok([element]) => element,
+            Err(item) => Element::Rule(self.new_rule(vec![item])),
+        };
+        let mut sequence = vec![element.clone(); min];
+        match max {
+            None => {
+                let id = self.new_rule(vec![]);
+                self.rules[id as usize] = Some(vec![vec![element, Element::Rule(id)], vec![]]);
+                sequence.push(Element::Rule(id));
+            }
+            Some(max) if max > min => {
+                let mut optional = None;
+                for _ in min..max {
+                    let alternative = match optional {
+                        Some(inner) => vec![element.clone(), Element::Rule(inner)],
+                        None => vec![element.clone()],
+                    };
+                    optional = Some(self.new_rule(vec![alternative, vec![]]));
+                }
+                sequence.extend(optional.map(Element::Rule));
+            }
+            Some(_) => {}
+        }
+        sequence
+    }
+
+    /// A char of a literal or a char class, with escapes
+    fn char(&mut self) -> Result<char> {
+        let c = self.next()?;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let escaped = self.next()?;
+        let hex_len = match escaped {
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            'n' => return Ok('\n'),
+            'r' => return Ok('\r'),
+            't' => return Ok('\t'),
+            _ => return Ok(escaped),
+        };
+        let mut value = 0;
+        for _ in 0..hex_len {
+            let Some(digit) = self.next()?.to_digit(16) else {
+                bail!("grammar: invalid escape at char {}", self.pos)
+            };
+            value = value * 16 + digit;
+        }
+        match char::from_u32(value) {
+            Some(c) => Ok(c),
+            None => bail!("grammar: invalid char escape at char {}", self.pos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Grammar;
+
+    fn is_match(grammar: &Grammar, text: &str) -> bool {
+        let mut state = grammar.start();
+        for byte in text.bytes() {
+            match grammar.step(&state, byte) {
+                Some(next) => state = next,
+                None => return false,
+            }
+        }
+        grammar.is_accepting(&state)
+    }
+
+    #[test]
+    fn arithmetic_grammar() {
+        let grammar = Grammar::parse(
+            r#"
+# nested arithmetic
+root ::= expr
+expr ::= term (("+" | "-") term)*
+term ::= num | "(" expr ")"
+num  ::= [1-9] [0-9]{0,2} | "0"
+"#,
+        )
+        .unwrap();
+        assert!(is_match(&grammar, "1+(20-3)"));
+        assert!(is_match(&grammar, "((0))"));
+        assert!(is_match(&grammar, "999"));
+        assert!(!is_match(&grammar, "1000"));
+        assert!(!is_match(&grammar, "1+"));
+        assert!(!is_match(&grammar, "(1"));
+        assert!(!is_match(&grammar, "01"));
+    }
+
+    #[test]
+    fn chars_and_escapes() {
+        let grammar = Grammar::parse("root ::= \"é\" [^\\n\"]+ \"\\x21\"?").unwrap();
+        assert!(is_match(&grammar, "éa b!"));
+        assert!(is_match(&grammar, "é日本"));
+        assert!(!is_match(&grammar, "é"));
+        assert!(!is_match(&grammar, "éa\nb"));
+    }
+
+    #[test]
+    fn invalid_grammars() {
+        assert!(Grammar::parse("expr ::= \"a\"").is_err());
+        assert!(Grammar::parse("root ::= item").is_err());
+        assert!(Grammar::parse("root ::= root \"a\" | \"b\"").is_err());
+        assert!(Grammar::parse("root ::= \"x\"? root").is_err());
+    }
+}
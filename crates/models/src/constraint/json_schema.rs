@@ -0,0 +1,290 @@
+use candle_core::{bail, Result};
+use serde_json::{Map, Value};
+
+const STRING_CHAR: &str = r#"([^"\\\x00-\x1F\x7F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+const INTEGER: &str = r"-?(0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?";
+const BOOLEAN: &str = "(true|false)";
+const NULL: &str = "null";
+const DATE: &str = "[0-9]{4}-(0[1-9]|1[0-2])-(0[1-9]|[12][0-9]|3[01])";
+const TIME: &str = r"([01][0-9]|2[0-3]):[0-5][0-9]:[0-5][0-9](\.[0-9]+)?(Z|[+-]([01][0-9]|2[0-3]):[0-5][0-9])?";
+const UUID: &str = "[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}";
+/// separators allow a single space, more whitespace only lets the model ramble
+const ITEM_SEP: &str = ",[ ]?";
+const KEY_SEP: &str = ":[ ]?";
+/// nesting depth of the objects and arrays which have no schema
+const GENERIC_DEPTH: usize = 3;
+/// regexes cannot express recursion, so recursive `$ref`s are unrolled up to this depth
+const MAX_REF_DEPTH: usize = 5;
+
+/// Compile a JSON schema into a regex matching the JSON documents valid for the schema.
+///
+/// Object properties are generated in the order of the schema, and only the required ones are mandatory.
+pub fn schema_to_regex(schema: &Value) -> Result<String> {
+    SchemaCompiler { root: schema }.value(schema, 0)
+}
+
+struct SchemaCompiler<'a> {
+    root: &'a Value,
+}
+
+impl SchemaCompiler<'_> {
+    fn value(&self, schema: &Value, ref_depth: usize) -> Result<String> {
+        let obj = match schema {
+            Value::Bool(true) => return Ok(generic_value(GENERIC_DEPTH)),
+            Value::Object(obj) => obj,
+            _ => bail!("unsupported json schema {schema}"),
+        };
+        if let Some(reference) = obj.get("$ref").and_then(Value::as_str) {
+            if ref_depth >= MAX_REF_DEPTH {
+                bail!("json schema $ref {reference} is nested too deeply")
+            }
+            return self.value(self.resolve(reference)?, ref_depth + 1);
+        }
+        if let Some(value) = obj.get("const") {
+            return Ok(literal(value));
+        }
+        if let Some(Value::Array(values)) = obj.get("enum") {
+            return Ok(alternatives(values.iter().map(literal).collect()));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(Value::Array(schemas)) = obj.get(key) {
+                let regexes = schemas.iter().map(|s| self.value(s, ref_depth)).collect::<Result<Vec<_>>>()?;
+                return Ok(alternatives(regexes));
+            }
+        }
+        if let Some(Value::Array(schemas)) = obj.get("allOf") {
+            match schemas.as_slice() {
+                [schema] => return self.value(schema, ref_depth),
+                _ => bail!("json schema allOf is only supported with a single schema"),
+            }
+        }
+        match obj.get("type") {
+            Some(Value::String(ty)) => self.typed(ty, obj, ref_depth),
+            Some(Value::Array(types)) => {
+                let regexes = types
+                    .iter()
+                    .map(|ty| match ty.as_str() {
+                        Some(ty) => self.typed(ty, obj, ref_depth),
+                        None => bail!("invalid json schema type {ty}"),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(alternatives(regexes))
+            }
+            Some(ty) => bail!("invalid json schema type {ty}"),
+            None if obj.contains_key("properties") => self.object(obj, ref_depth),
+            None if obj.contains_key("items") => self.array(obj, ref_depth),
+            None => Ok(generic_value(GENERIC_DEPTH)),
+        }
+    }
+
+    fn typed(&self, ty: &str, obj: &Map<String, Value>, ref_depth: usize) -> Result<String> {
+        match ty {
+            "string" => Ok(string(obj)),
+            "integer" => Ok(INTEGER.to_string()),
+            "number" => Ok(NUMBER.to_string()),
+            "boolean" => Ok(BOOLEAN.to_string()),
+            "null" => Ok(NULL.to_string()),
+            "array" => self.array(obj, ref_depth),
+            "object" => self.object(obj, ref_depth),
+            _ => bail!("unsupported json schema type {ty}"),
+        }
+    }
+
+    fn array(&self, obj: &Map<String, Value>, ref_depth: usize) -> Result<String> {
+        let item = match obj.get("items") {
+            Some(items) => self.value(items, ref_depth)?,
+            None => generic_value(GENERIC_DEPTH - 1),
+        };
+        let min = obj.get("minItems").and_then(Value::as_u64).unwrap_or(0);
+        let max = obj.get("maxItems").and_then(Value::as_u64);
+        if max == Some(0) {
+            return Ok(r"\[\]".to_string());
+        }
+        let repeat = match max {
+            Some(max) => format!("{{{},{}}}", min.saturating_sub(1), max - 1),
+            None => format!("{{{},}}", min.saturating_sub(1)),
+        };
+        let items = format!("({item})({ITEM_SEP}({item})){repeat}");
+        if min == 0 {
+            Ok(format!(r"\[({items})?\]"))
+        } else {
+            Ok(format!(r"\[{items}\]"))
+        }
+    }
+
+    fn object(&self, obj: &Map<String, Value>, ref_depth: usize) -> Result<String> {
+        let Some(Value::Object(properties)) = obj.get("properties") else {
+            return Ok(generic_object(GENERIC_DEPTH - 1));
+        };
+        let required = match obj.get("required") {
+            Some(Value::Array(required)) => required.iter().filter_map(Value::as_str).collect::<Vec<_>>(),
+            _ => vec![],
+        };
+        let properties = properties
+            .iter()
+            .map(|(name, schema)| {
+                let regex = format!("{}{KEY_SEP}({})", literal(&Value::String(name.clone())), self.value(schema, ref_depth)?);
+                Ok((regex, required.contains(&name.as_str())))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(format!(r"\{{{}\}}", properties_regex(&properties)))
+    }
+
+    /// Resolve a local reference like `#/$defs/name`
+    fn resolve(&self, reference: &str) -> Result<&Value> {
+        let Some(pointer) = reference.strip_prefix('#') else {
+            bail!("only local json schema $ref are supported, got {reference}")
+        };
+        match self.root.pointer(pointer) {
+            Some(schema) => Ok(schema),
+            None => bail!("json schema $ref {reference} not found"),
+        }
+    }
+}
+
+/// Comma separated properties, where the optional ones can be skipped
+fn properties_regex(properties: &[(String, bool)]) -> String {
+    if properties.is_empty() {
+        return String::new();
+    }
+    // after the first property, every other one is prefixed by a separator
+    let rest = |start: usize| -> String {
+        properties[start..]
+            .iter()
+            .map(|(regex, required)| {
+                if *required {
+                    format!("{ITEM_SEP}{regex}")
+                } else {
+                    format!("({ITEM_SEP}{regex})?")
+                }
+            })
+            .collect()
+    };
+    // the first property is any of the optional ones before the first required one, or the required one
+    let mut firsts = vec![];
+    for (i, (regex, required)) in properties.iter().enumerate() {
+        firsts.push(format!("{regex}{}", rest(i + 1)));
+        if *required {
+            return alternatives(firsts);
+        }
+    }
+    format!("{}?", alternatives(firsts))
+}
+
+fn string(obj: &Map<String, Value>) -> String {
+    if let Some(pattern) = obj.get("pattern").and_then(Value::as_str) {
+        let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+        let pattern = pattern.strip_suffix('$').unwrap_or(pattern);
+        return format!(r#""({pattern})""#);
+    }
+    match obj.get("format").and_then(Value::as_str) {
+        Some("date") => return format!(r#""{DATE}""#),
+        Some("time") => return format!(r#""{TIME}""#),
+        Some("date-time") => return format!(r#""{DATE}T{TIME}""#),
+        Some("uuid") => return format!(r#""{UUID}""#),
+        _ => {}
+    }
+    let min = obj.get("minLength").and_then(Value::as_u64);
+    let max = obj.get("maxLength").and_then(Value::as_u64);
+    match (min, max) {
+        (None, None) => format!(r#""{STRING_CHAR}*""#),
+        (min, Some(max)) => format!(r#""{STRING_CHAR}{{{},{max}}}""#, min.unwrap_or(0)),
+        (Some(min), None) => format!(r#""{STRING_CHAR}{{{min},}}""#),
+    }
+}
+
+fn generic_value(depth: usize) -> String {
+    let mut regexes = vec![format!(r#""{STRING_CHAR}*""#), NUMBER.to_string(), BOOLEAN.to_string(), NULL.to_string()];
+    if depth > 0 {
+        regexes.push(generic_object(depth - 1));
+        regexes.push(generic_array(depth - 1));
+    }
+    alternatives(regexes)
+}
+
+fn generic_object(depth: usize) -> String {
+    let property = format!(r#""{STRING_CHAR}*"{KEY_SEP}{}"#, generic_value(depth));
+    format!(r"\{{({property}({ITEM_SEP}{property})*)?\}}")
+}
+
+fn generic_array(depth: usize) -> String {
+    let item = generic_value(depth);
+    format!(r"\[({item}({ITEM_SEP}{item})*)?\]")
+}
+
+fn alternatives(regexes: Vec<String>) -> String {
+    format!("({})", regexes.join("|"))
+}
+
+/// Regex matching exactly the compact JSON of the value
+fn literal(value: &Value) -> String {
+    let json = value.to_string();
+    let mut regex = String::with_capacity(json.len());
+    for c in json.chars() {
+        if r"\.+*?()|[]{}^$#&-~".contains(c) {
+            regex.push('\\');
+        }
+        regex.push(c);
+    }
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::schema_to_regex;
+    use crate::constraint::regex_matcher::RegexMatcher;
+
+    fn is_match(matcher: &RegexMatcher, text: &str) -> bool {
+        let mut state = matcher.start();
+        for byte in text.bytes() {
+            match matcher.step(state, byte) {
+                Some(next) => state = next,
+                None => return false,
+            }
+        }
+        matcher.is_accepting(state)
+    }
+
+    #[test]
+    fn object_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string", "maxLength": 8},
+                "age": {"type": "integer"},
+                "tags": {"type": "array", "items": {"enum": ["a", "b"]}, "maxItems": 2},
+                "ok": {"type": ["boolean", "null"]}
+            },
+            "required": ["age"]
+        });
+        let matcher = RegexMatcher::new(&schema_to_regex(&schema).unwrap()).unwrap();
+        assert!(is_match(&matcher, r#"{"name": "Bob", "age": 42, "tags": ["a", "b"], "ok": null}"#));
+        assert!(is_match(&matcher, r#"{"age":-1}"#));
+        assert!(is_match(&matcher, r#"{"name":"x\"y","age":0,"ok":true}"#));
+        assert!(!is_match(&matcher, r#"{"name":"Bob"}"#));
+        assert!(!is_match(&matcher, r#"{"age":01}"#));
+        assert!(!is_match(&matcher, r#"{"age":1,"tags":["a","b","a"]}"#));
+        assert!(!is_match(&matcher, r#"{"age":1,"name":"Bob"}"#));
+        assert!(!is_match(&matcher, r#"{"name":"too long name","age":1}"#));
+    }
+
+    #[test]
+    fn refs_and_generic_values() {
+        let schema = json!({
+            "$defs": {"point": {"type": "object", "properties": {"x": {"type": "number"}, "y": {"type": "number"}}, "required": ["x", "y"]}},
+            "type": "object",
+            "properties": {"points": {"type": "array", "items": {"$ref": "#/$defs/point"}, "minItems": 1}, "extra": {}},
+            "required": ["points"]
+        });
+        let matcher = RegexMatcher::new(&schema_to_regex(&schema).unwrap()).unwrap();
+        assert!(is_match(&matcher, r#"{"points":[{"x":1.5,"y":-2e3}],"extra":{"a":[1,"b",null]}}"#));
+        assert!(!is_match(&matcher, r#"{"points":[]}"#));
+
+        let matcher = RegexMatcher::new(&schema_to_regex(&json!({"type": "object"})).unwrap()).unwrap();
+        assert!(is_match(&matcher, r#"{"a": {"b": [true, false]}, "c": "d"}"#));
+        assert!(!is_match(&matcher, r#"["a"]"#));
+    }
+}
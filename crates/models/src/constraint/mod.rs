@@ -0,0 +1,176 @@
+//! Constrained decoding: the logits of the tokens which cannot continue a valid output are masked before sampling.
+
+use std::{collections::HashMap, sync::Arc};
+
+use candle_core::{bail, DType, Result, Tensor};
+use protocol::Constraint;
+use regex_automata::util::primitives::StateID;
+
+use grammar::{Grammar, GrammarState};
+use regex_matcher::RegexMatcher;
+
+mod grammar;
+mod json_schema;
+mod regex_matcher;
+mod vocab;
+
+pub use vocab::TokenVocab;
+
+enum Matcher {
+    Regex(Box<RegexMatcher>),
+    Grammar(Grammar),
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum MatchState {
+    Regex(StateID),
+    Grammar(GrammarState),
+}
+
+impl Matcher {
+    fn start(&self) -> MatchState {
+        match self {
+            Matcher::Regex(regex) => MatchState::Regex(regex.start()),
+            Matcher::Grammar(grammar) => MatchState::Grammar(grammar.start()),
+        }
+    }
+
+    fn step(&self, state: &MatchState, byte: u8) -> Option<MatchState> {
+        match (self, state) {
+            (Matcher::Regex(regex), MatchState::Regex(state)) => regex.step(*state, byte).map(MatchState::Regex),
+            (Matcher::Grammar(grammar), MatchState::Grammar(state)) => grammar.step(state, byte).map(MatchState::Grammar),
+            _ => None,
+        }
+    }
+
+    fn is_accepting(&self, state: &MatchState) -> bool {
+        match (self, state) {
+            (Matcher::Regex(regex), MatchState::Regex(state)) => regex.is_accepting(*state),
+            (Matcher::Grammar(grammar), MatchState::Grammar(state)) => grammar.is_accepting(state),
+            _ => false,
+        }
+    }
+}
+
+/// Track the output of a chat session against a constraint, and mask the logits of the next token
+pub struct TokenConstraint {
+    matcher: Matcher,
+    state: MatchState,
+    vocab: Arc<TokenVocab>,
+    eos_tokens: Vec<u32>,
+    allowed_cache: HashMap<MatchState, Arc<Vec<u32>>>,
+}
+
+impl TokenConstraint {
+    /// The `eos_tokens` are only allowed once the output is complete
+    pub fn new(constraint: &Constraint, vocab: Arc<TokenVocab>, eos_tokens: Vec<u32>) -> Result<Self> {
+        let matcher = match constraint {
+            Constraint::JsonSchema(schema) => Matcher::Regex(Box::new(RegexMatcher::new(&json_schema::schema_to_regex(schema)?)?)),
+            Constraint::Regex(regex) => Matcher::Regex(Box::new(RegexMatcher::new(regex)?)),
+            Constraint::Grammar(grammar) => Matcher::Grammar(Grammar::parse(grammar)?),
+        };
+        let state = matcher.start();
+        Ok(Self {
+            matcher,
+            state,
+            vocab,
+            eos_tokens,
+            allowed_cache: HashMap::new(),
+        })
+    }
+
+    /// Set the logits of every token which is not allowed to -inf
+    pub fn mask_logits(&mut self, logits: &Tensor) -> Result<Tensor> {
+        let allowed = self.allowed_tokens();
+        let accepting = self.matcher.is_accepting(&self.state);
+        if allowed.is_empty() && (!accepting || self.eos_tokens.is_empty()) {
+            bail!("no token can continue the constrained output")
+        }
+        let eos_tokens = if accepting {
+            self.eos_tokens.as_slice()
+        } else {
+            &[]
+        };
+
+        let device = logits.device();
+        let logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        let mut masked = vec![f32::NEG_INFINITY; logits.len()];
+        for token in allowed.iter().chain(eos_tokens) {
+            if let Some(logit) = logits.get(*token as usize) {
+                masked[*token as usize] = *logit;
+            }
+        }
+        let masked_len = masked.len();
+        Tensor::from_vec(masked, masked_len, device)
+    }
+
+    /// Advance the output with the sampled token
+    pub fn advance(&mut self, token: u32) -> Result<()> {
+        if self.eos_tokens.contains(&token) {
+            return Ok(());
+        }
+        let Some(bytes) = self.vocab.token_bytes(token) else {
+            bail!("token {token} is not allowed by the constraint")
+        };
+        let mut state = self.state.clone();
+        for byte in bytes {
+            match self.matcher.step(&state, *byte) {
+                Some(next) => state = next,
+                None => bail!("token {token} is not allowed by the constraint"),
+            }
+        }
+        self.state = state;
+        Ok(())
+    }
+
+    fn allowed_tokens(&mut self) -> Arc<Vec<u32>> {
+        if let Some(allowed) = self.allowed_cache.get(&self.state) {
+            return allowed.clone();
+        }
+        let matcher = &self.matcher;
+        let allowed = Arc::new(self.vocab.matching_tokens(self.state.clone(), |state, byte| matcher.step(state, byte)));
+        self.allowed_cache.insert(self.state.clone(), allowed.clone());
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{Device, Tensor};
+    use protocol::Constraint;
+
+    use super::{TokenConstraint, TokenVocab};
+
+    #[test]
+    fn mask_logits() {
+        let tokens = ["{", "}", "\"a\"", ":", "1", "12", "x", "<eos>"];
+        let vocab = tokens
+            .iter()
+            .map(|t| {
+                if *t == "<eos>" {
+                    None
+                } else {
+                    Some(t.as_bytes().to_vec())
+                }
+            })
+            .collect();
+        let vocab = Arc::new(TokenVocab::from_tokens(vocab));
+        let constraint = Constraint::JsonSchema(serde_json::json!({"type": "object", "properties": {"a": {"type": "integer"}}, "required": ["a"]}));
+        let mut constraint = TokenConstraint::new(&constraint, vocab, vec![7]).unwrap();
+
+        let logits = Tensor::new(&[0f32; 8], &Device::Cpu).unwrap();
+        let mut allowed = vec![];
+        for token in [0, 2, 3, 5, 1] {
+            let masked = constraint.mask_logits(&logits).unwrap().to_vec1::<f32>().unwrap();
+            allowed.push(masked.iter().enumerate().filter(|(_, l)| l.is_finite()).map(|(i, _)| i).collect::<Vec<_>>());
+            constraint.advance(token).unwrap();
+        }
+        assert_eq!(allowed, vec![vec![0], vec![2], vec![3], vec![4, 5], vec![1, 4, 5]]);
+
+        let masked = constraint.mask_logits(&logits).unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(masked.iter().position(|l| l.is_finite()), Some(7));
+        assert!(constraint.advance(6).is_err());
+    }
+}
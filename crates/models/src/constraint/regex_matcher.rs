@@ -0,0 +1,89 @@
+use std::collections::{HashMap, HashSet};
+
+use candle_core::{Error, Result};
+use regex_automata::{
+    dfa::{dense, Automaton, StartKind},
+    util::{primitives::StateID, start},
+    Anchored, MatchKind,
+};
+
+/// Limit the memory of a compiled constraint, big repetition counts can explode the DFA
+const DFA_SIZE_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Match a regex over the whole output, byte by byte, with a DFA
+pub struct RegexMatcher {
+    dfa: dense::DFA<Vec<u32>>,
+    start: StateID,
+    /// states from which the whole output can still match
+    live: HashSet<StateID>,
+}
+
+impl RegexMatcher {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let config = dense::Config::new()
+            .start_kind(StartKind::Anchored)
+            // every match must be kept, a leftmost-first DFA drops the longer alternatives once one matched
+            .match_kind(MatchKind::All)
+            .dfa_size_limit(Some(DFA_SIZE_LIMIT))
+            .determinize_size_limit(Some(DFA_SIZE_LIMIT));
+        let dfa = dense::Builder::new()
+            .configure(config)
+            .build(pattern)
+            .map_err(|e| Error::Msg(format!("cannot compile constraint regex: {e}")))?;
+        let start = dfa.start_state(&start::Config::new().anchored(Anchored::Yes)).map_err(Error::wrap)?;
+        let live = live_states(&dfa, start);
+        Ok(Self { dfa, start, live })
+    }
+
+    pub fn start(&self) -> StateID {
+        self.start
+    }
+
+    pub fn step(&self, state: StateID, byte: u8) -> Option<StateID> {
+        let next = self.dfa.next_state(state, byte);
+        self.live.contains(&next).then_some(next)
+    }
+
+    /// The output matches the regex if it ends here
+    pub fn is_accepting(&self, state: StateID) -> bool {
+        self.dfa.is_match_state(self.dfa.next_eoi_state(state))
+    }
+}
+
+/// Find the states which can reach a match at the end of the input.
+///
+/// DFA matches are delayed by one byte, so a state after a complete match is not dead yet even if nothing can follow.
+fn live_states(dfa: &dense::DFA<Vec<u32>>, start: StateID) -> HashSet<StateID> {
+    let mut states = vec![start];
+    let mut index = HashMap::from([(start, 0)]);
+    let mut reverse_edges: Vec<Vec<usize>> = vec![vec![]];
+    let mut i = 0;
+    while i < states.len() {
+        let state = states[i];
+        for byte in 0..=255u8 {
+            let next = dfa.next_state(state, byte);
+            if dfa.is_dead_state(next) || dfa.is_quit_state(next) {
+                continue;
+            }
+            let j = *index.entry(next).or_insert_with(|| {
+                states.push(next);
+                reverse_edges.push(vec![]);
+                states.len() - 1
+            });
+            reverse_edges[j].push(i);
+        }
+        i += 1;
+    }
+
+    let mut live = states.iter().map(|state| dfa.is_match_state(dfa.next_eoi_state(*state))).collect::<Vec<_>>();
+    let mut pending = (0..states.len()).filter(|i| live[*i]).collect::<Vec<_>>();
+    while let Some(j) = pending.pop() {
+        for &i in &reverse_edges[j] {
+            if !live[i] {
+                live[i] = true;
+                pending.push(i);
+            }
+        }
+    }
+    states.into_iter().zip(live).filter(|(_, live)| *live).map(|(state, _)| state).collect()
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use tokenizers::{decoders::DecoderWrapper, Tokenizer};
+
+/// The bytes of every token, in a trie for walking all tokens which share a prefix at once.
+///
+/// Special tokens have no bytes, they are never allowed by a constraint.
+pub struct TokenVocab {
+    tokens: Vec<Option<Vec<u8>>>,
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    tokens: Vec<u32>,
+}
+
+impl TokenVocab {
+    pub fn new(tokenizer: &Tokenizer) -> Self {
+        let vocab = tokenizer.get_vocab(true);
+        let added_tokens = tokenizer.get_added_tokens_decoder();
+        let byte_level = matches!(tokenizer.get_decoder(), Some(DecoderWrapper::ByteLevel(_)));
+        let char_bytes = byte_level_char_bytes();
+
+        let size = vocab.values().max().map(|id| *id as usize + 1).unwrap_or(0);
+        let mut tokens = vec![None; size];
+        for (token, id) in vocab {
+            let bytes = match added_tokens.get(&id) {
+                Some(added) if added.special => continue,
+                Some(added) => added.content.as_bytes().to_vec(),
+                None if byte_level => token
+                    .chars()
+                    .map(|c| char_bytes.get(&c).copied())
+                    .collect::<Option<Vec<_>>>()
+                    .unwrap_or_else(|| token.as_bytes().to_vec()),
+                None => sentencepiece_bytes(&token),
+            };
+            tokens[id as usize] = Some(bytes);
+        }
+        Self::from_tokens(tokens)
+    }
+
+    pub fn from_tokens(tokens: Vec<Option<Vec<u8>>>) -> Self {
+        let mut nodes = vec![TrieNode::default()];
+        for (id, bytes) in tokens.iter().enumerate() {
+            let Some(bytes) = bytes.as_ref().filter(|b| !b.is_empty()) else {
+                continue;
+            };
+            let mut node = 0;
+            for byte in bytes {
+                node = match nodes[node].children.iter().find(|(b, _)| b == byte) {
+                    Some((_, child)) => *child,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.push((*byte, child));
+                        child
+                    }
+                };
+            }
+            nodes[node].tokens.push(id as u32);
+        }
+        Self { tokens, nodes }
+    }
+
+    pub fn token_bytes(&self, token: u32) -> Option<&[u8]> {
+        self.tokens.get(token as usize)?.as_deref().filter(|b| !b.is_empty())
+    }
+
+    /// Find every token whose bytes can all be consumed by `step` from the `start` state
+    pub fn matching_tokens<S: Clone>(&self, start: S, step: impl Fn(&S, u8) -> Option<S>) -> Vec<u32> {
+        let mut matched = vec![];
+        let mut pending = vec![(0, start)];
+        while let Some((node, state)) = pending.pop() {
+            let node = &self.nodes[node];
+            matched.extend_from_slice(&node.tokens);
+            for (byte, child) in &node.children {
+                if let Some(next) = step(&state, *byte) {
+                    pending.push((*child, next));
+                }
+            }
+        }
+        matched.sort_unstable();
+        matched
+    }
+}
+
+/// Sentencepiece tokens use `▁` for spaces and `<0xNN>` for byte fallback
+fn sentencepiece_bytes(token: &str) -> Vec<u8> {
+    if let Some(hex) = token.strip_prefix("<0x").and_then(|t| t.strip_suffix('>')) {
+        if let Ok(byte) = u8::from_str_radix(hex, 16) {
+            return vec![byte];
+        }
+    }
+    token.replace('▁', " ").into_bytes()
+}
+
+/// Inverse of the GPT-2 byte to unicode mapping of byte-level BPE tokenizers
+fn byte_level_char_bytes() -> HashMap<char, u8> {
+    let mut printable = (b'!'..=b'~').chain(0xA1..=0xAC).chain(0xAE..=0xFF).collect::<Vec<u8>>();
+    let mut chars = printable.iter().map(|b| *b as u32).collect::<Vec<_>>();
+    let mut n = 0;
+    for byte in 0..=255u8 {
+        if !printable.contains(&byte) {
+            printable.push(byte);
+            chars.push(256 + n);
+            n += 1;
+        }
+    }
+    printable.into_iter().zip(chars).filter_map(|(byte, c)| char::from_u32(c).map(|c| (c, byte))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{byte_level_char_bytes, sentencepiece_bytes};
+
+    #[test]
+    fn token_bytes() {
+        let char_bytes = byte_level_char_bytes();
+        assert_eq!(char_bytes.len(), 256);
+        assert_eq!(char_bytes[&'Ġ'], b' ');
+        assert_eq!(char_bytes[&'Ċ'], b'\n');
+        assert_eq!(char_bytes[&'a'], b'a');
+
+        assert_eq!(sentencepiece_bytes("▁hello"), b" hello");
+        assert_eq!(sentencepiece_bytes("<0x0A>"), b"\n");
+    }
+}
@@ -0,0 +1,443 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use candle_core::{bail, Result, Tensor};
+use protocol::{ChatCfg, ChatEvent, FinishReason, OverflowPolicy, Session};
+use tokenizers::Tokenizer;
+use tokio::sync::{mpsc::Sender, Mutex};
+
+use crate::{beam_search::beam_search, constraint::TokenVocab, token_sampler::TokenSampler};
+
+/// The steps of a chat on the layers chain, shared by single chats and conversations
+#[async_trait::async_trait]
+pub(crate) trait ChatSteps: Send + Sync {
+    fn tokenize(&self, prompt: &str) -> Result<Vec<u32>>;
+    /// Context length of the model, in tokens
+    fn context_window(&self) -> usize;
+    fn tokenizer(&self) -> &Tokenizer;
+    /// Tokens ending the answer
+    fn eos_tokens(&self) -> Vec<u32>;
+    /// Vocabulary of the constraints, the models keep it once built
+    fn token_vocab(&self) -> Arc<TokenVocab> {
+        Arc::new(TokenVocab::new(self.tokenizer()))
+    }
+    /// Vision models take the images of the prompt, sent to the layers chain when the session starts
+    fn accepts_images(&self) -> bool {
+        false
+    }
+    /// Start the session on the layers chain, returns the len of the prompt prefix already in its kv caches
+    async fn start(&self, session: Session, cfg: &ChatCfg, tokens: &[u32]) -> Result<u32>;
+    /// Forward `tokens[cached_len..]` through the layers chain, returns the output of the last chunk
+    async fn prefill(&self, session: Session, cfg: &ChatCfg, tokens: &[u32], cached_len: u32) -> Result<(Tensor, u32)>;
+    /// Generate the answer from the prefill output, returns the generated tokens.
+    /// The last one is sampled but never forwarded, so it is not in the kv caches.
+    /// By default every sampled token is forwarded alone with `decode`
+    async fn generate(&self, session: Session, cfg: ChatCfg, tokens: &[u32], prefilled: (Tensor, u32), tx: Sender<ChatEvent>) -> Result<(FinishReason, Vec<u32>)> {
+        let mut tokens = tokens.to_vec();
+        let prompt_len = tokens.len();
+        let mut sampler = TokenSampler::new(self, cfg, prompt_len, tx)?;
+        let mut finish_reason = if sampler.cfg.max_len == 0 {
+            FinishReason::Length
+        } else {
+            let mut logits = self.logits(session, prefilled).await?;
+            loop {
+                if let Some(reason) = sampler.next(logits, &mut tokens).await? {
+                    break reason;
+                }
+                logits = self.decode(session, sampler.generated, tokens[tokens.len() - 1], tokens.len() as u32 - 1).await?;
+            }
+        };
+        if let Some(reason) = sampler.output.finish().await? {
+            finish_reason = reason;
+        }
+        Ok((finish_reason, tokens.split_off(prompt_len)))
+    }
+    /// Logits of the token after the prefill output
+    async fn logits(&self, _session: Session, _prefilled: (Tensor, u32)) -> Result<Tensor> {
+        bail!("model cannot run a beam search")
+    }
+    /// Forward `token` at `index_pos`, returns the logits of the next token
+    async fn decode(&self, _session: Session, _step: u32, _token: u32, _index_pos: u32) -> Result<Tensor> {
+        bail!("model cannot run a beam search")
+    }
+    /// Start `forked` with a copy of the kv caches of the session on every hop
+    async fn fork(&self, _session: Session, _forked: Session) -> Result<()> {
+        bail!("model cannot run a beam search")
+    }
+    async fn finish(&self, session: Session);
+}
+
+/// Generate the answer by sampling or beam search, returns the generated tokens which are in the kv caches of the session
+async fn generate<M: ChatSteps>(model: &M, session: Session, cfg: ChatCfg, tokens: &[u32], prefilled: (Tensor, u32), tx: Sender<ChatEvent>) -> Result<(FinishReason, Vec<u32>)> {
+    match cfg.beam_search {
+        // the beams run in forks of the session, which keeps only the prompt
+        Some(beam) => Ok((beam_search(model, session, cfg, beam, tokens, prefilled, tx).await?, vec![])),
+        None => {
+            let (reason, mut generated) = model.generate(session, cfg, tokens, prefilled, tx).await?;
+            // the last token is sampled but never forwarded
+            generated.pop();
+            Ok((reason, generated))
+        }
+    }
+}
+
+/// Fit the prompt in the context window before any hop runs, as the overflow policy says.
+///
+/// With `OverflowPolicy::Error` a longer prompt is rejected and the generation stops at the end of the window,
+/// otherwise the oldest tokens after the attention sinks are dropped and the hops slide their kv caches during the generation
+fn fit_window(mut tokens: Vec<u32>, cfg: &mut ChatCfg, window: usize) -> Result<Vec<u32>> {
+    check_window(tokens.len(), cfg, window)?;
+    if tokens.len() >= window {
+        // room for at least one generated token
+        let sinks = cfg.overflow.sinks().min(window - 1);
+        let dropped = tokens.len() - (window - 1);
+        log::warn!("[Conversations] prompt of {} tokens truncated to the context window of {window} tokens", tokens.len());
+        tokens.drain(sinks..sinks + dropped);
+    }
+    if cfg.overflow == OverflowPolicy::Error {
+        cfg.max_len = cfg.max_len.min((window - tokens.len()) as u32);
+    }
+    Ok(tokens)
+}
+
+/// Reject a prompt of `len` tokens the overflow policy can't fit in the context window.
+///
+/// A prompt with images is never truncated, the dropped tokens could hold image tokens
+fn check_window(len: usize, cfg: &ChatCfg, window: usize) -> Result<()> {
+    if len >= window && (cfg.overflow == OverflowPolicy::Error || !cfg.images.is_empty()) {
+        bail!("prompt of {len} tokens does not fit the context window of {window} tokens")
+    }
+    Ok(())
+}
+
+/// Check the prompt before the answer starts streaming, with the same errors as `chat` and `Conversations::chat_turn`
+pub(crate) fn check_prompt<M: ChatSteps>(model: &M, cfg: &ChatCfg, prompt: &str) -> Result<()> {
+    check_images(model, cfg)?;
+    check_window(model.tokenize(prompt)?.len(), cfg, model.context_window())
+}
+
+fn check_images<M: ChatSteps>(model: &M, cfg: &ChatCfg) -> Result<()> {
+    if !cfg.images.is_empty() && !model.accepts_images() {
+        bail!("model does not accept images, the prompt has {}", cfg.images.len())
+    }
+    Ok(())
+}
+
+/// Answer the prompt in its own session, finished after the answer
+pub(crate) async fn chat<M: ChatSteps>(model: &M, session: Session, mut cfg: ChatCfg, prompt: &str, tx: Sender<ChatEvent>) -> Result<FinishReason> {
+    check_images(model, &cfg)?;
+    let tokens = fit_window(model.tokenize(prompt)?, &mut cfg, model.context_window())?;
+    let cached_len = match model.start(session, &cfg, &tokens).await {
+        Ok(cached_len) => cached_len,
+        Err(e) => {
+            log::error!("failed to start layers worker: {e}");
+            return Err(e);
+        }
+    };
+    let res = match model.prefill(session, &cfg, &tokens, cached_len).await {
+        Ok(prefilled) => generate(model, session, cfg, &tokens, prefilled, tx).await.map(|(reason, _)| reason),
+        Err(e) => Err(e),
+    };
+    model.finish(session).await;
+    res
+}
+
+struct Conversation {
+    session: Session,
+    /// tokens in the kv caches of the session, the prompt and answer of the last turn
+    tokens: Vec<u32>,
+    last_used: Instant,
+}
+
+/// Sessions kept between the turns of conversations, with their kv caches on every hop of the layers chain.
+///
+/// A turn holds the lock of its conversation, None when the conversation has no session.
+#[derive(Default)]
+pub(crate) struct Conversations {
+    conversations: spin::Mutex<HashMap<String, Arc<Mutex<Option<Conversation>>>>>,
+}
+
+impl Conversations {
+    /// Answer a turn, the prompt is the whole conversation.
+    ///
+    /// When the prompt continues the tokens of the last turn only the new ones are forwarded,
+    /// otherwise or when a hop lost the session the whole prompt goes through a new session.
+    /// A prompt with images always goes through a new session, which gets the states of all its images
+    pub async fn chat_turn<M: ChatSteps>(&self, model: &M, id: &str, mut cfg: ChatCfg, prompt: &str, tx: Sender<ChatEvent>) -> Result<FinishReason> {
+        check_images(model, &cfg)?;
+        let tokens = fit_window(model.tokenize(prompt)?, &mut cfg, model.context_window())?;
+        let conversation = self.conversations.lock().entry(id.to_string()).or_default().clone();
+        let mut conversation = conversation.lock().await;
+
+        let mut reused = None;
+        if let Some(last) = conversation
+            .as_ref()
+            .filter(|last| cfg.images.is_empty() && tokens.len() > last.tokens.len() && tokens.starts_with(&last.tokens))
+        {
+            match model.prefill(last.session, &cfg, &tokens, last.tokens.len() as u32).await {
+                Ok(prefilled) => reused = Some((last.session, prefilled)),
+                Err(e) => log::warn!("[Conversations] conversation {id} lost its session, prefill the whole prompt again: {e}"),
+            }
+        }
+        let (session, prefilled) = match reused {
+            Some(reused) => reused,
+            None => {
+                if let Some(last) = conversation.take() {
+                    model.finish(last.session).await;
+                }
+                let session = Session::new();
+                let cached_len = model.start(session, &cfg, &tokens).await?;
+                match model.prefill(session, &cfg, &tokens, cached_len).await {
+                    Ok(prefilled) => (session, prefilled),
+                    Err(e) => {
+                        model.finish(session).await;
+                        return Err(e);
+                    }
+                }
+            }
+        };
+
+        match generate(model, session, cfg, &tokens, prefilled, tx).await {
+            Ok((reason, generated)) => {
+                let mut tokens = tokens;
+                tokens.extend_from_slice(&generated);
+                *conversation = Some(Conversation {
+                    session,
+                    tokens,
+                    last_used: Instant::now(),
+                });
+                Ok(reason)
+            }
+            Err(e) => {
+                model.finish(session).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Finish the sessions of the conversations idle for longer than `ttl`
+    pub async fn expire<M: ChatSteps>(&self, model: &M, ttl: Duration) {
+        let mut expired = vec![];
+        self.conversations.lock().retain(|id, conversation| {
+            // a turn is running or about to run
+            if Arc::strong_count(conversation) > 1 {
+                return true;
+            }
+            let Ok(mut conversation) = conversation.try_lock() else {
+                return true;
+            };
+            match conversation.as_ref() {
+                Some(last) if last.last_used.elapsed() < ttl => true,
+                _ => {
+                    if let Some(last) = conversation.take() {
+                        log::info!("[Conversations] conversation {id} idle for {:?}, finish its session", last.last_used.elapsed());
+                        expired.push(last.session);
+                    }
+                    false
+                }
+            }
+        });
+        for session in expired {
+            model.finish(session).await;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, OnceLock},
+        time::Duration,
+    };
+
+    use candle_core::{Device, Result, Tensor};
+    use protocol::{ChatCfg, ChatEvent, FinishReason, OverflowPolicy, Session};
+    use spin::Mutex;
+    use tokenizers::{models::wordlevel::WordLevel, Tokenizer};
+    use tokio::sync::mpsc::{channel, Sender};
+
+    use super::{check_prompt, fit_window, ChatSteps, Conversations};
+
+    /// Model of the tests, the prompts are numbers and every answer is `answer`.
+    /// The kv caches of a session hold its forwarded tokens, `next_logits` of the last one are the logits of the next token
+    pub(crate) struct MockSteps {
+        pub eos_tokens: Vec<u32>,
+        pub answer: Vec<u32>,
+        pub next_logits: fn(u32) -> Result<Tensor>,
+        pub kv: Mutex<HashMap<Session, Vec<u32>>>,
+        pub prefills: Mutex<Vec<(Session, u32)>>,
+        pub finished: Mutex<Vec<Session>>,
+        /// session lost by a hop, its prefill fails
+        pub lost: Mutex<Option<Session>>,
+    }
+
+    impl Default for MockSteps {
+        fn default() -> Self {
+            Self {
+                eos_tokens: vec![],
+                answer: vec![100, 101],
+                next_logits: |_| Tensor::zeros(1, candle_core::DType::F32, &Device::Cpu),
+                kv: Mutex::default(),
+                prefills: Mutex::default(),
+                finished: Mutex::default(),
+                lost: Mutex::default(),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ChatSteps for MockSteps {
+        fn tokenize(&self, prompt: &str) -> Result<Vec<u32>> {
+            Ok(prompt.split(' ').map(|t| t.parse().unwrap()).collect())
+        }
+
+        fn context_window(&self) -> usize {
+            64
+        }
+
+        fn tokenizer(&self) -> &Tokenizer {
+            static TOKENIZER: OnceLock<Tokenizer> = OnceLock::new();
+            TOKENIZER.get_or_init(|| Tokenizer::new(WordLevel::default()))
+        }
+
+        fn eos_tokens(&self) -> Vec<u32> {
+            self.eos_tokens.clone()
+        }
+
+        async fn start(&self, session: Session, _cfg: &ChatCfg, _tokens: &[u32]) -> Result<u32> {
+            self.kv.lock().insert(session, vec![]);
+            Ok(0)
+        }
+
+        async fn prefill(&self, session: Session, _cfg: &ChatCfg, tokens: &[u32], cached_len: u32) -> Result<(Tensor, u32)> {
+            if *self.lost.lock() == Some(session) {
+                candle_core::bail!("session {session} not found")
+            }
+            self.prefills.lock().push((session, cached_len));
+            self.kv.lock().insert(session, tokens.to_vec());
+            Ok((Tensor::zeros(1, candle_core::DType::F32, &Device::Cpu)?, 1))
+        }
+
+        async fn generate(&self, session: Session, _cfg: ChatCfg, _tokens: &[u32], _prefilled: (Tensor, u32), _tx: Sender<ChatEvent>) -> Result<(FinishReason, Vec<u32>)> {
+            // the last token of the answer is never forwarded
+            if let Some(kv) = self.kv.lock().get_mut(&session) {
+                kv.extend(self.answer.iter().take(self.answer.len().saturating_sub(1)));
+            }
+            Ok((FinishReason::Stop, self.answer.clone()))
+        }
+
+        async fn decode(&self, session: Session, _step: u32, token: u32, index_pos: u32) -> Result<Tensor> {
+            let mut kv = self.kv.lock();
+            let kv = kv.get_mut(&session).expect("session should be started");
+            assert_eq!(kv.len(), index_pos as usize, "session {session} forwarded at a wrong position");
+            kv.push(token);
+            (self.next_logits)(token)
+        }
+
+        async fn fork(&self, session: Session, forked: Session) -> Result<()> {
+            let mut kv = self.kv.lock();
+            let tokens = kv.get(&session).expect("forked session should exist").clone();
+            kv.insert(forked, tokens);
+            Ok(())
+        }
+
+        async fn finish(&self, session: Session) {
+            self.kv.lock().remove(&session);
+            self.finished.lock().push(session);
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_session_between_turns() {
+        let steps = MockSteps::default();
+        let conversations = Conversations::default();
+        let (tx, _rx) = channel(10);
+        let turn = |prompt: &'static str| conversations.chat_turn(&steps, "conv", ChatCfg::default(), prompt, tx.clone());
+
+        turn("1 2 3").await.unwrap();
+        // the kv caches hold the prompt and the answer but its last token
+        turn("1 2 3 100 101 4 5").await.unwrap();
+        let prefills = steps.prefills.lock().clone();
+        assert_eq!(prefills.iter().map(|(_, len)| *len).collect::<Vec<_>>(), vec![0, 4]);
+        assert_eq!(prefills[0].0, prefills[1].0);
+
+        // a prompt not continuing the last turn starts again in a new session
+        turn("1 2 9").await.unwrap();
+        let (session, cached_len) = steps.prefills.lock()[2];
+        assert_ne!(session, prefills[0].0);
+        assert_eq!(cached_len, 0);
+        assert_eq!(*steps.finished.lock(), vec![prefills[0].0]);
+
+        // a hop lost the session, the whole prompt is forwarded in a new session
+        *steps.lost.lock() = Some(session);
+        turn("1 2 9 100 7").await.unwrap();
+        let (new_session, cached_len) = *steps.prefills.lock().last().unwrap();
+        assert_ne!(new_session, session);
+        assert_eq!(cached_len, 0);
+
+        conversations.expire(&steps, Duration::from_secs(60)).await;
+        assert_eq!(steps.finished.lock().len(), 2);
+        conversations.expire(&steps, Duration::ZERO).await;
+        assert_eq!(steps.finished.lock().last(), Some(&new_session));
+        assert!(conversations.conversations.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reject_images_of_text_models() {
+        let steps = MockSteps::default();
+        let (tx, _rx) = channel(10);
+        let cfg = ChatCfg {
+            images: vec![Arc::new(vec![0x89, b'P', b'N', b'G'])],
+            ..Default::default()
+        };
+        assert!(super::chat(&steps, Session::new(), cfg, "1 2", tx).await.is_err());
+        assert!(steps.prefills.lock().is_empty());
+    }
+
+    #[test]
+    fn fit_prompt_in_window() {
+        let tokens = (0..10).collect::<Vec<u32>>();
+        let mut cfg = ChatCfg::default();
+        assert!(fit_window(tokens.clone(), &mut cfg, 10).is_err());
+        assert_eq!(fit_window(tokens.clone(), &mut cfg, 16).unwrap(), tokens);
+        assert_eq!(cfg.max_len, 6);
+
+        let mut cfg = ChatCfg {
+            overflow: OverflowPolicy::Truncate,
+            ..Default::default()
+        };
+        assert_eq!(fit_window(tokens.clone(), &mut cfg, 8).unwrap(), vec![3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(cfg.max_len, ChatCfg::default().max_len);
+        cfg.overflow = OverflowPolicy::SlidingWindow { sinks: 2 };
+        assert_eq!(fit_window(tokens, &mut cfg, 8).unwrap(), vec![0, 1, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn check_prompt_before_streaming() {
+        let steps = MockSteps::default();
+        let long = vec!["1"; 64].join(" ");
+        assert!(check_prompt(&steps, &ChatCfg::default(), "1 2 3").is_ok());
+        assert!(check_prompt(&steps, &ChatCfg::default(), &long).is_err());
+        let cfg = ChatCfg {
+            overflow: OverflowPolicy::Truncate,
+            ..Default::default()
+        };
+        assert!(check_prompt(&steps, &cfg, &long).is_ok());
+    }
+
+    #[test]
+    fn never_truncate_images() {
+        let tokens = (0..10).collect::<Vec<u32>>();
+        let mut cfg = ChatCfg {
+            overflow: OverflowPolicy::Truncate,
+            images: vec![Arc::new(vec![0x89, b'P', b'N', b'G'])],
+            ..Default::default()
+        };
+        assert!(fit_window(tokens.clone(), &mut cfg, 8).is_err());
+        assert_eq!(fit_window(tokens.clone(), &mut cfg, 16).unwrap(), tokens);
+    }
+}
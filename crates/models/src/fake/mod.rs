@@ -4,7 +4,7 @@ use std::{
 };
 
 use candle_core::{Device, Result, Shape, Tensor};
-use protocol::Session;
+use protocol::{ChatEvent, FinishReason, Session};
 use tokio::sync::mpsc::Sender;
 
 use crate::{ChatCfg, ChatCompletionRequest, ChatModel, ModelLayersWorker};
@@ -26,18 +26,23 @@ impl<W: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static> ChatModel for
         todo!()
     }
 
-    async fn chat(&self, session: Session, cfg: ChatCfg, _prompt: &str, tx: Sender<String>) -> Result<()> {
+    async fn chat(&self, session: Session, cfg: ChatCfg, _prompt: &str, tx: Sender<ChatEvent>) -> Result<FinishReason> {
         self.layers_worker.start(session, cfg.clone()).await?;
         let start_gen = Instant::now();
         for index in 0..cfg.max_len {
             let tensor = Tensor::from_vec(vec![index], Shape::from_dims(&[1]), &self.device).unwrap();
             let (_output, _) = self.layers_worker.forward(session, index, (tensor, index), index).await?;
-            tx.send(format!("{index} ")).await.unwrap();
+            tx.send(ChatEvent::Delta {
+                text: format!("{index} "),
+                logprobs: vec![],
+            })
+            .await
+            .unwrap();
         }
         let dt = start_gen.elapsed();
         println!("\n\n{} tokens generated ({} token/s)\n", cfg.max_len, (cfg.max_len - 1) as f64 / dt.as_secs_f64(),);
         self.layers_worker.finish(session).await;
-        Ok(())
+        Ok(FinishReason::Length)
     }
 }
 
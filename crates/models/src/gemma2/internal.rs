@@ -0,0 +1,360 @@
+use std::ops::Range;
+
+use candle_core::{DType, IndexOp, Module, Result, Tensor, D};
+use candle_nn::{embedding, linear_no_bias, Embedding, Linear, RmsNorm, VarBuilder};
+
+use crate::llama::internal::{Cache, Config, LlamaEosToks};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Gemma2Config {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    /// not hidden_size / num_attention_heads for every size
+    pub head_dim: usize,
+    pub rms_norm_eps: f64,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f32,
+    pub max_position_embeddings: usize,
+    pub attn_logit_softcapping: Option<f64>,
+    pub final_logit_softcapping: Option<f64>,
+    /// the queries are scaled by its inverse square root instead of the one of head_dim
+    pub query_pre_attn_scalar: usize,
+    /// attention span of the local layers, every even layer is local and every odd one global
+    pub sliding_window: Option<usize>,
+    pub bos_token_id: Option<u32>,
+    pub eos_token_id: Option<LlamaEosToks>,
+}
+
+fn default_rope_theta() -> f32 {
+    10_000.0
+}
+
+impl Gemma2Config {
+    /// Config of the kv caches, a llama one whose hidden size is the one of the attention heads
+    pub fn cache_config(&self) -> Config {
+        Config {
+            hidden_size: self.num_attention_heads * self.head_dim,
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads,
+            use_flash_attn: false,
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+            bos_token_id: self.bos_token_id,
+            eos_token_id: self.eos_token_id.clone(),
+            rope_scaling: None,
+            max_position_embeddings: self.max_position_embeddings,
+            tie_word_embeddings: true,
+            attention_bias: false,
+            rope_interleaved: false,
+            rope_freq_factors: None,
+        }
+    }
+
+    /// Attention span of the layer `layer_idx` of the whole model, None for a global layer
+    pub fn layer_sliding_window(&self, layer_idx: usize) -> Option<usize> {
+        if layer_idx.is_multiple_of(2) {
+            self.sliding_window
+        } else {
+            None
+        }
+    }
+}
+
+/// RmsNorm of gemma, the weights are offsets from 1
+fn rms_norm(size: usize, eps: f64, vb: VarBuilder) -> Result<RmsNorm> {
+    let weight = (vb.get(size, "weight")? + 1.0)?;
+    Ok(RmsNorm::new(weight, eps))
+}
+
+fn soft_cap(xs: &Tensor, cap: Option<f64>) -> Result<Tensor> {
+    match cap {
+        Some(cap) => (xs / cap)?.tanh()? * cap,
+        None => Ok(xs.clone()),
+    }
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
+    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(mask.shape().dims())?;
+    mask.where_cond(&on_true, on_false)
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    head_dim: usize,
+    scale: f64,
+    softcapping: Option<f64>,
+    sliding_window: Option<usize>,
+    span: tracing::Span,
+}
+
+impl Attention {
+    /// Each row of `x` is a different session, with its own position and cache
+    fn forward(&self, x: &Tensor, index_pos: &[usize], block_idx: usize, caches: &mut [&mut Cache]) -> Result<Tensor> {
+        let _enter = self.span.enter();
+        let (b_sz, seq_len, _) = x.dims3()?;
+        let q = self.q_proj.forward(x)?;
+        let k = self.k_proj.forward(x)?;
+        let v = self.v_proj.forward(x)?;
+
+        let q = q.reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim))?.transpose(1, 2)?.contiguous()?;
+        let k = k.reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?.transpose(1, 2)?.contiguous()?;
+        let v = v.reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?.transpose(1, 2)?;
+
+        let mut ys = Vec::with_capacity(b_sz);
+        for (i, cache) in caches.iter_mut().enumerate() {
+            ys.push(self.attend(&q.narrow(0, i, 1)?, &k.narrow(0, i, 1)?, &v.narrow(0, i, 1)?, index_pos[i], block_idx, cache)?);
+        }
+        let y = Tensor::cat(&ys, 0)?;
+        let y = y.transpose(1, 2)?.reshape((b_sz, seq_len, self.num_attention_heads * self.head_dim))?;
+        self.o_proj.forward(&y)
+    }
+
+    fn attend(&self, q: &Tensor, k: &Tensor, v: &Tensor, index_pos: usize, block_idx: usize, cache: &mut Cache) -> Result<Tensor> {
+        let seq_len = q.dim(2)?;
+        let (cos, sin) = cache.rope(index_pos, seq_len)?;
+        let q = candle_nn::rotary_emb::rope(q, &cos, &sin)?;
+        let k = candle_nn::rotary_emb::rope(k, &cos, &sin)?;
+
+        let (k, v) = cache.kv(block_idx).append(&k.contiguous()?, &v.contiguous()?)?;
+        let k = crate::utils::repeat_kv(k, self.num_attention_heads / self.num_key_value_heads)?;
+        let v = crate::utils::repeat_kv(v, self.num_attention_heads / self.num_key_value_heads)?;
+
+        let in_dtype = q.dtype();
+        let q = q.to_dtype(DType::F32)?;
+        let k = k.to_dtype(DType::F32)?;
+        let v = v.to_dtype(DType::F32)?;
+        let att = (q.matmul(&k.t()?)? * self.scale)?;
+        let att = soft_cap(&att, self.softcapping)?;
+        let kv_len = att.dim(D::Minus1)?;
+        // a single token only needs a mask when its window is shorter than the cache
+        let att = if seq_len > 1 || self.sliding_window.is_some_and(|window| window < kv_len) {
+            let mask = cache.mask(seq_len, kv_len, self.sliding_window)?.broadcast_as(att.shape())?;
+            masked_fill(&att, &mask, f32::NEG_INFINITY)?
+        } else {
+            att
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Gemma2Config, layer_idx: usize) -> Result<Self> {
+        let size_q = cfg.num_attention_heads * cfg.head_dim;
+        let size_kv = cfg.num_key_value_heads * cfg.head_dim;
+        Ok(Self {
+            q_proj: linear_no_bias(cfg.hidden_size, size_q, vb.pp("q_proj"))?,
+            k_proj: linear_no_bias(cfg.hidden_size, size_kv, vb.pp("k_proj"))?,
+            v_proj: linear_no_bias(cfg.hidden_size, size_kv, vb.pp("v_proj"))?,
+            o_proj: linear_no_bias(size_q, cfg.hidden_size, vb.pp("o_proj"))?,
+            num_attention_heads: cfg.num_attention_heads,
+            num_key_value_heads: cfg.num_key_value_heads,
+            head_dim: cfg.head_dim,
+            scale: 1. / (cfg.query_pre_attn_scalar as f64).sqrt(),
+            softcapping: cfg.attn_logit_softcapping,
+            sliding_window: cfg.layer_sliding_window(layer_idx),
+            span: tracing::span!(tracing::Level::TRACE, "attn"),
+        })
+    }
+}
+
+/// GeGLU with the tanh approximation of gelu
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+}
+
+impl Mlp {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let x = (self.gate_proj.forward(x)?.gelu()? * self.up_proj.forward(x)?)?;
+        self.down_proj.forward(&x)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Gemma2Config) -> Result<Self> {
+        Ok(Self {
+            gate_proj: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("gate_proj"))?,
+            up_proj: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("up_proj"))?,
+            down_proj: linear_no_bias(cfg.intermediate_size, cfg.hidden_size, vb.pp("down_proj"))?,
+        })
+    }
+}
+
+/// The attention and the mlp both have a norm before and after them
+#[derive(Debug, Clone)]
+struct Block {
+    input_layernorm: RmsNorm,
+    attn: Attention,
+    post_attention_layernorm: RmsNorm,
+    pre_feedforward_layernorm: RmsNorm,
+    mlp: Mlp,
+    post_feedforward_layernorm: RmsNorm,
+    span: tracing::Span,
+}
+
+impl Block {
+    fn forward(&self, x: &Tensor, index_pos: &[usize], block_idx: usize, caches: &mut [&mut Cache]) -> Result<Tensor> {
+        let _enter = self.span.enter();
+        let residual = x;
+        let x = self.input_layernorm.forward(x)?;
+        let x = self.attn.forward(&x, index_pos, block_idx, caches)?;
+        let x = (self.post_attention_layernorm.forward(&x)? + residual)?;
+        let residual = &x;
+        let y = self.mlp.forward(&self.pre_feedforward_layernorm.forward(&x)?)?;
+        self.post_feedforward_layernorm.forward(&y)? + residual
+    }
+
+    fn load(vb: VarBuilder, cfg: &Gemma2Config, layer_idx: usize) -> Result<Self> {
+        let norm = |name: &str| rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp(name));
+        Ok(Self {
+            input_layernorm: norm("input_layernorm")?,
+            attn: Attention::load(vb.pp("self_attn"), cfg, layer_idx)?,
+            post_attention_layernorm: norm("post_attention_layernorm")?,
+            pre_feedforward_layernorm: norm("pre_feedforward_layernorm")?,
+            mlp: Mlp::load(vb.pp("mlp"), cfg)?,
+            post_feedforward_layernorm: norm("post_feedforward_layernorm")?,
+            span: tracing::span!(tracing::Level::TRACE, "block"),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Gemma2Layers {
+    blocks: Vec<Block>,
+}
+
+impl Gemma2Layers {
+    pub fn forward(&self, x: Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        self.forward_batch(x, &[index_pos], &mut [cache])
+    }
+
+    /// Forward several sessions together, row `i` of `x` is at `index_pos[i]` with `caches[i]`
+    pub fn forward_batch(&self, mut x: Tensor, index_pos: &[usize], caches: &mut [&mut Cache]) -> Result<Tensor> {
+        if x.dim(0)? != caches.len() || index_pos.len() != caches.len() {
+            candle_core::bail!("batch of {} rows with {} positions and {} caches", x.dim(0)?, index_pos.len(), caches.len())
+        }
+        for (block_idx, block) in self.blocks.iter().enumerate() {
+            x = block.forward(&x, index_pos, block_idx, caches)?;
+        }
+        Ok(x)
+    }
+
+    /// Only the layers in `range` are loaded, each one is local or global by its index in the whole model
+    pub fn load(vb: VarBuilder, cfg: &Gemma2Config, range: Range<u32>) -> Result<Self> {
+        if range.end > cfg.num_hidden_layers as u32 {
+            candle_core::bail!("layers {range:?} out of the {} layers of the model", cfg.num_hidden_layers)
+        }
+        let blocks = range.map(|i| Block::load(vb.pp(format!("model.layers.{i}")), cfg, i as usize)).collect::<Result<Vec<_>>>()?;
+        Ok(Self { blocks })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Gemma2Pre {
+    wte: Embedding,
+    hidden_size: usize,
+}
+
+impl Gemma2Pre {
+    /// The embeddings are scaled by the square root of the hidden size
+    pub fn forward(&self, x: &Tensor) -> Result<(Tensor, usize)> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let xs = (self.wte.forward(x)? * (self.hidden_size as f64).sqrt())?;
+        Ok((xs, seq_len))
+    }
+
+    pub fn load(vb: &VarBuilder, cfg: &Gemma2Config) -> Result<Self> {
+        Ok(Self {
+            wte: embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("model.embed_tokens"))?,
+            hidden_size: cfg.hidden_size,
+        })
+    }
+}
+
+/// The head is tied to the embeddings and its logits are soft capped
+#[derive(Debug, Clone)]
+pub struct Gemma2Post {
+    norm: RmsNorm,
+    lm_head: Linear,
+    softcapping: Option<f64>,
+}
+
+impl Gemma2Post {
+    pub fn forward(&self, x: &Tensor, seq_len: usize) -> Result<Tensor> {
+        let x = self.norm.forward(x)?;
+        let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
+        let logits = self.lm_head.forward(&x)?.to_dtype(DType::F32)?;
+        soft_cap(&logits, self.softcapping)
+    }
+
+    pub fn load(vb: &VarBuilder, cfg: &Gemma2Config) -> Result<Self> {
+        let wte = embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("model.embed_tokens"))?;
+        Ok(Self {
+            norm: rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("model.norm"))?,
+            lm_head: Linear::new(wte.embeddings().clone(), None),
+            softcapping: cfg.final_logit_softcapping,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::{VarBuilder, VarMap};
+    use protocol::OverflowPolicy;
+
+    use super::{Cache, Gemma2Config, Gemma2Layers};
+    use crate::kv_cache::KvQuantization;
+
+    fn config() -> Gemma2Config {
+        serde_json::from_str(
+            r#"{"hidden_size": 16, "intermediate_size": 24, "vocab_size": 32, "num_hidden_layers": 2, "num_attention_heads": 2, "num_key_value_heads": 1,
+                "head_dim": 12, "rms_norm_eps": 1e-6, "max_position_embeddings": 64, "attn_logit_softcapping": 50.0, "final_logit_softcapping": 30.0,
+                "query_pre_attn_scalar": 12, "sliding_window": 4, "eos_token_id": [1, 107]}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn local_layer_attends_to_sliding_window() {
+        let device = Device::Cpu;
+        let cfg = config();
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        // layer 0 is local, layer 1 global
+        let local = Gemma2Layers::load(vb.clone(), &cfg, 0..1).unwrap();
+        let global = Gemma2Layers::load(vb, &cfg, 1..2).unwrap();
+        let xs = Tensor::randn(0f32, 1., (1, 10, cfg.hidden_size), &device).unwrap();
+        let last = |layers: &Gemma2Layers, xs: &Tensor| {
+            let mut cache = Cache::new(true, KvQuantization::None, OverflowPolicy::Error, DType::F32, &cfg.cache_config(), &device).unwrap();
+            let ys = layers.forward(xs.clone(), 0, &mut cache).unwrap();
+            ys.narrow(1, xs.dim(1).unwrap() - 1, 1).unwrap()
+        };
+        let diff = |a: Tensor, b: Tensor| (a - b).unwrap().abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+
+        // rope is relative, so the last token of a local layer sees the same as with only its window
+        let window = xs.narrow(1, 10 - 4, 4).unwrap();
+        assert!(diff(last(&local, &xs), last(&local, &window)) < 1e-4);
+        assert!(diff(last(&global, &xs), last(&global, &window)) > 1e-4);
+
+        // token by token matches the whole prompt
+        let mut cache = Cache::new(true, KvQuantization::None, OverflowPolicy::Error, DType::F32, &cfg.cache_config(), &device).unwrap();
+        let mut ys = vec![];
+        for pos in 0..10 {
+            ys.push(local.forward(xs.narrow(1, pos, 1).unwrap(), pos, &mut cache).unwrap());
+        }
+        assert!(diff(ys.pop().unwrap(), last(&local, &xs)) < 1e-4);
+    }
+}
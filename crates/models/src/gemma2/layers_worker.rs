@@ -0,0 +1,79 @@
+use std::ops::Range;
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+use protocol::llm::PrefixKey;
+
+use crate::{
+    kv_cache::{KvQuantization, SessionCaches},
+    ChatCfg, KvBudgetConfig, KvUsage, ModelLayersWorker, Session,
+};
+
+use crate::llama::internal::Cache;
+
+use super::internal::{Gemma2Config, Gemma2Layers};
+
+pub struct Gemma2LayersWorker {
+    caches: SessionCaches<Cache>,
+    kv_quantization: KvQuantization,
+    layers: Gemma2Layers,
+    cfg: Gemma2Config,
+    dtype: DType,
+    device: Device,
+}
+
+impl Gemma2LayersWorker {
+    pub fn new(range: Range<u32>, vb: VarBuilder, cfg: Gemma2Config, dtype: DType, device: Device, kv_budget: KvBudgetConfig) -> Result<Self> {
+        let layers = Gemma2Layers::load(vb, &cfg, range.clone())?;
+        let block_bytes = range.len() * kv_budget.quantization.block_bytes(cfg.num_key_value_heads, cfg.head_dim, dtype);
+        Ok(Self {
+            kv_quantization: kv_budget.quantization,
+            caches: SessionCaches::new(kv_budget, block_bytes as u64),
+            layers,
+            cfg,
+            dtype,
+            device,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl ModelLayersWorker<(Tensor, u32)> for Gemma2LayersWorker {
+    async fn start(&self, session: Session, cfg: ChatCfg) -> Result<()> {
+        let cache = Cache::new(true, self.kv_quantization, cfg.overflow, self.dtype, &self.cfg.cache_config(), &self.device)?;
+        self.caches.start(session, cache)
+    }
+
+    async fn start_with_prefix(&self, session: Session, cfg: ChatCfg, prefixes: Vec<PrefixKey>) -> Result<u32> {
+        self.start(session, cfg).await?;
+        Ok(self.caches.register_prefixes(session, prefixes))
+    }
+
+    async fn forward(&self, session: Session, _step: u32, (xs, seq_len): (Tensor, u32), index_pos: u32) -> Result<(Tensor, u32)> {
+        let (cache, pos) = self.caches.forward_cache(session, index_pos, seq_len)?;
+        let mut cache_mut = cache.lock();
+        let res = self.layers.forward(xs, pos, &mut cache_mut)?;
+        self.caches.store_prefix(session, index_pos + seq_len, &cache_mut);
+        Ok((res, seq_len))
+    }
+
+    async fn forward_batch(&self, batch: Vec<(Session, u32, (Tensor, u32), u32)>) -> Vec<Result<(Tensor, u32)>> {
+        self.caches.forward_batch(batch, |xs, _, positions, caches| self.layers.forward_batch(xs, positions, caches))
+    }
+
+    async fn rollback(&self, session: Session, count: u32) -> Result<()> {
+        self.caches.rollback(session, count)
+    }
+
+    async fn fork(&self, session: Session, forked: Session) -> Result<()> {
+        self.caches.fork(session, forked)
+    }
+
+    async fn finish(&self, session: Session) {
+        self.caches.finish(session);
+    }
+
+    fn kv_usage(&self) -> KvUsage {
+        self.caches.usage()
+    }
+}
@@ -0,0 +1,220 @@
+//! Gemma 2 has norms before and after both the attention and the mlp, soft capped attention and final logits,
+//! local sliding window attention on every other layer and a GeGLU mlp.
+//! It is split into embedding, layers and head like llama, so the 9B and 27B models are sharded across devices.
+
+use std::{
+    ops::Range,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+use internal::{Gemma2Config, Gemma2Post, Gemma2Pre};
+use protocol::{ChatEvent, FinishReason, ModelManifest, PromptTemplate, Session};
+use tokenizers::Tokenizer;
+use tokio::sync::mpsc::Sender;
+
+mod internal;
+mod layers_worker;
+
+pub use layers_worker::Gemma2LayersWorker;
+
+use crate::{
+    constraint::TokenVocab,
+    conversation::{self, ChatSteps, Conversations},
+    llama::internal::LlamaEosToks,
+    prefill::prefill,
+    prefix_cache::prefix_keys,
+    prompt::ChatTemplate,
+    resource::ResourceSource,
+    ChatCfg, ChatCompletionRequest, ChatModel, KvBudgetConfig, ModelLayersWorker,
+};
+
+const BOS_TOKEN: &str = "<bos>";
+/// Closes the model turn, the config only has it with the eos of the whole text in recent revisions
+const END_OF_TURN_TOKEN: &str = "<end_of_turn>";
+
+pub struct Gemma2Resource {
+    pub repo: String,
+    pub tokenizer_repo: String,
+    pub tokenizer: String,
+    pub config: String,
+    pub model: String,
+    pub tokenizer_config: Option<String>,
+    pub prompt_template: PromptTemplate,
+    pub source: ResourceSource,
+}
+
+impl Gemma2Resource {
+    pub fn from_manifest(manifest: &ModelManifest, source: ResourceSource) -> Self {
+        Self {
+            repo: manifest.repo.clone(),
+            tokenizer_repo: manifest.tokenizer_repo().to_string(),
+            tokenizer: manifest.tokenizer.clone(),
+            config: manifest.config.clone().unwrap_or_else(|| "config.json".to_string()),
+            model: manifest.weights.clone(),
+            tokenizer_config: manifest.tokenizer_config.clone(),
+            prompt_template: manifest.prompt_template,
+            source,
+        }
+    }
+
+    pub async fn num_layers(&self) -> Result<u32> {
+        Ok(self.load_config().await?.num_hidden_layers as u32)
+    }
+
+    async fn load_config(&self) -> Result<Gemma2Config> {
+        let config_filename = self.source.get(&self.repo, &self.config).await?;
+        let config = std::fs::read(config_filename)?;
+        serde_json::from_slice(&config).map_err(candle_core::Error::wrap)
+    }
+
+    async fn load_weights(&self, dtype: DType, device: &Device) -> Result<VarBuilder<'static>> {
+        let filenames = self.source.get_safetensors(&self.repo, &self.model).await?;
+        unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, device) }
+    }
+}
+
+pub struct Gemma2Model<W: ModelLayersWorker<(Tensor, u32)>> {
+    device: Device,
+    tokenizer: Tokenizer,
+    pre: Gemma2Pre,
+    post: Gemma2Post,
+    layers_worker: W,
+    config: Gemma2Config,
+    chat_template: ChatTemplate,
+    token_vocab: OnceLock<Arc<TokenVocab>>,
+    conversations: Conversations,
+}
+
+impl<W: ModelLayersWorker<(Tensor, u32)>> Gemma2Model<W> {
+    pub async fn new(resource: &Gemma2Resource, device: Device, dtype: DType, layers_worker: W) -> Result<Self> {
+        let tokenizer_filename = resource.source.get(&resource.tokenizer_repo, &resource.tokenizer).await?;
+        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(candle_core::Error::msg)?;
+
+        let chat_template = ChatTemplate::load(&resource.source, &resource.tokenizer_repo, resource.tokenizer_config.as_deref(), resource.prompt_template).await;
+        let config = resource.load_config().await?;
+        let vb = resource.load_weights(dtype, &device).await?;
+        let pre = Gemma2Pre::load(&vb, &config)?;
+        let post = Gemma2Post::load(&vb, &config)?;
+
+        Ok(Self {
+            device,
+            tokenizer,
+            pre,
+            post,
+            layers_worker,
+            config,
+            chat_template,
+            token_vocab: OnceLock::new(),
+            conversations: Conversations::default(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl<W: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static> ChatModel for Gemma2Model<W> {
+    fn build_prompt(&self, request: &ChatCompletionRequest) -> String {
+        self.chat_template.render(request)
+    }
+
+    fn check_prompt(&self, cfg: &ChatCfg, prompt: &str) -> Result<()> {
+        conversation::check_prompt(self, cfg, prompt)
+    }
+
+    async fn chat(&self, session: Session, cfg: ChatCfg, prompt: &str, tx: Sender<ChatEvent>) -> Result<FinishReason> {
+        conversation::chat(self, session, cfg, prompt, tx).await
+    }
+
+    async fn chat_turn(&self, conversation: &str, cfg: ChatCfg, prompt: &str, tx: Sender<ChatEvent>) -> Result<FinishReason> {
+        self.conversations.chat_turn(self, conversation, cfg, prompt, tx).await
+    }
+
+    async fn expire_conversations(&self, ttl: Duration) {
+        self.conversations.expire(self, ttl).await
+    }
+}
+
+impl<W: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static> Gemma2Model<W> {
+    fn bos_token(&self) -> Option<u32> {
+        self.config.bos_token_id.or_else(|| self.tokenizer.token_to_id(BOS_TOKEN))
+    }
+}
+
+#[async_trait::async_trait]
+impl<W: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static> ChatSteps for Gemma2Model<W> {
+    /// The chat templates of gemma start with `<bos>`, the tokenizer only adds it when the prompt does not
+    fn tokenize(&self, prompt: &str) -> Result<Vec<u32>> {
+        let mut tokens = self.tokenizer.encode(prompt, false).map_err(candle_core::Error::msg)?.get_ids().to_vec();
+        match self.bos_token() {
+            Some(bos) if tokens.first() != Some(&bos) => tokens.insert(0, bos),
+            _ => {}
+        }
+        Ok(tokens)
+    }
+
+    fn context_window(&self) -> usize {
+        self.config.max_position_embeddings
+    }
+
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    fn token_vocab(&self) -> Arc<TokenVocab> {
+        self.token_vocab.get_or_init(|| Arc::new(TokenVocab::new(&self.tokenizer))).clone()
+    }
+
+    fn eos_tokens(&self) -> Vec<u32> {
+        let mut eos_tokens = match self.config.eos_token_id.clone() {
+            Some(LlamaEosToks::Single(id)) => vec![id],
+            Some(LlamaEosToks::Multiple(ids)) => ids,
+            None => vec![],
+        };
+        match self.tokenizer.token_to_id(END_OF_TURN_TOKEN) {
+            Some(end_of_turn) if !eos_tokens.contains(&end_of_turn) => eos_tokens.push(end_of_turn),
+            _ => {}
+        }
+        eos_tokens
+    }
+
+    async fn start(&self, session: Session, cfg: &ChatCfg, tokens: &[u32]) -> Result<u32> {
+        let prefixes = prefix_keys(tokens, cfg.prefill_chunk_size);
+        self.layers_worker.start_with_prefix(session, cfg.clone(), prefixes).await
+    }
+
+    async fn prefill(&self, session: Session, cfg: &ChatCfg, tokens: &[u32], cached_len: u32) -> Result<(Tensor, u32)> {
+        log::info!("[Gemma2Model] session {session} prefill {} prompt tokens with {cached_len} cached", tokens.len());
+        let chunks = tokens[cached_len as usize..]
+            .chunks(cfg.prefill_chunk_size.max(1))
+            .map(|chunk| self.pre.forward(&Tensor::new(chunk, &self.device)?.unsqueeze(0)?).map(|(input, seq_len)| (input, seq_len as u32)))
+            .collect::<Result<Vec<_>>>()?;
+        prefill(&self.layers_worker, session, cached_len, chunks).await
+    }
+
+    async fn logits(&self, _session: Session, (logits, seq_len): (Tensor, u32)) -> Result<Tensor> {
+        self.post.forward(&logits, seq_len as usize)?.squeeze(0)
+    }
+
+    async fn decode(&self, session: Session, step: u32, token: u32, index_pos: u32) -> Result<Tensor> {
+        let (input, seq_len) = self.pre.forward(&Tensor::new(&[token], &self.device)?.unsqueeze(0)?)?;
+        let (logits, _) = self.layers_worker.forward(session, step, (input, seq_len as u32), index_pos).await?;
+        self.post.forward(&logits, seq_len)?.squeeze(0)
+    }
+
+    async fn fork(&self, session: Session, forked: Session) -> Result<()> {
+        self.layers_worker.fork(session, forked).await
+    }
+
+    async fn finish(&self, session: Session) {
+        self.layers_worker.finish(session).await
+    }
+}
+
+/// Only the tensors of the layers in `range` are loaded
+pub async fn new_layers(resource: &Gemma2Resource, dtype: DType, device: Device, range: Range<u32>, kv_budget: KvBudgetConfig) -> Result<Gemma2LayersWorker> {
+    let config = resource.load_config().await?;
+    let vb = resource.load_weights(dtype, &device).await?;
+    Gemma2LayersWorker::new(range, vb, config, dtype, device, kv_budget)
+}
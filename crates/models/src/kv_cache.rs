@@ -0,0 +1,897 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use candle_core::{bail, DType, Result, Tensor};
+use protocol::{llm::PrefixKey, OverflowPolicy, Session};
+use spin::Mutex;
+use utils::shared_map::SharedHashMap;
+
+use crate::{
+    batch::{forward_stacked, forward_stacked_async},
+    prefix_cache::PrefixCache,
+};
+
+/// Positions in a block of the paged kv caches
+pub const KV_BLOCK_SIZE: usize = 32;
+
+/// How the full blocks of the kv caches are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KvQuantization {
+    /// in the dtype of the model
+    #[default]
+    None,
+    /// int8 with f32 scales in every block, per channel for the keys and per position for the values
+    Int8,
+}
+
+impl KvQuantization {
+    /// Bytes of the keys and values of a full block of `kv_heads` heads, `dtype` is the dtype of the model
+    pub fn block_bytes(&self, kv_heads: usize, head_dim: usize, dtype: DType) -> usize {
+        match self {
+            KvQuantization::None => 2 * kv_heads * head_dim * KV_BLOCK_SIZE * dtype.size_in_bytes(),
+            KvQuantization::Int8 => kv_heads * (2 * head_dim * KV_BLOCK_SIZE + (head_dim + KV_BLOCK_SIZE) * DType::F32.size_in_bytes()),
+        }
+    }
+
+    /// Store the keys and values of a block along `dim`, only full blocks are quantized
+    fn block(&self, k: Tensor, v: Tensor, dim: usize) -> Result<(KvTensor, KvTensor)> {
+        match self {
+            KvQuantization::Int8 if k.dim(dim)? == KV_BLOCK_SIZE => Ok((KvTensor::int8(&k, dim)?, KvTensor::int8(&v, v.rank() - 1)?)),
+            _ => Ok((KvTensor::Plain(k), KvTensor::Plain(v))),
+        }
+    }
+}
+
+/// Keys or values of a block
+#[derive(Debug, Clone)]
+enum KvTensor {
+    Plain(Tensor),
+    /// `q` is the tensor divided by `scale`, shifted by 128 to fit in u8
+    Int8 {
+        q: Tensor,
+        scale: Tensor,
+        dtype: DType,
+    },
+}
+
+impl KvTensor {
+    /// Quantize `xs` with a scale for every slice along `reduce_dim`
+    fn int8(xs: &Tensor, reduce_dim: usize) -> Result<Self> {
+        let dtype = xs.dtype();
+        let xs = xs.to_dtype(DType::F32)?;
+        let scale = (xs.abs()?.max_keepdim(reduce_dim)? / 127.)?.clamp(f32::MIN_POSITIVE, f32::MAX)?;
+        let q = (xs.broadcast_div(&scale)?.round()? + 128.)?.clamp(1f32, 255f32)?.to_dtype(DType::U8)?;
+        Ok(Self::Int8 { q, scale, dtype })
+    }
+
+    fn tensor(&self) -> Result<Tensor> {
+        match self {
+            KvTensor::Plain(xs) => Ok(xs.clone()),
+            KvTensor::Int8 { q, scale, dtype } => (q.to_dtype(DType::F32)? - 128.)?.broadcast_mul(scale)?.to_dtype(*dtype),
+        }
+    }
+}
+
+/// Kv cache of one layer of a session, allocated by blocks of `KV_BLOCK_SIZE` positions as the sequence grows.
+///
+/// Blocks are never written in place, the last partial block is replaced when it grows.
+/// So clones of a cache share their full blocks, like the prefix cache and the sessions restored from it.
+/// With `KvQuantization::Int8` a block is quantized once full, and dequantized at every attention
+#[derive(Debug, Clone)]
+pub struct PagedKvCache {
+    dim: usize,
+    quantization: KvQuantization,
+    blocks: Vec<(KvTensor, KvTensor)>,
+    len: usize,
+}
+
+impl PagedKvCache {
+    /// `dim` is the sequence dimension of the keys and values, of shape (b, heads, seq, head_dim)
+    pub fn new(dim: usize, quantization: KvQuantization) -> Self {
+        Self {
+            dim,
+            quantization,
+            blocks: vec![],
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append the new keys and values, returns the keys and values of the whole sequence
+    pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let seq_len = k.dim(self.dim)?;
+        let mut offset = 0;
+        while offset < seq_len {
+            let filled = self.len % KV_BLOCK_SIZE;
+            let len = (KV_BLOCK_SIZE - filled).min(seq_len - offset);
+            let (k, v) = (k.narrow(self.dim, offset, len)?, v.narrow(self.dim, offset, len)?);
+            match self.blocks.last_mut() {
+                Some((block_k, block_v)) if filled > 0 => {
+                    let k = Tensor::cat(&[&block_k.tensor()?, &k], self.dim)?;
+                    let v = Tensor::cat(&[&block_v.tensor()?, &v], self.dim)?;
+                    (*block_k, *block_v) = self.quantization.block(k, v, self.dim)?;
+                }
+                _ => {
+                    let block = self.quantization.block(k.contiguous()?, v.contiguous()?, self.dim)?;
+                    self.blocks.push(block)
+                }
+            }
+            self.len += len;
+            offset += len;
+        }
+        match self.kv()? {
+            Some(kv) => Ok(kv),
+            None => bail!("append nothing to an empty kv cache"),
+        }
+    }
+
+    /// Keys and values of the whole sequence, None if empty
+    pub fn kv(&self) -> Result<Option<(Tensor, Tensor)>> {
+        if self.blocks.is_empty() {
+            return Ok(None);
+        }
+        let (ks, vs): (Vec<_>, Vec<_>) = self.blocks.iter().map(|(k, v)| Ok((k.tensor()?, v.tensor()?))).collect::<Result<Vec<_>>>()?.into_iter().unzip();
+        Ok(Some((Tensor::cat(&ks, self.dim)?, Tensor::cat(&vs, self.dim)?)))
+    }
+
+    /// Copy of the cache with only its first `len` positions, sharing the full blocks
+    pub fn truncate(&self, len: usize) -> Result<Self> {
+        let len = len.min(self.len);
+        let mut blocks = self.blocks[..len / KV_BLOCK_SIZE].to_vec();
+        if !len.is_multiple_of(KV_BLOCK_SIZE) {
+            let (k, v) = &self.blocks[len / KV_BLOCK_SIZE];
+            let k = k.tensor()?.narrow(self.dim, 0, len % KV_BLOCK_SIZE)?.contiguous()?;
+            let v = v.tensor()?.narrow(self.dim, 0, len % KV_BLOCK_SIZE)?.contiguous()?;
+            blocks.push((KvTensor::Plain(k), KvTensor::Plain(v)));
+        }
+        Ok(Self {
+            dim: self.dim,
+            quantization: self.quantization,
+            blocks,
+            len,
+        })
+    }
+
+    /// Drop the last `count` positions
+    pub fn rollback(&mut self, count: usize) -> Result<()> {
+        if count > self.len {
+            bail!("cannot roll back {count} of {} positions", self.len)
+        }
+        *self = self.truncate(self.len - count)?;
+        Ok(())
+    }
+
+    /// Drop the positions in `range`, the keys after it move back by `range.len()` positions with `shift_keys`
+    pub fn evict(&mut self, range: Range<usize>, shift_keys: impl FnOnce(&Tensor) -> Result<Tensor>) -> Result<()> {
+        let Some((k, v)) = self.kv()? else {
+            return Ok(());
+        };
+        let end = range.end.min(self.len);
+        let mut cache = Self::new(self.dim, self.quantization);
+        if range.start > 0 {
+            cache.append(&k.narrow(self.dim, 0, range.start)?, &v.narrow(self.dim, 0, range.start)?)?;
+        }
+        if end < self.len {
+            let k = shift_keys(&k.narrow(self.dim, end, self.len - end)?)?;
+            cache.append(&k, &v.narrow(self.dim, end, self.len - end)?)?;
+        }
+        *self = cache;
+        Ok(())
+    }
+}
+
+/// Positions to evict from a kv cache of `cached` positions before appending `seq_len` new ones,
+/// so that it stays within a context window of `window` positions. None when they fit.
+///
+/// Whole blocks are evicted after the attention sinks, so the eviction happens once every `KV_BLOCK_SIZE` tokens
+pub fn overflow_eviction(policy: OverflowPolicy, window: usize, cached: usize, seq_len: usize) -> Result<Option<Range<usize>>> {
+    if cached + seq_len <= window {
+        return Ok(None);
+    }
+    if policy == OverflowPolicy::Error {
+        bail!("context window of {window} tokens exceeded, {cached} tokens cached and {seq_len} new")
+    }
+    let sinks = policy.sinks().min(cached);
+    let overflow = cached + seq_len - window;
+    let count = overflow.next_multiple_of(KV_BLOCK_SIZE).min(cached - sinks);
+    if count < overflow {
+        bail!("{seq_len} new tokens do not fit the context window of {window} tokens with {sinks} attention sinks")
+    }
+    Ok(Some(sinks..sinks + count))
+}
+
+/// Move the rotary embedded keys `k` of shape (b, heads, seq, head_dim) back by `shift` positions,
+/// `cos` and `sin` are the rope tables of the model, `interleaved` when it rotates pairs of adjacent dims.
+///
+/// The rotation is done in f32 as the kept keys are rotated again at every eviction
+pub fn shift_rope_keys(k: &Tensor, cos: &Tensor, sin: &Tensor, shift: usize, interleaved: bool) -> Result<Tensor> {
+    let seq_len = k.dim(2)?;
+    let half = cos.dim(1)?;
+    // the rotation by -shift has the same cosines and opposite sines
+    let cos = cos.narrow(0, shift, 1)?.to_dtype(DType::F32)?.broadcast_as((seq_len, half))?.contiguous()?;
+    let sin = sin.narrow(0, shift, 1)?.to_dtype(DType::F32)?.neg()?.broadcast_as((seq_len, half))?.contiguous()?;
+    let k32 = k.to_dtype(DType::F32)?.contiguous()?;
+    let shifted = if interleaved {
+        candle_nn::rotary_emb::rope_i(&k32, &cos, &sin)?
+    } else {
+        candle_nn::rotary_emb::rope(&k32, &cos, &sin)?
+    };
+    shifted.to_dtype(k.dtype())
+}
+
+#[derive(Debug, Clone)]
+pub struct KvBudgetConfig {
+    /// max bytes of the kv caches of a layers worker, None for no limit
+    pub max_bytes: Option<u64>,
+    /// a session not forwarded for this long can be evicted to make room for another one
+    pub min_idle: Duration,
+    /// how the kv caches are stored, quantized blocks fit more sessions in `max_bytes`
+    pub quantization: KvQuantization,
+}
+
+impl Default for KvBudgetConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: None,
+            min_idle: Duration::from_secs(10),
+            quantization: KvQuantization::None,
+        }
+    }
+}
+
+/// Kv cache occupancy of a layers worker
+#[derive(Debug, Clone, Default)]
+pub struct KvUsage {
+    pub used_bytes: u64,
+    /// 0 when there is no limit
+    pub max_bytes: u64,
+    pub sessions: u32,
+    pub evictions: u64,
+}
+
+struct SessionBlocks {
+    blocks: usize,
+    last_used: Instant,
+}
+
+/// What a reservation evicted, the worker drops their caches
+#[derive(Debug, Default, PartialEq)]
+pub struct Evicted {
+    pub sessions: Vec<Session>,
+    /// ids of the stored prefixes
+    pub prefixes: Vec<u64>,
+}
+
+#[derive(Default)]
+struct BudgetState {
+    sessions: HashMap<Session, SessionBlocks>,
+    /// id and blocks of the stored prefixes, oldest first
+    prefixes: Vec<(u64, usize)>,
+    used: usize,
+    evictions: u64,
+}
+
+/// Kv memory budget of a layers worker, counted in blocks of `KV_BLOCK_SIZE` positions of every local layer.
+///
+/// The caches of the stored prefixes are charged too, they outlive the sessions which stored them.
+/// When the blocks run out the oldest stored prefixes are evicted first, then the least recently used idle sessions,
+/// else the session is refused. The worker drops the caches of the evicted sessions, their next forward fails.
+pub struct KvBudget {
+    cfg: KvBudgetConfig,
+    block_bytes: u64,
+    state: Mutex<BudgetState>,
+}
+
+impl KvBudget {
+    /// `block_bytes` is the size of the keys and values of one block of every local layer
+    pub fn new(cfg: KvBudgetConfig, block_bytes: u64) -> Self {
+        Self {
+            cfg,
+            block_bytes: block_bytes.max(1),
+            state: Default::default(),
+        }
+    }
+
+    fn max_blocks(&self) -> Option<usize> {
+        self.cfg.max_bytes.map(|max_bytes| (max_bytes / self.block_bytes) as usize)
+    }
+
+    /// Admit a new session, it needs at least one free block
+    pub fn admit(&self, session: Session) -> Result<Evicted> {
+        let mut state = self.state.lock();
+        state.sessions.insert(session, SessionBlocks { blocks: 0, last_used: Instant::now() });
+        let idle = self.idle_sessions(&state, session);
+        match self.make_room(&mut state, 1, idle) {
+            Ok(evicted) => Ok(evicted),
+            Err(e) => {
+                state.sessions.remove(&session);
+                Err(e)
+            }
+        }
+    }
+
+    /// Reserve the blocks for `len` positions of the session
+    pub fn reserve(&self, session: Session, len: usize) -> Result<Evicted> {
+        let mut state = self.state.lock();
+        let Some(current) = state.sessions.get_mut(&session) else {
+            bail!("session {session} has no kv cache, it was finished or evicted")
+        };
+        current.last_used = Instant::now();
+        let needed = len.div_ceil(KV_BLOCK_SIZE).saturating_sub(current.blocks);
+        if needed == 0 {
+            return Ok(Evicted::default());
+        }
+        let idle = self.idle_sessions(&state, session);
+        let evicted = self.make_room(&mut state, needed, idle)?;
+        if let Some(current) = state.sessions.get_mut(&session) {
+            current.blocks += needed;
+        }
+        state.used += needed;
+        Ok(evicted)
+    }
+
+    /// Charge the blocks of `len` positions for a stored prefix, only older prefixes are evicted for it
+    pub fn charge_prefix(&self, id: u64, len: usize) -> Result<Vec<u64>> {
+        let mut state = self.state.lock();
+        let needed = len.div_ceil(KV_BLOCK_SIZE);
+        let evicted = self.make_room(&mut state, needed, vec![])?;
+        state.prefixes.push((id, needed));
+        state.used += needed;
+        Ok(evicted.prefixes)
+    }
+
+    pub fn release_prefix(&self, id: u64) {
+        let mut state = self.state.lock();
+        if let Some(i) = state.prefixes.iter().position(|(prefix, _)| *prefix == id) {
+            let (_, blocks) = state.prefixes.remove(i);
+            state.used -= blocks;
+        }
+    }
+
+    /// Sessions other than `session` which can be evicted, least recently used first
+    fn idle_sessions(&self, state: &BudgetState, session: Session) -> Vec<(Session, usize)> {
+        let mut idle = state
+            .sessions
+            .iter()
+            .filter(|(s, usage)| **s != session && usage.blocks > 0 && usage.last_used.elapsed() >= self.cfg.min_idle)
+            .map(|(s, usage)| (usage.last_used, usage.blocks, *s))
+            .collect::<Vec<_>>();
+        idle.sort_by_key(|(last_used, ..)| *last_used);
+        idle.into_iter().map(|(_, blocks, s)| (s, blocks)).collect()
+    }
+
+    /// Evict the stored prefixes then the `idle` sessions until `blocks` are free, nothing is evicted if they are not enough
+    fn make_room(&self, state: &mut BudgetState, blocks: usize, idle: Vec<(Session, usize)>) -> Result<Evicted> {
+        let Some(max_blocks) = self.max_blocks() else {
+            return Ok(Evicted::default());
+        };
+        let mut free = max_blocks.saturating_sub(state.used);
+        let mut prefixes = 0;
+        for (_, prefix_blocks) in &state.prefixes {
+            if free >= blocks {
+                break;
+            }
+            free += prefix_blocks;
+            prefixes += 1;
+        }
+        let mut sessions = vec![];
+        for (idle_session, idle_blocks) in idle {
+            if free >= blocks {
+                break;
+            }
+            free += idle_blocks;
+            sessions.push(idle_session);
+        }
+        if free < blocks {
+            bail!("kv cache budget exhausted, {} of {max_blocks} blocks used and no idle session to evict", state.used)
+        }
+        let prefixes = state.prefixes.drain(..prefixes).collect::<Vec<_>>();
+        for (_, prefix_blocks) in &prefixes {
+            state.used -= prefix_blocks;
+        }
+        for session in &sessions {
+            if let Some(usage) = state.sessions.remove(session) {
+                state.used -= usage.blocks;
+            }
+            log::info!("[KvBudget] evict idle session {session}");
+        }
+        state.evictions += sessions.len() as u64;
+        Ok(Evicted {
+            sessions,
+            prefixes: prefixes.into_iter().map(|(id, _)| id).collect(),
+        })
+    }
+
+    pub fn release(&self, session: Session) {
+        let mut state = self.state.lock();
+        if let Some(usage) = state.sessions.remove(&session) {
+            state.used -= usage.blocks;
+        }
+    }
+
+    pub fn usage(&self) -> KvUsage {
+        let state = self.state.lock();
+        KvUsage {
+            used_bytes: state.used as u64 * self.block_bytes,
+            max_bytes: self.max_blocks().map_or(0, |max_blocks| max_blocks as u64 * self.block_bytes),
+            sessions: state.sessions.len() as u32,
+            evictions: state.evictions,
+        }
+    }
+}
+
+/// Kv cache of a session in a layers worker, covering all its local layers
+pub trait SessionCache: Clone + Send {
+    /// Positions in the kv caches
+    fn len(&self) -> usize;
+
+    /// Nothing was forwarded with this cache yet
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Replace the kv caches with the first `len` positions of the ones of `prefix`
+    fn restore(&mut self, prefix: &Self, len: usize) -> Result<()>;
+
+    /// Drop the last `count` positions of the kv caches
+    fn rollback(&mut self, count: usize) -> Result<()>;
+
+    /// Make room in the context window for a chunk of `seq_len` tokens at `index_pos`, as the overflow policy of the session says.
+    /// Returns the position of the chunk in the kv caches, before `index_pos` once the oldest tokens were evicted
+    fn make_room(&mut self, index_pos: usize, seq_len: usize) -> Result<usize>;
+
+    /// Context length of the model, the kv caches never hold more positions
+    fn window(&self) -> usize;
+}
+
+/// Cache of an item of a batch, ready for its forward
+struct BatchCache<C> {
+    session: Session,
+    /// position of the chunk in the session
+    index_pos: u32,
+    cache: Arc<Mutex<C>>,
+    /// position of the chunk in the cache
+    pos: usize,
+}
+
+/// Kv caches of the sessions of a layers worker, within its kv budget and with its prefix cache.
+///
+/// The caches of the sessions evicted by the budget are dropped, the worker only runs the forward.
+pub struct SessionCaches<C> {
+    caches: SharedHashMap<Session, Arc<Mutex<C>>>,
+    prefix_cache: PrefixCache<C>,
+    budget: KvBudget,
+}
+
+impl<C: SessionCache> SessionCaches<C> {
+    /// `block_bytes` is the size of the keys and values of one block of every local layer
+    pub fn new(cfg: KvBudgetConfig, block_bytes: u64) -> Self {
+        Self {
+            caches: Default::default(),
+            prefix_cache: Default::default(),
+            budget: KvBudget::new(cfg, block_bytes),
+        }
+    }
+
+    fn drop_caches(&self, evicted: Evicted) {
+        for session in evicted.sessions {
+            self.caches.remove(&session);
+        }
+        self.prefix_cache.evict(&evicted.prefixes);
+    }
+
+    pub fn start(&self, session: Session, cache: C) -> Result<()> {
+        self.drop_caches(self.budget.admit(session)?);
+        self.caches.insert(session, Arc::new(Mutex::new(cache)));
+        Ok(())
+    }
+
+    /// Remember the prefixes of the session prompt, returns the len of the longest one already cached
+    pub fn register_prefixes(&self, session: Session, prefixes: Vec<PrefixKey>) -> u32 {
+        self.prefix_cache.register(session, prefixes)
+    }
+
+    pub fn cache(&self, session: Session) -> Result<Arc<Mutex<C>>> {
+        let Some(cache) = self.caches.get_clone(&session) else { bail!("session {session} not started") };
+        Ok(cache)
+    }
+
+    /// The cache of the session for a forward of `seq_len` tokens at `index_pos`, and the position of the chunk in it.
+    ///
+    /// The cache is restored from the prefix cache when the first forward starts after a cached prefix.
+    /// Its kv blocks are reserved before making room, past the context window the chunk goes after the tokens left in the cache
+    pub fn forward_cache(&self, session: Session, index_pos: u32, seq_len: u32) -> Result<(Arc<Mutex<C>>, usize)> {
+        let cache = self.cache(session)?;
+        let mut cache_mut = cache.lock();
+        if cache_mut.is_empty() {
+            match self.prefix_cache.restore(session, index_pos) {
+                Some((prefix, _)) => cache_mut.restore(&prefix, index_pos as usize)?,
+                None if index_pos > 0 => bail!("session {session} starts at {index_pos} but its prefix is not cached"),
+                None => {}
+            }
+        }
+        self.reserve(session, (cache_mut.len() + seq_len as usize).min(cache_mut.window()))?;
+        let pos = cache_mut.make_room(index_pos as usize, seq_len as usize)?;
+        drop(cache_mut);
+        Ok((cache, pos))
+    }
+
+    /// Reserve the kv blocks of the session up to `end_pos`, dropping the caches of the evicted sessions and prefixes
+    fn reserve(&self, session: Session, end_pos: usize) -> Result<()> {
+        self.drop_caches(self.budget.reserve(session, end_pos)?);
+        Ok(())
+    }
+
+    /// Forward a batch of chunks of the same length with the caches of their sessions.
+    /// `forward(xs, index_pos, positions, caches)` runs the local layers, `positions` are the ones of the chunks in the caches.
+    ///
+    /// The cache of each item is prepared on its own, an item whose cache fails is left out of the batch with its error
+    pub fn forward_batch<F>(&self, batch: Vec<(Session, u32, (Tensor, u32), u32)>, forward: F) -> Vec<Result<(Tensor, u32)>>
+    where
+        F: FnOnce(Tensor, &[usize], &[usize], &mut [&mut C]) -> Result<Tensor>,
+    {
+        forward_stacked(
+            batch,
+            |session, index_pos, seq_len| self.batch_cache(session, index_pos, seq_len),
+            |items, xs, seq_len| {
+                let index_pos = items.iter().map(|item| item.index_pos as usize).collect::<Vec<_>>();
+                let positions = items.iter().map(|item| item.pos).collect::<Vec<_>>();
+                let mut guards = items.iter().map(|item| item.cache.lock()).collect::<Vec<_>>();
+                let mut caches = guards.iter_mut().map(|cache| &mut **cache).collect::<Vec<_>>();
+                let res = forward(xs, &index_pos, &positions, &mut caches)?;
+                for (item, cache) in items.iter().zip(&caches) {
+                    self.store_prefix(item.session, item.index_pos + seq_len, cache);
+                }
+                Ok(res)
+            },
+        )
+    }
+
+    /// Same as `forward_batch` for the workers which wait for other nodes in the middle of their layers, they lock the caches themselves
+    pub async fn forward_batch_async<F, Fut>(&self, batch: Vec<(Session, u32, (Tensor, u32), u32)>, forward: F) -> Vec<Result<(Tensor, u32)>>
+    where
+        F: FnOnce(Tensor, Vec<usize>, Vec<Arc<Mutex<C>>>) -> Fut,
+        Fut: Future<Output = Result<Tensor>>,
+    {
+        forward_stacked_async(
+            batch,
+            |session, index_pos, seq_len| self.batch_cache(session, index_pos, seq_len),
+            |items, xs, seq_len| async move {
+                let positions = items.iter().map(|item| item.pos).collect();
+                let res = forward(xs, positions, items.iter().map(|item| item.cache.clone()).collect()).await?;
+                for item in &items {
+                    self.store_prefix(item.session, item.index_pos + seq_len, &item.cache.lock());
+                }
+                Ok(res)
+            },
+        )
+        .await
+    }
+
+    fn batch_cache(&self, session: Session, index_pos: u32, seq_len: u32) -> Result<BatchCache<C>> {
+        let (cache, pos) = self.forward_cache(session, index_pos, seq_len)?;
+        Ok(BatchCache { session, index_pos, cache, pos })
+    }
+
+    /// Keep the cache once it holds the prompt prefix of the session, for the next sessions with the same prefix
+    pub fn store_prefix(&self, session: Session, end_pos: u32, cache: &C) {
+        if self.prefix_cache.store_at(session) == Some(end_pos) {
+            self.prefix_cache.store(session, cache.clone(), cache.len(), &self.budget);
+        }
+    }
+
+    pub fn rollback(&self, session: Session, count: u32) -> Result<()> {
+        self.cache(session)?.lock().rollback(count as usize)
+    }
+
+    /// Start `forked` with a copy of the cache of `session`, the copy shares the full kv blocks
+    pub fn fork(&self, session: Session, forked: Session) -> Result<()> {
+        let cache = self.cache(session)?.lock().clone();
+        self.drop_caches(self.budget.admit(forked)?);
+        self.reserve(forked, cache.len())?;
+        self.caches.insert(forked, Arc::new(Mutex::new(cache)));
+        Ok(())
+    }
+
+    pub fn finish(&self, session: Session) {
+        self.caches.remove(&session);
+        self.prefix_cache.finish(session);
+        self.budget.release(session);
+    }
+
+    pub fn usage(&self) -> KvUsage {
+        self.budget.usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use candle_core::{DType, Device, Tensor};
+    use protocol::{OverflowPolicy, Session};
+
+    use super::{overflow_eviction, shift_rope_keys, KvBudget, KvBudgetConfig, KvQuantization, KvTensor, PagedKvCache, SessionCache, SessionCaches, KV_BLOCK_SIZE};
+    use crate::prefix_cache::prefix_keys;
+
+    #[test]
+    fn paged_append_and_truncate() {
+        let device = Device::Cpu;
+        let xs = Tensor::arange(0f32, 80., &device).unwrap().reshape((1, 1, 80, 1)).unwrap();
+        let mut cache = PagedKvCache::new(2, KvQuantization::None);
+        cache.append(&xs.narrow(2, 0, 40).unwrap(), &xs.narrow(2, 0, 40).unwrap()).unwrap();
+        let fork = cache.clone();
+        let (k, _) = cache.append(&xs.narrow(2, 40, 40).unwrap(), &xs.narrow(2, 40, 40).unwrap()).unwrap();
+        assert_eq!(cache.blocks.len(), 80usize.div_ceil(KV_BLOCK_SIZE));
+        assert_eq!(k.flatten_all().unwrap().to_vec1::<f32>().unwrap(), xs.flatten_all().unwrap().to_vec1::<f32>().unwrap());
+
+        // the clone taken before keeps its own partial block
+        let (k, _) = fork.kv().unwrap().unwrap();
+        assert_eq!(k.dim(2).unwrap(), 40);
+        let (k, _) = cache.truncate(35).unwrap().kv().unwrap().unwrap();
+        assert_eq!(k.flatten_all().unwrap().to_vec1::<f32>().unwrap(), (0..35).map(|i| i as f32).collect::<Vec<_>>());
+        assert!(PagedKvCache::new(2, KvQuantization::None).kv().unwrap().is_none());
+    }
+
+    #[test]
+    fn int8_blocks_close_to_plain() {
+        let device = Device::Cpu;
+        let xs = Tensor::randn(0f32, 1., (1, 2, 80, 16), &device).unwrap().to_dtype(DType::F16).unwrap();
+        let mut plain = PagedKvCache::new(2, KvQuantization::None);
+        let mut int8 = PagedKvCache::new(2, KvQuantization::Int8);
+        for pos in (0..80).step_by(10) {
+            let chunk = xs.narrow(2, pos, 10).unwrap();
+            plain.append(&chunk, &chunk).unwrap();
+            int8.append(&chunk, &chunk).unwrap();
+        }
+
+        // the two full blocks are quantized, the partial one is not
+        let bytes = |cache: &PagedKvCache| {
+            let tensor_bytes = |t: &KvTensor| match t {
+                KvTensor::Plain(xs) => xs.elem_count() * xs.dtype().size_in_bytes(),
+                KvTensor::Int8 { q, scale, .. } => q.elem_count() + scale.elem_count() * DType::F32.size_in_bytes(),
+            };
+            cache.blocks.iter().map(|(k, v)| tensor_bytes(k) + tensor_bytes(v)).sum::<usize>()
+        };
+        let full_block = KvQuantization::Int8.block_bytes(2, 16, DType::F16);
+        assert_eq!(bytes(&int8), 2 * full_block + bytes(&plain) / 5);
+        assert!(full_block < KvQuantization::None.block_bytes(2, 16, DType::F16));
+
+        let (k, v) = int8.kv().unwrap().unwrap();
+        assert_eq!(k.dtype(), DType::F16);
+        for ys in [k, v] {
+            let max_diff = (ys.to_dtype(DType::F32).unwrap() - xs.to_dtype(DType::F32).unwrap())
+                .unwrap()
+                .abs()
+                .unwrap()
+                .max_all()
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap();
+            // a step of the int8 grid is at most max(|x|) / 127 and max(|x|) is about 4 for a normal distribution
+            assert!(max_diff < 0.03, "int8 kv cache differs by {max_diff}");
+        }
+        let (k, _) = int8.truncate(40).unwrap().kv().unwrap().unwrap();
+        assert_eq!(k.dim(2).unwrap(), 40);
+    }
+
+    #[test]
+    fn sliding_window_keeps_sinks_and_shifts_keys() {
+        let device = Device::Cpu;
+        let window = 40;
+        let theta = Tensor::new(&[1f32, 0.1], &device).unwrap();
+        let idx_theta = Tensor::arange(0u32, window as u32, &device)
+            .unwrap()
+            .to_dtype(DType::F32)
+            .unwrap()
+            .reshape((window, 1))
+            .unwrap()
+            .matmul(&theta.reshape((1, 2)).unwrap())
+            .unwrap();
+        let (cos, sin) = (idx_theta.cos().unwrap(), idx_theta.sin().unwrap());
+        let rope = |x: &Tensor, pos: usize| {
+            let len = x.dim(2).unwrap();
+            candle_nn::rotary_emb::rope(&x.contiguous().unwrap(), &cos.narrow(0, pos, len).unwrap(), &sin.narrow(0, pos, len).unwrap()).unwrap()
+        };
+
+        let xs = Tensor::randn(0f32, 1., (1, 1, window, 4), &device).unwrap();
+        let mut cache = PagedKvCache::new(2, KvQuantization::None);
+        cache.append(&rope(&xs, 0), &xs).unwrap();
+
+        assert!(overflow_eviction(OverflowPolicy::Error, window, window, 1).is_err());
+        assert_eq!(overflow_eviction(OverflowPolicy::Truncate, window, 30, 10).unwrap(), None);
+        assert_eq!(overflow_eviction(OverflowPolicy::Truncate, window, window, 1).unwrap(), Some(0..KV_BLOCK_SIZE));
+        let policy = OverflowPolicy::SlidingWindow { sinks: 4 };
+        let range = overflow_eviction(policy, window, window, 1).unwrap().unwrap();
+        assert_eq!(range, 4..4 + KV_BLOCK_SIZE);
+        assert!(overflow_eviction(policy, window, window, window).is_err());
+
+        // the kept keys are the ones of the sinks and of the last tokens embedded at their new positions
+        cache.evict(range.clone(), |k| shift_rope_keys(k, &cos, &sin, range.len(), false)).unwrap();
+        assert_eq!(cache.len(), window - KV_BLOCK_SIZE);
+        let expected = Tensor::cat(&[rope(&xs.narrow(2, 0, 4).unwrap(), 0), rope(&xs.narrow(2, range.end, window - range.end).unwrap(), 4)], 2).unwrap();
+        let (k, v) = cache.kv().unwrap().unwrap();
+        let max_diff = (k - expected).unwrap().abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+        assert!(max_diff < 1e-5, "shifted keys differ by {max_diff}");
+        assert_eq!(
+            v.narrow(2, 4, 4).unwrap().flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            xs.narrow(2, range.end, 4).unwrap().flatten_all().unwrap().to_vec1::<f32>().unwrap()
+        );
+    }
+
+    #[test]
+    fn shift_interleaved_rope_keys() {
+        let device = Device::Cpu;
+        let theta = Tensor::new(&[1f32, 0.1], &device).unwrap();
+        let idx_theta = Tensor::arange(0f32, 8., &device).unwrap().reshape((8, 1)).unwrap().matmul(&theta.reshape((1, 2)).unwrap()).unwrap();
+        let (cos, sin) = (idx_theta.cos().unwrap(), idx_theta.sin().unwrap());
+        let rope_i = |x: &Tensor, pos: usize| candle_nn::rotary_emb::rope_i(x, &cos.narrow(0, pos, 2).unwrap(), &sin.narrow(0, pos, 2).unwrap()).unwrap();
+
+        // keys embedded at positions 5 and 6 moved back by 3 are the keys embedded at 2 and 3
+        let xs = Tensor::randn(0f32, 1., (1, 1, 2, 4), &device).unwrap();
+        let shifted = shift_rope_keys(&rope_i(&xs, 5), &cos, &sin, 3, true).unwrap();
+        let max_diff = (shifted - rope_i(&xs, 2)).unwrap().abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+        assert!(max_diff < 1e-5, "shifted keys differ by {max_diff}");
+    }
+
+    #[test]
+    fn budget_evicts_idle_sessions() {
+        let cfg = KvBudgetConfig {
+            max_bytes: Some(4 * 100),
+            min_idle: Duration::ZERO,
+            ..Default::default()
+        };
+        let budget = KvBudget::new(cfg, 100);
+        let (a, b, c) = (Session::new(), Session::new(), Session::new());
+        assert!(budget.admit(a).unwrap().sessions.is_empty());
+        budget.reserve(a, 3 * KV_BLOCK_SIZE).unwrap();
+        assert!(budget.admit(b).unwrap().sessions.is_empty());
+        budget.reserve(b, 1).unwrap();
+        assert_eq!(budget.usage().used_bytes, 400);
+
+        // c needs a block, a is the least recently used
+        assert_eq!(budget.admit(c).unwrap().sessions, vec![a]);
+        assert!(budget.reserve(a, 1).is_err());
+        budget.reserve(c, 2 * KV_BLOCK_SIZE).unwrap();
+        let usage = budget.usage();
+        assert_eq!((usage.used_bytes, usage.max_bytes, usage.sessions, usage.evictions), (300, 400, 2, 1));
+
+        // no session is idle long enough to be evicted, the new one is refused
+        let budget = KvBudget::new(
+            KvBudgetConfig {
+                max_bytes: Some(100),
+                min_idle: Duration::from_secs(60),
+                ..Default::default()
+            },
+            100,
+        );
+        budget.admit(a).unwrap();
+        budget.reserve(a, 1).unwrap();
+        assert!(budget.admit(b).is_err());
+        budget.release(a);
+        assert!(budget.admit(b).is_ok());
+    }
+
+    #[test]
+    fn budget_evicts_prefixes_first() {
+        let cfg = KvBudgetConfig {
+            max_bytes: Some(4 * 100),
+            min_idle: Duration::ZERO,
+            ..Default::default()
+        };
+        let budget = KvBudget::new(cfg, 100);
+        let (a, b) = (Session::new(), Session::new());
+        budget.admit(a).unwrap();
+        budget.reserve(a, 2 * KV_BLOCK_SIZE).unwrap();
+        assert!(budget.charge_prefix(1, 2 * KV_BLOCK_SIZE).unwrap().is_empty());
+        assert_eq!(budget.usage().used_bytes, 400);
+
+        // a prefix only evicts older prefixes, never a session
+        assert_eq!(budget.charge_prefix(2, KV_BLOCK_SIZE).unwrap(), vec![1]);
+        assert!(budget.charge_prefix(3, 4 * KV_BLOCK_SIZE).is_err());
+
+        // the stored prefix goes before the idle session
+        budget.admit(b).unwrap();
+        let evicted = budget.reserve(b, 2 * KV_BLOCK_SIZE).unwrap();
+        assert_eq!((evicted.sessions, evicted.prefixes), (vec![], vec![2]));
+        budget.release_prefix(2);
+        assert_eq!(budget.usage().used_bytes, 400);
+    }
+
+    /// Cache which only counts its positions
+    #[derive(Clone)]
+    struct Positions(usize);
+
+    impl SessionCache for Positions {
+        fn len(&self) -> usize {
+            self.0
+        }
+
+        fn restore(&mut self, _prefix: &Self, len: usize) -> candle_core::Result<()> {
+            self.0 = len;
+            Ok(())
+        }
+
+        fn rollback(&mut self, count: usize) -> candle_core::Result<()> {
+            self.0 -= count;
+            Ok(())
+        }
+
+        fn make_room(&mut self, _index_pos: usize, _seq_len: usize) -> candle_core::Result<usize> {
+            Ok(self.0)
+        }
+
+        fn window(&self) -> usize {
+            1024
+        }
+    }
+
+    #[test]
+    fn session_caches_fork_and_evict() {
+        let cfg = KvBudgetConfig {
+            max_bytes: Some(4 * 100),
+            min_idle: Duration::ZERO,
+            ..Default::default()
+        };
+        let caches = SessionCaches::new(cfg, 100);
+        let (a, b, c) = (Session::new(), Session::new(), Session::new());
+        caches.start(a, Positions(0)).unwrap();
+        let (cache, pos) = caches.forward_cache(a, 0, 40).unwrap();
+        assert_eq!(pos, 0);
+        cache.lock().0 = 40;
+
+        // the fork has its own copy of the cache and reserves its blocks
+        caches.fork(a, b).unwrap();
+        caches.rollback(b, 10).unwrap();
+        assert_eq!((caches.cache(a).unwrap().lock().0, caches.cache(b).unwrap().lock().0), (40, 30));
+        assert_eq!(caches.usage().used_bytes, 400);
+
+        // c evicts the least recently used session, its cache is dropped
+        caches.start(c, Positions(0)).unwrap();
+        assert!(caches.cache(a).is_err());
+        caches.finish(b);
+        assert_eq!(caches.usage().sessions, 1);
+    }
+
+    #[test]
+    fn prefix_evicted_before_the_first_forward() {
+        let cfg = KvBudgetConfig {
+            max_bytes: Some(4 * 100),
+            min_idle: Duration::ZERO,
+            ..Default::default()
+        };
+        let caches = SessionCaches::new(cfg, 100);
+        let prompt = (0..13).collect::<Vec<u32>>();
+        let (a, b, c) = (Session::new(), Session::new(), Session::new());
+        caches.start(a, Positions(0)).unwrap();
+        caches.register_prefixes(a, prefix_keys(&prompt, 4));
+        let (cache, _) = caches.forward_cache(a, 0, 12).unwrap();
+        cache.lock().0 = 12;
+        caches.store_prefix(a, 12, &cache.lock());
+        caches.finish(a);
+
+        caches.start(b, Positions(0)).unwrap();
+        assert_eq!(caches.register_prefixes(b, prefix_keys(&prompt, 4)), 12);
+        // c takes every block, the stored prefix is evicted before b forwards anything
+        caches.start(c, Positions(0)).unwrap();
+        caches.forward_cache(c, 0, 4 * KV_BLOCK_SIZE as u32).unwrap();
+        caches.finish(c);
+        assert_eq!(caches.usage().used_bytes, 0);
+
+        // b still starts after the prefix it was told is cached
+        let (cache, pos) = caches.forward_cache(b, 12, 1).unwrap();
+        assert_eq!((cache.lock().0, pos), (12, 12));
+    }
+}
@@ -1,20 +1,58 @@
+use std::{ops::Range, sync::Arc, time::Duration};
+
 use candle_core::utils::{cuda_is_available, metal_is_available};
-use candle_core::{Device, Result};
-use protocol::{ChatCfg, ChatCompletionRequest, Session};
+use candle_core::{Device, Result, Tensor};
+use protocol::{llm::PrefixKey, ChatCfg, ChatCompletionRequest, ChatEvent, FinishReason, Session};
 use tokio::sync::mpsc::Sender;
 
+pub use batch::{BatchConfig, BatchScheduler};
+pub use kv_cache::{KvBudgetConfig, KvQuantization, KvUsage};
+pub use manifest::{manifest_dtype, model_layers, new_chat_model, new_layers_worker};
+pub use mllama::{CrossAttentionStates, ImageSpan};
+pub use prompt::{build_prompt, ChatTemplate};
+pub use resource::ResourceSource;
+
+mod batch;
+mod beam_search;
+mod chat_output;
+mod constraint;
+mod conversation;
 pub mod fake;
+pub mod gemma2;
+mod kv_cache;
 pub mod llama;
 mod logits_processor;
+mod manifest;
+pub mod mixtral;
+pub mod mllama;
 pub mod phi3;
+mod prefill;
+mod prefix_cache;
+mod prompt;
+pub mod qwen2;
 pub mod remote;
+mod resource;
+mod stop_matcher;
 mod token_output_stream;
+mod token_sampler;
 mod utils;
 
 #[async_trait::async_trait]
 pub trait ChatModel: Send + Sync + 'static {
     fn build_prompt(&self, request: &ChatCompletionRequest) -> String;
-    async fn chat(&self, session: Session, cfg: ChatCfg, prompt: &str, tx: Sender<String>) -> Result<()>;
+    /// Reject the prompt before the answer starts streaming, the chat itself fails later otherwise
+    fn check_prompt(&self, _cfg: &ChatCfg, _prompt: &str) -> Result<()> {
+        Ok(())
+    }
+    /// Stream the generated text as `ChatEvent::Delta` and return why the generation stopped
+    async fn chat(&self, session: Session, cfg: ChatCfg, prompt: &str, tx: Sender<ChatEvent>) -> Result<FinishReason>;
+    /// Answer a turn of the conversation `conversation`, the prompt holds the whole conversation.
+    /// Models keeping the session between turns override this, by default each turn is a new session
+    async fn chat_turn(&self, _conversation: &str, cfg: ChatCfg, prompt: &str, tx: Sender<ChatEvent>) -> Result<FinishReason> {
+        self.chat(Session::new(), cfg, prompt, tx).await
+    }
+    /// Finish the sessions of the conversations idle for longer than `ttl`
+    async fn expire_conversations(&self, _ttl: Duration) {}
 }
 
 #[async_trait::async_trait]
@@ -27,12 +65,121 @@ pub trait ModelPreprocessor<IN, OUT> {
 }
 
 #[async_trait::async_trait]
-pub trait ModelLayersWorker<E>: Send + Sync + 'static {
+pub trait ModelLayersWorker<E: Send + 'static>: Send + Sync + 'static {
     async fn start(&self, session: Session, config: ChatCfg) -> Result<()>;
+    /// Start a session whose prompt begins with `prefixes`, returns the len of the longest one already in the kv cache,
+    /// like a shared system prompt. The first forward of the session is then at that index_pos
+    async fn start_with_prefix(&self, session: Session, config: ChatCfg, _prefixes: Vec<PrefixKey>) -> Result<u32> {
+        self.start(session, config).await.map(|_| 0)
+    }
     /// Async function for allowing remote execute
     /// This function calculate from input to output embedding
     async fn forward(&self, session: Session, step: u32, embedding: E, index_pos: u32) -> Result<E>;
+    /// Forward one (session, step, embedding, index_pos) item per session at once, the results are in the same order.
+    /// Workers which can run sessions together override this, by default the items are forwarded one by one
+    async fn forward_batch(&self, batch: Vec<(Session, u32, E, u32)>) -> Vec<Result<E>> {
+        let mut outputs = Vec::with_capacity(batch.len());
+        for (session, step, embedding, index_pos) in batch {
+            outputs.push(self.forward(session, step, embedding, index_pos).await);
+        }
+        outputs
+    }
+    /// Drop the last `count` positions of the kv caches of the session, like the draft tokens rejected by speculative decoding.
+    /// The next forward of the session is then `count` positions earlier
+    async fn rollback(&self, session: Session, _count: u32) -> Result<()> {
+        candle_core::bail!("layers worker cannot roll back the kv caches of session {session}")
+    }
+    /// Start `forked` with a copy of the kv caches of the session, like the beams of a beam search
+    async fn fork(&self, session: Session, _forked: Session) -> Result<()> {
+        candle_core::bail!("layers worker cannot fork session {session}")
+    }
     async fn finish(&self, session: Session);
+    /// Occupancy of the kv caches of the local layers
+    fn kv_usage(&self) -> KvUsage {
+        KvUsage::default()
+    }
+    /// Number of experts of the mixture-of-experts layers and the ones held locally, None for a dense model
+    fn local_experts(&self) -> Option<(u32, Range<u32>)> {
+        None
+    }
+    /// Run the experts which are not local on other nodes
+    fn set_remote_experts(&self, _remote: Arc<dyn RemoteExperts>) {}
+    /// Forward the hidden states of the tokens routed to each local expert of the mixture-of-experts layer `layer`, for the hop running that layer.
+    /// The outputs are in the same order, not yet weighted by the router
+    async fn forward_experts(&self, layer: u32, _inputs: Vec<(u32, Tensor)>) -> Result<Vec<Tensor>> {
+        candle_core::bail!("layers worker has no experts of layer {layer}")
+    }
+    /// Keep the cross-attention states of the images of the session for its next forwards.
+    /// Workers without cross-attention layers have nothing to keep
+    async fn set_cross_attention(&self, _session: Session, _states: CrossAttentionStates) -> Result<()> {
+        Ok(())
+    }
+    /// Layers of the whole model which attend the images, only the hops running some of them need the cross-attention states
+    fn cross_attention_layers(&self) -> &[u32] {
+        &[]
+    }
+}
+
+/// Experts of mixture-of-experts layers held by other nodes, they keep no state between tokens
+#[async_trait::async_trait]
+pub trait RemoteExperts: Send + Sync + 'static {
+    /// Forward the hidden states of the tokens routed to each expert of the layer `layer`, the outputs are in the same order
+    async fn forward_experts(&self, layer: u32, inputs: Vec<(u32, Tensor)>) -> Result<Vec<Tensor>>;
+}
+
+#[async_trait::async_trait]
+impl<E: Send + 'static> ModelLayersWorker<E> for Box<dyn ModelLayersWorker<E>> {
+    async fn start(&self, session: Session, config: ChatCfg) -> Result<()> {
+        self.as_ref().start(session, config).await
+    }
+
+    async fn start_with_prefix(&self, session: Session, config: ChatCfg, prefixes: Vec<PrefixKey>) -> Result<u32> {
+        self.as_ref().start_with_prefix(session, config, prefixes).await
+    }
+
+    async fn forward(&self, session: Session, step: u32, embedding: E, index_pos: u32) -> Result<E> {
+        self.as_ref().forward(session, step, embedding, index_pos).await
+    }
+
+    async fn forward_batch(&self, batch: Vec<(Session, u32, E, u32)>) -> Vec<Result<E>> {
+        self.as_ref().forward_batch(batch).await
+    }
+
+    async fn rollback(&self, session: Session, count: u32) -> Result<()> {
+        self.as_ref().rollback(session, count).await
+    }
+
+    async fn fork(&self, session: Session, forked: Session) -> Result<()> {
+        self.as_ref().fork(session, forked).await
+    }
+
+    async fn finish(&self, session: Session) {
+        self.as_ref().finish(session).await
+    }
+
+    fn kv_usage(&self) -> KvUsage {
+        self.as_ref().kv_usage()
+    }
+
+    fn local_experts(&self) -> Option<(u32, Range<u32>)> {
+        self.as_ref().local_experts()
+    }
+
+    fn set_remote_experts(&self, remote: Arc<dyn RemoteExperts>) {
+        self.as_ref().set_remote_experts(remote)
+    }
+
+    async fn forward_experts(&self, layer: u32, inputs: Vec<(u32, Tensor)>) -> Result<Vec<Tensor>> {
+        self.as_ref().forward_experts(layer, inputs).await
+    }
+
+    async fn set_cross_attention(&self, session: Session, states: CrossAttentionStates) -> Result<()> {
+        self.as_ref().set_cross_attention(session, states).await
+    }
+
+    fn cross_attention_layers(&self) -> &[u32] {
+        self.as_ref().cross_attention_layers()
+    }
 }
 
 #[async_trait::async_trait]
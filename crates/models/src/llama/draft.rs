@@ -0,0 +1,241 @@
+use candle_core::{bail, DType, Device, Result, Tensor, D};
+use protocol::{OverflowPolicy, DEFAULT_ATTENTION_SINKS};
+
+use crate::{ChatCfg, KvBudgetConfig, ModelLayersWorker, Session};
+
+use super::{
+    internal::{LlamaPost, LlamaPre},
+    LlamaLayersWorker, ModelResource,
+};
+
+/// Small model run whole by the gateway, proposing the next tokens greedily for speculative decoding
+pub struct DraftModel {
+    pre: LlamaPre,
+    layers: LlamaLayersWorker,
+    post: LlamaPost,
+    vocab_size: usize,
+    /// tokens proposed at every step
+    tokens: u32,
+    device: Device,
+}
+
+impl DraftModel {
+    pub async fn new(resource: &ModelResource, device: Device, dtype: DType, tokens: u32) -> Result<Self> {
+        let config = resource.load_config(false).await?;
+        let (pre, post) = resource.load_pre_post(&config, dtype, &device).await?;
+        let vocab_size = config.vocab_size;
+        let range = 0..config.num_hidden_layers as u32;
+        let layers = resource.load_layers(config, range, dtype, device.clone(), KvBudgetConfig::default()).await?;
+        Ok(Self {
+            pre,
+            layers,
+            post,
+            vocab_size,
+            tokens,
+            device,
+        })
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    pub fn tokens(&self) -> u32 {
+        self.tokens
+    }
+
+    pub async fn start(&self, session: Session) -> Result<()> {
+        // the draft may have a shorter context than the model, its proposals only need the recent tokens
+        let cfg = ChatCfg {
+            overflow: OverflowPolicy::SlidingWindow { sinks: DEFAULT_ATTENTION_SINKS },
+            ..Default::default()
+        };
+        self.layers.start(session, cfg).await
+    }
+
+    /// Propose the `count` tokens following `tokens`, of which the first `drafted` are already in the kv cache.
+    /// The kv cache then holds all of them but the last proposed one
+    pub async fn propose(&self, session: Session, tokens: &[u32], drafted: usize, count: usize, chunk_size: usize) -> Result<Vec<u32>> {
+        let mut pos = drafted;
+        let mut logits = None;
+        for chunk in tokens[drafted..].chunks(chunk_size.max(1)) {
+            logits = Some(self.forward(session, chunk, pos).await?);
+            pos += chunk.len();
+        }
+        let Some(mut logits) = logits else { bail!("draft of session {session} has no new token") };
+        let mut proposed = Vec::with_capacity(count);
+        loop {
+            let next = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
+            proposed.push(next);
+            if proposed.len() >= count {
+                return Ok(proposed);
+            }
+            logits = self.forward(session, &[next], pos).await?;
+            pos += 1;
+        }
+    }
+
+    pub async fn rollback(&self, session: Session, count: u32) -> Result<()> {
+        self.layers.rollback(session, count).await
+    }
+
+    pub async fn finish(&self, session: Session) {
+        self.layers.finish(session).await
+    }
+
+    /// Logits of the token after `tokens`, forwarded at `pos`
+    async fn forward(&self, session: Session, tokens: &[u32], pos: usize) -> Result<Tensor> {
+        let (input, seq_len) = self.pre.forward(&Tensor::new(tokens, &self.device)?.unsqueeze(0)?)?;
+        let (hidden, _) = self.layers.forward(session, 0, (input, seq_len as u32), pos as u32).await?;
+        self.post.forward(&hidden, seq_len)?.squeeze(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::{VarBuilder, VarMap};
+    use protocol::{ChatCfg, ModelArchitecture, PromptTemplate, Session};
+    use tokenizers::{models::wordlevel::WordLevel, Tokenizer};
+    use tokio::sync::mpsc::channel;
+
+    use super::DraftModel;
+    use crate::{
+        conversation::{ChatSteps, Conversations},
+        kv_cache::SessionCache,
+        llama::{
+            internal::{Config, LlamaPost, LlamaPre},
+            LlamaLayersWorker, LlamaModel,
+        },
+        prompt::ChatTemplate,
+        token_sampler::TokenSampler,
+        KvBudgetConfig, ModelLayersWorker,
+    };
+
+    fn config() -> Config {
+        Config {
+            hidden_size: 16,
+            intermediate_size: 24,
+            vocab_size: 32,
+            num_hidden_layers: 2,
+            num_attention_heads: 2,
+            num_key_value_heads: 1,
+            use_flash_attn: false,
+            rms_norm_eps: 1e-5,
+            rope_theta: 10000.,
+            bos_token_id: None,
+            eos_token_id: None,
+            rope_scaling: None,
+            max_position_embeddings: 64,
+            tie_word_embeddings: false,
+            attention_bias: false,
+            rope_interleaved: false,
+            rope_freq_factors: None,
+        }
+    }
+
+    fn layers(vb: &VarBuilder) -> LlamaLayersWorker {
+        LlamaLayersWorker::new(0..2, vb.clone(), config(), DType::F32, Device::Cpu, KvBudgetConfig::default()).unwrap()
+    }
+
+    fn model(vb: &VarBuilder) -> LlamaModel<LlamaLayersWorker> {
+        LlamaModel {
+            architecture: ModelArchitecture::Llama,
+            device: Device::Cpu,
+            tokenizer: Tokenizer::new(WordLevel::default()),
+            pre: LlamaPre::load(vb, &config()).unwrap(),
+            post: LlamaPost::load(vb, &config()).unwrap(),
+            layers_worker: layers(vb),
+            config: config(),
+            chat_template: ChatTemplate::builtin(PromptTemplate::Llama3),
+            token_vocab: OnceLock::new(),
+            conversations: Conversations::default(),
+            draft: None,
+            vision: None,
+        }
+    }
+
+    fn draft(vb: &VarBuilder) -> DraftModel {
+        DraftModel {
+            pre: LlamaPre::load(vb, &config()).unwrap(),
+            layers: layers(vb),
+            post: LlamaPost::load(vb, &config()).unwrap(),
+            vocab_size: 32,
+            tokens: 3,
+            device: Device::Cpu,
+        }
+    }
+
+    /// Greedy generation after the prompt, with the draft when there is one. The session is left started
+    async fn generate(model: &LlamaModel<LlamaLayersWorker>, draft: Option<&DraftModel>, session: Session, prompt: &[u32]) -> Vec<u32> {
+        let cfg = ChatCfg {
+            temperature: 0.,
+            repeat_penalty: 1.,
+            max_len: 12,
+            ..Default::default()
+        };
+        model.layers_worker.start(session, cfg.clone()).await.unwrap();
+        let (hidden, seq_len) = ChatSteps::prefill(model, session, &cfg, prompt, 0).await.unwrap();
+        let (tx, _rx) = channel(64);
+        let mut sampler = TokenSampler::new(model, cfg, prompt.len(), tx).unwrap();
+        let mut tokens = prompt.to_vec();
+        let logits = model.post.forward(&hidden, seq_len as usize).unwrap().squeeze(0).unwrap();
+        assert!(sampler.next(logits, &mut tokens).await.unwrap().is_none());
+        match draft {
+            Some(draft) => {
+                draft.start(session).await.unwrap();
+                model.speculate(session, draft, &mut sampler, &mut tokens).await.unwrap()
+            }
+            None => model.generate_steps(session, &mut sampler, &mut tokens).await.unwrap(),
+        };
+        tokens
+    }
+
+    /// The linear layers get zeros from a varmap, every var is set to random values once loaded
+    fn randomize(varmap: &VarMap) {
+        for var in varmap.all_vars() {
+            var.set(&Tensor::randn(0f32, 0.5, var.shape(), &Device::Cpu).unwrap()).unwrap();
+        }
+    }
+
+    fn cache_len(layers: &LlamaLayersWorker, session: Session) -> usize {
+        layers.caches.cache(session).unwrap().lock().len()
+    }
+
+    #[tokio::test]
+    async fn speculate_rolls_back_rejected_tokens() {
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let model = model(&vb);
+        randomize(&varmap);
+        let prompt = [3, 1, 4, 1, 5];
+        let expected = generate(&model, None, Session::new(), &prompt).await;
+        assert_eq!(expected.len(), prompt.len() + 12);
+
+        // the same weights propose the tokens of the model, a draft of other weights diverges from them
+        let other = VarMap::new();
+        let diverging = draft(&VarBuilder::from_varmap(&other, DType::F32, &Device::Cpu));
+        randomize(&other);
+        for (draft, all_accepted) in [(draft(&vb), true), (diverging, false)] {
+            let session = Session::new();
+            let tokens = generate(&model, Some(&draft), session, &prompt).await;
+            assert_eq!(tokens, expected);
+            // the rejected proposals are rolled back, the kv cache of the model keeps every token but the last one
+            assert_eq!(cache_len(&model.layers_worker, session), tokens.len() - 1);
+            let drafted = cache_len(&draft.layers, session);
+            assert!(drafted < tokens.len());
+            if all_accepted {
+                // the draft never forwarded its last proposal nor the token sampled after it
+                assert_eq!(drafted, tokens.len() - 2);
+            }
+
+            // what is left in the kv cache of the draft is the start of the tokens
+            let fresh = Session::new();
+            draft.start(fresh).await.unwrap();
+            let proposed = draft.propose(session, &tokens, drafted, 1, 32).await.unwrap();
+            assert_eq!(proposed, draft.propose(fresh, &tokens, 0, 1, 32).await.unwrap());
+        }
+    }
+}
@@ -1,6 +1,17 @@
-use candle_core::{DType, Device, IndexOp, Result, Tensor, D};
-use candle_nn::{embedding, linear_no_bias as linear, Embedding, Linear, Module, RmsNorm, VarBuilder};
-use std::{collections::HashMap, f32::consts::PI, ops::Range};
+use candle_core::{
+    quantized::{gguf_file, QMatMul},
+    DType, Device, IndexOp, Result, Tensor, D,
+};
+use candle_nn::{embedding, Embedding, Module, RmsNorm, VarBuilder};
+use protocol::OverflowPolicy;
+use std::{
+    collections::HashMap,
+    f32::consts::PI,
+    io::{Read, Seek},
+    ops::Range,
+};
+
+use crate::kv_cache::{overflow_eviction, shift_rope_keys, KvQuantization, PagedKvCache, SessionCache};
 
 pub const DEFAULT_MAX_SEQ_LEN: usize = 4096;
 
@@ -44,14 +55,10 @@ pub struct LlamaConfig {
     pub rope_scaling: Option<Llama3RopeConfig>,
     pub max_position_embeddings: usize,
     pub tie_word_embeddings: Option<bool>,
+    #[serde(default)]
+    pub attention_bias: bool,
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
-pub struct LLamaVisionConfig {
-    pub text_config: LlamaConfig,
-}
-
-
 impl LlamaConfig {
     pub fn num_key_value_heads(&self) -> usize {
         self.num_key_value_heads.unwrap_or(self.num_attention_heads)
@@ -79,6 +86,9 @@ impl LlamaConfig {
             rope_scaling: self.rope_scaling,
             max_position_embeddings: self.max_position_embeddings,
             tie_word_embeddings: self.tie_word_embeddings.unwrap_or(false),
+            attention_bias: self.attention_bias,
+            rope_interleaved: false,
+            rope_freq_factors: None,
         }
     }
 }
@@ -99,9 +109,64 @@ pub struct Config {
     pub rope_scaling: Option<Llama3RopeConfig>,
     pub max_position_embeddings: usize,
     pub tie_word_embeddings: bool,
+    /// biases of the query, key and value projections, like qwen2
+    pub attention_bias: bool,
+    /// rope over pairs of consecutive dims, the q and k weights of GGUF files are permuted for it
+    pub rope_interleaved: bool,
+    /// llama3 rope scaling of GGUF files, each frequency is divided by its factor
+    pub rope_freq_factors: Option<Vec<f32>>,
 }
 
 impl Config {
+    /// Config of a llama or qwen2 GGUF file, with its rope frequency factors when it has some
+    pub fn from_gguf<R: Read + Seek>(ct: &gguf_file::Content, reader: &mut R) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle_core::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+        // qwen2 files have the same keys under their own prefix
+        let arch = md_get("general.architecture")?.to_string()?.clone();
+        if arch != "llama" && arch != "qwen2" {
+            candle_core::bail!("gguf of a {arch} model is not a llama or qwen2 model")
+        }
+        let key = |s: &str| format!("{arch}.{s}");
+        let num_attention_heads = md_get(&key("attention.head_count"))?.to_u32()? as usize;
+        let vocab_size = match ct.tensor_infos.get("token_embd.weight") {
+            Some(info) => info.shape.dims()[0],
+            None => candle_core::bail!("cannot find token_embd.weight in gguf"),
+        };
+        let rope_freq_factors = match ct.tensor_infos.contains_key("rope_freqs.weight") {
+            true => Some(ct.tensor(reader, "rope_freqs.weight", &Device::Cpu)?.dequantize(&Device::Cpu)?.to_vec1::<f32>()?),
+            false => None,
+        };
+        Ok(Self {
+            hidden_size: md_get(&key("embedding_length"))?.to_u32()? as usize,
+            intermediate_size: md_get(&key("feed_forward_length"))?.to_u32()? as usize,
+            vocab_size,
+            num_hidden_layers: md_get(&key("block_count"))?.to_u32()? as usize,
+            num_attention_heads,
+            num_key_value_heads: ct
+                .metadata
+                .get(&key("attention.head_count_kv"))
+                .map(|v| v.to_u32())
+                .transpose()?
+                .map_or(num_attention_heads, |v| v as usize),
+            // the layers run in f32 with quantized weights
+            use_flash_attn: false,
+            rms_norm_eps: md_get(&key("attention.layer_norm_rms_epsilon"))?.to_f32()? as f64,
+            rope_theta: ct.metadata.get(&key("rope.freq_base")).map(|v| v.to_f32()).transpose()?.unwrap_or_else(default_rope),
+            bos_token_id: ct.metadata.get("tokenizer.ggml.bos_token_id").map(|v| v.to_u32()).transpose()?,
+            eos_token_id: ct.metadata.get("tokenizer.ggml.eos_token_id").map(|v| v.to_u32()).transpose()?.map(LlamaEosToks::Single),
+            rope_scaling: None,
+            max_position_embeddings: md_get(&key("context_length"))?.to_u32()? as usize,
+            tie_word_embeddings: !ct.tensor_infos.contains_key("output.weight"),
+            attention_bias: ct.tensor_infos.contains_key("blk.0.attn_q.bias"),
+            // only the q and k weights of llama are permuted by llama.cpp
+            rope_interleaved: arch == "llama",
+            rope_freq_factors,
+        })
+    }
+
     pub fn config_7b_v1(use_flash_attn: bool) -> Self {
         Self {
             hidden_size: 4096,
@@ -118,6 +183,9 @@ impl Config {
             rope_scaling: None,
             max_position_embeddings: DEFAULT_MAX_SEQ_LEN,
             tie_word_embeddings: false,
+            attention_bias: false,
+            rope_interleaved: false,
+            rope_freq_factors: None,
         }
     }
 
@@ -137,15 +205,22 @@ impl Config {
             rope_scaling: None,
             max_position_embeddings: DEFAULT_MAX_SEQ_LEN,
             tie_word_embeddings: false,
+            attention_bias: false,
+            rope_interleaved: false,
+            rope_freq_factors: None,
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Cache {
-    masks: HashMap<usize, Tensor>,
+    masks: HashMap<(usize, usize, Option<usize>), Tensor>,
     pub use_kv_cache: bool,
-    kvs: Vec<Option<(Tensor, Tensor)>>,
+    kvs: Vec<PagedKvCache>,
+    overflow: OverflowPolicy,
+    /// positions in the rope tables, the kv caches never hold more
+    window: usize,
+    rope_interleaved: bool,
     cos: Tensor,
     sin: Tensor,
     device: Device,
@@ -157,7 +232,7 @@ fn calculate_default_inv_freq(cfg: &Config) -> Vec<f32> {
 }
 
 impl Cache {
-    pub fn new(use_kv_cache: bool, dtype: DType, config: &Config, device: &Device) -> Result<Self> {
+    pub fn new(use_kv_cache: bool, quantization: KvQuantization, overflow: OverflowPolicy, dtype: DType, config: &Config, device: &Device) -> Result<Self> {
         // precompute freqs_cis
         let theta = match &config.rope_scaling {
             None
@@ -185,6 +260,10 @@ impl Cache {
                     .collect::<Vec<_>>()
             }
         };
+        let theta = match &config.rope_freq_factors {
+            Some(factors) => theta.iter().zip(factors).map(|(freq, factor)| freq / factor).collect(),
+            None => theta,
+        };
 
         let theta = Tensor::new(theta, device)?;
 
@@ -199,38 +278,100 @@ impl Cache {
         Ok(Self {
             masks: HashMap::new(),
             use_kv_cache,
-            kvs: vec![None; config.num_hidden_layers],
+            kvs: vec![PagedKvCache::new(2, quantization); config.num_hidden_layers],
+            overflow,
+            window: config.max_position_embeddings,
+            rope_interleaved: config.rope_interleaved,
             device: device.clone(),
             cos,
             sin,
         })
     }
 
-    fn mask(&mut self, t: usize) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
-        } else {
-            let mask: Vec<_> = (0..t).flat_map(|i| (0..t).map(move |j| u8::from(j > i))).collect();
-            let mask = Tensor::from_slice(&mask, (t, t), &self.device)?;
-            self.masks.insert(t, mask.clone());
-            Ok(mask)
+    /// Rope tables of `seq_len` positions from `index_pos`
+    pub(crate) fn rope(&self, index_pos: usize, seq_len: usize) -> Result<(Tensor, Tensor)> {
+        Ok((self.cos.narrow(0, index_pos, seq_len)?, self.sin.narrow(0, index_pos, seq_len)?))
+    }
+
+    /// Kv cache of the local layer `block_idx`, `make_room` keeps it within the context window
+    pub(crate) fn kv(&mut self, block_idx: usize) -> &mut PagedKvCache {
+        &mut self.kvs[block_idx]
+    }
+
+    /// Causal mask of `t` new tokens over `kv_len` keys, the new tokens are the last ones of the keys.
+    /// With a sliding window a token only attends to the `sliding_window` last positions, itself included
+    pub(crate) fn mask(&mut self, t: usize, kv_len: usize, sliding_window: Option<usize>) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&(t, kv_len, sliding_window)) {
+            return Ok(mask.clone());
         }
+        let offset = kv_len.saturating_sub(t);
+        let mask: Vec<_> = (0..t)
+            .flat_map(|i| (0..kv_len).map(move |j| u8::from(j > i + offset || sliding_window.is_some_and(|window| j + window <= i + offset))))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (t, kv_len), &self.device)?;
+        self.masks.insert((t, kv_len, sliding_window), mask.clone());
+        Ok(mask)
+    }
+}
+
+impl SessionCache for Cache {
+    fn len(&self) -> usize {
+        self.kvs.iter().map(|kv| kv.len()).max().unwrap_or(0)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.kvs.iter().all(|kv| kv.is_empty())
+    }
+
+    fn restore(&mut self, prefix: &Cache, len: usize) -> Result<()> {
+        self.kvs = prefix.kvs.iter().map(|kv| kv.truncate(len)).collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    fn rollback(&mut self, count: usize) -> Result<()> {
+        for kv in self.kvs.iter_mut().filter(|kv| !kv.is_empty()) {
+            kv.rollback(count)?;
+        }
+        Ok(())
+    }
+
+    fn make_room(&mut self, index_pos: usize, seq_len: usize) -> Result<usize> {
+        if !self.use_kv_cache {
+            // the whole context is forwarded every time
+            if index_pos + seq_len > self.window {
+                candle_core::bail!("context window of {} tokens exceeded without kv cache", self.window)
+            }
+            return Ok(index_pos);
+        }
+        let cached = self.len();
+        let Some(evicted) = overflow_eviction(self.overflow, self.window, cached, seq_len)? else {
+            return Ok(cached);
+        };
+        let (cos, sin, interleaved) = (&self.cos, &self.sin, self.rope_interleaved);
+        for kv in self.kvs.iter_mut().filter(|kv| !kv.is_empty()) {
+            kv.evict(evicted.clone(), |k| shift_rope_keys(k, cos, sin, evicted.len(), interleaved))?;
+        }
+        Ok(cached - evicted.len())
+    }
+
+    fn window(&self) -> usize {
+        self.window
     }
 }
 
 #[derive(Debug, Clone)]
-struct CausalSelfAttention {
+pub(crate) struct CausalSelfAttention {
     q_proj: Linear,
     k_proj: Linear,
     v_proj: Linear,
-    o_proj: Linear,
+    o_proj: QMatMul,
     num_attention_heads: usize,
     num_key_value_heads: usize,
     head_dim: usize,
     use_flash_attn: bool,
+    rope_interleaved: bool,
     span: tracing::Span,
     span_rot: tracing::Span,
-    max_position_embeddings: usize,
 }
 
 #[cfg(feature = "flash-attn")]
@@ -247,12 +388,16 @@ impl CausalSelfAttention {
     fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize, cache: &Cache) -> Result<Tensor> {
         let _enter = self.span_rot.enter();
         let (_b_sz, _, seq_len, _hidden_size) = x.dims4()?;
-        let cos = cache.cos.narrow(0, index_pos, seq_len)?;
-        let sin = cache.sin.narrow(0, index_pos, seq_len)?;
-        candle_nn::rotary_emb::rope(x, &cos, &sin)
+        let (cos, sin) = cache.rope(index_pos, seq_len)?;
+        if self.rope_interleaved {
+            candle_nn::rotary_emb::rope_i(x, &cos, &sin)
+        } else {
+            candle_nn::rotary_emb::rope(x, &cos, &sin)
+        }
     }
 
-    fn forward(&self, x: &Tensor, index_pos: usize, block_idx: usize, cache: &mut Cache) -> Result<Tensor> {
+    /// Each row of `x` is a different session, with its own position and cache
+    pub(crate) fn forward(&self, x: &Tensor, index_pos: &[usize], block_idx: usize, caches: &mut [&mut Cache]) -> Result<Tensor> {
         let _enter = self.span.enter();
         let (b_sz, seq_len, hidden_size) = x.dims3()?;
         let q = self.q_proj.forward(x)?;
@@ -261,25 +406,26 @@ impl CausalSelfAttention {
 
         let q = q.reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim))?.transpose(1, 2)?.contiguous()?;
         let k = k.reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?.transpose(1, 2)?.contiguous()?;
-        let mut v = v.reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?.transpose(1, 2)?;
+        let v = v.reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?.transpose(1, 2)?;
+
+        let mut ys = Vec::with_capacity(b_sz);
+        for (i, cache) in caches.iter_mut().enumerate() {
+            ys.push(self.attend(&q.narrow(0, i, 1)?, k.narrow(0, i, 1)?, v.narrow(0, i, 1)?, index_pos[i], block_idx, cache)?);
+        }
+        let y = Tensor::cat(&ys, 0)?;
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, hidden_size])?;
+        let y = self.o_proj.forward(&y)?;
+        Ok(y)
+    }
 
-        let q = self.apply_rotary_emb(&q, index_pos, cache)?;
+    /// Attention of a single session over its kv cache
+    fn attend(&self, q: &Tensor, k: Tensor, mut v: Tensor, index_pos: usize, block_idx: usize, cache: &mut Cache) -> Result<Tensor> {
+        let seq_len = q.dim(2)?;
+        let q = self.apply_rotary_emb(q, index_pos, cache)?;
         let mut k = self.apply_rotary_emb(&k, index_pos, cache)?;
 
         if cache.use_kv_cache {
-            if let Some((cache_k, cache_v)) = &cache.kvs[block_idx] {
-                k = Tensor::cat(&[cache_k, &k], 2)?.contiguous()?;
-                v = Tensor::cat(&[cache_v, &v], 2)?.contiguous()?;
-                let k_seq_len = k.dims()[1];
-                if k_seq_len > self.max_position_embeddings {
-                    k = k.narrow(D::Minus1, k_seq_len - self.max_position_embeddings, self.max_position_embeddings)?.contiguous()?
-                }
-                let v_seq_len = v.dims()[1];
-                if v_seq_len > 2 * self.max_position_embeddings {
-                    v = v.narrow(D::Minus1, v_seq_len - self.max_position_embeddings, self.max_position_embeddings)?.contiguous()?
-                }
-            }
-            cache.kvs[block_idx] = Some((k.clone(), v.clone()))
+            (k, v) = cache.kv(block_idx).append(&k.contiguous()?, &v.contiguous()?)?;
         }
 
         let k = self.repeat_kv(k)?;
@@ -301,15 +447,13 @@ impl CausalSelfAttention {
             let att = if seq_len == 1 {
                 att
             } else {
-                let mask = cache.mask(seq_len)?.broadcast_as(att.shape())?;
+                let mask = cache.mask(seq_len, att.dim(D::Minus1)?, None)?.broadcast_as(att.shape())?;
                 masked_fill(&att, &mask, f32::NEG_INFINITY)?
             };
             let att = candle_nn::ops::softmax(&att, D::Minus1)?;
             // Convert to contiguous as matmul doesn't support strided vs for now.
             att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?
         };
-        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, hidden_size])?;
-        let y = self.o_proj.forward(&y)?;
         Ok(y)
     }
 
@@ -317,15 +461,15 @@ impl CausalSelfAttention {
         crate::utils::repeat_kv(x, self.num_attention_heads / self.num_key_value_heads)
     }
 
-    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+    pub(crate) fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "attn");
         let span_rot = tracing::span!(tracing::Level::TRACE, "attn-rot");
         let size_in = cfg.hidden_size;
         let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
         let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
-        let q_proj = linear(size_in, size_q, vb.pp("q_proj"))?;
-        let k_proj = linear(size_in, size_kv, vb.pp("k_proj"))?;
-        let v_proj = linear(size_in, size_kv, vb.pp("v_proj"))?;
+        let q_proj = Linear::load(size_in, size_q, cfg.attention_bias, vb.pp("q_proj"))?;
+        let k_proj = Linear::load(size_in, size_kv, cfg.attention_bias, vb.pp("k_proj"))?;
+        let v_proj = Linear::load(size_in, size_kv, cfg.attention_bias, vb.pp("v_proj"))?;
         let o_proj = linear(size_q, size_in, vb.pp("o_proj"))?;
         Ok(Self {
             q_proj,
@@ -336,11 +480,83 @@ impl CausalSelfAttention {
             num_key_value_heads: cfg.num_key_value_heads,
             head_dim: cfg.hidden_size / cfg.num_attention_heads,
             use_flash_attn: cfg.use_flash_attn,
+            rope_interleaved: cfg.rope_interleaved,
             span,
             span_rot,
-            max_position_embeddings: cfg.max_position_embeddings,
         })
     }
+
+    fn load_gguf<R: Read + Seek>(ct: &gguf_file::Content, r: &mut R, prefix: &str, cfg: &Config, device: &Device) -> Result<Self> {
+        Ok(Self {
+            q_proj: Linear::load_gguf(ct, r, &format!("{prefix}.attn_q"), device)?,
+            k_proj: Linear::load_gguf(ct, r, &format!("{prefix}.attn_k"), device)?,
+            v_proj: Linear::load_gguf(ct, r, &format!("{prefix}.attn_v"), device)?,
+            o_proj: qlinear(ct, r, &format!("{prefix}.attn_output"), device)?,
+            num_attention_heads: cfg.num_attention_heads,
+            num_key_value_heads: cfg.num_key_value_heads,
+            head_dim: cfg.hidden_size / cfg.num_attention_heads,
+            use_flash_attn: cfg.use_flash_attn,
+            rope_interleaved: cfg.rope_interleaved,
+            span: tracing::span!(tracing::Level::TRACE, "attn"),
+            span_rot: tracing::span!(tracing::Level::TRACE, "attn-rot"),
+        })
+    }
+}
+
+/// Linear layer without bias, as a matmul so that it can also be quantized
+fn linear(size_in: usize, size_out: usize, vb: VarBuilder) -> Result<QMatMul> {
+    Ok(QMatMul::Tensor(vb.get((size_out, size_in), "weight")?))
+}
+
+/// Linear layer of a GGUF file, the common quantized types run without dequantizing
+fn qlinear<R: Read + Seek>(ct: &gguf_file::Content, r: &mut R, name: &str, device: &Device) -> Result<QMatMul> {
+    QMatMul::from_qtensor(ct.tensor(r, &format!("{name}.weight"), device)?)
+}
+
+/// Linear layer with an optional bias, the query, key and value projections of qwen2 have one
+#[derive(Debug, Clone)]
+struct Linear {
+    weight: QMatMul,
+    bias: Option<Tensor>,
+}
+
+impl Linear {
+    fn load(size_in: usize, size_out: usize, bias: bool, vb: VarBuilder) -> Result<Self> {
+        let bias = match bias {
+            true => Some(vb.get(size_out, "bias")?),
+            false => None,
+        };
+        Ok(Self {
+            weight: linear(size_in, size_out, vb)?,
+            bias,
+        })
+    }
+
+    fn load_gguf<R: Read + Seek>(ct: &gguf_file::Content, r: &mut R, name: &str, device: &Device) -> Result<Self> {
+        let bias_name = format!("{name}.bias");
+        let bias = match ct.tensor_infos.contains_key(&bias_name) {
+            true => Some(ct.tensor(r, &bias_name, device)?.dequantize(device)?),
+            false => None,
+        };
+        Ok(Self {
+            weight: qlinear(ct, r, name, device)?,
+            bias,
+        })
+    }
+}
+
+impl Module for Linear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = self.weight.forward(xs)?;
+        match &self.bias {
+            Some(bias) => ys.broadcast_add(bias),
+            None => Ok(ys),
+        }
+    }
+}
+
+fn qrms_norm<R: Read + Seek>(ct: &gguf_file::Content, r: &mut R, name: &str, eps: f64, device: &Device) -> Result<RmsNorm> {
+    Ok(RmsNorm::new(ct.tensor(r, &format!("{name}.weight"), device)?.dequantize(device)?, eps))
 }
 
 fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
@@ -351,21 +567,21 @@ fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor>
 }
 
 #[derive(Debug, Clone)]
-struct Mlp {
-    c_fc1: Linear,
-    c_fc2: Linear,
-    c_proj: Linear,
+pub(crate) struct Mlp {
+    c_fc1: QMatMul,
+    c_fc2: QMatMul,
+    c_proj: QMatMul,
     span: tracing::Span,
 }
 
 impl Mlp {
-    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+    pub(crate) fn forward(&self, x: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
         let x = (candle_nn::ops::silu(&self.c_fc1.forward(x)?)? * self.c_fc2.forward(x)?)?;
         self.c_proj.forward(&x)
     }
 
-    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+    pub(crate) fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "mlp");
         let h_size = cfg.hidden_size;
         let i_size = cfg.intermediate_size;
@@ -374,10 +590,19 @@ impl Mlp {
         let c_proj = linear(i_size, h_size, vb.pp("down_proj"))?;
         Ok(Self { c_fc1, c_fc2, c_proj, span })
     }
+
+    fn load_gguf<R: Read + Seek>(ct: &gguf_file::Content, r: &mut R, prefix: &str, device: &Device) -> Result<Self> {
+        Ok(Self {
+            c_fc1: qlinear(ct, r, &format!("{prefix}.ffn_gate"), device)?,
+            c_fc2: qlinear(ct, r, &format!("{prefix}.ffn_up"), device)?,
+            c_proj: qlinear(ct, r, &format!("{prefix}.ffn_down"), device)?,
+            span: tracing::span!(tracing::Level::TRACE, "mlp"),
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
-struct Block {
+pub(crate) struct Block {
     rms_1: RmsNorm,
     attn: CausalSelfAttention,
     rms_2: RmsNorm,
@@ -386,17 +611,17 @@ struct Block {
 }
 
 impl Block {
-    fn forward(&self, x: &Tensor, index_pos: usize, block_idx: usize, cache: &mut Cache) -> Result<Tensor> {
+    pub(crate) fn forward(&self, x: &Tensor, index_pos: &[usize], block_idx: usize, caches: &mut [&mut Cache]) -> Result<Tensor> {
         let _enter = self.span.enter();
         let residual = x;
         let x = self.rms_1.forward(x)?;
-        let x = (self.attn.forward(&x, index_pos, block_idx, cache)? + residual)?;
+        let x = (self.attn.forward(&x, index_pos, block_idx, caches)? + residual)?;
         let residual = &x;
         let x = (self.mlp.forward(&self.rms_2.forward(&x)?)? + residual)?;
         Ok(x)
     }
 
-    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+    pub(crate) fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "block");
         let attn = CausalSelfAttention::load(vb.pp("self_attn"), cfg)?;
         let mlp = Mlp::load(vb.pp("mlp"), cfg)?;
@@ -404,6 +629,16 @@ impl Block {
         let rms_2 = candle_nn::rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("post_attention_layernorm"))?;
         Ok(Self { rms_1, attn, rms_2, mlp, span })
     }
+
+    fn load_gguf<R: Read + Seek>(ct: &gguf_file::Content, r: &mut R, prefix: &str, cfg: &Config, device: &Device) -> Result<Self> {
+        Ok(Self {
+            rms_1: qrms_norm(ct, r, &format!("{prefix}.attn_norm"), cfg.rms_norm_eps, device)?,
+            attn: CausalSelfAttention::load_gguf(ct, r, prefix, cfg, device)?,
+            rms_2: qrms_norm(ct, r, &format!("{prefix}.ffn_norm"), cfg.rms_norm_eps, device)?,
+            mlp: Mlp::load_gguf(ct, r, prefix, device)?,
+            span: tracing::span!(tracing::Level::TRACE, "block"),
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -412,9 +647,17 @@ pub struct LlamaLayers {
 }
 
 impl LlamaLayers {
-    pub fn forward(&self, mut x: Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+    pub fn forward(&self, x: Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        self.forward_batch(x, &[index_pos], &mut [cache])
+    }
+
+    /// Forward several sessions together, row `i` of `x` is at `index_pos[i]` with `caches[i]`
+    pub fn forward_batch(&self, mut x: Tensor, index_pos: &[usize], caches: &mut [&mut Cache]) -> Result<Tensor> {
+        if x.dim(0)? != caches.len() || index_pos.len() != caches.len() {
+            candle_core::bail!("batch of {} rows with {} positions and {} caches", x.dim(0)?, index_pos.len(), caches.len())
+        }
         for (block_idx, block) in self.blocks.iter().enumerate() {
-            x = block.forward(&x, index_pos, block_idx, cache)?;
+            x = block.forward(&x, index_pos, block_idx, caches)?;
         }
         Ok(x)
     }
@@ -431,6 +674,15 @@ impl LlamaLayers {
 
         Ok(Self { blocks })
     }
+
+    /// Only the `blk.{i}` tensors of the layers in `range` are read from the GGUF file
+    pub fn load_gguf<R: Read + Seek>(ct: &gguf_file::Content, r: &mut R, cfg: &Config, range: Range<u32>, device: &Device) -> Result<Self> {
+        if range.end > cfg.num_hidden_layers as u32 {
+            candle_core::bail!("layers {range:?} out of the {} layers of the model", cfg.num_hidden_layers)
+        }
+        let blocks = range.map(|i| Block::load_gguf(ct, r, &format!("blk.{i}"), cfg, device)).collect::<Result<Vec<_>>>()?;
+        Ok(Self { blocks })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -449,12 +701,20 @@ impl LlamaPre {
 
         Ok(Self { wte })
     }
+
+    /// The embeddings are dequantized, the hidden states of GGUF models are F32
+    pub fn load_gguf<R: Read + Seek>(ct: &gguf_file::Content, r: &mut R, cfg: &Config, device: &Device) -> Result<Self> {
+        let embeddings = ct.tensor(r, "token_embd.weight", device)?.dequantize(device)?;
+        Ok(Self {
+            wte: Embedding::new(embeddings, cfg.hidden_size),
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct LlamaPost {
     ln_f: RmsNorm,
-    lm_head: Linear,
+    lm_head: QMatMul,
 }
 
 impl LlamaPost {
@@ -465,14 +725,126 @@ impl LlamaPost {
         logits.to_dtype(DType::F32)
     }
 
+    /// Logits of every position of a single sequence, of shape (seq_len, vocab_size)
+    pub fn forward_all(&self, x: &Tensor) -> Result<Tensor> {
+        let x = self.ln_f.forward(x)?.squeeze(0)?;
+        let logits = self.lm_head.forward(&x)?;
+        logits.to_dtype(DType::F32)
+    }
+
     pub fn load(vb: &VarBuilder, cfg: &Config) -> Result<Self> {
         let lm_head = if cfg.tie_word_embeddings {
             let wte = embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("model.embed_tokens"))?;
-            Linear::new(wte.embeddings().clone(), None)
+            QMatMul::Tensor(wte.embeddings().clone())
         } else {
             linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?
         };
         let ln_f = candle_nn::rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("model.norm"))?;
         Ok(Self { ln_f, lm_head })
     }
+
+    pub fn load_gguf<R: Read + Seek>(ct: &gguf_file::Content, r: &mut R, cfg: &Config, device: &Device) -> Result<Self> {
+        let lm_head = if cfg.tie_word_embeddings {
+            qlinear(ct, r, "token_embd", device)?
+        } else {
+            qlinear(ct, r, "output", device)?
+        };
+        let ln_f = qrms_norm(ct, r, "output_norm", cfg.rms_norm_eps, device)?;
+        Ok(Self { ln_f, lm_head })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use candle_core::{
+        quantized::{gguf_file, GgmlDType, QTensor},
+        DType, Device, Tensor,
+    };
+    use protocol::OverflowPolicy;
+
+    use super::{Cache, Config, LlamaLayers};
+    use crate::kv_cache::KvQuantization;
+
+    /// GGUF file of a model with 2 layers, hidden size 16, 2 heads and 1 kv head
+    fn gguf(arch: &str, attention_bias: bool, rope_freqs: bool) -> Vec<u8> {
+        let device = Device::Cpu;
+        let tensor = |shape: &[usize]| QTensor::quantize(&Tensor::randn(0f32, 0.1, shape, &device).unwrap(), GgmlDType::F32).unwrap();
+        let mut tensors = vec![("token_embd.weight".to_string(), tensor(&[32, 16]))];
+        if rope_freqs {
+            let factors = Tensor::new(&[1f32, 2., 4., 8.], &device).unwrap();
+            tensors.push(("rope_freqs.weight".to_string(), QTensor::quantize(&factors, GgmlDType::F32).unwrap()));
+        }
+        for i in 0..2 {
+            let shapes: [(&str, &[usize]); 9] = [
+                ("attn_q.weight", &[16, 16]),
+                ("attn_k.weight", &[8, 16]),
+                ("attn_v.weight", &[8, 16]),
+                ("attn_output.weight", &[16, 16]),
+                ("ffn_gate.weight", &[24, 16]),
+                ("ffn_up.weight", &[24, 16]),
+                ("ffn_down.weight", &[16, 24]),
+                ("attn_norm.weight", &[16]),
+                ("ffn_norm.weight", &[16]),
+            ];
+            tensors.extend(shapes.iter().map(|(name, shape)| (format!("blk.{i}.{name}"), tensor(shape))));
+            if attention_bias {
+                tensors.extend([("attn_q.bias", 16), ("attn_k.bias", 8), ("attn_v.bias", 8)].map(|(name, len)| (format!("blk.{i}.{name}"), tensor(&[len]))));
+            }
+        }
+
+        let metadata = [
+            ("general.architecture".to_string(), gguf_file::Value::String(arch.to_string())),
+            (format!("{arch}.attention.head_count"), gguf_file::Value::U32(2)),
+            (format!("{arch}.attention.head_count_kv"), gguf_file::Value::U32(1)),
+            (format!("{arch}.embedding_length"), gguf_file::Value::U32(16)),
+            (format!("{arch}.feed_forward_length"), gguf_file::Value::U32(24)),
+            (format!("{arch}.block_count"), gguf_file::Value::U32(2)),
+            (format!("{arch}.attention.layer_norm_rms_epsilon"), gguf_file::Value::F32(1e-5)),
+            (format!("{arch}.context_length"), gguf_file::Value::U32(64)),
+        ];
+        let mut file = Cursor::new(vec![]);
+        gguf_file::write(
+            &mut file,
+            &metadata.iter().map(|(key, value)| (key.as_str(), value)).collect::<Vec<_>>(),
+            &tensors.iter().map(|(name, tensor)| (name.as_str(), tensor)).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        file.into_inner()
+    }
+
+    fn load(file: Vec<u8>) -> (Config, LlamaLayers) {
+        let mut reader = Cursor::new(file);
+        let ct = gguf_file::Content::read(&mut reader).unwrap();
+        let cfg = Config::from_gguf(&ct, &mut reader).unwrap();
+        assert!(LlamaLayers::load_gguf(&ct, &mut reader, &cfg, 1..3, &Device::Cpu).is_err());
+        let layers = LlamaLayers::load_gguf(&ct, &mut reader, &cfg, 0..2, &Device::Cpu).unwrap();
+        (cfg, layers)
+    }
+
+    #[test]
+    fn load_gguf_layers() {
+        let (cfg, layers) = load(gguf("llama", false, true));
+        assert_eq!((cfg.hidden_size, cfg.intermediate_size, cfg.vocab_size, cfg.num_hidden_layers), (16, 24, 32, 2));
+        assert_eq!((cfg.num_attention_heads, cfg.num_key_value_heads, cfg.max_position_embeddings), (2, 1, 64));
+        assert!(cfg.rope_interleaved && cfg.tie_word_embeddings && !cfg.attention_bias);
+        assert_eq!(cfg.rope_freq_factors, Some(vec![1., 2., 4., 8.]));
+        let mut cache = Cache::new(true, KvQuantization::None, OverflowPolicy::Error, DType::F32, &cfg, &Device::Cpu).unwrap();
+        let xs = Tensor::randn(0f32, 1., (1, 3, 16), &Device::Cpu).unwrap();
+        assert_eq!(layers.forward(xs, 0, &mut cache).unwrap().dims(), &[1, 3, 16]);
+
+        // qwen2 has the same keys under its own prefix, with the biases of the q, k and v projections
+        let (cfg, layers) = load(gguf("qwen2", true, false));
+        assert_eq!((cfg.hidden_size, cfg.num_key_value_heads), (16, 1));
+        assert!(!cfg.rope_interleaved && cfg.attention_bias);
+        assert_eq!(cfg.rope_freq_factors, None);
+        let mut cache = Cache::new(true, KvQuantization::None, OverflowPolicy::Error, DType::F32, &cfg, &Device::Cpu).unwrap();
+        let xs = Tensor::randn(0f32, 1., (1, 3, 16), &Device::Cpu).unwrap();
+        assert_eq!(layers.forward(xs, 0, &mut cache).unwrap().dims(), &[1, 3, 16]);
+
+        let mut reader = Cursor::new(gguf("gemma2", false, false));
+        let ct = gguf_file::Content::read(&mut reader).unwrap();
+        assert!(Config::from_gguf(&ct, &mut reader).is_err());
+    }
 }
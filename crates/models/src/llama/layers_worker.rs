@@ -1,11 +1,16 @@
-use std::{ops::Range, sync::Arc};
+use std::{
+    io::{Read, Seek},
+    ops::Range,
+};
 
-use candle_core::{DType, Device, Result, Tensor};
+use candle_core::{quantized::gguf_file, DType, Device, Result, Tensor};
 use candle_nn::VarBuilder;
-use spin::Mutex;
-use utils::shared_map::SharedHashMap;
+use protocol::llm::PrefixKey;
 
-use crate::{ChatCfg, ModelLayersWorker, Session};
+use crate::{
+    kv_cache::{KvQuantization, SessionCaches},
+    ChatCfg, KvBudgetConfig, KvUsage, ModelLayersWorker, Session,
+};
 
 use super::{
     internal::{Cache, Config, LlamaLayers},
@@ -13,7 +18,8 @@ use super::{
 };
 
 pub struct LlamaLayersWorker {
-    caches: SharedHashMap<Session, Arc<Mutex<Cache>>>,
+    pub(super) caches: SessionCaches<Cache>,
+    kv_quantization: KvQuantization,
     llama: LlamaLayers,
     cfg: Config,
     dtype: DType,
@@ -21,34 +27,68 @@ pub struct LlamaLayersWorker {
 }
 
 impl LlamaLayersWorker {
-    pub fn new(range: Range<u32>, vb: VarBuilder, cfg: Config, dtype: DType, device: Device) -> Result<Self> {
-        let llama = LlamaLayers::load(vb, &cfg, range)?;
-        Ok(Self {
-            caches: Default::default(),
+    pub fn new(range: Range<u32>, vb: VarBuilder, cfg: Config, dtype: DType, device: Device, kv_budget: KvBudgetConfig) -> Result<Self> {
+        let llama = LlamaLayers::load(vb, &cfg, range.clone())?;
+        Ok(Self::with_layers(llama, range, cfg, dtype, device, kv_budget))
+    }
+
+    /// Layers of a GGUF model, the weights keep their quantized type and the hidden states are F32
+    pub fn new_gguf<R: Read + Seek>(range: Range<u32>, ct: &gguf_file::Content, reader: &mut R, cfg: Config, device: Device, kv_budget: KvBudgetConfig) -> Result<Self> {
+        let llama = LlamaLayers::load_gguf(ct, reader, &cfg, range.clone(), &device)?;
+        Ok(Self::with_layers(llama, range, cfg, DType::F32, device, kv_budget))
+    }
+
+    fn with_layers(llama: LlamaLayers, range: Range<u32>, cfg: Config, dtype: DType, device: Device, kv_budget: KvBudgetConfig) -> Self {
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let block_bytes = range.len() * kv_budget.quantization.block_bytes(cfg.num_key_value_heads, head_dim, dtype);
+        Self {
+            kv_quantization: kv_budget.quantization,
+            caches: SessionCaches::new(kv_budget, block_bytes as u64),
             llama,
             cfg,
             dtype,
             device,
-        })
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl ModelLayersWorker<(Tensor, u32)> for LlamaLayersWorker {
-    async fn start(&self, session: Session, _cfg: ChatCfg) -> Result<()> {
-        let cache = Cache::new(USE_KV_CACHE, self.dtype, &self.cfg, &self.device).unwrap();
-        self.caches.insert(session, Arc::new(Mutex::new(cache)));
-        Ok(())
+    async fn start(&self, session: Session, cfg: ChatCfg) -> Result<()> {
+        let cache = Cache::new(USE_KV_CACHE, self.kv_quantization, cfg.overflow, self.dtype, &self.cfg, &self.device)?;
+        self.caches.start(session, cache)
+    }
+
+    async fn start_with_prefix(&self, session: Session, cfg: ChatCfg, prefixes: Vec<PrefixKey>) -> Result<u32> {
+        self.start(session, cfg).await?;
+        Ok(self.caches.register_prefixes(session, prefixes))
     }
 
     async fn forward(&self, session: Session, _step: u32, (xs, seq_len): (Tensor, u32), index_pos: u32) -> Result<(Tensor, u32)> {
-        let cache = self.caches.get_clone(&session).unwrap();
+        let (cache, pos) = self.caches.forward_cache(session, index_pos, seq_len)?;
         let mut cache_mut = cache.lock();
-        let res = self.llama.forward(xs, index_pos as usize, &mut cache_mut)?;
+        let res = self.llama.forward(xs, pos, &mut cache_mut)?;
+        self.caches.store_prefix(session, index_pos + seq_len, &cache_mut);
         Ok((res, seq_len))
     }
 
+    async fn forward_batch(&self, batch: Vec<(Session, u32, (Tensor, u32), u32)>) -> Vec<Result<(Tensor, u32)>> {
+        self.caches.forward_batch(batch, |xs, _, positions, caches| self.llama.forward_batch(xs, positions, caches))
+    }
+
+    async fn rollback(&self, session: Session, count: u32) -> Result<()> {
+        self.caches.rollback(session, count)
+    }
+
+    async fn fork(&self, session: Session, forked: Session) -> Result<()> {
+        self.caches.fork(session, forked)
+    }
+
     async fn finish(&self, session: Session) {
-        self.caches.remove(&session);
+        self.caches.finish(session);
+    }
+
+    fn kv_usage(&self) -> KvUsage {
+        self.caches.usage()
     }
 }
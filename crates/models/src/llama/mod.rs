@@ -1,208 +1,436 @@
-use std::{ops::Range, path::PathBuf};
+use std::{
+    ops::Range,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
-use candle_core::{DType, Device, Result, Tensor};
+use candle_core::{bail, quantized::gguf_file, DType, Device, Result, Tensor};
 use candle_nn::VarBuilder;
-use hf_hub::{api::tokio::Api, Repo, RepoType};
 use internal::{Config, LlamaConfig, LlamaEosToks, LlamaPost, LlamaPre};
-use protocol::Session;
+use protocol::{ChatEvent, FinishReason, ModelArchitecture, ModelManifest, PromptTemplate, Session};
 use tokenizers::Tokenizer;
 use tokio::sync::mpsc::Sender;
 
-mod internal;
+mod draft;
+pub(crate) mod internal;
 mod layers_worker;
 
+pub use draft::DraftModel;
 pub use layers_worker::LlamaLayersWorker;
 
 const EOS_TOKEN: &str = "</s>";
 const USE_KV_CACHE: bool = true;
 
 use crate::{
-    logits_processor::{LogitsProcessor, Sampling},
-    token_output_stream::TokenOutputStream,
-    utils::{apply_repeat_penalty, hub_load_safetensors},
-    ChatCfg, ChatCompletionRequest, ChatModel, ModelLayersWorker,
+    constraint::TokenVocab,
+    conversation::{self, ChatSteps, Conversations},
+    mixtral::MixtralConfig,
+    mllama::{image_token_spans, MllamaConfig, MllamaVision},
+    prefill::prefill,
+    prefix_cache::prefix_keys,
+    prompt::ChatTemplate,
+    qwen2::{Qwen2Config, IM_END_TOKEN},
+    resource::ResourceSource,
+    token_sampler::TokenSampler,
+    ChatCfg, ChatCompletionRequest, ChatModel, CrossAttentionStates, ImageSpan, KvBudgetConfig, ModelLayersWorker,
 };
 
-async fn tokenizer_path(repo: &str, filename: &str) -> PathBuf {
-    let api = Api::new().unwrap();
-    let repo = api.repo(Repo::with_revision(repo.to_string(), RepoType::Model, "main".to_string()));
-    repo.get(filename).await.unwrap()
-}
-
-async fn config_path(repo: &str, filename: &str) -> PathBuf {
-    let api = Api::new().unwrap();
-    let repo = api.repo(Repo::with_revision(repo.to_string(), RepoType::Model, "main".to_string()));
-    repo.get(filename).await.unwrap()
-}
-
-pub async fn model_filenames(repo: &str, filename: &str) -> Vec<PathBuf> {
-    let api = Api::new().unwrap();
-    let repo = api.repo(Repo::with_revision(repo.to_string(), RepoType::Model, "main".to_string()));
-    if filename.ends_with(".js") {
-        hub_load_safetensors(&repo, filename).await.unwrap()
-    } else {
-        vec![repo.get(filename).await.unwrap()]
-    }
-}
-
 pub struct ModelResource {
+    /// llama, qwen2, mixtral or mllama, which differ by their config
+    pub architecture: ModelArchitecture,
     pub repo: String,
+    pub tokenizer_repo: String,
     pub tokenizer: String,
     pub config: String,
     pub model: String,
+    pub tokenizer_config: Option<String>,
+    pub prompt_template: PromptTemplate,
+    pub source: ResourceSource,
+}
+
+impl ModelResource {
+    pub fn from_manifest(manifest: &ModelManifest, source: ResourceSource) -> Self {
+        Self {
+            architecture: manifest.architecture,
+            repo: manifest.repo.clone(),
+            tokenizer_repo: manifest.tokenizer_repo().to_string(),
+            tokenizer: manifest.tokenizer.clone(),
+            config: manifest.config.clone().unwrap_or_else(|| "config.json".to_string()),
+            model: manifest.weights.clone(),
+            tokenizer_config: manifest.tokenizer_config.clone(),
+            prompt_template: manifest.prompt_template,
+            source,
+        }
+    }
+
+    pub async fn num_layers(&self) -> Result<u32> {
+        Ok(self.load_config(false).await?.num_hidden_layers as u32)
+    }
+
+    /// GGUF weights hold the config and quantized tensors, the tokenizer comes from `tokenizer_repo`
+    pub fn is_gguf(&self) -> bool {
+        self.model.ends_with(".gguf")
+    }
+
+    async fn open_gguf(&self) -> Result<(gguf_file::Content, std::fs::File)> {
+        let mut file = std::fs::File::open(self.source.get(&self.repo, &self.model).await?)?;
+        let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(&self.model))?;
+        Ok((content, file))
+    }
+
+    async fn load_config(&self, use_flash_attn: bool) -> Result<Config> {
+        if self.is_gguf() {
+            let (content, mut file) = self.open_gguf().await?;
+            return Config::from_gguf(&content, &mut file);
+        }
+        let config_filename = self.source.get(&self.repo, &self.config).await?;
+        let config = std::fs::read(config_filename)?;
+        match self.architecture {
+            ModelArchitecture::Qwen2 => serde_json::from_slice::<Qwen2Config>(&config).map_err(candle_core::Error::wrap)?.into_config(use_flash_attn),
+            ModelArchitecture::Mixtral => serde_json::from_slice::<MixtralConfig>(&config).map_err(candle_core::Error::wrap)?.into_config(use_flash_attn),
+            ModelArchitecture::Mllama => Ok(serde_json::from_slice::<MllamaConfig>(&config)
+                .map_err(candle_core::Error::wrap)?
+                .text_config
+                .llama
+                .into_config(use_flash_attn)),
+            _ => Ok(serde_json::from_slice::<LlamaConfig>(&config).map_err(candle_core::Error::wrap)?.into_config(use_flash_attn)),
+        }
+    }
+
+    pub(crate) async fn load_weights(&self, dtype: DType, device: &Device) -> Result<VarBuilder<'static>> {
+        let filenames = self.source.get_safetensors(&self.repo, &self.model).await?;
+        unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, device) }
+    }
+
+    async fn load_pre_post(&self, config: &Config, dtype: DType, device: &Device) -> Result<(LlamaPre, LlamaPost)> {
+        if self.is_gguf() {
+            let (content, mut file) = self.open_gguf().await?;
+            let pre = LlamaPre::load_gguf(&content, &mut file, config, device)?;
+            let post = LlamaPost::load_gguf(&content, &mut file, config, device)?;
+            return Ok((pre, post));
+        }
+        let vb = self.load_weights(dtype, device).await?;
+        if self.architecture == ModelArchitecture::Mllama {
+            // the text model of mllama has 8 more embeddings than its vocab, for the image token
+            let vb = vb.pp("language_model");
+            let pre_config = Config {
+                vocab_size: config.vocab_size + 8,
+                ..config.clone()
+            };
+            return Ok((LlamaPre::load(&vb, &pre_config)?, LlamaPost::load(&vb, config)?));
+        }
+        Ok((LlamaPre::load(&vb, config)?, LlamaPost::load(&vb, config)?))
+    }
+
+    /// Vision model of mllama with its image token, None for the text models
+    async fn load_vision(&self, dtype: DType, device: &Device) -> Result<Option<(MllamaVision, u32)>> {
+        if self.architecture != ModelArchitecture::Mllama {
+            return Ok(None);
+        }
+        let config = std::fs::read(self.source.get(&self.repo, &self.config).await?)?;
+        let config = serde_json::from_slice::<MllamaConfig>(&config).map_err(candle_core::Error::wrap)?;
+        let vb = self.load_weights(dtype, device).await?;
+        Ok(Some((MllamaVision::load(vb, &config, dtype, device)?, config.image_token_index)))
+    }
+
+    /// Only the tensors of the layers in `range` are loaded
+    async fn load_layers(&self, config: Config, range: Range<u32>, dtype: DType, device: Device, kv_budget: KvBudgetConfig) -> Result<LlamaLayersWorker> {
+        if self.is_gguf() {
+            let (content, mut file) = self.open_gguf().await?;
+            return LlamaLayersWorker::new_gguf(range, &content, &mut file, config, device, kv_budget);
+        }
+        let vb = self.load_weights(dtype, &device).await?;
+        LlamaLayersWorker::new(range, vb, config, dtype, device, kv_budget)
+    }
 }
 
 pub struct LlamaModel<W: ModelLayersWorker<(Tensor, u32)>> {
+    architecture: ModelArchitecture,
     device: Device,
     tokenizer: Tokenizer,
     pre: LlamaPre,
     post: LlamaPost,
     layers_worker: W,
     config: Config,
+    chat_template: ChatTemplate,
+    token_vocab: OnceLock<Arc<TokenVocab>>,
+    conversations: Conversations,
+    draft: Option<DraftModel>,
+    /// vision model and image token of mllama, which encodes the images of the prompts
+    vision: Option<(MllamaVision, u32)>,
 }
 
 impl<W: ModelLayersWorker<(Tensor, u32)>> LlamaModel<W> {
-    pub async fn new(resource: &ModelResource, device: Device, dtype: DType, layers_worker: W, use_flash_attn: bool) -> Self {
-        let tokenizer_filename = tokenizer_path(&resource.repo, &resource.tokenizer).await;
-        let tokenizer = Tokenizer::from_file(tokenizer_filename).unwrap();
-
-        let config_filename = config_path(&resource.repo, &resource.config).await;
-        let config: LlamaConfig = serde_json::from_slice(&std::fs::read(config_filename).unwrap()).unwrap();
-        let config = config.into_config(use_flash_attn);
+    pub async fn new(resource: &ModelResource, device: Device, dtype: DType, layers_worker: W, use_flash_attn: bool) -> Result<Self> {
+        let tokenizer_filename = resource.source.get(&resource.tokenizer_repo, &resource.tokenizer).await?;
+        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(candle_core::Error::msg)?;
 
-        let filenames = model_filenames(&resource.repo, &resource.model).await;
-        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device).unwrap() };
+        let chat_template = ChatTemplate::load(&resource.source, &resource.tokenizer_repo, resource.tokenizer_config.as_deref(), resource.prompt_template).await;
+        let config = resource.load_config(use_flash_attn).await?;
+        let (pre, post) = resource.load_pre_post(&config, dtype, &device).await?;
+        let vision = resource.load_vision(dtype, &device).await?;
 
-        let pre = LlamaPre::load(&vb, &config).unwrap();
-        let post = LlamaPost::load(&vb, &config).unwrap();
-
-        Self {
+        Ok(Self {
+            architecture: resource.architecture,
             device,
             tokenizer,
             pre,
             layers_worker,
             post,
             config,
+            chat_template,
+            token_vocab: OnceLock::new(),
+            conversations: Conversations::default(),
+            draft: None,
+            vision,
+        })
+    }
+
+    /// Generate with speculative decoding, the draft model must have the same tokenizer
+    pub fn with_draft(mut self, draft: DraftModel) -> Result<Self> {
+        if draft.vocab_size() != self.config.vocab_size {
+            candle_core::bail!("draft model has a vocab of {} tokens, the model has {}", draft.vocab_size(), self.config.vocab_size)
         }
+        self.draft = Some(draft);
+        Ok(self)
     }
 }
 
 #[async_trait::async_trait]
 impl<W: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static> ChatModel for LlamaModel<W> {
     fn build_prompt(&self, request: &ChatCompletionRequest) -> String {
-        let mut prompt = String::from("<|begin_of_text|>");
-        for message in &request.messages {
-            for content in message.content.contents() {
-                match message.role.as_str() {
-                    "system" => {
-                        prompt.push_str(&format!("<|start_header_id|>system<|end_header_id|>\n{content}<|eot_id|>"));
-                    }
-                    "user" => {
-                        prompt.push_str(&format!("<|start_header_id|>user<|end_header_id|>\n{content}<|eot_id|>"));
-                    }
-                    "assistant" => {
-                        prompt.push_str(&format!("<|start_header_id|>assistant<|end_header_id|>\n{content}<|eot_id|>"));
-                    }
-                    _ => {
-                        log::warn!("unsupported role: {}", message.role)
-                    }
-                }
-            }
-        }
-        prompt.push_str("<|start_header_id|>assistant<|end_header_id|>");
-        prompt
+        self.chat_template.render(request)
     }
 
-    async fn chat(&self, session: Session, cfg: ChatCfg, prompt: &str, tx: Sender<String>) -> Result<()> {
-        let eos_token_id = self.config.eos_token_id.clone().or_else(|| self.tokenizer.token_to_id(EOS_TOKEN).map(LlamaEosToks::Single));
-        let mut tokens = self.tokenizer.encode(prompt, true).unwrap().get_ids().to_vec();
-        let mut tokenizer = TokenOutputStream::new(self.tokenizer.clone());
-        println!("tokens {tokens:?}");
+    fn check_prompt(&self, cfg: &ChatCfg, prompt: &str) -> Result<()> {
+        conversation::check_prompt(self, cfg, prompt)
+    }
 
-        let mut logits_processor = {
-            let temperature = cfg.temperature;
-            let sampling = if cfg.temperature <= 0. {
-                Sampling::ArgMax
-            } else {
-                match (cfg.top_k, cfg.top_p) {
-                    (None, None) => Sampling::All { temperature },
-                    (Some(k), None) => Sampling::TopK { k, temperature },
-                    (None, Some(p)) => Sampling::TopP { p, temperature },
-                    (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
-                }
-            };
-            LogitsProcessor::from_sampling(cfg.seed, sampling)
-        };
+    async fn chat(&self, session: Session, cfg: ChatCfg, prompt: &str, tx: Sender<ChatEvent>) -> Result<FinishReason> {
+        conversation::chat(self, session, cfg, prompt, tx).await
+    }
+
+    async fn chat_turn(&self, conversation: &str, cfg: ChatCfg, prompt: &str, tx: Sender<ChatEvent>) -> Result<FinishReason> {
+        self.conversations.chat_turn(self, conversation, cfg, prompt, tx).await
+    }
 
-        let mut start_gen = std::time::Instant::now();
-        let mut index_pos = 0;
-        let mut token_generated = 0;
+    async fn expire_conversations(&self, ttl: Duration) {
+        self.conversations.expire(self, ttl).await
+    }
+}
 
-        if let Err(e) = self.layers_worker.start(session, cfg.clone()).await {
-            log::error!("failed to start layers worker: {e}");
+impl<W: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static> LlamaModel<W> {
+    /// Start the session then send it the cross-attention states of the images, their spans come from the image tokens of the prompt.
+    /// The prefix caches don't hold the images, so the whole prompt is forwarded
+    async fn start_with_images(&self, session: Session, cfg: &ChatCfg, tokens: &[u32]) -> Result<()> {
+        let Some((vision, image_token)) = &self.vision else { bail!("model does not accept images") };
+        let spans = image_token_spans(tokens, *image_token);
+        if spans.len() != cfg.images.len() {
+            bail!("prompt has {} image tokens for {} images", spans.len(), cfg.images.len())
+        }
+        let (states, keys) = vision.encode(&cfg.images)?;
+        let images = spans.into_iter().zip(keys).map(|((token_from, token_to), keys)| ImageSpan { token_from, token_to, keys }).collect();
+        self.layers_worker.start(session, cfg.clone()).await?;
+        if let Err(e) = self.layers_worker.set_cross_attention(session, CrossAttentionStates { states, images }).await {
+            self.layers_worker.finish(session).await;
             return Err(e);
         }
-        for index in 0..cfg.max_len {
-            let (context_size, context_index) = if USE_KV_CACHE && index > 0 {
-                (1, index_pos)
+        Ok(())
+    }
+
+    /// Generate one token per forward through the layers chain
+    async fn generate_steps(&self, session: Session, sampler: &mut TokenSampler, tokens: &mut Vec<u32>) -> Result<FinishReason> {
+        loop {
+            let (logits, seq_len) = if USE_KV_CACHE {
+                let input = Tensor::new(&tokens[tokens.len() - 1..], &self.device)?.unsqueeze(0)?;
+                let (input, seq_len) = self.pre.forward(&input)?;
+                let (logits, _) = self.layers_worker.forward(session, sampler.generated, (input, seq_len as u32), tokens.len() as u32 - 1).await?;
+                (logits, seq_len)
             } else {
-                (tokens.len(), 0)
+                let (logits, seq_len) = ChatSteps::prefill(self, session, &sampler.cfg, tokens, 0).await?;
+                (logits, seq_len as usize)
             };
-            if index == 1 {
-                start_gen = std::time::Instant::now()
+            let logits = self.post.forward(&logits, seq_len)?.squeeze(0)?;
+            if let Some(reason) = sampler.next(logits, tokens).await? {
+                return Ok(reason);
             }
-            let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
-            let input = Tensor::new(ctxt, &&self.device)?.unsqueeze(0)?;
-            let (input, seq_len) = self.pre.forward(&input)?;
-            let (logits, _) = self.layers_worker.forward(session, 0, (input, seq_len as u32), context_index).await?;
-            let logits = self.post.forward(&logits, seq_len)?;
-            let logits = logits.squeeze(0)?;
-            let logits = if cfg.repeat_penalty == 1. {
-                logits
+        }
+    }
+
+    /// Generate with the tokens proposed by the draft model, the layers chain verifies them all in one forward.
+    /// The rejected ones are rolled back out of the kv caches of the chain and of the draft
+    async fn generate_speculative(&self, session: Session, draft: &DraftModel, sampler: &mut TokenSampler, tokens: &mut Vec<u32>) -> Result<FinishReason> {
+        draft.start(session).await?;
+        let res = self.speculate(session, draft, sampler, tokens).await;
+        draft.finish(session).await;
+        res
+    }
+
+    async fn speculate(&self, session: Session, draft: &DraftModel, sampler: &mut TokenSampler, tokens: &mut Vec<u32>) -> Result<FinishReason> {
+        // tokens already in the kv cache of the draft
+        let mut drafted = 0;
+        let (mut proposed_sum, mut accepted_sum) = (0, 0);
+        loop {
+            let remaining = sampler.cfg.max_len.saturating_sub(sampler.generated) as usize;
+            let count = (draft.tokens() as usize).min(remaining.saturating_sub(1));
+            let proposed = if count > 0 {
+                draft.propose(session, tokens, drafted, count, sampler.cfg.prefill_chunk_size).await?
             } else {
-                let start_at = tokens.len().saturating_sub(cfg.repeat_last_n);
-                apply_repeat_penalty(&logits, cfg.repeat_penalty, &tokens[start_at..])?
+                vec![]
             };
-            index_pos += ctxt.len() as u32;
 
-            let next_token = logits_processor.sample(&logits)?;
-            token_generated += 1;
-            tokens.push(next_token);
+            // the last token and the proposed ones, the logits of each position verify the next proposed token
+            let pos = tokens.len() - 1;
+            let input = [&tokens[pos..], proposed.as_slice()].concat();
+            let (input, seq_len) = self.pre.forward(&Tensor::new(input.as_slice(), &self.device)?.unsqueeze(0)?)?;
+            let (hidden, _) = self.layers_worker.forward(session, sampler.generated, (input, seq_len as u32), pos as u32).await?;
+            let logits = self.post.forward_all(&hidden)?;
 
-            match eos_token_id {
-                Some(LlamaEosToks::Single(eos_tok_id)) if next_token == eos_tok_id => {
-                    break;
-                }
-                Some(LlamaEosToks::Multiple(ref eos_ids)) if eos_ids.contains(&next_token) => {
+            // a proposed token is accepted when the model samples it, so the output follows the distribution of the model
+            let mut accepted = 0;
+            let mut finish_reason = None;
+            for (i, proposed) in proposed.iter().map(Some).chain([None]).enumerate() {
+                finish_reason = sampler.next(logits.get(i)?, tokens).await?;
+                if finish_reason.is_some() || proposed != tokens.last() {
                     break;
                 }
-                _ => (),
+                accepted += 1;
             }
-            if let Some(t) = tokenizer.next_token(next_token)? {
-                if let Err(e) = tx.send(t).await {
-                    log::error!("error sending message: {}", e);
-                    break;
+            proposed_sum += count;
+            accepted_sum += accepted;
+
+            // the kv caches keep all the tokens but the last sampled one
+            if accepted < count {
+                self.layers_worker.rollback(session, (count - accepted) as u32).await?;
+            }
+            if count > 0 {
+                let kept = accepted.min(count - 1);
+                if kept < count - 1 {
+                    draft.rollback(session, (count - 1 - kept) as u32).await?;
                 }
+                drafted = pos + 1 + kept;
+            }
+            if let Some(reason) = finish_reason {
+                log::info!("[LlamaModel] session {session} speculative decoding accepted {accepted_sum}/{proposed_sum} proposed tokens");
+                return Ok(reason);
             }
         }
-        if let Some(rest) = tokenizer.decode_rest().unwrap() {
-            if let Err(e) = tx.send(rest).await {
-                log::error!("error sending message: {}", e);
+    }
+}
+
+#[async_trait::async_trait]
+impl<W: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static> ChatSteps for LlamaModel<W> {
+    fn tokenize(&self, prompt: &str) -> Result<Vec<u32>> {
+        Ok(self.tokenizer.encode(prompt, true).map_err(candle_core::Error::msg)?.get_ids().to_vec())
+    }
+
+    fn context_window(&self) -> usize {
+        self.config.max_position_embeddings
+    }
+
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    fn token_vocab(&self) -> Arc<TokenVocab> {
+        self.token_vocab.get_or_init(|| Arc::new(TokenVocab::new(&self.tokenizer))).clone()
+    }
+
+    fn eos_tokens(&self) -> Vec<u32> {
+        let mut eos_tokens = match self.config.eos_token_id.clone().or_else(|| self.tokenizer.token_to_id(EOS_TOKEN).map(LlamaEosToks::Single)) {
+            Some(LlamaEosToks::Single(id)) => vec![id],
+            Some(LlamaEosToks::Multiple(ids)) => ids,
+            None => vec![],
+        };
+        // the ChatML turns of qwen2 end with `<|im_end|>`, its config may only have the eos of the whole text
+        if self.architecture == ModelArchitecture::Qwen2 {
+            match self.tokenizer.token_to_id(IM_END_TOKEN) {
+                Some(im_end) if !eos_tokens.contains(&im_end) => eos_tokens.push(im_end),
+                _ => {}
             }
         }
+        eos_tokens
+    }
+
+    fn accepts_images(&self) -> bool {
+        self.vision.is_some()
+    }
+
+    async fn start(&self, session: Session, cfg: &ChatCfg, tokens: &[u32]) -> Result<u32> {
+        if !cfg.images.is_empty() {
+            return self.start_with_images(session, cfg, tokens).await.map(|_| 0);
+        }
+        let prefixes = if USE_KV_CACHE {
+            prefix_keys(tokens, cfg.prefill_chunk_size)
+        } else {
+            vec![]
+        };
+        self.layers_worker.start_with_prefix(session, cfg.clone(), prefixes).await
+    }
+
+    async fn prefill(&self, session: Session, cfg: &ChatCfg, tokens: &[u32], cached_len: u32) -> Result<(Tensor, u32)> {
+        // the prompt goes through the layers chain in chunks, without the kv cache every chunk would need the whole context
+        let (cached_len, chunk_size) = if USE_KV_CACHE {
+            (cached_len, cfg.prefill_chunk_size.max(1))
+        } else {
+            (0, tokens.len())
+        };
+        let chunks = tokens[cached_len as usize..]
+            .chunks(chunk_size)
+            .map(|chunk| self.pre.forward(&Tensor::new(chunk, &self.device)?.unsqueeze(0)?).map(|(input, seq_len)| (input, seq_len as u32)))
+            .collect::<Result<Vec<_>>>()?;
+        prefill(&self.layers_worker, session, cached_len, chunks).await
+    }
+
+    async fn generate(&self, session: Session, cfg: ChatCfg, tokens: &[u32], prefilled: (Tensor, u32), tx: Sender<ChatEvent>) -> Result<(FinishReason, Vec<u32>)> {
+        let mut tokens = tokens.to_vec();
+        let prompt_len = tokens.len();
+        println!("tokens {tokens:?}");
+        let mut sampler = TokenSampler::new(self, cfg, prompt_len, tx)?;
+
+        let mut finish_reason = if sampler.cfg.max_len == 0 {
+            Some(FinishReason::Length)
+        } else {
+            let (logits, seq_len) = prefilled;
+            let logits = self.post.forward(&logits, seq_len as usize)?.squeeze(0)?;
+            sampler.next(logits, &mut tokens).await?
+        };
+        let start_gen = std::time::Instant::now();
+        if finish_reason.is_none() {
+            finish_reason = Some(match &self.draft {
+                Some(draft) if USE_KV_CACHE => self.generate_speculative(session, draft, &mut sampler, &mut tokens).await?,
+                _ => self.generate_steps(session, &mut sampler, &mut tokens).await?,
+            });
+        }
+        let mut finish_reason = finish_reason.unwrap_or(FinishReason::Length);
+        if let Some(reason) = sampler.output.finish().await? {
+            finish_reason = reason;
+        }
         let dt = start_gen.elapsed();
-        println!("\n\n{} tokens generated ({} token/s)\n", token_generated, (token_generated - 1) as f64 / dt.as_secs_f64(),);
-        self.layers_worker.finish(session).await;
-        Ok(())
+        println!("\n\n{} tokens generated ({} token/s)\n", sampler.generated, (sampler.generated as f64 - 1.0) / dt.as_secs_f64(),);
+        Ok((finish_reason, tokens.split_off(prompt_len)))
+    }
+
+    async fn logits(&self, _session: Session, (logits, seq_len): (Tensor, u32)) -> Result<Tensor> {
+        self.post.forward(&logits, seq_len as usize)?.squeeze(0)
     }
-}
 
-pub async fn new_layers(resource: &ModelResource, dtype: DType, device: Device, use_flash_attn: bool, range: Range<u32>) -> LlamaLayersWorker {
-    let config_filename = config_path(&resource.repo, &resource.config).await;
-    let config: LlamaConfig = serde_json::from_slice(&std::fs::read(config_filename).unwrap()).unwrap();
-    let config = config.into_config(use_flash_attn);
+    async fn decode(&self, session: Session, step: u32, token: u32, index_pos: u32) -> Result<Tensor> {
+        let (input, seq_len) = self.pre.forward(&Tensor::new(&[token], &self.device)?.unsqueeze(0)?)?;
+        let (logits, _) = self.layers_worker.forward(session, step, (input, seq_len as u32), index_pos).await?;
+        self.post.forward(&logits, seq_len)?.squeeze(0)
+    }
+
+    async fn fork(&self, session: Session, forked: Session) -> Result<()> {
+        self.layers_worker.fork(session, forked).await
+    }
+
+    async fn finish(&self, session: Session) {
+        self.layers_worker.finish(session).await
+    }
+}
 
-    let filenames = model_filenames(&resource.repo, &resource.model).await;
-    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device).unwrap() };
-    LlamaLayersWorker::new(range, vb, config, dtype, device).unwrap()
+pub async fn new_layers(resource: &ModelResource, dtype: DType, device: Device, use_flash_attn: bool, range: Range<u32>, kv_budget: KvBudgetConfig) -> Result<LlamaLayersWorker> {
+    let config = resource.load_config(use_flash_attn).await?;
+    resource.load_layers(config, range, dtype, device, kv_budget).await
 }
@@ -1,4 +1,5 @@
 use candle_core::{DType, Error, Result, Tensor};
+use protocol::ChatCfg;
 use rand::{distributions::Distribution, SeedableRng};
 
 #[derive(Clone, PartialEq, Debug)]
@@ -8,6 +9,38 @@ pub enum Sampling {
     TopK { k: usize, temperature: f64 },
     TopP { p: f64, temperature: f64 },
     TopKThenTopP { k: usize, p: f64, temperature: f64 },
+    MinP { p: f64, temperature: f64 },
+    Typical { p: f64, temperature: f64 },
+}
+
+impl Sampling {
+    pub fn from_cfg(cfg: &ChatCfg) -> Self {
+        let temperature = cfg.temperature;
+        if temperature <= 0. {
+            return Sampling::ArgMax;
+        }
+        if let Some(p) = cfg.min_p {
+            return Sampling::MinP { p, temperature };
+        }
+        if let Some(p) = cfg.typical_p {
+            return Sampling::Typical { p, temperature };
+        }
+        match (cfg.top_k, cfg.top_p) {
+            (None, None) => Sampling::All { temperature },
+            (Some(k), None) => Sampling::TopK { k, temperature },
+            (None, Some(p)) => Sampling::TopP { p, temperature },
+            (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+        }
+    }
+}
+
+/// Log-probability of a sampled token, with the most likely tokens at the same position
+#[derive(Clone, PartialEq, Debug)]
+pub struct Logprobs {
+    pub token: u32,
+    pub logprob: f32,
+    /// most likely tokens by descending logprob
+    pub top: Vec<(u32, f32)>,
 }
 
 pub struct LogitsProcessor {
@@ -40,6 +73,10 @@ impl LogitsProcessor {
         Self::from_sampling(seed, sampling)
     }
 
+    pub fn from_cfg(cfg: &ChatCfg) -> Self {
+        Self::from_sampling(cfg.seed, Sampling::from_cfg(cfg))
+    }
+
     fn sample_argmax(&mut self, logits: Tensor) -> Result<u32> {
         let logits_v: Vec<f32> = logits.to_vec1()?;
         let next_token = logits_v.iter().enumerate().max_by(|(_, u), (_, v)| u.total_cmp(v)).map(|(i, _)| i as u32).unwrap();
@@ -106,10 +143,38 @@ impl LogitsProcessor {
         }
     }
 
+    /// min-p sampling samples from the tokens with a probability of at least min_p times the probability of the most likely token,
+    /// so the cut-off follows the confidence of the model.
+    fn sample_minp(&mut self, prs: &mut [f32], min_p: f32) -> Result<u32> {
+        apply_min_p(prs, min_p);
+        self.sample_multinomial(&prs.to_vec())
+    }
+
+    /// locally typical sampling samples from the tokens whose information content is the closest to the entropy of the distribution,
+    /// keeping the smallest such set that exceeds probability typical_p.
+    fn sample_typical(&mut self, prs: &mut [f32], typical_p: f32) -> Result<u32> {
+        apply_typical_p(prs, typical_p);
+        self.sample_multinomial(&prs.to_vec())
+    }
+
     pub fn sample(&mut self, logits: &Tensor) -> Result<u32> {
         self.sample_f(logits, |_| {})
     }
 
+    /// Sample the next token, with its logprobs when `top_logprobs` is set.
+    /// Logprobs are from the model distribution, before temperature and top-k/top-p truncation.
+    pub fn sample_with_logprobs(&mut self, logits: &Tensor, top_logprobs: Option<usize>) -> Result<(u32, Option<Logprobs>)> {
+        let next_token = self.sample(logits)?;
+        let logprobs = match top_logprobs {
+            Some(top_n) => {
+                let logits_v: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+                Some(logprobs(&logits_v, next_token, top_n))
+            }
+            None => None,
+        };
+        Ok((next_token, logprobs))
+    }
+
     pub fn sample_f(&mut self, logits: &Tensor, f: impl FnOnce(&mut [f32])) -> Result<u32> {
         let logits = logits.to_dtype(DType::F32)?;
         let prs = |temperature: f64| -> Result<Vec<f32>> {
@@ -144,7 +209,117 @@ impl LogitsProcessor {
                 let mut prs = prs(*temperature)?;
                 self.sample_topk_topp(&mut prs, *k, *p as f32)?
             }
+            Sampling::MinP { p, temperature } => {
+                let mut prs = prs(*temperature)?;
+                self.sample_minp(&mut prs, *p as f32)?
+            }
+            Sampling::Typical { p, temperature } => {
+                let mut prs = prs(*temperature)?;
+                if *p <= 0.0 || *p >= 1.0 {
+                    self.sample_multinomial(&prs)?
+                } else {
+                    self.sample_typical(&mut prs, *p as f32)?
+                }
+            }
         };
         Ok(next_token)
     }
 }
+
+/// Clamp to zero the probabilities lower than `min_p` times the highest one
+fn apply_min_p(prs: &mut [f32], min_p: f32) {
+    let max = prs.iter().copied().fold(0f32, f32::max);
+    let threshold = max * min_p;
+    for p in prs.iter_mut() {
+        if *p < threshold {
+            *p = 0.0;
+        }
+    }
+}
+
+/// Clamp to zero the probabilities outside the smallest locally typical set exceeding `typical_p`
+fn apply_typical_p(prs: &mut [f32], typical_p: f32) {
+    let entropy = -prs.iter().filter(|&&p| p > 0.0).map(|p| p * p.ln()).sum::<f32>();
+    let distance = |p: f32| {
+        if p > 0.0 {
+            (-p.ln() - entropy).abs()
+        } else {
+            f32::INFINITY
+        }
+    };
+    let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
+    argsort_indices.sort_by(|&i, &j| distance(prs[i]).total_cmp(&distance(prs[j])));
+
+    let mut cumsum = 0.;
+    for index in &argsort_indices {
+        if cumsum >= typical_p {
+            prs[*index] = 0.0;
+        } else {
+            cumsum += prs[*index];
+        }
+    }
+}
+
+fn logprobs(logits: &[f32], token: u32, top_n: usize) -> Logprobs {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum_exp = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+    let logprob = |i: usize| logits[i] - log_sum_exp;
+
+    let top_n = top_n.min(logits.len());
+    let mut indices = (0..logits.len()).collect::<Vec<_>>();
+    if top_n > 0 && top_n < indices.len() {
+        indices.select_nth_unstable_by(top_n - 1, |&i, &j| logits[j].total_cmp(&logits[i]));
+    }
+    indices.truncate(top_n);
+    indices.sort_by(|&i, &j| logits[j].total_cmp(&logits[i]));
+
+    Logprobs {
+        token,
+        logprob: logprob(token as usize),
+        top: indices.into_iter().map(|i| (i as u32, logprob(i))).filter(|(_, l)| l.is_finite()).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::{apply_min_p, apply_typical_p, LogitsProcessor, Sampling};
+
+    #[test]
+    fn min_p() {
+        let mut prs = vec![0.5, 0.3, 0.15, 0.05];
+        apply_min_p(&mut prs, 0.2);
+        assert_eq!(prs, vec![0.5, 0.3, 0.15, 0.0]);
+    }
+
+    #[test]
+    fn typical_p() {
+        // entropy is ~1.14 and the information contents are 0.69, 1.20, 1.90 and 3.00, so 0.3 is the most typical, then 0.5 and 0.15
+        let mut prs = vec![0.5, 0.3, 0.15, 0.05];
+        apply_typical_p(&mut prs, 0.4);
+        assert_eq!(prs, vec![0.5, 0.3, 0.0, 0.0]);
+
+        let mut prs = vec![0.5, 0.3, 0.15, 0.05];
+        apply_typical_p(&mut prs, 0.9);
+        assert_eq!(prs, vec![0.5, 0.3, 0.15, 0.0]);
+    }
+
+    #[test]
+    fn sample_with_logprobs() {
+        let logits = Tensor::new(&[1f32, 3., 2., 0.], &Device::Cpu).unwrap();
+        let mut processor = LogitsProcessor::from_sampling(42, Sampling::ArgMax);
+        let (token, logprobs) = processor.sample_with_logprobs(&logits, Some(2)).unwrap();
+        let logprobs = logprobs.expect("Should have logprobs");
+        assert_eq!(token, 1);
+        assert_eq!(logprobs.token, 1);
+
+        let log_sum_exp = [1f32, 3., 2., 0.].iter().map(|l| l.exp()).sum::<f32>().ln();
+        assert!((logprobs.logprob - (3. - log_sum_exp)).abs() < 1e-5);
+        assert_eq!(logprobs.top.iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![1, 2]);
+        assert!((logprobs.top[1].1 - (2. - log_sum_exp)).abs() < 1e-5);
+
+        let (_, logprobs) = processor.sample_with_logprobs(&logits, None).unwrap();
+        assert_eq!(logprobs, None);
+    }
+}
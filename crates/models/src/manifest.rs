@@ -0,0 +1,104 @@
+use std::{ops::Range, sync::Arc};
+
+use candle_core::{DType, Device, Result, Tensor};
+use protocol::{ModelArchitecture, ModelDType, ModelManifest};
+
+use crate::{fake, gemma2, llama, mixtral, mllama, phi3, BatchConfig, BatchScheduler, ChatModel, KvBudgetConfig, ModelLayersWorker, ResourceSource};
+
+pub fn manifest_dtype(dtype: ModelDType) -> DType {
+    match dtype {
+        ModelDType::F16 => DType::F16,
+        ModelDType::Bf16 => DType::BF16,
+        ModelDType::F32 => DType::F32,
+    }
+}
+
+/// Number of layers of the model, from the manifest or else from the model config
+pub async fn model_layers(manifest: &ModelManifest, source: ResourceSource) -> Result<u32> {
+    if let Some(layers) = manifest.layers {
+        return Ok(layers);
+    }
+    match manifest.architecture {
+        ModelArchitecture::Llama | ModelArchitecture::Qwen2 | ModelArchitecture::Mixtral | ModelArchitecture::Mllama => llama::ModelResource::from_manifest(manifest, source).num_layers().await,
+        ModelArchitecture::Phi3 => phi3::Phi3Resource::from_manifest(manifest, source).num_layers().await,
+        ModelArchitecture::Gemma2 => gemma2::Gemma2Resource::from_manifest(manifest, source).num_layers().await,
+        ModelArchitecture::Fake => candle_core::bail!("model {} must declare its layers in the manifest", manifest.id),
+    }
+}
+
+/// Load the layers worker of the model described by the manifest, only the layers in `range` are loaded.
+/// The decode steps of concurrent sessions are batched together, their kv caches share the `kv_budget`.
+/// A mixture-of-experts model only loads the `experts` of its layers, all of them when None
+pub async fn new_layers_worker(
+    manifest: &ModelManifest,
+    source: ResourceSource,
+    device: &Device,
+    range: Range<u32>,
+    experts: Option<Range<u32>>,
+    kv_budget: KvBudgetConfig,
+) -> Result<Box<dyn ModelLayersWorker<(Tensor, u32)>>> {
+    match manifest.architecture {
+        // qwen2 runs with the llama layers
+        ModelArchitecture::Llama | ModelArchitecture::Qwen2 => {
+            let resource = llama::ModelResource::from_manifest(manifest, source);
+            let layers_worker = llama::new_layers(&resource, manifest_dtype(manifest.dtype), device.clone(), false, range, kv_budget).await?;
+            Ok(Box::new(BatchScheduler::new(layers_worker, BatchConfig::default())))
+        }
+        ModelArchitecture::Phi3 => {
+            let resource = phi3::Phi3Resource::from_manifest(manifest, source);
+            let layers_worker = phi3::Phi3LayersWorker::new(&resource, false, range, device, kv_budget).await?;
+            Ok(Box::new(BatchScheduler::new(layers_worker, BatchConfig::default())))
+        }
+        ModelArchitecture::Gemma2 => {
+            let resource = gemma2::Gemma2Resource::from_manifest(manifest, source);
+            let layers_worker = gemma2::new_layers(&resource, manifest_dtype(manifest.dtype), device.clone(), range, kv_budget).await?;
+            Ok(Box::new(BatchScheduler::new(layers_worker, BatchConfig::default())))
+        }
+        ModelArchitecture::Mixtral => {
+            let resource = llama::ModelResource::from_manifest(manifest, source);
+            let layers_worker = mixtral::new_layers(&resource, manifest_dtype(manifest.dtype), device.clone(), range, experts, kv_budget).await?;
+            Ok(Box::new(BatchScheduler::new(layers_worker, BatchConfig::default())))
+        }
+        ModelArchitecture::Mllama => {
+            let resource = llama::ModelResource::from_manifest(manifest, source);
+            let layers_worker = mllama::new_layers(&resource, manifest_dtype(manifest.dtype), device.clone(), range, kv_budget).await?;
+            Ok(Box::new(BatchScheduler::new(layers_worker, BatchConfig::default())))
+        }
+        ModelArchitecture::Fake => Ok(Box::new(fake::FakeLayersWorker::new(range))),
+    }
+}
+
+/// Load the chat model described by the manifest, which runs its layers with `layers_worker`
+pub async fn new_chat_model<W: ModelLayersWorker<(Tensor, u32)>>(manifest: &ModelManifest, source: ResourceSource, device: &Device, layers_worker: W) -> Result<Arc<dyn ChatModel>> {
+    match manifest.architecture {
+        ModelArchitecture::Llama | ModelArchitecture::Qwen2 => {
+            let resource = llama::ModelResource::from_manifest(manifest, source);
+            let model = llama::LlamaModel::new(&resource, device.clone(), manifest_dtype(manifest.dtype), layers_worker, false).await?;
+            match (manifest.draft_manifest(), &manifest.draft) {
+                (Some(draft_manifest), Some(draft)) => {
+                    let resource = llama::ModelResource::from_manifest(&draft_manifest, resource.source.clone());
+                    let draft = llama::DraftModel::new(&resource, device.clone(), manifest_dtype(draft.dtype), draft.tokens).await?;
+                    Ok(Arc::new(model.with_draft(draft)?))
+                }
+                _ => Ok(Arc::new(model)),
+            }
+        }
+        ModelArchitecture::Phi3 => {
+            let resource = phi3::Phi3Resource::from_manifest(manifest, source);
+            let model = phi3::Phi3Model::new(&resource, device.clone(), layers_worker).await?;
+            Ok(Arc::new(model))
+        }
+        // mixtral and mllama run with the llama pre/post, mllama also loads its vision model
+        ModelArchitecture::Mixtral | ModelArchitecture::Mllama => {
+            let resource = llama::ModelResource::from_manifest(manifest, source);
+            let model = llama::LlamaModel::new(&resource, device.clone(), manifest_dtype(manifest.dtype), layers_worker, false).await?;
+            Ok(Arc::new(model))
+        }
+        ModelArchitecture::Gemma2 => {
+            let resource = gemma2::Gemma2Resource::from_manifest(manifest, source);
+            let model = gemma2::Gemma2Model::new(&resource, device.clone(), manifest_dtype(manifest.dtype), layers_worker).await?;
+            Ok(Arc::new(model))
+        }
+        ModelArchitecture::Fake => Ok(Arc::new(fake::FakeModel::new(device.clone(), layers_worker).await)),
+    }
+}
@@ -0,0 +1,205 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use candle_core::{bail, DType, Module, Result, Tensor};
+use candle_nn::{linear_no_bias, Linear, RmsNorm, VarBuilder};
+
+use super::MixtralConfig;
+use crate::llama::internal::{Cache, CausalSelfAttention, Config};
+
+/// An expert is a SwiGLU mlp
+#[derive(Debug, Clone)]
+struct Expert {
+    w1: Linear,
+    w2: Linear,
+    w3: Linear,
+}
+
+impl Expert {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = (candle_nn::ops::silu(&self.w1.forward(xs)?)? * self.w3.forward(xs)?)?;
+        self.w2.forward(&xs)
+    }
+
+    fn load(vb: VarBuilder, cfg: &MixtralConfig) -> Result<Self> {
+        Ok(Self {
+            w1: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("w1"))?,
+            w2: linear_no_bias(cfg.intermediate_size, cfg.hidden_size, vb.pp("w2"))?,
+            w3: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("w3"))?,
+        })
+    }
+}
+
+/// Tokens sent to an expert by the router, with the weight of the expert in the output of each token
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpertTokens {
+    pub expert: u32,
+    pub tokens: Vec<u32>,
+    pub weights: Vec<f32>,
+}
+
+/// Top `k` experts of each token by the router logits `logits` of shape (tokens, experts), their weights are the softmax of their logits
+pub fn route(logits: &Tensor, k: usize) -> Result<Vec<ExpertTokens>> {
+    let logits = logits.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+    let mut routes = BTreeMap::<u32, ExpertTokens>::new();
+    for (token, logits) in logits.iter().enumerate() {
+        let mut experts = (0..logits.len()).collect::<Vec<_>>();
+        experts.sort_by(|a, b| logits[*b].total_cmp(&logits[*a]));
+        experts.truncate(k);
+        let max = logits[experts[0]];
+        let exps = experts.iter().map(|e| (logits[*e] - max).exp()).collect::<Vec<_>>();
+        let sum: f32 = exps.iter().sum();
+        for (expert, exp) in experts.into_iter().zip(exps) {
+            let route = routes.entry(expert as u32).or_insert_with(|| ExpertTokens {
+                expert: expert as u32,
+                tokens: vec![],
+                weights: vec![],
+            });
+            route.tokens.push(token as u32);
+            route.weights.push(exp / sum);
+        }
+    }
+    Ok(routes.into_values().collect())
+}
+
+/// Sum the outputs of the experts into the output of each of the `tokens`, weighted by the router
+pub fn combine(routes: &[ExpertTokens], outputs: Vec<Tensor>, tokens: usize) -> Result<Tensor> {
+    let Some(first) = outputs.first() else { bail!("no expert output") };
+    let (dtype, device) = (first.dtype(), first.device().clone());
+    let mut ys = Tensor::zeros((tokens, first.dim(1)?), dtype, &device)?;
+    for (route, output) in routes.iter().zip(outputs) {
+        let weights = Tensor::new(route.weights.as_slice(), &device)?.to_dtype(dtype)?.unsqueeze(1)?;
+        let idx = Tensor::new(route.tokens.as_slice(), &device)?;
+        ys = ys.index_add(&idx, &output.broadcast_mul(&weights)?, 0)?;
+    }
+    Ok(ys)
+}
+
+/// Mixture-of-experts block, the attention is the one of llama and the mlp is replaced by the experts.
+/// Only the experts of the local shard are loaded
+#[derive(Debug, Clone)]
+struct Block {
+    rms_1: RmsNorm,
+    attn: CausalSelfAttention,
+    rms_2: RmsNorm,
+    gate: Linear,
+    experts: BTreeMap<u32, Expert>,
+}
+
+impl Block {
+    fn load(vb: VarBuilder, cfg: &MixtralConfig, llama_cfg: &Config, experts: Range<u32>) -> Result<Self> {
+        let moe = vb.pp("block_sparse_moe");
+        Ok(Self {
+            rms_1: candle_nn::rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?,
+            attn: CausalSelfAttention::load(vb.pp("self_attn"), llama_cfg)?,
+            rms_2: candle_nn::rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("post_attention_layernorm"))?,
+            gate: linear_no_bias(cfg.hidden_size, cfg.num_local_experts, moe.pp("gate"))?,
+            experts: experts.map(|e| Ok((e, Expert::load(moe.pp(format!("experts.{e}")), cfg)?))).collect::<Result<_>>()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MixtralLayers {
+    blocks: Vec<Block>,
+    range: Range<u32>,
+    num_experts_per_tok: usize,
+}
+
+impl MixtralLayers {
+    /// Only the layers in `range` are loaded, with only the `experts` of each of them
+    pub fn load(vb: VarBuilder, cfg: &MixtralConfig, llama_cfg: &Config, range: Range<u32>, experts: Range<u32>) -> Result<Self> {
+        if range.end > cfg.num_hidden_layers as u32 {
+            bail!("layers {range:?} out of the {} layers of the model", cfg.num_hidden_layers)
+        }
+        if experts.end > cfg.num_local_experts as u32 {
+            bail!("experts {experts:?} out of the {} experts of the model", cfg.num_local_experts)
+        }
+        let blocks = range
+            .clone()
+            .map(|i| Block::load(vb.pp(format!("model.layers.{i}")), cfg, llama_cfg, experts.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            blocks,
+            range,
+            num_experts_per_tok: cfg.num_experts_per_tok,
+        })
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Index of the block `block_idx` in the whole model
+    pub fn layer(&self, block_idx: usize) -> u32 {
+        self.range.start + block_idx as u32
+    }
+
+    /// The attention of the block with its residual, and the normed hidden states of shape (tokens, hidden) for the experts
+    pub fn attention(&self, block_idx: usize, x: &Tensor, index_pos: &[usize], caches: &mut [&mut Cache]) -> Result<(Tensor, Tensor)> {
+        let block = &self.blocks[block_idx];
+        let residual = x;
+        let x = block.rms_1.forward(x)?;
+        let x = (block.attn.forward(&x, index_pos, block_idx, caches)? + residual)?;
+        let xs = block.rms_2.forward(&x)?;
+        let xs = xs.flatten_to(1)?;
+        Ok((x, xs))
+    }
+
+    /// The top experts of each token of `xs` of shape (tokens, hidden)
+    pub fn route(&self, block_idx: usize, xs: &Tensor) -> Result<Vec<ExpertTokens>> {
+        route(&self.blocks[block_idx].gate.forward(xs)?, self.num_experts_per_tok)
+    }
+
+    pub fn has_expert(&self, block_idx: usize, expert: u32) -> bool {
+        self.blocks[block_idx].experts.contains_key(&expert)
+    }
+
+    pub fn forward_expert(&self, block_idx: usize, expert: u32, xs: &Tensor) -> Result<Tensor> {
+        match self.blocks[block_idx].experts.get(&expert) {
+            Some(e) => e.forward(xs),
+            None => bail!("expert {expert} of layer {} is not local", self.layer(block_idx)),
+        }
+    }
+
+    /// Block of the layer `layer` of the whole model
+    pub fn block_idx(&self, layer: u32) -> Result<usize> {
+        if !self.range.contains(&layer) {
+            bail!("layer {layer} is not in the local layers {:?}", self.range)
+        }
+        Ok((layer - self.range.start) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{combine, route, ExpertTokens};
+
+    #[test]
+    fn route_top_experts() {
+        let logits = Tensor::new(&[[0f32, 2., 1., 2.], [3., 0., 1., 0.]], &Device::Cpu).unwrap();
+        let routes = route(&logits, 2).unwrap();
+        assert_eq!(
+            routes.iter().map(|r| (r.expert, r.tokens.clone())).collect::<Vec<_>>(),
+            vec![(0, vec![1]), (1, vec![0]), (2, vec![1]), (3, vec![0])]
+        );
+        // the weights are the softmax of the logits of the top experts only
+        assert_eq!((routes[1].weights[0], routes[3].weights[0]), (0.5, 0.5));
+        assert!((routes[0].weights[0] - 1. / (1. + (-2f32).exp())).abs() < 1e-6);
+        assert!((routes[2].weights[0] - 1. / (1. + 2f32.exp())).abs() < 1e-6);
+
+        // the outputs of the experts are summed back into their tokens
+        let outputs = routes.iter().map(|r| Tensor::ones((r.tokens.len(), 2), DType::F32, &Device::Cpu).unwrap()).collect();
+        for y in combine(&routes, outputs, 2).unwrap().to_vec2::<f32>().unwrap().iter().flatten() {
+            assert!((y - 1.).abs() < 1e-6);
+        }
+        let single = [ExpertTokens {
+            expert: 0,
+            tokens: vec![1],
+            weights: vec![0.25],
+        }];
+        let ys = combine(&single, vec![Tensor::ones((1, 2), DType::F32, &Device::Cpu).unwrap()], 2).unwrap();
+        assert_eq!(ys.to_vec2::<f32>().unwrap(), vec![vec![0., 0.], vec![0.25, 0.25]]);
+    }
+}
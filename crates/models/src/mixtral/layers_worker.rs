@@ -0,0 +1,220 @@
+use std::{
+    ops::Range,
+    sync::{Arc, OnceLock},
+};
+
+use candle_core::{bail, DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+use protocol::llm::PrefixKey;
+use spin::Mutex;
+
+use crate::{
+    kv_cache::{KvQuantization, SessionCaches},
+    llama::internal::{Cache, Config},
+    ChatCfg, KvBudgetConfig, KvUsage, ModelLayersWorker, RemoteExperts, Session,
+};
+
+use super::{
+    internal::{combine, MixtralLayers},
+    MixtralConfig,
+};
+
+pub struct MixtralLayersWorker {
+    caches: SessionCaches<Cache>,
+    kv_quantization: KvQuantization,
+    layers: MixtralLayers,
+    num_experts: u32,
+    experts: Range<u32>,
+    remote: OnceLock<Arc<dyn RemoteExperts>>,
+    cfg: Config,
+    dtype: DType,
+    device: Device,
+}
+
+impl MixtralLayersWorker {
+    pub fn new(range: Range<u32>, experts: Range<u32>, vb: VarBuilder, cfg: MixtralConfig, dtype: DType, device: Device, kv_budget: KvBudgetConfig) -> Result<Self> {
+        let num_experts = cfg.num_local_experts as u32;
+        let llama_cfg = cfg.clone().into_config(false)?;
+        let layers = MixtralLayers::load(vb, &cfg, &llama_cfg, range.clone(), experts.clone())?;
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let block_bytes = range.len() * kv_budget.quantization.block_bytes(cfg.num_key_value_heads, head_dim, dtype);
+        Ok(Self {
+            kv_quantization: kv_budget.quantization,
+            caches: SessionCaches::new(kv_budget, block_bytes as u64),
+            layers,
+            num_experts,
+            experts,
+            remote: OnceLock::new(),
+            cfg: llama_cfg,
+            dtype,
+            device,
+        })
+    }
+
+    /// Forward the rows of `x`, one per session, through the local layers.
+    /// The caches are only locked for the attention, the experts of a layer may run on other nodes
+    async fn forward_layers(&self, mut x: Tensor, index_pos: &[usize], caches: &[Arc<Mutex<Cache>>]) -> Result<Tensor> {
+        let (b_sz, seq_len, hidden_size) = x.dims3()?;
+        for block_idx in 0..self.layers.num_blocks() {
+            let (residual, xs) = {
+                let mut guards = caches.iter().map(|cache| cache.lock()).collect::<Vec<_>>();
+                let mut caches_mut = guards.iter_mut().map(|cache| &mut **cache).collect::<Vec<_>>();
+                self.layers.attention(block_idx, &x, index_pos, &mut caches_mut)?
+            };
+            let ys = self.forward_moe(block_idx, &xs).await?;
+            x = (residual + ys.reshape((b_sz, seq_len, hidden_size))?)?;
+        }
+        Ok(x)
+    }
+
+    /// Send each token of `xs` through its top experts, the local ones here and the others on their owners
+    async fn forward_moe(&self, block_idx: usize, xs: &Tensor) -> Result<Tensor> {
+        let routes = self.layers.route(block_idx, xs)?;
+        let mut outputs = vec![None; routes.len()];
+        let mut remote_inputs = vec![];
+        for (i, route) in routes.iter().enumerate() {
+            let inputs = xs.index_select(&Tensor::new(route.tokens.as_slice(), &self.device)?, 0)?;
+            if self.layers.has_expert(block_idx, route.expert) {
+                outputs[i] = Some(self.layers.forward_expert(block_idx, route.expert, &inputs)?);
+            } else {
+                remote_inputs.push((i, route.expert, inputs));
+            }
+        }
+
+        if !remote_inputs.is_empty() {
+            let layer = self.layers.layer(block_idx);
+            let Some(remote) = self.remote.get() else {
+                bail!("experts of layer {layer} are not all local and there are no remote experts")
+            };
+            let (idxs, inputs): (Vec<_>, Vec<_>) = remote_inputs.into_iter().map(|(i, expert, inputs)| (i, (expert, inputs))).unzip();
+            let remote_outputs = remote.forward_experts(layer, inputs).await?;
+            if remote_outputs.len() != idxs.len() {
+                bail!("remote experts of layer {layer} returned {} outputs for {} inputs", remote_outputs.len(), idxs.len())
+            }
+            for (i, output) in idxs.into_iter().zip(remote_outputs) {
+                outputs[i] = Some(output.to_device(&self.device)?.to_dtype(xs.dtype())?);
+            }
+        }
+        combine(&routes, outputs.into_iter().flatten().collect(), xs.dim(0)?)
+    }
+}
+
+#[async_trait::async_trait]
+impl ModelLayersWorker<(Tensor, u32)> for MixtralLayersWorker {
+    async fn start(&self, session: Session, cfg: ChatCfg) -> Result<()> {
+        let cache = Cache::new(true, self.kv_quantization, cfg.overflow, self.dtype, &self.cfg, &self.device)?;
+        self.caches.start(session, cache)
+    }
+
+    async fn start_with_prefix(&self, session: Session, cfg: ChatCfg, prefixes: Vec<PrefixKey>) -> Result<u32> {
+        self.start(session, cfg).await?;
+        Ok(self.caches.register_prefixes(session, prefixes))
+    }
+
+    async fn forward(&self, session: Session, _step: u32, (xs, seq_len): (Tensor, u32), index_pos: u32) -> Result<(Tensor, u32)> {
+        let (cache, pos) = self.caches.forward_cache(session, index_pos, seq_len)?;
+        let res = self.forward_layers(xs, &[pos], std::slice::from_ref(&cache)).await?;
+        self.caches.store_prefix(session, index_pos + seq_len, &cache.lock());
+        Ok((res, seq_len))
+    }
+
+    async fn forward_batch(&self, batch: Vec<(Session, u32, (Tensor, u32), u32)>) -> Vec<Result<(Tensor, u32)>> {
+        self.caches
+            .forward_batch_async(batch, |xs, positions, caches| async move { self.forward_layers(xs, &positions, &caches).await })
+            .await
+    }
+
+    async fn rollback(&self, session: Session, count: u32) -> Result<()> {
+        self.caches.rollback(session, count)
+    }
+
+    async fn fork(&self, session: Session, forked: Session) -> Result<()> {
+        self.caches.fork(session, forked)
+    }
+
+    async fn finish(&self, session: Session) {
+        self.caches.finish(session);
+    }
+
+    fn kv_usage(&self) -> KvUsage {
+        self.caches.usage()
+    }
+
+    fn local_experts(&self) -> Option<(u32, Range<u32>)> {
+        Some((self.num_experts, self.experts.clone()))
+    }
+
+    fn set_remote_experts(&self, remote: Arc<dyn RemoteExperts>) {
+        if self.remote.set(remote).is_err() {
+            log::warn!("[MixtralLayersWorker] remote experts already set");
+        }
+    }
+
+    async fn forward_experts(&self, layer: u32, inputs: Vec<(u32, Tensor)>) -> Result<Vec<Tensor>> {
+        let block_idx = self.layers.block_idx(layer)?;
+        inputs
+            .into_iter()
+            .map(|(expert, xs)| self.layers.forward_expert(block_idx, expert, &xs.to_device(&self.device)?.to_dtype(self.dtype)?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use candle_core::{DType, Device, Result, Tensor};
+    use candle_nn::{VarBuilder, VarMap};
+
+    use super::MixtralLayersWorker;
+    use crate::{mixtral::MixtralConfig, KvBudgetConfig, ModelLayersWorker, RemoteExperts};
+
+    /// Other node holding the missing experts
+    struct MockRemote {
+        worker: MixtralLayersWorker,
+        inputs: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl RemoteExperts for Arc<MockRemote> {
+        async fn forward_experts(&self, layer: u32, inputs: Vec<(u32, Tensor)>) -> Result<Vec<Tensor>> {
+            self.inputs.fetch_add(inputs.len(), Ordering::Relaxed);
+            self.worker.forward_experts(layer, inputs).await
+        }
+    }
+
+    #[tokio::test]
+    async fn partial_shard_matches_all_local() {
+        let device = Device::Cpu;
+        let cfg = serde_json::from_str::<MixtralConfig>(
+            r#"{"hidden_size": 16, "intermediate_size": 24, "vocab_size": 32, "num_hidden_layers": 2, "num_attention_heads": 2, "num_key_value_heads": 1,
+                "max_position_embeddings": 64, "rms_norm_eps": 1e-5, "bos_token_id": 1, "eos_token_id": 2, "sliding_window": null,
+                "num_local_experts": 4, "num_experts_per_tok": 2}"#,
+        )
+        .unwrap();
+        // the workers share the random weights of the varmap
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let worker = |experts| MixtralLayersWorker::new(1..2, experts, vb.clone(), cfg.clone(), DType::F32, device.clone(), KvBudgetConfig::default()).unwrap();
+        let local = worker(0..4);
+        let partial = worker(0..2);
+        let remote = Arc::new(MockRemote {
+            worker: worker(2..4),
+            inputs: AtomicUsize::new(0),
+        });
+        partial.set_remote_experts(Arc::new(remote.clone()));
+
+        let xs = Tensor::randn(0f32, 1., (8, cfg.hidden_size), &device).unwrap();
+        let expected = local.forward_moe(0, &xs).await.unwrap();
+        let ys = partial.forward_moe(0, &xs).await.unwrap();
+        assert!(remote.inputs.load(Ordering::Relaxed) > 0);
+        let diff = (ys - expected).unwrap().abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+        assert!(diff < 1e-5);
+
+        // without remote experts the missing ones are an error
+        assert!(worker(0..2).forward_moe(0, &xs).await.is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! Mixtral is llama with a mixture-of-experts instead of the mlp of each layer, so it runs with the llama pre/post.
+//! A layers worker may hold only some of the experts of its layers, the others run on the nodes which own them.
+
+use std::ops::Range;
+
+use candle_core::{bail, DType, Device, Result};
+
+use crate::{
+    llama::{
+        internal::{Config, LlamaEosToks},
+        LlamaModel, ModelResource,
+    },
+    KvBudgetConfig,
+};
+
+mod internal;
+mod layers_worker;
+
+pub use layers_worker::MixtralLayersWorker;
+
+pub type MixtralModel<W> = LlamaModel<W>;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct MixtralConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub max_position_embeddings: usize,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f32,
+    pub rms_norm_eps: f64,
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
+    pub bos_token_id: Option<u32>,
+    pub eos_token_id: Option<u32>,
+    pub sliding_window: Option<usize>,
+    /// experts of each layer
+    pub num_local_experts: usize,
+    /// experts which each token goes through
+    pub num_experts_per_tok: usize,
+}
+
+fn default_rope_theta() -> f32 {
+    1_000_000.0
+}
+
+impl MixtralConfig {
+    pub(crate) fn into_config(self, use_flash_attn: bool) -> Result<Config> {
+        if self.sliding_window.is_some() {
+            bail!("sliding window attention of mixtral is not supported")
+        }
+        Ok(Config {
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads,
+            use_flash_attn,
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+            bos_token_id: self.bos_token_id,
+            eos_token_id: self.eos_token_id.map(LlamaEosToks::Single),
+            rope_scaling: None,
+            max_position_embeddings: self.max_position_embeddings,
+            tie_word_embeddings: self.tie_word_embeddings,
+            attention_bias: false,
+            rope_interleaved: false,
+            rope_freq_factors: None,
+        })
+    }
+}
+
+/// Only the tensors of the layers in `range` are loaded, with only the `experts` of each of them, all of them when None
+pub async fn new_layers(resource: &ModelResource, dtype: DType, device: Device, range: Range<u32>, experts: Option<Range<u32>>, kv_budget: KvBudgetConfig) -> Result<MixtralLayersWorker> {
+    let config = std::fs::read(resource.source.get(&resource.repo, &resource.config).await?)?;
+    let config = serde_json::from_slice::<MixtralConfig>(&config).map_err(candle_core::Error::wrap)?;
+    let experts = experts.unwrap_or(0..config.num_local_experts as u32);
+    let vb = resource.load_weights(dtype, &device).await?;
+    MixtralLayersWorker::new(range, experts, vb, config, dtype, device, kv_budget)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MixtralConfig;
+
+    #[test]
+    fn mixtral_config() {
+        // config.json of Mixtral-8x7B-Instruct-v0.1
+        let json = r#"{"architectures": ["MixtralForCausalLM"], "attention_dropout": 0.0, "bos_token_id": 1, "eos_token_id": 2, "hidden_act": "silu", "hidden_size": 4096, "initializer_range": 0.02, "intermediate_size": 14336, "max_position_embeddings": 32768, "model_type": "mixtral", "num_attention_heads": 32, "num_experts_per_tok": 2, "num_hidden_layers": 32, "num_key_value_heads": 8, "num_local_experts": 8, "output_router_logits": false, "rms_norm_eps": 1e-05, "rope_theta": 1000000.0, "router_aux_loss_coef": 0.02, "sliding_window": null, "tie_word_embeddings": false, "torch_dtype": "bfloat16", "use_cache": true, "vocab_size": 32000}"#;
+        let config = serde_json::from_str::<MixtralConfig>(json).unwrap();
+        assert_eq!((config.num_local_experts, config.num_experts_per_tok), (8, 2));
+        let config = config.into_config(false).unwrap();
+        assert!(!config.attention_bias);
+        assert_eq!(config.num_key_value_heads, 8);
+
+        let sliding = json.replace(r#""sliding_window": null"#, r#""sliding_window": 4096"#);
+        assert!(serde_json::from_str::<MixtralConfig>(&sliding).unwrap().into_config(false).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+//! Preprocessing of the images like the image processor of mllama in transformers:
+//! the image is resized into the best canvas of tiles, padded, normalized and split into the tiles.
+
+use candle_core::{DType, Device, Result, Tensor};
+use image::{imageops::FilterType, DynamicImage};
+
+use super::MllamaVisionConfig;
+
+const IMAGE_MEAN: [f32; 3] = [0.481_454_66, 0.457_827_5, 0.408_210_73];
+const IMAGE_STD: [f32; 3] = [0.268_629_54, 0.261_302_6, 0.275_777_1];
+
+/// Tiles of an image, padded with empty tiles up to the max number of tiles
+#[derive(Debug)]
+pub struct ImageTiles {
+    /// (max_num_tiles, channels, tile_size, tile_size)
+    pub pixels: Tensor,
+    /// 1-based index of the arrangement of the tiles in the supported aspect ratios, 0 is for padding
+    pub aspect_ratio_id: u32,
+    pub num_tiles: usize,
+}
+
+/// Canvas (height, width) of whole tiles for an image, the one which upscales the image the least,
+/// or when all of them downscale it the one which downscales it the least. Ties go to the smallest canvas
+pub fn optimal_canvas(height: usize, width: usize, aspect_ratios: &[(usize, usize)], tile_size: usize) -> (usize, usize) {
+    let scales = aspect_ratios
+        .iter()
+        .map(|(rows, cols)| {
+            let scale_h = (rows * tile_size) as f64 / height as f64;
+            let scale_w = (cols * tile_size) as f64 / width as f64;
+            scale_h.min(scale_w)
+        })
+        .collect::<Vec<_>>();
+    let upscale = scales.iter().copied().filter(|s| *s >= 1.0).reduce(f64::min);
+    let selected = upscale.unwrap_or_else(|| scales.iter().copied().fold(f64::MIN, f64::max));
+    let (rows, cols) = aspect_ratios
+        .iter()
+        .zip(&scales)
+        .filter(|(_, scale)| **scale == selected)
+        .map(|(ratio, _)| *ratio)
+        .min_by_key(|(rows, cols)| rows * cols)
+        .expect("Should have an aspect ratio");
+    (rows * tile_size, cols * tile_size)
+}
+
+/// Size (height, width) of the image resized into the canvas, keeping its aspect ratio and at least a tile on each side
+pub fn fit_to_canvas(height: usize, width: usize, canvas: (usize, usize), tile_size: usize) -> (usize, usize) {
+    let target_h = height.clamp(tile_size, canvas.0);
+    let target_w = width.clamp(tile_size, canvas.1);
+    let scale_h = target_h as f64 / height as f64;
+    let scale_w = target_w as f64 / width as f64;
+    if scale_w < scale_h {
+        (((height as f64 * scale_w).floor() as usize).clamp(1, target_h), target_w)
+    } else {
+        (target_h, ((width as f64 * scale_h).floor() as usize).clamp(1, target_w))
+    }
+}
+
+/// Resize, pad and normalize the image then split it into row-major tiles.
+/// The resize is bilinear without the antialiasing of transformers, so the pixels are close but not equal
+pub fn preprocess(image: &DynamicImage, cfg: &MllamaVisionConfig, device: &Device) -> Result<ImageTiles> {
+    let tile = cfg.image_size;
+    let (height, width) = (image.height() as usize, image.width() as usize);
+    let canvas = optimal_canvas(height, width, &cfg.supported_aspect_ratios, tile);
+    let (new_h, new_w) = fit_to_canvas(height, width, canvas, tile);
+    let resized = image.resize_exact(new_w as u32, new_h as u32, FilterType::Triangle).to_rgb8();
+
+    // padded with black on the bottom and the right before the normalization
+    let pixels = Tensor::from_vec(resized.into_raw(), (new_h, new_w, 3), device)?
+        .permute((2, 0, 1))?
+        .pad_with_zeros(1, 0, canvas.0 - new_h)?
+        .pad_with_zeros(2, 0, canvas.1 - new_w)?
+        .to_dtype(DType::F32)?;
+    let mean = Tensor::new(&IMAGE_MEAN, device)?.reshape((3, 1, 1))?;
+    let std = Tensor::new(&IMAGE_STD, device)?.reshape((3, 1, 1))?;
+    let pixels = (pixels / 255.0)?.broadcast_sub(&mean)?.broadcast_div(&std)?;
+
+    let (rows, cols) = (canvas.0 / tile, canvas.1 / tile);
+    let num_tiles = rows * cols;
+    let pixels = pixels
+        .reshape((3, rows, tile, cols, tile))?
+        .permute((1, 3, 0, 2, 4))?
+        .reshape((num_tiles, 3, tile, tile))?
+        .pad_with_zeros(0, 0, cfg.max_num_tiles - num_tiles)?;
+    let aspect_ratio_id = cfg.supported_aspect_ratios.iter().position(|ratio| *ratio == (rows, cols)).expect("Should have the aspect ratio") as u32 + 1;
+    Ok(ImageTiles { pixels, aspect_ratio_id, num_tiles })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fit_to_canvas, optimal_canvas};
+
+    const RATIOS: [(usize, usize); 8] = [(1, 1), (1, 2), (1, 3), (1, 4), (2, 1), (2, 2), (3, 1), (4, 1)];
+
+    #[test]
+    fn canvas_of_tiles() {
+        // small images are upscaled into a single tile
+        assert_eq!(optimal_canvas(300, 400, &RATIOS, 560), (560, 560));
+        // a wide image fits in a row of tiles with the least upscaling
+        assert_eq!(optimal_canvas(500, 1500, &RATIOS, 560), (560, 1680));
+        // a large square image is downscaled the least into 2x2 tiles
+        assert_eq!(optimal_canvas(2000, 2000, &RATIOS, 560), (1120, 1120));
+
+        assert_eq!(fit_to_canvas(500, 1500, (560, 1680), 560), (500, 1500));
+        assert_eq!(fit_to_canvas(300, 400, (560, 560), 560), (420, 560));
+        assert_eq!(fit_to_canvas(2000, 1000, (1120, 1120), 560), (1120, 560));
+    }
+}
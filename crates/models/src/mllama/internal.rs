@@ -0,0 +1,326 @@
+use std::ops::Range;
+
+use candle_core::{bail, DType, Device, Module, Result, Tensor};
+use candle_nn::{linear_no_bias, Linear, RmsNorm, VarBuilder};
+
+use super::{CrossAttentionStates, ImageSpan, MllamaTextConfig};
+use crate::llama::internal::{Block, Cache, Config, Mlp};
+
+/// Images of a session with their keys and values for each local cross-attention layer, computed once when they are set
+#[derive(Debug, Clone)]
+pub struct SessionImages {
+    images: Vec<ImageSpan>,
+    num_keys: usize,
+    kvs: Vec<(Tensor, Tensor)>,
+}
+
+/// Attention of the text over the images, the queries and keys are normalized per head
+#[derive(Debug, Clone)]
+struct CrossAttention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    q_norm: RmsNorm,
+    k_norm: RmsNorm,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    head_dim: usize,
+}
+
+impl CrossAttention {
+    /// Keys and values of the states of the images, of shape (1, num_key_value_heads, keys, head_dim)
+    fn kv(&self, states: &Tensor) -> Result<(Tensor, Tensor)> {
+        let (keys, _) = states.dims2()?;
+        let heads = |proj: &Linear| proj.forward(states)?.reshape((1, keys, self.num_key_value_heads, self.head_dim))?.transpose(1, 2)?.contiguous();
+        Ok((self.k_norm.forward(&heads(&self.k_proj)?)?, heads(&self.v_proj)?))
+    }
+
+    fn forward(&self, x: &Tensor, (k, v): &(Tensor, Tensor), mask: &Tensor) -> Result<Tensor> {
+        let (b_sz, seq_len, hidden_size) = x.dims3()?;
+        let q = self
+            .q_proj
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let q = self.q_norm.forward(&q)?.to_dtype(DType::F32)?;
+        let n_rep = self.num_attention_heads / self.num_key_value_heads;
+        let k = crate::utils::repeat_kv(k.clone(), n_rep)?.to_dtype(DType::F32)?;
+        let v = crate::utils::repeat_kv(v.clone(), n_rep)?.to_dtype(DType::F32)?;
+        let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?.broadcast_add(mask)?;
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        let y = att.matmul(&v)?.to_dtype(x.dtype())?.transpose(1, 2)?.reshape((b_sz, seq_len, hidden_size))?;
+        self.o_proj.forward(&y)
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let size_q = head_dim * cfg.num_attention_heads;
+        let size_kv = head_dim * cfg.num_key_value_heads;
+        Ok(Self {
+            q_proj: linear_no_bias(cfg.hidden_size, size_q, vb.pp("q_proj"))?,
+            k_proj: linear_no_bias(cfg.hidden_size, size_kv, vb.pp("k_proj"))?,
+            v_proj: linear_no_bias(cfg.hidden_size, size_kv, vb.pp("v_proj"))?,
+            o_proj: linear_no_bias(size_q, cfg.hidden_size, vb.pp("o_proj"))?,
+            q_norm: candle_nn::rms_norm(head_dim, cfg.rms_norm_eps, vb.pp("q_norm"))?,
+            k_norm: candle_nn::rms_norm(head_dim, cfg.rms_norm_eps, vb.pp("k_norm"))?,
+            num_attention_heads: cfg.num_attention_heads,
+            num_key_value_heads: cfg.num_key_value_heads,
+            head_dim,
+        })
+    }
+}
+
+/// Layer which attends the images instead of the text, both its attention and mlp are gated
+#[derive(Debug, Clone)]
+struct CrossBlock {
+    rms_1: RmsNorm,
+    attn: CrossAttention,
+    attn_gate: Tensor,
+    rms_2: RmsNorm,
+    mlp: Mlp,
+    mlp_gate: Tensor,
+}
+
+impl CrossBlock {
+    /// `rows` drops the mlp output of the tokens which attend no image
+    fn forward(&self, x: &Tensor, kv: &(Tensor, Tensor), mask: &Tensor, rows: &Tensor) -> Result<Tensor> {
+        let h = self.attn.forward(&self.rms_1.forward(x)?, kv, mask)?;
+        let x = (x + h.broadcast_mul(&self.attn_gate)?)?;
+        let h = self.mlp.forward(&self.rms_2.forward(&x)?)?.broadcast_mul(rows)?.broadcast_mul(&self.mlp_gate)?;
+        x + h
+    }
+
+    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+        Ok(Self {
+            rms_1: candle_nn::rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?,
+            attn: CrossAttention::load(vb.pp("cross_attn"), cfg)?,
+            attn_gate: vb.get(1, "cross_attn_attn_gate")?.tanh()?,
+            rms_2: candle_nn::rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("post_attention_layernorm"))?,
+            mlp: Mlp::load(vb.pp("mlp"), cfg)?,
+            mlp_gate: vb.get(1, "cross_attn_mlp_gate")?.tanh()?,
+        })
+    }
+}
+
+/// Additive mask of the tokens at `positions` over the keys of the images they attend, with 1 for the tokens which attend an image and 0 for the others.
+/// The tokens which attend no image attend all the keys and their mlp output is dropped, like transformers
+pub fn cross_attention_mask(images: &[ImageSpan], num_keys: usize, positions: Range<u32>, device: &Device) -> Result<(Tensor, Tensor)> {
+    let seq_len = positions.len();
+    let mut mask = vec![f32::NEG_INFINITY; seq_len * num_keys];
+    let mut rows = vec![0f32; seq_len];
+    for (i, pos) in positions.enumerate() {
+        let row = &mut mask[i * num_keys..(i + 1) * num_keys];
+        for image in images.iter().filter(|image| image.attended_by(pos)) {
+            if image.keys.end as usize > num_keys {
+                bail!("image keys {:?} out of the {num_keys} keys", image.keys)
+            }
+            row[image.keys.start as usize..image.keys.end as usize].fill(0.0);
+            rows[i] = 1.0;
+        }
+        if rows[i] == 0.0 {
+            row.fill(0.0);
+        }
+    }
+    Ok((Tensor::from_vec(mask, (seq_len, num_keys), device)?, Tensor::from_vec(rows, (1, seq_len, 1), device)?))
+}
+
+#[derive(Debug, Clone)]
+enum Layer {
+    SelfAttention(Block),
+    CrossAttention(CrossBlock),
+}
+
+#[derive(Debug, Clone)]
+pub struct MllamaLayers {
+    layers: Vec<Layer>,
+}
+
+impl MllamaLayers {
+    /// Only the layers in `range` are loaded, the self-attention ones are the blocks of llama
+    pub fn load(vb: VarBuilder, cfg: &MllamaTextConfig, llama_cfg: &Config, range: Range<u32>) -> Result<Self> {
+        if range.end > llama_cfg.num_hidden_layers as u32 {
+            bail!("layers {range:?} out of the {} layers of the model", llama_cfg.num_hidden_layers)
+        }
+        let layers = range
+            .map(|i| {
+                let vb = vb.pp(format!("language_model.model.layers.{i}"));
+                Ok(match cfg.cross_attention_layers.contains(&i) {
+                    true => Layer::CrossAttention(CrossBlock::load(vb, llama_cfg)?),
+                    false => Layer::SelfAttention(Block::load(vb, llama_cfg)?),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { layers })
+    }
+
+    /// Keys and values of the images for the local cross-attention layers
+    pub fn session_images(&self, states: &CrossAttentionStates, dtype: DType, device: &Device) -> Result<SessionImages> {
+        let xs = states.states.to_device(device)?.to_dtype(dtype)?;
+        let kvs = self
+            .layers
+            .iter()
+            .filter_map(|layer| match layer {
+                Layer::CrossAttention(block) => Some(block.attn.kv(&xs)),
+                Layer::SelfAttention(_) => None,
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(SessionImages {
+            images: states.images.clone(),
+            num_keys: xs.dim(0)?,
+            kvs,
+        })
+    }
+
+    /// Forward several sessions together, row `i` of `x` is at `index_pos[i]` with `caches[i]`.
+    /// The sessions with images have them with the position of the row in the session, the others skip the cross-attention layers
+    pub fn forward_batch(&self, mut x: Tensor, index_pos: &[usize], caches: &mut [&mut Cache], images: &[Option<(&SessionImages, u32)>]) -> Result<Tensor> {
+        if x.dim(0)? != caches.len() || index_pos.len() != caches.len() || images.len() != caches.len() {
+            bail!("batch of {} rows with {} positions, {} caches and {} images", x.dim(0)?, index_pos.len(), caches.len(), images.len())
+        }
+        let seq_len = x.dim(1)? as u32;
+        // the masks are the same for all the cross-attention layers
+        let masks = images
+            .iter()
+            .map(|images| {
+                images
+                    .map(|(images, pos)| {
+                        let (mask, rows) = cross_attention_mask(&images.images, images.num_keys, pos..pos + seq_len, x.device())?;
+                        Ok((images, mask, rows.to_dtype(x.dtype())?))
+                    })
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let with_images = masks.iter().any(|mask| mask.is_some());
+
+        let mut cross_idx = 0;
+        for (block_idx, layer) in self.layers.iter().enumerate() {
+            match layer {
+                Layer::SelfAttention(block) => x = block.forward(&x, index_pos, block_idx, caches)?,
+                Layer::CrossAttention(block) if with_images => {
+                    let rows = masks
+                        .iter()
+                        .enumerate()
+                        .map(|(i, mask)| {
+                            let row = x.narrow(0, i, 1)?;
+                            match mask {
+                                Some((images, mask, rows)) => block.forward(&row, &images.kvs[cross_idx], mask, rows),
+                                None => Ok(row),
+                            }
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    x = Tensor::cat(&rows, 0)?;
+                    cross_idx += 1;
+                }
+                Layer::CrossAttention(_) => cross_idx += 1,
+            }
+        }
+        Ok(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::{VarBuilder, VarMap};
+
+    use super::{cross_attention_mask, CrossBlock, ImageSpan};
+    use crate::llama::internal::Config;
+
+    fn config() -> Config {
+        Config {
+            hidden_size: 16,
+            intermediate_size: 24,
+            vocab_size: 32,
+            num_hidden_layers: 2,
+            num_attention_heads: 2,
+            num_key_value_heads: 1,
+            use_flash_attn: false,
+            rms_norm_eps: 1e-5,
+            rope_theta: 10000.,
+            bos_token_id: None,
+            eos_token_id: None,
+            rope_scaling: None,
+            max_position_embeddings: 64,
+            tie_word_embeddings: false,
+            attention_bias: false,
+            rope_interleaved: false,
+            rope_freq_factors: None,
+        }
+    }
+
+    #[test]
+    fn mask_of_image_spans() {
+        let images = [
+            ImageSpan {
+                token_from: 1,
+                token_to: Some(3),
+                keys: 0..2,
+            },
+            ImageSpan {
+                token_from: 3,
+                token_to: None,
+                keys: 4..6,
+            },
+        ];
+        let (mask, rows) = cross_attention_mask(&images, 8, 0..5, &Device::Cpu).unwrap();
+        let inf = f32::NEG_INFINITY;
+        assert_eq!(
+            mask.to_vec2::<f32>().unwrap(),
+            vec![
+                vec![0.0; 8],
+                vec![0.0, 0.0, inf, inf, inf, inf, inf, inf],
+                vec![0.0, 0.0, inf, inf, inf, inf, inf, inf],
+                vec![inf, inf, inf, inf, 0.0, 0.0, inf, inf],
+                vec![inf, inf, inf, inf, 0.0, 0.0, inf, inf],
+            ]
+        );
+        assert_eq!(rows.flatten_all().unwrap().to_vec1::<f32>().unwrap(), vec![0.0, 1.0, 1.0, 1.0, 1.0]);
+
+        // generated tokens keep attending the last image
+        let (_, rows) = cross_attention_mask(&images, 8, 100..101, &Device::Cpu).unwrap();
+        assert_eq!(rows.flatten_all().unwrap().to_vec1::<f32>().unwrap(), vec![1.0]);
+    }
+
+    #[test]
+    fn cross_block_attends_the_image_of_each_token() {
+        let device = Device::Cpu;
+        let cfg = config();
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        // the gates of a new varmap are zeros, the block is loaded again once its vars are random
+        CrossBlock::load(vb.clone(), &cfg).unwrap();
+        for var in varmap.all_vars() {
+            var.set(&Tensor::randn(0f32, 0.5, var.shape(), &device).unwrap()).unwrap();
+        }
+        let block = CrossBlock::load(vb, &cfg).unwrap();
+
+        let images = [
+            ImageSpan {
+                token_from: 2,
+                token_to: Some(4),
+                keys: 0..3,
+            },
+            ImageSpan {
+                token_from: 4,
+                token_to: None,
+                keys: 3..6,
+            },
+        ];
+        let states = Tensor::randn(0f32, 1., (6, 16), &device).unwrap();
+        let kv = block.attn.kv(&states).unwrap();
+        assert_eq!((kv.0.dims(), kv.1.dims()), (&[1, 1, 6, 8][..], &[1, 1, 6, 8][..]));
+        let x = Tensor::randn(0f32, 1., (1, 6, 16), &device).unwrap();
+        let (mask, rows) = cross_attention_mask(&images, 6, 0..6, &device).unwrap();
+        let ys = block.forward(&x, &kv, &mask, &rows).unwrap();
+        assert_eq!(ys.dims(), &[1, 6, 16]);
+
+        // other states of the second image only change the tokens attending it, and the ones before the first image which attend all the keys
+        let other = Tensor::cat(&[states.narrow(0, 0, 3).unwrap(), Tensor::randn(0f32, 1., (3, 16), &device).unwrap()], 0).unwrap();
+        let other_ys = block.forward(&x, &block.attn.kv(&other).unwrap(), &mask, &rows).unwrap();
+        let diffs = (ys - other_ys).unwrap().squeeze(0).unwrap().abs().unwrap().max(1).unwrap().to_vec1::<f32>().unwrap();
+        assert!(diffs[2] < 1e-6 && diffs[3] < 1e-6, "{diffs:?}");
+        assert!(diffs[4] > 1e-4 && diffs[5] > 1e-4, "{diffs:?}");
+    }
+}
@@ -0,0 +1,139 @@
+use std::{ops::Range, sync::Arc};
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+use protocol::llm::PrefixKey;
+
+use crate::{
+    kv_cache::{KvQuantization, SessionCache, SessionCaches},
+    llama::internal::{Cache, Config},
+    ChatCfg, CrossAttentionStates, KvBudgetConfig, KvUsage, ModelLayersWorker, Session,
+};
+
+use super::{
+    internal::{MllamaLayers, SessionImages},
+    MllamaTextConfig,
+};
+
+/// Kv caches of a session with its images, a fork attends the same images
+#[derive(Debug, Clone)]
+struct MllamaCache {
+    cache: Cache,
+    /// keys and values of the images for the local cross-attention layers, None for a session without images
+    images: Option<Arc<SessionImages>>,
+}
+
+impl SessionCache for MllamaCache {
+    fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    fn restore(&mut self, prefix: &Self, len: usize) -> Result<()> {
+        self.cache.restore(&prefix.cache, len)
+    }
+
+    fn rollback(&mut self, count: usize) -> Result<()> {
+        self.cache.rollback(count)
+    }
+
+    fn make_room(&mut self, index_pos: usize, seq_len: usize) -> Result<usize> {
+        self.cache.make_room(index_pos, seq_len)
+    }
+
+    fn window(&self) -> usize {
+        self.cache.window()
+    }
+}
+
+pub struct MllamaLayersWorker {
+    caches: SessionCaches<MllamaCache>,
+    kv_quantization: KvQuantization,
+    layers: MllamaLayers,
+    cross_attention_layers: Vec<u32>,
+    cfg: Config,
+    dtype: DType,
+    device: Device,
+}
+
+impl MllamaLayersWorker {
+    pub fn new(range: Range<u32>, vb: VarBuilder, cfg: MllamaTextConfig, dtype: DType, device: Device, kv_budget: KvBudgetConfig) -> Result<Self> {
+        let llama_cfg = cfg.llama.clone().into_config(false);
+        let layers = MllamaLayers::load(vb, &cfg, &llama_cfg, range.clone())?;
+        // the cross-attention layers have no kv cache
+        let head_dim = llama_cfg.hidden_size / llama_cfg.num_attention_heads;
+        let self_layers = range.filter(|i| !cfg.cross_attention_layers.contains(i)).count();
+        let block_bytes = self_layers * kv_budget.quantization.block_bytes(llama_cfg.num_key_value_heads, head_dim, dtype);
+        Ok(Self {
+            kv_quantization: kv_budget.quantization,
+            caches: SessionCaches::new(kv_budget, block_bytes as u64),
+            layers,
+            cross_attention_layers: cfg.cross_attention_layers,
+            cfg: llama_cfg,
+            dtype,
+            device,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl ModelLayersWorker<(Tensor, u32)> for MllamaLayersWorker {
+    async fn start(&self, session: Session, cfg: ChatCfg) -> Result<()> {
+        let cache = Cache::new(true, self.kv_quantization, cfg.overflow, self.dtype, &self.cfg, &self.device)?;
+        self.caches.start(session, MllamaCache { cache, images: None })
+    }
+
+    async fn start_with_prefix(&self, session: Session, cfg: ChatCfg, prefixes: Vec<PrefixKey>) -> Result<u32> {
+        self.start(session, cfg).await?;
+        Ok(self.caches.register_prefixes(session, prefixes))
+    }
+
+    async fn forward(&self, session: Session, step: u32, xs: (Tensor, u32), index_pos: u32) -> Result<(Tensor, u32)> {
+        let mut res = self.forward_batch(vec![(session, step, xs, index_pos)]).await;
+        res.pop().expect("Should have the result of the session")
+    }
+
+    async fn forward_batch(&self, batch: Vec<(Session, u32, (Tensor, u32), u32)>) -> Vec<Result<(Tensor, u32)>> {
+        self.caches.forward_batch(batch, |xs, index_pos, positions, caches| {
+            let images = caches.iter().map(|cache| cache.images.clone()).collect::<Vec<_>>();
+            let mut caches = caches.iter_mut().map(|cache| &mut cache.cache).collect::<Vec<_>>();
+            // the images are attended by the positions of the tokens in the session, even after a shift of the context window
+            let images = images
+                .iter()
+                .zip(index_pos)
+                .map(|(images, pos)| images.as_deref().map(|images| (images, *pos as u32)))
+                .collect::<Vec<_>>();
+            self.layers.forward_batch(xs, positions, &mut caches, &images)
+        })
+    }
+
+    async fn rollback(&self, session: Session, count: u32) -> Result<()> {
+        self.caches.rollback(session, count)
+    }
+
+    async fn fork(&self, session: Session, forked: Session) -> Result<()> {
+        self.caches.fork(session, forked)
+    }
+
+    async fn finish(&self, session: Session) {
+        self.caches.finish(session);
+    }
+
+    fn kv_usage(&self) -> KvUsage {
+        self.caches.usage()
+    }
+
+    async fn set_cross_attention(&self, session: Session, states: CrossAttentionStates) -> Result<()> {
+        let cache = self.caches.cache(session)?;
+        let images = self.layers.session_images(&states, self.dtype, &self.device)?;
+        cache.lock().images = Some(Arc::new(images));
+        Ok(())
+    }
+
+    fn cross_attention_layers(&self) -> &[u32] {
+        &self.cross_attention_layers
+    }
+}
@@ -0,0 +1,154 @@
+//! Llama 3.2 vision (mllama) is llama with cross-attention layers over the states of the images, so it runs with the llama pre/post.
+//! The gateway encodes the images with the vision model and sends the projected states to the layers workers,
+//! each text token attends the images of its span, from its `<|image|>` token to the next image.
+
+use std::ops::Range;
+
+use candle_core::{DType, Device, Result, Tensor};
+use protocol::llm;
+
+use crate::{
+    llama::{internal::LlamaConfig, ModelResource},
+    KvBudgetConfig,
+};
+
+pub(crate) mod image;
+mod internal;
+mod layers_worker;
+pub(crate) mod vision;
+
+pub use layers_worker::MllamaLayersWorker;
+pub use vision::MllamaVision;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct MllamaTextConfig {
+    #[serde(flatten)]
+    pub llama: LlamaConfig,
+    /// layers which attend the images instead of the text
+    pub cross_attention_layers: Vec<u32>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct MllamaVisionConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub attention_heads: usize,
+    /// layers of the local transformer, over the patches of each tile
+    pub num_hidden_layers: usize,
+    /// gated layers of the global transformer, over the patches of all the tiles
+    pub num_global_layers: usize,
+    /// size of a square tile
+    pub image_size: usize,
+    pub patch_size: usize,
+    pub num_channels: usize,
+    pub max_num_tiles: usize,
+    pub norm_eps: f64,
+    pub vision_output_dim: usize,
+    /// layers of the local transformer whose inputs are added to the output
+    pub intermediate_layers_indices: Vec<usize>,
+    /// arrangements of tiles, as (rows, columns)
+    pub supported_aspect_ratios: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct MllamaConfig {
+    pub text_config: MllamaTextConfig,
+    pub vision_config: MllamaVisionConfig,
+    pub image_token_index: u32,
+}
+
+/// Tokens of a session which attend the keys of an image
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageSpan {
+    pub token_from: u32,
+    /// None when the span lasts until the end of the session, generated tokens included
+    pub token_to: Option<u32>,
+    pub keys: Range<u32>,
+}
+
+impl ImageSpan {
+    pub fn attended_by(&self, pos: u32) -> bool {
+        pos >= self.token_from && self.token_to.is_none_or(|to| pos < to)
+    }
+
+    pub fn from_proto(span: &llm::ImageSpan) -> Self {
+        Self {
+            token_from: span.token_from,
+            token_to: (span.token_to > 0).then_some(span.token_to),
+            keys: span.keys_from..span.keys_to,
+        }
+    }
+
+    pub fn to_proto(&self) -> llm::ImageSpan {
+        llm::ImageSpan {
+            token_from: self.token_from,
+            token_to: self.token_to.unwrap_or(0),
+            keys_from: self.keys.start,
+            keys_to: self.keys.end,
+        }
+    }
+}
+
+/// Projected states of the images of a session, of shape (keys, hidden_size), with the tokens attending each image
+#[derive(Debug, Clone)]
+pub struct CrossAttentionStates {
+    pub states: Tensor,
+    pub images: Vec<ImageSpan>,
+}
+
+/// Spans of the image tokens in `tokens`, each image is attended until the next image token and the last one until the end.
+/// Consecutive image tokens are all attended by the text after them, like the processor of transformers
+pub fn image_token_spans(tokens: &[u32], image_token: u32) -> Vec<(u32, Option<u32>)> {
+    let starts = tokens.iter().enumerate().filter(|(_, t)| **t == image_token).map(|(i, _)| i as u32).collect::<Vec<_>>();
+    let mut spans = starts.iter().enumerate().map(|(i, start)| (*start, starts.get(i + 1).copied())).collect::<Vec<_>>();
+    let mut last_end = None;
+    for (start, end) in spans.iter_mut().rev() {
+        if *end == Some(*start + 1) {
+            *end = last_end;
+        }
+        last_end = *end;
+    }
+    spans
+}
+
+/// Only the text layers in `range` are loaded, the cross-attention ones included
+pub async fn new_layers(resource: &ModelResource, dtype: DType, device: Device, range: Range<u32>, kv_budget: KvBudgetConfig) -> Result<MllamaLayersWorker> {
+    let config = std::fs::read(resource.source.get(&resource.repo, &resource.config).await?)?;
+    let config = serde_json::from_slice::<MllamaConfig>(&config).map_err(candle_core::Error::wrap)?;
+    let vb = resource.load_weights(dtype, &device).await?;
+    MllamaLayersWorker::new(range, vb, config.text_config, dtype, device, kv_budget)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{image_token_spans, ImageSpan, MllamaConfig};
+
+    #[test]
+    fn mllama_config() {
+        // config.json of Llama-3.2-11B-Vision-Instruct, without the fields which are not read
+        let json = r#"{"architectures": ["MllamaForConditionalGeneration"], "image_token_index": 128256, "model_type": "mllama",
+            "text_config": {"bos_token_id": 128000, "cross_attention_layers": [3, 8, 13, 18, 23, 28, 33, 38], "eos_token_id": [128001, 128008, 128009], "hidden_act": "silu", "hidden_size": 4096, "intermediate_size": 14336, "max_position_embeddings": 131072, "model_type": "mllama_text_model", "num_attention_heads": 32, "num_hidden_layers": 40, "num_key_value_heads": 8, "rms_norm_eps": 1e-05, "rope_scaling": {"factor": 8.0, "high_freq_factor": 4.0, "low_freq_factor": 1.0, "original_max_position_embeddings": 8192, "rope_type": "llama3"}, "rope_theta": 500000.0, "tie_word_embeddings": false, "vocab_size": 128256},
+            "vision_config": {"attention_heads": 16, "hidden_act": "gelu", "hidden_size": 1280, "image_size": 560, "intermediate_layers_indices": [3, 7, 15, 23, 30], "intermediate_size": 5120, "max_num_tiles": 4, "model_type": "mllama_vision_model", "norm_eps": 1e-05, "num_channels": 3, "num_global_layers": 8, "num_hidden_layers": 32, "patch_size": 14, "supported_aspect_ratios": [[1, 1], [1, 2], [1, 3], [1, 4], [2, 1], [2, 2], [3, 1], [4, 1]], "vision_output_dim": 7680}}"#;
+        let config = serde_json::from_str::<MllamaConfig>(json).unwrap();
+        assert_eq!(config.image_token_index, 128256);
+        assert_eq!(config.text_config.cross_attention_layers.len(), 8);
+        assert_eq!(config.vision_config.supported_aspect_ratios[4], (2, 1));
+        let llama = config.text_config.llama.into_config(false);
+        assert_eq!((llama.num_hidden_layers, llama.num_key_value_heads), (40, 8));
+    }
+
+    #[test]
+    fn image_spans() {
+        assert_eq!(image_token_spans(&[0, 9, 1, 2, 9, 9, 3], 9), vec![(1, Some(4)), (4, None), (5, None)]);
+        assert_eq!(image_token_spans(&[9, 9, 1, 9, 2], 9), vec![(0, Some(3)), (1, Some(3)), (3, None)]);
+        assert!(image_token_spans(&[0, 1], 9).is_empty());
+
+        let span = ImageSpan {
+            token_from: 4,
+            token_to: None,
+            keys: 6404..12808,
+        };
+        assert!(!span.attended_by(3) && span.attended_by(4) && span.attended_by(1000));
+        assert_eq!(ImageSpan::from_proto(&span.to_proto()), span);
+    }
+}
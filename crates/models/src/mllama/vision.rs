@@ -0,0 +1,319 @@
+//! Vision model of mllama: the patches of the tiles of an image go through a local transformer then a gated global one,
+//! the output with the states of some intermediate layers is projected into the hidden size of the text model.
+
+use std::{ops::Range, sync::Arc};
+
+use candle_core::{DType, Device, Error, Module, Result, Tensor, D};
+use candle_nn::{conv2d_no_bias, embedding, layer_norm, linear, linear_no_bias, Conv2d, Conv2dConfig, Embedding, LayerNorm, Linear, VarBuilder};
+
+use super::{
+    image::{preprocess, ImageTiles},
+    MllamaConfig, MllamaVisionConfig,
+};
+
+#[derive(Debug, Clone)]
+struct VisionAttention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    num_heads: usize,
+    head_dim: usize,
+}
+
+impl VisionAttention {
+    /// The heads are attended one by one, the patches of all the tiles make a long sequence
+    fn forward(&self, x: &Tensor, mask: &Tensor) -> Result<Tensor> {
+        let (b_sz, seq_len, hidden_size) = x.dims3()?;
+        let heads = |proj: &Linear| proj.forward(x)?.reshape((b_sz, seq_len, self.num_heads, self.head_dim))?.transpose(1, 2)?.to_dtype(DType::F32);
+        let (q, k, v) = (heads(&self.q_proj)?, heads(&self.k_proj)?, heads(&self.v_proj)?);
+        let mut ys = Vec::with_capacity(self.num_heads);
+        for h in 0..self.num_heads {
+            let q = q.narrow(1, h, 1)?.contiguous()?;
+            let k = k.narrow(1, h, 1)?.contiguous()?;
+            let v = v.narrow(1, h, 1)?.contiguous()?;
+            let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?.broadcast_add(mask)?;
+            let att = candle_nn::ops::softmax_last_dim(&att)?;
+            ys.push(att.matmul(&v)?);
+        }
+        let y = Tensor::cat(&ys, 1)?.to_dtype(x.dtype())?.transpose(1, 2)?.reshape((b_sz, seq_len, hidden_size))?;
+        self.o_proj.forward(&y)
+    }
+
+    fn load(vb: VarBuilder, cfg: &MllamaVisionConfig) -> Result<Self> {
+        let size = cfg.hidden_size;
+        Ok(Self {
+            q_proj: linear_no_bias(size, size, vb.pp("q_proj"))?,
+            k_proj: linear_no_bias(size, size, vb.pp("k_proj"))?,
+            v_proj: linear_no_bias(size, size, vb.pp("v_proj"))?,
+            o_proj: linear_no_bias(size, size, vb.pp("o_proj"))?,
+            num_heads: cfg.attention_heads,
+            head_dim: size / cfg.attention_heads,
+        })
+    }
+}
+
+/// Layer of the vision transformers, the layers of the global one gate their attention and mlp
+#[derive(Debug, Clone)]
+struct VisionLayer {
+    input_layernorm: LayerNorm,
+    attn: VisionAttention,
+    post_attention_layernorm: LayerNorm,
+    fc1: Linear,
+    fc2: Linear,
+    /// tanh of the attention and mlp gates
+    gates: Option<(Tensor, Tensor)>,
+}
+
+impl VisionLayer {
+    fn forward(&self, x: &Tensor, mask: &Tensor) -> Result<Tensor> {
+        let h = self.attn.forward(&self.input_layernorm.forward(x)?, mask)?;
+        let h = match &self.gates {
+            Some((gate_attn, _)) => h.broadcast_mul(gate_attn)?,
+            None => h,
+        };
+        let x = (x + h)?;
+        let h = self.fc2.forward(&self.fc1.forward(&self.post_attention_layernorm.forward(&x)?)?.gelu_erf()?)?;
+        let h = match &self.gates {
+            Some((_, gate_ffn)) => h.broadcast_mul(gate_ffn)?,
+            None => h,
+        };
+        x + h
+    }
+
+    fn load(vb: VarBuilder, cfg: &MllamaVisionConfig, gated: bool) -> Result<Self> {
+        let gates = match gated {
+            true => Some((vb.get(1, "gate_attn")?.tanh()?, vb.get(1, "gate_ffn")?.tanh()?)),
+            false => None,
+        };
+        Ok(Self {
+            input_layernorm: layer_norm(cfg.hidden_size, cfg.norm_eps, vb.pp("input_layernorm"))?,
+            attn: VisionAttention::load(vb.pp("self_attn"), cfg)?,
+            post_attention_layernorm: layer_norm(cfg.hidden_size, cfg.norm_eps, vb.pp("post_attention_layernorm"))?,
+            fc1: linear(cfg.hidden_size, cfg.intermediate_size, vb.pp("mlp.fc1"))?,
+            fc2: linear(cfg.intermediate_size, cfg.hidden_size, vb.pp("mlp.fc2"))?,
+            gates,
+        })
+    }
+}
+
+/// Gated embedding of each tile by the arrangement of the tiles
+#[derive(Debug, Clone)]
+struct TileEmbedding {
+    embedding: Embedding,
+    gate: Tensor,
+}
+
+impl TileEmbedding {
+    /// `x` is of shape (max_num_tiles, patches, hidden_size)
+    fn forward(&self, x: &Tensor, aspect_ratio_id: &Tensor) -> Result<Tensor> {
+        let (tiles, _, hidden_size) = x.dims3()?;
+        let embedding = self.embedding.forward(aspect_ratio_id)?.reshape((tiles, 1, hidden_size))?;
+        x.broadcast_add(&embedding.broadcast_mul(&self.gate)?)
+    }
+
+    fn load(vb: VarBuilder, cfg: &MllamaVisionConfig) -> Result<Self> {
+        Ok(Self {
+            embedding: embedding(cfg.supported_aspect_ratios.len() + 1, cfg.max_num_tiles * cfg.hidden_size, vb.pp("embedding"))?,
+            gate: vb.get(1, "gate")?.tanh()?,
+        })
+    }
+}
+
+/// Embedding of the patch positions, gated with an embedding of each patch of each tile by the arrangement of the tiles
+#[derive(Debug, Clone)]
+struct PositionEmbedding {
+    embedding: Tensor,
+    tile_embedding: Embedding,
+    gate: Tensor,
+}
+
+impl PositionEmbedding {
+    fn forward(&self, x: &Tensor, aspect_ratio_id: &Tensor) -> Result<Tensor> {
+        let x = x.broadcast_add(&self.embedding.broadcast_mul(&(1.0 - &self.gate)?)?)?;
+        let tile_embedding = self.tile_embedding.forward(aspect_ratio_id)?.reshape(x.shape())?;
+        x + tile_embedding.broadcast_mul(&self.gate)?
+    }
+
+    fn load(vb: VarBuilder, cfg: &MllamaVisionConfig, num_patches: usize) -> Result<Self> {
+        Ok(Self {
+            embedding: vb.get((num_patches, cfg.hidden_size), "embedding")?,
+            tile_embedding: embedding(cfg.supported_aspect_ratios.len() + 1, cfg.max_num_tiles * num_patches * cfg.hidden_size, vb.pp("tile_embedding"))?,
+            gate: vb.get(1, "gate")?.tanh()?,
+        })
+    }
+}
+
+/// Vision model with its projector, kept by the gateway which encodes the images of the prompts
+#[derive(Debug, Clone)]
+pub struct MllamaVision {
+    patch_embedding: Conv2d,
+    class_embedding: Tensor,
+    pre_tile_embedding: TileEmbedding,
+    position_embedding: PositionEmbedding,
+    post_tile_embedding: TileEmbedding,
+    layernorm_pre: LayerNorm,
+    layernorm_post: LayerNorm,
+    transformer: Vec<VisionLayer>,
+    global_transformer: Vec<VisionLayer>,
+    projector: Linear,
+    cfg: MllamaVisionConfig,
+    dtype: DType,
+    device: Device,
+}
+
+impl MllamaVision {
+    /// Patches of a tile, with the class embedding
+    fn num_patches(&self) -> usize {
+        (self.cfg.image_size / self.cfg.patch_size).pow(2) + 1
+    }
+
+    /// Cross-attention states of the images in the order of the prompt, of shape (keys, text hidden size),
+    /// with the keys of the valid tiles of each image. Every image has the keys of the max number of tiles
+    pub fn encode(&self, images: &[Arc<Vec<u8>>]) -> Result<(Tensor, Vec<Range<u32>>)> {
+        let keys_per_image = self.cfg.max_num_tiles * self.num_patches();
+        let mut states = Vec::with_capacity(images.len());
+        let mut keys = Vec::with_capacity(images.len());
+        for (i, image) in images.iter().enumerate() {
+            let image = image::load_from_memory(image).map_err(Error::wrap)?;
+            let tiles = preprocess(&image, &self.cfg, &self.device)?;
+            let from = (i * keys_per_image) as u32;
+            keys.push(from..from + (tiles.num_tiles * self.num_patches()) as u32);
+            let x = self.projector.forward(&self.forward(&tiles)?)?;
+            states.push(x.reshape((keys_per_image, ()))?);
+        }
+        Tensor::cat(&states, 0).map(|states| (states, keys))
+    }
+
+    /// States of the patches of all the tiles, of shape (max_num_tiles, patches, vision_output_dim)
+    fn forward(&self, tiles: &ImageTiles) -> Result<Tensor> {
+        let hidden_size = self.cfg.hidden_size;
+        let max_tiles = self.cfg.max_num_tiles;
+        let num_patches = self.num_patches();
+        let aspect_ratio_id = Tensor::new(&[tiles.aspect_ratio_id], &self.device)?;
+
+        let x = self.patch_embedding.forward(&tiles.pixels.to_dtype(self.dtype)?)?.flatten_from(2)?.transpose(1, 2)?;
+        let x = self.pre_tile_embedding.forward(&x, &aspect_ratio_id)?;
+        let class_embedding = self.class_embedding.reshape((1, 1, hidden_size))?.broadcast_as((max_tiles, 1, hidden_size))?;
+        let x = Tensor::cat(&[&class_embedding, &x], 1)?;
+        let x = self.position_embedding.forward(&x, &aspect_ratio_id)?;
+        let x = self.layernorm_pre.forward(&x)?;
+
+        // the patches of each tile are padded to a multiple of 8
+        let padded_patches = num_patches.next_multiple_of(8);
+        let mask = attention_mask(max_tiles, tiles.num_tiles, num_patches, padded_patches, &self.device)?;
+        let mut x = x.pad_with_zeros(1, 0, padded_patches - num_patches)?.reshape((1, max_tiles * padded_patches, hidden_size))?;
+        let mut intermediates = vec![];
+        for (i, layer) in self.transformer.iter().enumerate() {
+            if self.cfg.intermediate_layers_indices.contains(&i) {
+                intermediates.push(x.clone());
+            }
+            x = layer.forward(&x, &mask)?;
+        }
+
+        let x = self.layernorm_post.forward(&x)?.reshape((max_tiles, padded_patches, hidden_size))?;
+        let mut x = self.post_tile_embedding.forward(&x, &aspect_ratio_id)?.reshape((1, max_tiles * padded_patches, hidden_size))?;
+        for layer in self.global_transformer.iter() {
+            x = layer.forward(&x, &mask)?;
+        }
+
+        let x = x.reshape((max_tiles, padded_patches, hidden_size))?.narrow(1, 0, num_patches)?;
+        let intermediates = Tensor::stack(&intermediates, D::Minus1)?
+            .reshape((max_tiles, padded_patches, hidden_size * intermediates.len()))?
+            .narrow(1, 0, num_patches)?;
+        Tensor::cat(&[&x, &intermediates], D::Minus1)
+    }
+
+    pub fn load(vb: VarBuilder, cfg: &MllamaConfig, dtype: DType, device: &Device) -> Result<Self> {
+        let vision = &cfg.vision_config;
+        let num_patches = (vision.image_size / vision.patch_size).pow(2) + 1;
+        let vb_m = vb.pp("vision_model");
+        let conv_cfg = Conv2dConfig {
+            stride: vision.patch_size,
+            ..Default::default()
+        };
+        let transformer = (0..vision.num_hidden_layers)
+            .map(|i| VisionLayer::load(vb_m.pp(format!("transformer.layers.{i}")), vision, false))
+            .collect::<Result<Vec<_>>>()?;
+        let global_transformer = (0..vision.num_global_layers)
+            .map(|i| VisionLayer::load(vb_m.pp(format!("global_transformer.layers.{i}")), vision, true))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            patch_embedding: conv2d_no_bias(vision.num_channels, vision.hidden_size, vision.patch_size, conv_cfg, vb_m.pp("patch_embedding"))?,
+            class_embedding: vb_m.get(vision.hidden_size, "class_embedding")?,
+            pre_tile_embedding: TileEmbedding::load(vb_m.pp("pre_tile_positional_embedding"), vision)?,
+            position_embedding: PositionEmbedding::load(vb_m.pp("gated_positional_embedding"), vision, num_patches)?,
+            post_tile_embedding: TileEmbedding::load(vb_m.pp("post_tile_positional_embedding"), vision)?,
+            layernorm_pre: layer_norm(vision.hidden_size, 1e-5, vb_m.pp("layernorm_pre"))?,
+            layernorm_post: layer_norm(vision.hidden_size, 1e-5, vb_m.pp("layernorm_post"))?,
+            transformer,
+            global_transformer,
+            projector: linear(vision.vision_output_dim, cfg.text_config.llama.hidden_size, vb.pp("multi_modal_projector"))?,
+            cfg: vision.clone(),
+            dtype,
+            device: device.clone(),
+        })
+    }
+}
+
+/// Additive mask of the patches of the tiles, the padding patches and the patches of the padding tiles don't attend each other
+fn attention_mask(max_tiles: usize, num_tiles: usize, num_patches: usize, padded_patches: usize, device: &Device) -> Result<Tensor> {
+    let padding = (0..max_tiles * padded_patches)
+        .map(|i| {
+            if i / padded_patches >= num_tiles || i % padded_patches >= num_patches {
+                1f32
+            } else {
+                0f32
+            }
+        })
+        .collect::<Vec<_>>();
+    let padding = Tensor::from_vec(padding, (max_tiles * padded_patches, 1), device)?;
+    padding.matmul(&padding.t()?)? * f32::MIN as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::Arc};
+
+    use candle_core::{DType, Device};
+    use candle_nn::{VarBuilder, VarMap};
+    use image::{ImageFormat, RgbImage};
+
+    use super::MllamaVision;
+    use crate::mllama::MllamaConfig;
+
+    fn png(width: u32, height: u32) -> Arc<Vec<u8>> {
+        let image = RgbImage::from_fn(width, height, |x, y| image::Rgb([(x * 16) as u8, (y * 16) as u8, 128]));
+        let mut bytes = Cursor::new(vec![]);
+        image.write_to(&mut bytes, ImageFormat::Png).unwrap();
+        Arc::new(bytes.into_inner())
+    }
+
+    #[test]
+    fn encode_images_into_their_keys() {
+        // tiles of 2x2 patches, the outputs of the layer 0 are added to the projected ones
+        let cfg = serde_json::from_str::<MllamaConfig>(
+            r#"{"image_token_index": 31,
+                "text_config": {"hidden_size": 16, "intermediate_size": 24, "vocab_size": 32, "num_hidden_layers": 2, "num_attention_heads": 2, "num_key_value_heads": 1,
+                    "rms_norm_eps": 1e-5, "max_position_embeddings": 64, "cross_attention_layers": [1]},
+                "vision_config": {"hidden_size": 8, "intermediate_size": 16, "attention_heads": 2, "num_hidden_layers": 2, "num_global_layers": 1, "image_size": 8,
+                    "patch_size": 4, "num_channels": 3, "max_num_tiles": 2, "norm_eps": 1e-5, "vision_output_dim": 16, "intermediate_layers_indices": [0],
+                    "supported_aspect_ratios": [[1, 1], [1, 2], [2, 1]]}}"#,
+        )
+        .unwrap();
+        let varmap = VarMap::new();
+        let vision = MllamaVision::load(VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu), &cfg, DType::F32, &Device::Cpu).unwrap();
+
+        // every image has the keys of 2 tiles of 5 patches, the wide one fills them and the square one only 1 tile
+        let (wide, square) = (png(16, 8), png(8, 8));
+        let (states, keys) = vision.encode(&[wide.clone(), square.clone()]).unwrap();
+        assert_eq!(states.dims(), &[20, 16]);
+        assert_eq!(keys, vec![0..10, 10..15]);
+
+        // the images are encoded on their own
+        let (alone, keys) = vision.encode(&[square]).unwrap();
+        assert_eq!(keys, vec![0..5]);
+        let diff = (states.narrow(0, 10, 10).unwrap() - alone).unwrap().abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+        assert!(diff < 1e-5);
+    }
+}
@@ -0,0 +1,117 @@
+use std::io::{Read, Seek};
+
+use candle_core::{bail, quantized::gguf_file, Device, Result};
+
+/// Hyper parameters of a Phi-3 family model (Phi-3, Phi-3.5, the medium and 128k variants), all read from the GGUF metadata
+#[derive(Debug, Clone)]
+pub struct Phi3Config {
+    pub block_count: usize,
+    pub context_length: usize,
+    pub embedding_length: usize,
+    pub feed_forward_length: usize,
+    pub head_count: usize,
+    pub head_count_kv: usize,
+    pub rms_eps: f64,
+    pub rope_dim: usize,
+    pub rope_freq_base: f32,
+    pub rope_scaling: Option<RopeScaling>,
+}
+
+/// LongRoPE of the long context variants
+#[derive(Debug, Clone, PartialEq)]
+pub struct RopeScaling {
+    /// divisors of the rope frequencies, one per pair of rotated dims
+    pub factors: Vec<f32>,
+    /// scale of the rotated queries and keys
+    pub attn_factor: f32,
+}
+
+impl Phi3Config {
+    pub fn from_gguf<R: Read + Seek>(ct: &gguf_file::Content, reader: &mut R, device: &Device) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+        if let Some(arch) = ct.metadata.get("general.architecture") {
+            let arch = arch.to_string()?;
+            if arch != "phi3" {
+                bail!("gguf of a {arch} model is not a phi3 model")
+            }
+        }
+
+        let head_count = md_get("phi3.attention.head_count")?.to_u32()? as usize;
+        let embedding_length = md_get("phi3.embedding_length")?.to_u32()? as usize;
+        let context_length = md_get("phi3.context_length")?.to_u32()? as usize;
+        let rope_dim = match ct.metadata.get("phi3.rope.dimension_count") {
+            Some(v) => v.to_u32()? as usize,
+            None => embedding_length / head_count,
+        };
+        let rope_freq_base = match ct.metadata.get("phi3.rope.freq_base") {
+            Some(v) => v.to_f32()?,
+            None => 10_000.,
+        };
+
+        // the long factors are for a context longer than the one of training, as the context window is the whole context length
+        let original_context_length = match ct.metadata.get("phi3.rope.scaling.original_context_length") {
+            Some(v) => v.to_u32()? as usize,
+            None => context_length,
+        };
+        let factors_name = if context_length > original_context_length {
+            "rope_factors_long.weight"
+        } else {
+            "rope_factors_short.weight"
+        };
+        let rope_scaling = if ct.tensor_infos.contains_key(factors_name) {
+            let factors = ct.tensor(reader, factors_name, device)?.dequantize(device)?.to_vec1::<f32>()?;
+            if factors.len() != rope_dim / 2 {
+                bail!("{factors_name} has {} factors for {rope_dim} rope dims", factors.len())
+            }
+            let attn_factor = match ct.metadata.get("phi3.rope.scaling.attn_factor") {
+                Some(v) => v.to_f32()?,
+                None => longrope_attn_factor(context_length, original_context_length),
+            };
+            Some(RopeScaling { factors, attn_factor })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            block_count: md_get("phi3.block_count")?.to_u32()? as usize,
+            context_length,
+            embedding_length,
+            feed_forward_length: md_get("phi3.feed_forward_length")?.to_u32()? as usize,
+            head_count,
+            head_count_kv: md_get("phi3.attention.head_count_kv")?.to_u32()? as usize,
+            rms_eps: md_get("phi3.attention.layer_norm_rms_epsilon")?.to_f32()? as f64,
+            rope_dim,
+            rope_freq_base,
+            rope_scaling,
+        })
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.embedding_length / self.head_count
+    }
+}
+
+/// Scale of the embeddings of a context extended from `original` to `extended` positions, when the file does not have it
+fn longrope_attn_factor(extended: usize, original: usize) -> f32 {
+    let scale = extended as f32 / original as f32;
+    if scale <= 1. {
+        1.
+    } else {
+        (1. + scale.ln() / (original as f32).ln()).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::longrope_attn_factor;
+
+    #[test]
+    fn longrope_attn_factor_of_128k() {
+        assert_eq!(longrope_attn_factor(4096, 4096), 1.);
+        // the factor of Phi-3-mini-128k, extended from 4k
+        assert!((longrope_attn_factor(131072, 4096) - 1.190_238).abs() < 1e-5);
+    }
+}
@@ -1,5 +1,7 @@
 use candle_core::{DType, Module, Result, Tensor, D};
-use candle_nn::{kv_cache::KvCache, RmsNorm};
+use candle_nn::RmsNorm;
+
+use crate::kv_cache::PagedKvCache;
 
 use super::mlp::Mlp;
 use super::qlinear::QLinear;
@@ -14,6 +16,8 @@ pub struct LayerWeights {
     pub n_head: usize,
     pub n_kv_head: usize,
     pub head_dim: usize,
+    /// LongRoPE scale of the rotated queries and keys, the rope tables stay rotations so that cached keys can be shifted
+    pub rope_scale: f64,
     pub cos: Tensor,
     pub sin: Tensor,
     pub neg_inf: Tensor,
@@ -34,10 +38,16 @@ impl LayerWeights {
         let (_b_sz, _h, seq_len, _n_embd) = xs.dims4()?;
         let cos = self.cos.narrow(0, index_pos, seq_len)?;
         let sin = self.sin.narrow(0, index_pos, seq_len)?;
-        candle_nn::rotary_emb::rope(&xs.contiguous()?, &cos, &sin)
+        let xs = candle_nn::rotary_emb::rope(&xs.contiguous()?, &cos, &sin)?;
+        if self.rope_scale == 1. {
+            Ok(xs)
+        } else {
+            xs * self.rope_scale
+        }
     }
 
-    pub fn forward_attn(&self, x: &Tensor, mask: Option<&Tensor>, index_pos: usize, kv_cache: &mut KvCache) -> Result<Tensor> {
+    /// Each row of `x` is a different session, with its own mask, position and kv cache
+    pub fn forward_attn(&self, x: &Tensor, masks: &[Option<&Tensor>], index_pos: &[usize], kv_caches: &mut [&mut PagedKvCache]) -> Result<Tensor> {
         let _enter = self.span_attn.enter();
         let (b_sz, seq_len, n_embd) = x.dims3()?;
         let qkv = self.attn_qkv.forward(x)?;
@@ -48,11 +58,23 @@ impl LayerWeights {
         let v = qkv.narrow(D::Minus1, query_pos + self.n_kv_head * self.head_dim, self.n_kv_head * self.head_dim)?;
 
         let q = q.reshape((b_sz, seq_len, self.n_head, self.head_dim))?.transpose(1, 2)?;
-        let k = k.reshape((b_sz, seq_len, self.n_head, self.head_dim))?.transpose(1, 2)?;
+        let k = k.reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?.transpose(1, 2)?;
         let v = v.reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?.transpose(1, 2)?;
 
-        let q = self.apply_rotary_emb(&q, index_pos)?.contiguous()?;
-        let k = self.apply_rotary_emb(&k, index_pos)?;
+        let mut ys = Vec::with_capacity(b_sz);
+        for (i, kv_cache) in kv_caches.iter_mut().enumerate() {
+            ys.push(self.attend(&q.narrow(0, i, 1)?, &k.narrow(0, i, 1)?, &v.narrow(0, i, 1)?, masks[i], index_pos[i], kv_cache)?);
+        }
+        let y = Tensor::cat(&ys, 0)?;
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
+        let y = self.attn_output.forward(&y)?;
+        Ok(y)
+    }
+
+    fn attend(&self, q: &Tensor, k: &Tensor, v: &Tensor, mask: Option<&Tensor>, index_pos: usize, kv_cache: &mut PagedKvCache) -> Result<Tensor> {
+        let seq_len = q.dim(2)?;
+        let q = self.apply_rotary_emb(q, index_pos)?.contiguous()?;
+        let k = self.apply_rotary_emb(k, index_pos)?;
 
         let (k, v) = kv_cache.append(&k.contiguous()?, &v.contiguous()?)?;
         // log::info!("[LayerWeights] add tensor to kv_cache => {:?} {:?}", k.shape(), v.shape());
@@ -80,8 +102,6 @@ impl LayerWeights {
             // Convert to contiguous as matmul doesn't support strided vs for now.
             att.matmul(&v)?
         };
-        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
-        let y = self.attn_output.forward(&y)?;
         Ok(y)
     }
 }
@@ -1,3 +1,4 @@
+pub mod config;
 pub mod layer_weights;
 pub mod mlp;
 pub mod qlinear;
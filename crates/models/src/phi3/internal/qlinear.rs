@@ -1,4 +1,7 @@
-use candle_core::{quantized::gguf_file, Device, Result, Tensor};
+use candle_core::{
+    quantized::{gguf_file, QTensor},
+    Device, Result, Tensor,
+};
 use candle_nn::Module;
 
 #[derive(Debug, Clone)]
@@ -9,8 +12,12 @@ pub struct QLinear {
 
 impl QLinear {
     pub fn new<R: std::io::Read + std::io::Seek>(ct: &gguf_file::Content, r: &mut R, name: &str, device: &Device) -> Result<Self> {
-        let span = tracing::span!(tracing::Level::TRACE, "qmatmul");
         let w = ct.tensor(r, &format!("{name}.weight"), device)?;
+        Self::from_qtensor(w)
+    }
+
+    pub fn from_qtensor(w: QTensor) -> Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "qmatmul");
         let inner = candle_core::quantized::QMatMul::from_qtensor(w)?;
         Ok(Self { inner, span })
     }
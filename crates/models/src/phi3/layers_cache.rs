@@ -1,35 +1,68 @@
-use std::sync::Arc;
+use candle_core::{Result, Tensor};
+use protocol::OverflowPolicy;
 
-use candle_nn::kv_cache::KvCache;
-use spin::Mutex;
-use utils::shared_map::SharedHashMap;
+use crate::kv_cache::{overflow_eviction, shift_rope_keys, KvQuantization, PagedKvCache, SessionCache};
 
-use crate::Session;
+use super::internal::layer_weights::LayerWeights;
 
+/// Kv caches of every local layer of a session, the full blocks are shared with the caches it was copied from
+#[derive(Debug, Clone)]
 pub struct LayersCache {
-    layers_cache: Vec<SharedHashMap<Session, Arc<Mutex<KvCache>>>>,
-    dim: usize,
-    max_seq_len: usize,
+    kvs: Vec<PagedKvCache>,
+    overflow: OverflowPolicy,
+    /// context length of the model, the kv caches never hold more
+    window: usize,
+    /// rope tables of the layers to shift the kept keys, None without local layers
+    rope: Option<(Tensor, Tensor)>,
 }
 
 impl LayersCache {
-    pub fn new(len: usize, dim: usize, max_seq_len: usize) -> Self {
-        let mut layers_cache = Vec::with_capacity(len);
-        for _ in 0..len {
-            layers_cache.push(Default::default());
+    pub fn new(layers: &[LayerWeights], quantization: KvQuantization, overflow: OverflowPolicy, window: usize) -> Self {
+        Self {
+            kvs: vec![PagedKvCache::new(2, quantization); layers.len()],
+            overflow,
+            window,
+            rope: layers.first().map(|layer| (layer.cos.clone(), layer.sin.clone())),
         }
-        Self { layers_cache, dim, max_seq_len }
     }
 
-    pub fn add_cache(&self, idx: usize, session: Session) {
-        self.layers_cache[idx].insert(session, Arc::new(Mutex::new(KvCache::new(self.dim, self.max_seq_len))));
+    pub fn layer(&mut self, idx: usize) -> &mut PagedKvCache {
+        &mut self.kvs[idx]
+    }
+}
+
+impl SessionCache for LayersCache {
+    fn len(&self) -> usize {
+        self.kvs.iter().map(|kv| kv.len()).max().unwrap_or(0)
+    }
+
+    fn restore(&mut self, prefix: &Self, len: usize) -> Result<()> {
+        self.kvs = prefix.kvs.iter().map(|kv| kv.truncate(len)).collect::<Result<_>>()?;
+        Ok(())
     }
 
-    pub fn get_cache(&self, idx: usize, session: Session) -> Arc<Mutex<KvCache>> {
-        self.layers_cache[idx].get_clone(&session).unwrap()
+    fn rollback(&mut self, count: usize) -> Result<()> {
+        for kv in self.kvs.iter_mut() {
+            kv.rollback(count)?;
+        }
+        Ok(())
+    }
+
+    fn make_room(&mut self, _index_pos: usize, seq_len: usize) -> Result<usize> {
+        let Some((cos, sin)) = &self.rope else {
+            return Ok(0);
+        };
+        let cached = self.len();
+        let Some(evicted) = overflow_eviction(self.overflow, self.window, cached, seq_len)? else {
+            return Ok(cached);
+        };
+        for kv in self.kvs.iter_mut() {
+            kv.evict(evicted.clone(), |k| shift_rope_keys(k, cos, sin, evicted.len(), false))?;
+        }
+        Ok(cached - evicted.len())
     }
 
-    pub fn del_cache(&self, idx: usize, session: Session) {
-        self.layers_cache[idx].remove(&session);
+    fn window(&self) -> usize {
+        self.window
     }
 }
@@ -1,41 +1,43 @@
 use std::ops::Range;
 
-use candle_core::{quantized::gguf_file, DType, Device, Result, Tensor};
+use candle_core::{bail, quantized::gguf_file, DType, Device, Result, Tensor};
 use candle_nn::Module;
+use protocol::llm::PrefixKey;
 
-use crate::{ChatCfg, ModelLayersWorker, Session};
+use crate::{
+    kv_cache::{KvQuantization, SessionCaches},
+    ChatCfg, KvBudgetConfig, KvUsage, ModelLayersWorker, Session,
+};
 
-use super::internal::{layer_weights::LayerWeights, mlp::Mlp, qlinear::QLinear};
+use super::internal::{config::Phi3Config, layer_weights::LayerWeights, mlp::Mlp, qlinear::QLinear};
 use super::layers_cache::LayersCache;
-use super::{model_path, rms_norm};
+use super::{rms_norm, Phi3Resource};
 
 pub struct Phi3LayersWorker {
     layers: Vec<LayerWeights>,
-    caches: LayersCache,
+    caches: SessionCaches<LayersCache>,
+    kv_quantization: KvQuantization,
+    /// context length of the model, the kv caches never hold more
+    window: usize,
     span: tracing::Span,
 }
 
 impl Phi3LayersWorker {
-    pub async fn new(use_flash_attn: bool, range: Range<u32>, device: &Device) -> Result<Self> {
-        let (layers, max_seq_len) = if !range.is_empty() {
-            let mut reader_f = std::fs::File::open(model_path().await).unwrap();
-            let ct = gguf_file::Content::read(&mut reader_f).unwrap();
+    pub async fn new(resource: &Phi3Resource, use_flash_attn: bool, range: Range<u32>, device: &Device, kv_budget: KvBudgetConfig) -> Result<Self> {
+        let (layers, window) = if !range.is_empty() {
+            let mut reader_f = std::fs::File::open(resource.model_path().await?)?;
+            let ct = gguf_file::Content::read(&mut reader_f)?;
             let reader = &mut reader_f;
+            let cfg = Phi3Config::from_gguf(&ct, reader, device)?;
+            if range.end as usize > cfg.block_count {
+                bail!("layers {range:?} out of the {} layers of the model", cfg.block_count)
+            }
 
-            let md_get = |s: &str| match ct.metadata.get(s) {
-                None => candle_core::bail!("cannot find {s} in metadata"),
-                Some(v) => Ok(v),
-            };
-
-            let max_seq_len = md_get("phi3.context_length")?.to_u32()? as usize;
-            let head_count = md_get("phi3.attention.head_count")?.to_u32()? as usize;
-            let head_count_kv = md_get("phi3.attention.head_count_kv")?.to_u32()? as usize;
-            let i_size = md_get("phi3.feed_forward_length")?.to_u32()? as usize;
-            let rms_eps = md_get("phi3.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
-            let embedding_length = md_get("phi3.embedding_length")?.to_u32()? as usize;
-            let head_dim = embedding_length / head_count;
-            let rope_dim = md_get("phi3.rope.dimension_count")?.to_u32()? as usize;
-            let (cos, sin) = precomput_freqs_cis(rope_dim, max_seq_len, 10_000., device)?;
+            let max_seq_len = cfg.context_length;
+            let i_size = cfg.feed_forward_length;
+            let rms_eps = cfg.rms_eps;
+            let (cos, sin) = precomput_freqs_cis(cfg.rope_dim, max_seq_len, cfg.rope_freq_base, cfg.rope_scaling.as_ref().map(|s| s.factors.as_slice()), device)?;
+            let rope_scale = cfg.rope_scaling.as_ref().map(|s| s.attn_factor as f64).unwrap_or(1.);
             let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
 
             let mut layers = Vec::with_capacity(range.len());
@@ -55,9 +57,10 @@ impl Phi3LayersWorker {
                     attn_norm,
                     ffn_norm,
                     mlp,
-                    n_head: head_count,
-                    n_kv_head: head_count_kv,
-                    head_dim,
+                    n_head: cfg.head_count,
+                    n_kv_head: cfg.head_count_kv,
+                    head_dim: cfg.head_dim(),
+                    rope_scale,
                     cos: cos.clone(),
                     sin: sin.clone(),
                     neg_inf: neg_inf.clone(),
@@ -71,62 +74,106 @@ impl Phi3LayersWorker {
             (vec![], 0)
         };
 
-        let span = tracing::span!(tracing::Level::TRACE, "layers_worker");
+        Ok(Self::with_layers(layers, window, kv_budget))
+    }
 
-        Ok(Self {
+    fn with_layers(layers: Vec<LayerWeights>, window: usize, kv_budget: KvBudgetConfig) -> Self {
+        // the keys and values are f32 when not quantized
+        let block_bytes = layers
+            .iter()
+            .map(|layer| kv_budget.quantization.block_bytes(layer.n_kv_head, layer.head_dim, DType::F32))
+            .sum::<usize>();
+        Self {
+            kv_quantization: kv_budget.quantization,
+            caches: SessionCaches::new(kv_budget, block_bytes as u64),
             layers,
-            caches: LayersCache::new(range.len(), 2, max_seq_len),
-            span,
-        })
+            window,
+            span: tracing::span!(tracing::Level::TRACE, "layers_worker"),
+        }
     }
 
-    fn mask(&self, t: usize, device: &Device) -> Result<Tensor> {
+    /// Causal mask of a chunk of `t` tokens starting at `index_pos`, the chunk also attends to every cached position before it
+    fn mask(&self, t: usize, index_pos: usize, device: &Device) -> Result<Tensor> {
         //TODO use LRU
-        let mask: Vec<_> = (0..t).flat_map(|i| (0..t).map(move |j| u8::from(j > i))).collect();
-        let mask = Tensor::from_slice(&mask, (t, t), device)?;
+        let kv_len = index_pos + t;
+        let mask: Vec<_> = (0..t).flat_map(|i| (0..kv_len).map(move |j| u8::from(j > i + index_pos))).collect();
+        let mask = Tensor::from_slice(&mask, (t, kv_len), device)?;
         Ok(mask)
     }
-}
 
-#[async_trait::async_trait]
-impl ModelLayersWorker<(Tensor, u32)> for Phi3LayersWorker {
-    async fn start(&self, session: Session, _cfg: ChatCfg) -> Result<()> {
-        for (idx, _) in self.layers.iter().enumerate() {
-            self.caches.add_cache(idx, session);
-        }
-        Ok(())
-    }
-
-    async fn forward(&self, session: Session, _step: u32, (mut xs, seq_len): (Tensor, u32), index_pos: u32) -> Result<(Tensor, u32)> {
+    /// Row `i` of `xs` is a chunk at `positions[i]` of `caches[i]`
+    fn forward_layers(&self, mut xs: Tensor, positions: &[usize], caches: &mut [&mut LayersCache]) -> Result<Tensor> {
         let _span = self.span.enter();
-        let mask = if seq_len == 1 {
-            None
-        } else {
-            Some(self.mask(seq_len as usize, xs.device())?)
-        };
+        let seq_len = xs.dim(1)?;
+        let masks = positions
+            .iter()
+            .map(|pos| {
+                if seq_len == 1 {
+                    Ok(None)
+                } else {
+                    self.mask(seq_len, *pos, xs.device()).map(Some)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let masks = masks.iter().map(|mask| mask.as_ref()).collect::<Vec<_>>();
         for (idx, layer) in self.layers.iter().enumerate() {
             let residual = &xs;
             let ys = xs.apply(&layer.attn_norm)?;
-            let kv_cache = self.caches.get_cache(idx, session);
-            let ys = layer.forward_attn(&ys, mask.as_ref(), index_pos as usize, &mut kv_cache.lock())?;
+            let mut kv_caches = caches.iter_mut().map(|cache| cache.layer(idx)).collect::<Vec<_>>();
+            let ys = layer.forward_attn(&ys, &masks, positions, &mut kv_caches)?;
             let ys = (ys + residual)?;
             let residual = &ys;
             let ys = ys.apply(&layer.ffn_norm)?;
             let ys = layer.mlp.forward(&ys)?;
             xs = (ys + residual)?;
         }
-        Ok((xs, seq_len))
+        Ok(xs)
+    }
+}
+
+#[async_trait::async_trait]
+impl ModelLayersWorker<(Tensor, u32)> for Phi3LayersWorker {
+    async fn start(&self, session: Session, cfg: ChatCfg) -> Result<()> {
+        self.caches.start(session, LayersCache::new(&self.layers, self.kv_quantization, cfg.overflow, self.window))
+    }
+
+    async fn start_with_prefix(&self, session: Session, cfg: ChatCfg, prefixes: Vec<PrefixKey>) -> Result<u32> {
+        self.start(session, cfg).await?;
+        Ok(self.caches.register_prefixes(session, prefixes))
+    }
+
+    async fn forward(&self, session: Session, step: u32, xs: (Tensor, u32), index_pos: u32) -> Result<(Tensor, u32)> {
+        let mut res = self.forward_batch(vec![(session, step, xs, index_pos)]).await;
+        res.pop().expect("Should have the result of the session")
+    }
+
+    async fn forward_batch(&self, batch: Vec<(Session, u32, (Tensor, u32), u32)>) -> Vec<Result<(Tensor, u32)>> {
+        self.caches.forward_batch(batch, |xs, _, positions, caches| self.forward_layers(xs, positions, caches))
+    }
+
+    async fn rollback(&self, session: Session, count: u32) -> Result<()> {
+        self.caches.rollback(session, count)
+    }
+
+    async fn fork(&self, session: Session, forked: Session) -> Result<()> {
+        self.caches.fork(session, forked)
     }
 
     async fn finish(&self, session: Session) {
-        for (idx, _) in self.layers.iter().enumerate() {
-            self.caches.del_cache(idx, session);
-        }
+        self.caches.finish(session);
+    }
+
+    fn kv_usage(&self) -> KvUsage {
+        self.caches.usage()
     }
 }
 
-fn precomput_freqs_cis(head_dim: usize, max_seq_len: usize, freq_base: f32, device: &Device) -> Result<(Tensor, Tensor)> {
-    let theta: Vec<_> = (0..head_dim).step_by(2).map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32)).collect();
+/// Rope tables of the positions up to `max_seq_len`, the frequencies are divided by the LongRoPE `factors` when set
+fn precomput_freqs_cis(head_dim: usize, max_seq_len: usize, freq_base: f32, factors: Option<&[f32]>, device: &Device) -> Result<(Tensor, Tensor)> {
+    let mut theta: Vec<_> = (0..head_dim).step_by(2).map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32)).collect();
+    if let Some(factors) = factors {
+        theta.iter_mut().zip(factors).for_each(|(theta, factor)| *theta /= factor);
+    }
     let theta = Tensor::new(theta.as_slice(), device)?;
     let idx_theta = Tensor::arange(0, max_seq_len as u32, device)?
         .to_dtype(DType::F32)?
@@ -136,3 +183,261 @@ fn precomput_freqs_cis(head_dim: usize, max_seq_len: usize, freq_base: f32, devi
     let sin = idx_theta.sin()?;
     Ok((cos, sin))
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{
+        quantized::{GgmlDType, QTensor},
+        Device, Tensor,
+    };
+    use candle_nn::RmsNorm;
+    use protocol::OverflowPolicy;
+
+    use super::{precomput_freqs_cis, Phi3LayersWorker};
+    use crate::{
+        kv_cache::KvQuantization,
+        phi3::internal::{layer_weights::LayerWeights, mlp::Mlp, qlinear::QLinear},
+        ChatCfg, KvBudgetConfig, ModelLayersWorker, Session,
+    };
+
+    const HIDDEN: usize = 16;
+    const HEADS: usize = 2;
+    const I_SIZE: usize = 24;
+    const MAX_SEQ_LEN: usize = 32;
+
+    fn linear(out_dim: usize, in_dim: usize, device: &Device) -> QLinear {
+        let w = Tensor::randn(0f32, 0.2, (out_dim, in_dim), device).unwrap();
+        QLinear::from_qtensor(QTensor::quantize(&w, GgmlDType::F32).unwrap()).unwrap()
+    }
+
+    /// Two layers with random weights
+    fn layers(device: &Device) -> Vec<LayerWeights> {
+        layers_with_kv_heads(HEADS, device)
+    }
+
+    fn layers_with_kv_heads(kv_heads: usize, device: &Device) -> Vec<LayerWeights> {
+        let head_dim = HIDDEN / HEADS;
+        let (cos, sin) = precomput_freqs_cis(head_dim, MAX_SEQ_LEN, 10_000., None, device).unwrap();
+        (0..2)
+            .map(|_| LayerWeights {
+                attn_qkv: linear(HIDDEN + 2 * kv_heads * head_dim, HIDDEN, device),
+                attn_output: linear(HIDDEN, HIDDEN, device),
+                attn_norm: RmsNorm::new(Tensor::ones(HIDDEN, candle_core::DType::F32, device).unwrap(), 1e-5),
+                ffn_norm: RmsNorm::new(Tensor::ones(HIDDEN, candle_core::DType::F32, device).unwrap(), 1e-5),
+                mlp: Mlp {
+                    ffn_up: linear(2 * I_SIZE, HIDDEN, device),
+                    ffn_down: linear(HIDDEN, I_SIZE, device),
+                    i_size: I_SIZE,
+                },
+                n_head: HEADS,
+                n_kv_head: kv_heads,
+                head_dim,
+                rope_scale: 1.,
+                cos: cos.clone(),
+                sin: sin.clone(),
+                neg_inf: Tensor::new(f32::NEG_INFINITY, device).unwrap(),
+                use_flash_attn: false,
+                span_attn: tracing::span!(tracing::Level::TRACE, "attn"),
+                span_rot: tracing::span!(tracing::Level::TRACE, "attn-rot"),
+            })
+            .collect()
+    }
+
+    fn worker(device: &Device) -> Phi3LayersWorker {
+        worker_of(layers(device), KvBudgetConfig::default())
+    }
+
+    fn worker_of(layers: Vec<LayerWeights>, kv_budget: KvBudgetConfig) -> Phi3LayersWorker {
+        Phi3LayersWorker::with_layers(layers, MAX_SEQ_LEN, kv_budget)
+    }
+
+    /// Forward the prompt in chunks and return the output of every position
+    async fn prefill(worker: &Phi3LayersWorker, xs: &Tensor, chunk_size: usize) -> Vec<f32> {
+        let session = Session::new();
+        worker.start(session, ChatCfg::default()).await.unwrap();
+        let seq_len = xs.dim(1).unwrap();
+        let mut outputs = vec![];
+        for pos in (0..seq_len).step_by(chunk_size) {
+            let len = chunk_size.min(seq_len - pos);
+            let chunk = xs.narrow(1, pos, len).unwrap();
+            let (ys, _) = worker.forward(session, 0, (chunk, len as u32), pos as u32).await.unwrap();
+            outputs.push(ys);
+        }
+        worker.finish(session).await;
+        Tensor::cat(&outputs, 1).unwrap().flatten_all().unwrap().to_vec1().unwrap()
+    }
+
+    #[tokio::test]
+    async fn chunked_prefill_matches_token_by_token() {
+        let device = Device::Cpu;
+        let worker = worker(&device);
+        let xs = Tensor::randn(0f32, 1., (1, 11, HIDDEN), &device).unwrap();
+
+        let expected = prefill(&worker, &xs, 1).await;
+        for chunk_size in [3, 4, 11] {
+            let outputs = prefill(&worker, &xs, chunk_size).await;
+            let max_diff = expected.iter().zip(&outputs).map(|(a, b)| (a - b).abs()).fold(0f32, f32::max);
+            assert!(max_diff < 1e-4, "chunk size {chunk_size} differs by {max_diff}");
+        }
+    }
+
+    #[tokio::test]
+    async fn grouped_query_attention_prefill() {
+        let device = Device::Cpu;
+        // like Phi-3-medium, the keys and values have fewer heads than the queries
+        let worker = worker_of(layers_with_kv_heads(1, &device), KvBudgetConfig::default());
+        let xs = Tensor::randn(0f32, 1., (1, 7, HIDDEN), &device).unwrap();
+
+        let expected = prefill(&worker, &xs, 1).await;
+        let outputs = prefill(&worker, &xs, 7).await;
+        let max_diff = expected.iter().zip(&outputs).map(|(a, b)| (a - b).abs()).fold(0f32, f32::max);
+        assert!(max_diff < 1e-4, "chunked prefill differs by {max_diff}");
+    }
+
+    #[tokio::test]
+    async fn batched_decode_matches_single() {
+        let device = Device::Cpu;
+        let worker = worker(&device);
+        let prompts = [5, 9].map(|len| Tensor::randn(0f32, 1., (1, len, HIDDEN), &device).unwrap());
+        let steps = [0, 1].map(|_| Tensor::randn(0f32, 1., (1, 1, HIDDEN), &device).unwrap());
+
+        let mut runs = vec![];
+        for _ in 0..2 {
+            let sessions = [Session::new(), Session::new()];
+            for (session, prompt) in sessions.iter().zip(&prompts) {
+                worker.start(*session, ChatCfg::default()).await.unwrap();
+                let len = prompt.dim(1).unwrap() as u32;
+                worker.forward(*session, 0, (prompt.clone(), len), 0).await.unwrap();
+            }
+            runs.push(sessions);
+        }
+
+        let batch = runs[0]
+            .iter()
+            .zip(&steps)
+            .zip(&prompts)
+            .map(|((session, xs), prompt)| (*session, 1, (xs.clone(), 1), prompt.dim(1).unwrap() as u32))
+            .collect();
+        let batched = worker.forward_batch(batch).await;
+        for (i, session) in runs[1].iter().enumerate() {
+            let (single, _) = worker.forward(*session, 1, (steps[i].clone(), 1), prompts[i].dim(1).unwrap() as u32).await.unwrap();
+            let max_diff = (single - &batched[i].as_ref().unwrap().0).unwrap().abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+            assert!(max_diff < 1e-4, "session {i} differs by {max_diff}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rollback_drops_rejected_positions() {
+        let device = Device::Cpu;
+        let worker = worker(&device);
+        let xs = Tensor::randn(0f32, 1., (1, 7, HIDDEN), &device).unwrap();
+        let rejected = Tensor::randn(0f32, 1., (1, 3, HIDDEN), &device).unwrap();
+        let accepted = Tensor::randn(0f32, 1., (1, 3, HIDDEN), &device).unwrap();
+
+        // the 3 rejected positions are rolled back then replaced, like a verified draft
+        let session = Session::new();
+        worker.start(session, ChatCfg::default()).await.unwrap();
+        worker.forward(session, 0, (xs.clone(), 7), 0).await.unwrap();
+        worker.forward(session, 1, (rejected, 3), 7).await.unwrap();
+        worker.rollback(session, 3).await.unwrap();
+        let (rolled_back, _) = worker.forward(session, 2, (accepted.clone(), 3), 7).await.unwrap();
+        assert!(worker.rollback(session, 11).await.is_err());
+
+        let fresh = Session::new();
+        worker.start(fresh, ChatCfg::default()).await.unwrap();
+        worker.forward(fresh, 0, (xs, 7), 0).await.unwrap();
+        let (expected, _) = worker.forward(fresh, 1, (accepted, 3), 7).await.unwrap();
+        let max_diff = (rolled_back - expected).unwrap().abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+        assert!(max_diff < 1e-5, "rolled back session differs by {max_diff}");
+    }
+
+    #[tokio::test]
+    async fn forked_session_continues_independently() {
+        let device = Device::Cpu;
+        let worker = worker(&device);
+        let xs = Tensor::randn(0f32, 1., (1, 6, HIDDEN), &device).unwrap();
+        let next = Tensor::randn(0f32, 1., (1, 1, HIDDEN), &device).unwrap();
+        let other = Tensor::randn(0f32, 1., (1, 1, HIDDEN), &device).unwrap();
+
+        let (session, forked) = (Session::new(), Session::new());
+        worker.start(session, ChatCfg::default()).await.unwrap();
+        worker.forward(session, 0, (xs.clone(), 6), 0).await.unwrap();
+        worker.fork(session, forked).await.unwrap();
+        // the session goes on with another token, the fork still has only the prompt
+        worker.forward(session, 1, (other, 1), 6).await.unwrap();
+        let (forked_out, _) = worker.forward(forked, 1, (next.clone(), 1), 6).await.unwrap();
+
+        let fresh = Session::new();
+        worker.start(fresh, ChatCfg::default()).await.unwrap();
+        worker.forward(fresh, 0, (xs, 6), 0).await.unwrap();
+        let (expected, _) = worker.forward(fresh, 1, (next, 1), 6).await.unwrap();
+        let max_diff = (forked_out - expected).unwrap().abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+        assert!(max_diff < 1e-5, "forked session differs by {max_diff}");
+        assert_eq!(worker.kv_usage().sessions, 3);
+    }
+
+    #[tokio::test]
+    async fn sliding_window_keeps_attention_sinks() {
+        let device = Device::Cpu;
+        let worker = worker(&device);
+        let xs = Tensor::randn(0f32, 1., (1, MAX_SEQ_LEN, HIDDEN), &device).unwrap();
+        let next = Tensor::randn(0f32, 1., (1, 1, HIDDEN), &device).unwrap();
+        let start = |overflow| {
+            let session = Session::new();
+            let worker = &worker;
+            async move {
+                worker.start(session, ChatCfg { overflow, ..Default::default() }).await.unwrap();
+                session
+            }
+        };
+
+        // the window is full, the next token overflows it
+        let failing = start(OverflowPolicy::Error).await;
+        worker.forward(failing, 0, (xs.clone(), MAX_SEQ_LEN as u32), 0).await.unwrap();
+        assert!(worker.forward(failing, 1, (next.clone(), 1), MAX_SEQ_LEN as u32).await.is_err());
+
+        // every token after the 4 sinks is evicted, as if the sinks were the whole prompt
+        let sliding = start(OverflowPolicy::SlidingWindow { sinks: 4 }).await;
+        worker.forward(sliding, 0, (xs.clone(), MAX_SEQ_LEN as u32), 0).await.unwrap();
+        let (ys, _) = worker.forward(sliding, 1, (next.clone(), 1), MAX_SEQ_LEN as u32).await.unwrap();
+        let sinks_only = start(OverflowPolicy::Error).await;
+        worker.forward(sinks_only, 0, (xs.narrow(1, 0, 4).unwrap(), 4), 0).await.unwrap();
+        let (expected, _) = worker.forward(sinks_only, 1, (next, 1), 4).await.unwrap();
+        let max_diff = (ys - expected).unwrap().abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+        assert!(max_diff < 1e-4, "sliding window differs by {max_diff}");
+    }
+
+    #[tokio::test]
+    async fn int8_kv_cache_close_to_plain() {
+        let device = Device::Cpu;
+        let layers = layers(&device);
+        let plain = worker_of(layers.clone(), KvBudgetConfig::default());
+        let int8 = worker_of(
+            layers,
+            KvBudgetConfig {
+                quantization: KvQuantization::Int8,
+                ..Default::default()
+            },
+        );
+        let xs = Tensor::randn(0f32, 1., (1, MAX_SEQ_LEN, HIDDEN), &device).unwrap();
+
+        let mut outputs = vec![];
+        let mut used_bytes = vec![];
+        for worker in [&plain, &int8] {
+            let session = Session::new();
+            worker.start(session, ChatCfg::default()).await.unwrap();
+            // the first chunk fills a block, the second attends to it quantized
+            let (first, _) = worker.forward(session, 0, (xs.narrow(1, 0, 24).unwrap(), 24), 0).await.unwrap();
+            let (second, _) = worker.forward(session, 1, (xs.narrow(1, 24, 8).unwrap(), 8), 24).await.unwrap();
+            outputs.push(Tensor::cat(&[first, second], 1).unwrap());
+            used_bytes.push(worker.kv_usage().used_bytes);
+            worker.finish(session).await;
+        }
+
+        let max_diff = (&outputs[0] - &outputs[1]).unwrap().abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+        let max_abs = outputs[0].abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+        assert!(max_diff / max_abs < 0.02, "int8 kv cache differs by {max_diff} of {max_abs}");
+        // f32 keys and values take 4 bytes, int8 a byte and a few scales
+        assert!(used_bytes[1] * 3 < used_bytes[0], "int8 kv cache uses {} of {} bytes", used_bytes[1], used_bytes[0]);
+    }
+}
@@ -1,20 +1,31 @@
-use std::path::PathBuf;
+use std::{
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 use candle_core::{
     quantized::{gguf_file, QTensor},
     Device, Result, Tensor,
 };
 use candle_nn::RmsNorm;
-use hf_hub::{api::tokio::Api, Repo, RepoType};
+pub use internal::config::Phi3Config;
 pub use layers_worker::Phi3LayersWorker;
 pub use postprocessing::Phi3Postprocessor;
 pub use preprocessing::Phi3Preprocessor;
+use protocol::{ChatEvent, FinishReason, ModelManifest, PromptTemplate};
 use tokenizers::Tokenizer;
 use tokio::sync::mpsc::Sender;
 
 use crate::{
-    logits_processor::{LogitsProcessor, Sampling},
-    token_output_stream::TokenOutputStream,
+    chat_output::ChatOutput,
+    constraint::{TokenConstraint, TokenVocab},
+    conversation::{self, ChatSteps, Conversations},
+    logits_processor::LogitsProcessor,
+    prefill::prefill,
+    prefix_cache::prefix_keys,
+    prompt::ChatTemplate,
+    resource::ResourceSource,
     utils, ChatCfg, ChatCompletionRequest, ChatModel, ModelLayersWorker, ModelPostprocessor, ModelPreprocessor, Session,
 };
 
@@ -24,22 +35,65 @@ mod layers_worker;
 mod postprocessing;
 mod preprocessing;
 
+/// `<|end|>` closes the assistant turn, `<|endoftext|>` the whole text
+const EOS_TOKENS: [&str; 2] = ["<|endoftext|>", "<|end|>"];
+
 fn rms_norm(w: QTensor, eps: f64) -> Result<RmsNorm> {
     let w = w.dequantize(&w.device())?;
     let rms = RmsNorm::new(w, eps);
     Ok(rms)
 }
 
-async fn tokenizer_path() -> PathBuf {
-    let api = Api::new().unwrap();
-    let repo = api.repo(Repo::with_revision("microsoft/Phi-3-mini-4k-instruct".to_string(), RepoType::Model, "main".to_string()));
-    repo.get("tokenizer.json").await.unwrap()
+pub struct Phi3Resource {
+    pub tokenizer_repo: String,
+    pub tokenizer: String,
+    pub model_repo: String,
+    pub model: String,
+    pub tokenizer_config: Option<String>,
+    pub prompt_template: PromptTemplate,
+    pub source: ResourceSource,
 }
 
-pub async fn model_path() -> PathBuf {
-    let api = Api::new().unwrap();
-    let repo = api.repo(Repo::with_revision("microsoft/Phi-3-mini-4k-instruct-gguf".to_string(), RepoType::Model, "main".to_string()));
-    repo.get("Phi-3-mini-4k-instruct-q4.gguf").await.unwrap()
+impl Default for Phi3Resource {
+    fn default() -> Self {
+        Self {
+            tokenizer_repo: "microsoft/Phi-3-mini-4k-instruct".to_string(),
+            tokenizer: "tokenizer.json".to_string(),
+            model_repo: "microsoft/Phi-3-mini-4k-instruct-gguf".to_string(),
+            model: "Phi-3-mini-4k-instruct-q4.gguf".to_string(),
+            tokenizer_config: Some("tokenizer_config.json".to_string()),
+            prompt_template: PromptTemplate::Phi3,
+            source: ResourceSource::default(),
+        }
+    }
+}
+
+impl Phi3Resource {
+    pub fn from_manifest(manifest: &ModelManifest, source: ResourceSource) -> Self {
+        Self {
+            tokenizer_repo: manifest.tokenizer_repo().to_string(),
+            tokenizer: manifest.tokenizer.clone(),
+            model_repo: manifest.repo.clone(),
+            model: manifest.weights.clone(),
+            tokenizer_config: manifest.tokenizer_config.clone(),
+            prompt_template: manifest.prompt_template,
+            source,
+        }
+    }
+
+    async fn tokenizer_path(&self) -> Result<PathBuf> {
+        self.source.get(&self.tokenizer_repo, &self.tokenizer).await
+    }
+
+    pub async fn model_path(&self) -> Result<PathBuf> {
+        self.source.get(&self.model_repo, &self.model).await
+    }
+
+    pub async fn num_layers(&self) -> Result<u32> {
+        let mut model_file = std::fs::File::open(self.model_path().await?)?;
+        let model = gguf_file::Content::read(&mut model_file)?;
+        Ok(Phi3Config::from_gguf(&model, &mut model_file, &Device::Cpu)?.block_count as u32)
+    }
 }
 
 pub struct Phi3Model<W: ModelLayersWorker<(Tensor, u32)>> {
@@ -48,129 +102,179 @@ pub struct Phi3Model<W: ModelLayersWorker<(Tensor, u32)>> {
     preprocessor: Phi3Preprocessor,
     layers_worker: W,
     postprocessor: Phi3Postprocessor,
+    chat_template: ChatTemplate,
+    token_vocab: OnceLock<Arc<TokenVocab>>,
+    conversations: Conversations,
+    context_window: usize,
 }
 
 impl<W: ModelLayersWorker<(Tensor, u32)>> Phi3Model<W> {
-    pub async fn new(device: Device, layers_worker: W) -> Self {
-        let tokenizer = Tokenizer::from_file(tokenizer_path().await).unwrap();
-        let mut model_file = std::fs::File::open(model_path().await).unwrap();
-        let model = gguf_file::Content::read(&mut model_file).unwrap();
-        let preprocessor = Phi3Preprocessor::new(&model, &mut model_file, &device).unwrap();
-        let postprocessor = Phi3Postprocessor::new(&model, &mut model_file, &device).unwrap();
-        Self {
+    pub async fn new(resource: &Phi3Resource, device: Device, layers_worker: W) -> Result<Self> {
+        let tokenizer = Tokenizer::from_file(resource.tokenizer_path().await?).map_err(candle_core::Error::msg)?;
+        let chat_template = ChatTemplate::load(&resource.source, &resource.tokenizer_repo, resource.tokenizer_config.as_deref(), resource.prompt_template).await;
+        let mut model_file = std::fs::File::open(resource.model_path().await?)?;
+        let model = gguf_file::Content::read(&mut model_file)?;
+        let config = Phi3Config::from_gguf(&model, &mut model_file, &device)?;
+        let preprocessor = Phi3Preprocessor::new(&model, &mut model_file, &config, &device)?;
+        let postprocessor = Phi3Postprocessor::new(&model, &mut model_file, &config, &device)?;
+        let context_window = config.context_length;
+        Ok(Self {
             device,
             tokenizer,
             preprocessor,
             layers_worker,
             postprocessor,
-        }
+            chat_template,
+            token_vocab: OnceLock::new(),
+            conversations: Conversations::default(),
+            context_window,
+        })
     }
 }
 
 #[async_trait::async_trait]
 impl<W: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static> ChatModel for Phi3Model<W> {
     fn build_prompt(&self, request: &ChatCompletionRequest) -> String {
-        let mut prompt = String::new();
-        for message in &request.messages {
-            for content in message.content.contents() {
-                match message.role.as_str() {
-                    "system" => {
-                        prompt.push_str(&format!("<|system|>\n{content}<|end|>"));
-                    }
-                    "user" => {
-                        prompt.push_str(&format!("<|user|>\n{content}<|end|>"));
-                    }
-                    "assistant" => {
-                        prompt.push_str(&format!("<|assistant|>\n{content}<|end|>"));
-                    }
-                    _ => {
-                        log::warn!("unsupported role: {}", message.role)
-                    }
-                }
-            }
-        }
-        prompt.push_str("<|assistant|>\n");
-        prompt
+        self.chat_template.render(request)
     }
 
-    async fn chat(&self, session: Session, cfg: ChatCfg, prompt: &str, tx: Sender<String>) -> Result<()> {
-        log::info!("chatting with Phi3 model");
-        log::info!("prompt: {prompt}");
-        let mut tos = TokenOutputStream::new(self.tokenizer.clone());
-        let tokens = tos.tokenizer().encode(prompt, true).unwrap();
-        let mut all_tokens = vec![];
-        let mut logits_processor = {
-            let temperature = cfg.temperature;
-            let sampling = if temperature <= 0. {
-                Sampling::ArgMax
-            } else {
-                match (cfg.top_k, cfg.top_p) {
-                    (None, None) => Sampling::All { temperature },
-                    (Some(k), None) => Sampling::TopK { k, temperature },
-                    (None, Some(p)) => Sampling::TopP { p, temperature },
-                    (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
-                }
-            };
-            LogitsProcessor::from_sampling(cfg.seed, sampling)
-        };
-        let tokens = tokens.get_ids();
+    fn check_prompt(&self, cfg: &ChatCfg, prompt: &str) -> Result<()> {
+        conversation::check_prompt(self, cfg, prompt)
+    }
+
+    async fn chat(&self, session: Session, cfg: ChatCfg, prompt: &str, tx: Sender<ChatEvent>) -> Result<FinishReason> {
+        conversation::chat(self, session, cfg, prompt, tx).await
+    }
+
+    async fn chat_turn(&self, conversation: &str, cfg: ChatCfg, prompt: &str, tx: Sender<ChatEvent>) -> Result<FinishReason> {
+        self.conversations.chat_turn(self, conversation, cfg, prompt, tx).await
+    }
+
+    async fn expire_conversations(&self, ttl: Duration) {
+        self.conversations.expire(self, ttl).await
+    }
+}
+
+impl<W: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static> Phi3Model<W> {
+    fn token_vocab(&self) -> Arc<TokenVocab> {
+        self.token_vocab.get_or_init(|| Arc::new(TokenVocab::new(&self.tokenizer))).clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl<W: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static> ChatSteps for Phi3Model<W> {
+    fn tokenize(&self, prompt: &str) -> Result<Vec<u32>> {
+        Ok(self.tokenizer.encode(prompt, true).map_err(candle_core::Error::msg)?.get_ids().to_vec())
+    }
+
+    fn context_window(&self) -> usize {
+        self.context_window
+    }
+
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    fn eos_tokens(&self) -> Vec<u32> {
+        EOS_TOKENS.iter().filter_map(|t| self.tokenizer.token_to_id(t)).collect()
+    }
+
+    async fn start(&self, session: Session, cfg: &ChatCfg, tokens: &[u32]) -> Result<u32> {
+        let prefixes = prefix_keys(tokens, cfg.prefill_chunk_size);
+        self.layers_worker.start_with_prefix(session, cfg.clone(), prefixes).await
+    }
 
-        if let Err(e) = self.layers_worker.start(session, cfg.clone()).await {
-            log::error!("failed to start layers worker: {e}");
-            return Err(e);
+    async fn prefill(&self, session: Session, cfg: &ChatCfg, tokens: &[u32], cached_len: u32) -> Result<(Tensor, u32)> {
+        log::info!("chatting with Phi3 model, {} prompt tokens with {cached_len} cached", tokens.len());
+        // we split the prompt into chunks, each chunk is one forward through the layers chain, for avoiding big message size
+        // the chunks are pipelined, a node works on a chunk while the next node works on the previous one
+        let mut chunks = vec![];
+        for chunk in tokens[cached_len as usize..].chunks(cfg.prefill_chunk_size.max(1)) {
+            let input = Tensor::new(chunk, &self.device)?.unsqueeze(0)?;
+            chunks.push(self.preprocessor.forward(session, input).await?);
         }
+        // only the token after the prompt is generated
+        prefill(&self.layers_worker, session, cached_len, chunks).await
+    }
 
-        // for first cycle, process input prompt
-        // we split it into tokens, and then process each token one by one for avoiding big message size
-        let mut next_token = {
-            let mut next_token = 0;
-            for (pos, token) in tokens.iter().enumerate() {
-                let input = Tensor::new(&[*token], &self.device)?.unsqueeze(0)?;
-                let step1 = self.preprocessor.forward(session, input).await?;
-                let step2 = self.layers_worker.forward(session, 0, step1, pos as u32).await?;
-                let logits = self.postprocessor.forward(session, step2).await?;
-                let logits = logits.squeeze(0)?;
-                next_token = logits_processor.sample(&logits)?
+    async fn generate(&self, session: Session, cfg: ChatCfg, tokens: &[u32], prefilled: (Tensor, u32), tx: Sender<ChatEvent>) -> Result<(FinishReason, Vec<u32>)> {
+        let mut output = ChatOutput::new(self.tokenizer.clone(), &cfg, tx);
+        let mut all_tokens = vec![];
+        let mut logits_processor = LogitsProcessor::from_cfg(&cfg);
+        let eos_tokens = self.eos_tokens();
+        let mut constraint = match &cfg.constraint {
+            Some(constraint) => {
+                let eos_tokens = eos_tokens.iter().chain(&cfg.stop_token_ids).copied().collect();
+                Some(TokenConstraint::new(constraint, self.token_vocab(), eos_tokens)?)
             }
-            next_token
+            None => None,
         };
 
-        all_tokens.push(next_token);
-        if let Some(t) = tos.next_token(next_token)? {
-            tx.send(t).await.unwrap();
-        }
-        let eos_token = *tos.tokenizer().get_vocab(true).get("<|endoftext|>").unwrap();
+        // for first cycle, sample from the output of the prompt
+        let (mut next_token, mut logprobs) = {
+            let logits = self.postprocessor.forward(session, prefilled).await?;
+            let logits = utils::apply_penalties(logits.squeeze(0)?, &cfg, &all_tokens, &all_tokens)?;
+            let logits = match constraint.as_mut() {
+                Some(constraint) => constraint.mask_logits(&logits)?,
+                None => logits,
+            };
+            logits_processor.sample_with_logprobs(&logits, cfg.logprobs)?
+        };
+
+        let mut finish_reason = FinishReason::Length;
+        let mut index = 0;
+        loop {
+            all_tokens.push(next_token);
+            if let Some(constraint) = constraint.as_mut() {
+                constraint.advance(next_token)?;
+            }
+            if eos_tokens.contains(&next_token) {
+                finish_reason = FinishReason::Stop;
+                break;
+            }
+            if let Some(reason) = output.push_token(next_token, logprobs.take()).await? {
+                finish_reason = reason;
+                break;
+            }
+            if tokens.len() as u32 + index + 1 >= cfg.max_len {
+                break;
+            }
 
-        for index in 0..(cfg.max_len - tokens.len() as u32) {
             let input = Tensor::new(&[next_token], &self.device)?.unsqueeze(0)?;
             let step1 = self.preprocessor.forward(session, input).await?;
-            let step2 = self.layers_worker.forward(session, index as u32 + 1, step1, tokens.len() as u32 + index).await?;
+            let step2 = self.layers_worker.forward(session, index + 1, step1, tokens.len() as u32 + index).await?;
             let logits = self.postprocessor.forward(session, step2).await?;
             let logits = logits.squeeze(0)?;
-            let logits = if cfg.repeat_penalty == 1. {
-                logits
-            } else {
-                let start_at = all_tokens.len().saturating_sub(cfg.repeat_last_n);
-                utils::apply_repeat_penalty(&logits, cfg.repeat_penalty, &all_tokens[start_at..])?
-            };
-            next_token = logits_processor.sample(&logits)?;
-            all_tokens.push(next_token);
-            if let Some(t) = tos.next_token(next_token)? {
-                if let Err(e) = tx.send(t).await {
-                    log::error!("error sending message: {}", e);
-                    break;
-                }
-            }
-            if next_token == eos_token {
-                break;
+            let logits = utils::apply_penalties(logits, &cfg, &all_tokens, &all_tokens)?;
+            let logits = match constraint.as_mut() {
+                Some(constraint) => constraint.mask_logits(&logits)?,
+                None => logits,
             };
+            (next_token, logprobs) = logits_processor.sample_with_logprobs(&logits, cfg.logprobs)?;
+            index += 1;
         }
-        if let Some(rest) = tos.decode_rest().map_err(candle_core::Error::msg)? {
-            if let Err(e) = tx.send(rest).await {
-                log::error!("error sending message: {}", e);
-            }
+        if let Some(reason) = output.finish().await? {
+            finish_reason = reason;
         }
-        self.layers_worker.finish(session).await;
-        Ok(())
+        Ok((finish_reason, all_tokens))
+    }
+
+    async fn logits(&self, session: Session, prefilled: (Tensor, u32)) -> Result<Tensor> {
+        self.postprocessor.forward(session, prefilled).await?.squeeze(0)
+    }
+
+    async fn decode(&self, session: Session, step: u32, token: u32, index_pos: u32) -> Result<Tensor> {
+        let input = Tensor::new(&[token], &self.device)?.unsqueeze(0)?;
+        let input = self.preprocessor.forward(session, input).await?;
+        let output = self.layers_worker.forward(session, step, input, index_pos).await?;
+        self.postprocessor.forward(session, output).await?.squeeze(0)
+    }
+
+    async fn fork(&self, session: Session, forked: Session) -> Result<()> {
+        self.layers_worker.fork(session, forked).await
+    }
+
+    async fn finish(&self, session: Session) {
+        self.layers_worker.finish(session).await
     }
 }
@@ -3,7 +3,10 @@ use candle_nn::{Module, RmsNorm};
 
 use crate::{ModelPostprocessor, Session};
 
-use super::{internal::qlinear::QLinear, rms_norm};
+use super::{
+    internal::{config::Phi3Config, qlinear::QLinear},
+    rms_norm,
+};
 
 pub struct Phi3Postprocessor {
     output: QLinear,
@@ -12,15 +15,9 @@ pub struct Phi3Postprocessor {
 }
 
 impl Phi3Postprocessor {
-    pub fn new<R: std::io::Seek + std::io::Read>(ct: &gguf_file::Content, reader: &mut R, device: &Device) -> Result<Self> {
-        let md_get = |s: &str| match ct.metadata.get(s) {
-            None => candle_core::bail!("cannot find {s} in metadata"),
-            Some(v) => Ok(v),
-        };
-
+    pub fn new<R: std::io::Seek + std::io::Read>(ct: &gguf_file::Content, reader: &mut R, cfg: &Phi3Config, device: &Device) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "postprocessing");
-        let rms_eps = md_get("phi3.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
-        let output_norm = rms_norm(ct.tensor(reader, "output_norm.weight", device)?, rms_eps)?;
+        let output_norm = rms_norm(ct.tensor(reader, "output_norm.weight", device)?, cfg.rms_eps)?;
         let output = QLinear::new(&ct, reader, "output", device)?;
 
         Ok(Self { span, output, output_norm })
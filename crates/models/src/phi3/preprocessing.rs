@@ -3,19 +3,16 @@ use candle_nn::{Embedding, Module};
 
 use crate::{ModelPreprocessor, Session};
 
+use super::internal::config::Phi3Config;
+
 pub struct Phi3Preprocessor {
     tok_embeddings: Embedding,
     span: tracing::Span,
 }
 
 impl Phi3Preprocessor {
-    pub fn new<R: std::io::Seek + std::io::Read>(ct: &gguf_file::Content, reader: &mut R, device: &Device) -> Result<Self> {
-        let md_get = |s: &str| match ct.metadata.get(s) {
-            None => candle_core::bail!("cannot find {s} in metadata"),
-            Some(v) => Ok(v),
-        };
-
-        let embedding_length = md_get("phi3.embedding_length")?.to_u32()? as usize;
+    pub fn new<R: std::io::Seek + std::io::Read>(ct: &gguf_file::Content, reader: &mut R, cfg: &Phi3Config, device: &Device) -> Result<Self> {
+        let embedding_length = cfg.embedding_length;
         let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
         let tok_embeddings = tok_embeddings.dequantize(device)?;
 
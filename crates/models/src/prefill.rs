@@ -0,0 +1,81 @@
+use candle_core::{bail, Result, Tensor};
+use futures_util::{stream, StreamExt};
+use protocol::Session;
+
+use crate::ModelLayersWorker;
+
+/// Max chunks in flight in the layers chain, each hop can work on a different chunk
+const PREFILL_PIPELINE_DEPTH: usize = 8;
+
+/// Send the prompt embeddings through the layers chain chunk by chunk, without waiting for a chunk before sending the next one.
+///
+/// The chunks start at `start_pos`, after the prompt prefix already in the kv caches.
+/// Each chunk is a forward at its own `index_pos`, the workers must apply the forwards of a session in `index_pos` order.
+/// Only the output of the last chunk is returned, it is the only one needed for sampling the next token.
+pub async fn prefill<W: ModelLayersWorker<(Tensor, u32)>>(worker: &W, session: Session, start_pos: u32, chunks: Vec<(Tensor, u32)>) -> Result<(Tensor, u32)> {
+    let mut index_pos = start_pos;
+    let forwards = chunks.into_iter().map(|(embedding, seq_len)| {
+        let pos = index_pos;
+        index_pos += seq_len;
+        worker.forward(session, 0, (embedding, seq_len), pos)
+    });
+    let mut outputs = stream::iter(forwards).buffered(PREFILL_PIPELINE_DEPTH);
+    let mut last = None;
+    while let Some(output) = outputs.next().await {
+        last = Some(output?);
+    }
+    match last {
+        Some(last) => Ok(last),
+        None => bail!("empty prompt"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use candle_core::{Device, Result, Tensor};
+    use protocol::{ChatCfg, Session};
+    use spin::Mutex;
+
+    use super::prefill;
+    use crate::ModelLayersWorker;
+
+    #[derive(Default)]
+    struct RecordWorker {
+        in_flight: AtomicU32,
+        max_in_flight: AtomicU32,
+        positions: Mutex<Vec<(u32, u32)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ModelLayersWorker<(Tensor, u32)> for RecordWorker {
+        async fn start(&self, _session: Session, _config: ChatCfg) -> Result<()> {
+            Ok(())
+        }
+
+        async fn forward(&self, _session: Session, _step: u32, (xs, seq_len): (Tensor, u32), index_pos: u32) -> Result<(Tensor, u32)> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            self.positions.lock().push((index_pos, seq_len));
+            tokio::task::yield_now().await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok((xs, seq_len))
+        }
+
+        async fn finish(&self, _session: Session) {}
+    }
+
+    #[tokio::test]
+    async fn pipelined_chunks() {
+        let worker = RecordWorker::default();
+        let tokens = (0..10u32).collect::<Vec<_>>();
+        let chunks = tokens.chunks(4).map(|c| (Tensor::new(c, &Device::Cpu).unwrap(), c.len() as u32)).collect();
+        let (last, seq_len) = prefill(&worker, Session::new(), 3, chunks).await.unwrap();
+
+        assert_eq!(last.to_vec1::<u32>().unwrap(), vec![8, 9]);
+        assert_eq!(seq_len, 2);
+        assert_eq!(*worker.positions.lock(), vec![(3, 4), (7, 4), (11, 2)]);
+        assert!(worker.max_in_flight.load(Ordering::SeqCst) > 1);
+    }
+}
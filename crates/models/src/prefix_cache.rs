@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use protocol::{llm::PrefixKey, Session};
+use spin::Mutex;
+use utils::shared_map::SharedHashMap;
+
+use crate::kv_cache::KvBudget;
+
+/// Kv caches kept per worker, each is the cache of a whole prompt prefix and big
+const PREFIX_CACHE_ENTRIES: usize = 4;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Keys of the prefixes of `tokens` which end on a chunk boundary, shortest first.
+///
+/// The last token is never part of a prefix, its logits are needed for generating.
+/// The hash must be the same on every node, so it is a FNV-1a and not the std hasher.
+pub fn prefix_keys(tokens: &[u32], chunk_size: usize) -> Vec<PrefixKey> {
+    let chunk_size = chunk_size.max(1);
+    let mut hash = FNV_OFFSET;
+    let mut keys = vec![];
+    for (i, token) in tokens.iter().enumerate() {
+        for byte in token.to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+        let len = i + 1;
+        if len % chunk_size == 0 && len < tokens.len() {
+            keys.push(PrefixKey { hash, len: len as u32 });
+        }
+    }
+    keys
+}
+
+struct PrefixEntry<C> {
+    /// id of the entry in the kv budget
+    id: u64,
+    /// hash to len of every prefix of the cached prompt, a shorter prompt restores from a part of the cache
+    prefixes: HashMap<u64, u32>,
+    cache: C,
+    last_used: u64,
+}
+
+/// Kv caches of prompt prefixes, shared by the sessions of a worker.
+///
+/// The cache of the longest prefix of a prompt is stored once the prefill went through it,
+/// sessions starting with any prefix of that prompt then restore from it instead of computing it again.
+/// The stored caches are charged to the kv budget of the worker, which evicts them before any session.
+/// The entry found for a session when it starts is pinned until its first forward, which may come after an eviction.
+pub struct PrefixCache<C> {
+    entries: Mutex<(Vec<PrefixEntry<C>>, u64)>,
+    sessions: SharedHashMap<Session, Vec<PrefixKey>>,
+    /// prefixes and cache of the entry found for the sessions not forwarded yet
+    pinned: SharedHashMap<Session, (HashMap<u64, u32>, C)>,
+}
+
+impl<C: Clone> Default for PrefixCache<C> {
+    fn default() -> Self {
+        Self {
+            entries: Mutex::new((vec![], 0)),
+            sessions: Default::default(),
+            pinned: Default::default(),
+        }
+    }
+}
+
+impl<C: Clone> PrefixCache<C> {
+    /// Remember the prefixes of the session prompt, returns the len of the longest one already cached
+    pub fn register(&self, session: Session, prefixes: Vec<PrefixKey>) -> u32 {
+        let cached = {
+            let (entries, _) = &*self.entries.lock();
+            prefixes.iter().rev().find_map(|key| {
+                let entry = entries.iter().find(|e| e.prefixes.contains_key(&key.hash))?;
+                self.pinned.insert(session, (entry.prefixes.clone(), entry.cache.clone()));
+                Some(key.len)
+            })
+        };
+        if !prefixes.is_empty() {
+            self.sessions.insert(session, prefixes);
+        }
+        cached.unwrap_or(0)
+    }
+
+    /// The cache to start the session from when its first forward is at `index_pos`, with the len of the cache.
+    /// The caller keeps only the first `index_pos` positions of it, the session is unpinned
+    pub fn restore(&self, session: Session, index_pos: u32) -> Option<(C, u32)> {
+        let pinned = self.pinned.remove(&session);
+        let prefixes = self.sessions.get_clone(&session)?;
+        let key = prefixes.iter().find(|key| key.len == index_pos)?;
+        let (entries, clock) = &mut *self.entries.lock();
+        let Some(entry) = entries.iter_mut().find(|e| e.prefixes.contains_key(&key.hash)) else {
+            // evicted since the session started
+            let (prefixes, cache) = pinned.filter(|(prefixes, _)| prefixes.contains_key(&key.hash))?;
+            return Some((cache, prefixes.values().max().copied().unwrap_or(0)));
+        };
+        *clock += 1;
+        entry.last_used = *clock;
+        let len = entry.prefixes.values().max().copied().unwrap_or(0);
+        Some((entry.cache.clone(), len))
+    }
+
+    /// The position at which the session cache should be stored, None if its prompt is already cached
+    pub fn store_at(&self, session: Session) -> Option<u32> {
+        let prefixes = self.sessions.get_clone(&session)?;
+        let last = prefixes.last()?;
+        let (entries, _) = &*self.entries.lock();
+        (!entries.iter().any(|e| e.prefixes.contains_key(&last.hash))).then_some(last.len)
+    }
+
+    /// Store the session cache of `len` positions, it must hold exactly the longest prefix of the session.
+    /// It is not stored when the budget has no room for it
+    pub fn store(&self, session: Session, cache: C, len: usize, budget: &KvBudget) {
+        let Some(prefixes) = self.sessions.get_clone(&session) else {
+            return;
+        };
+        let (entries, clock) = &mut *self.entries.lock();
+        if entries.len() >= PREFIX_CACHE_ENTRIES {
+            if let Some(oldest) = entries.iter().enumerate().min_by_key(|(_, e)| e.last_used).map(|(i, _)| i) {
+                budget.release_prefix(entries.swap_remove(oldest).id);
+            }
+        }
+        *clock += 1;
+        match budget.charge_prefix(*clock, len) {
+            Ok(evicted) => entries.retain(|e| !evicted.contains(&e.id)),
+            Err(e) => {
+                log::info!("[PrefixCache] prefix of session {session} not stored: {e}");
+                return;
+            }
+        }
+        entries.push(PrefixEntry {
+            id: *clock,
+            prefixes: prefixes.iter().map(|key| (key.hash, key.len)).collect(),
+            cache,
+            last_used: *clock,
+        });
+    }
+
+    /// Drop the entries evicted by the kv budget
+    pub fn evict(&self, ids: &[u64]) {
+        if !ids.is_empty() {
+            self.entries.lock().0.retain(|e| !ids.contains(&e.id));
+        }
+    }
+
+    pub fn finish(&self, session: Session) {
+        self.sessions.remove(&session);
+        self.pinned.remove(&session);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::Session;
+
+    use super::{prefix_keys, PrefixCache};
+    use crate::kv_cache::{KvBudget, KvBudgetConfig};
+
+    #[test]
+    fn shared_prefix() {
+        let system = (0..8).collect::<Vec<u32>>();
+        let prompt_a = [system.clone(), vec![100, 101, 102, 103, 104]].concat();
+        let prompt_b = [system.clone(), vec![200, 201, 202]].concat();
+
+        let keys_a = prefix_keys(&prompt_a, 4);
+        let keys_b = prefix_keys(&prompt_b, 4);
+        assert_eq!(keys_a.iter().map(|k| k.len).collect::<Vec<_>>(), vec![4, 8, 12]);
+        assert_eq!(keys_b.iter().map(|k| k.len).collect::<Vec<_>>(), vec![4, 8]);
+        assert_eq!(keys_a[1], keys_b[1]);
+
+        let cache = PrefixCache::<&str>::default();
+        let (a, b) = (Session::new(), Session::new());
+        assert_eq!(cache.register(a, keys_a), 0);
+        assert_eq!(cache.store_at(a), Some(12));
+        let budget = KvBudget::new(KvBudgetConfig::default(), 1);
+        cache.store(a, "cache of a", 12, &budget);
+        assert_eq!(cache.store_at(a), None);
+
+        // b shares the system prompt with a, it restores the first 8 positions of the cache of a
+        assert_eq!(cache.register(b, keys_b), 8);
+        assert_eq!(cache.restore(b, 8), Some(("cache of a", 12)));
+        assert_eq!(cache.restore(b, 4), Some(("cache of a", 12)));
+        assert_eq!(cache.restore(b, 6), None);
+    }
+}
@@ -0,0 +1,372 @@
+use candle_core::Result;
+use minijinja::{context, Environment, Error, ErrorKind, Value};
+use protocol::{ChatCompletionRequest, Message, PromptTemplate, StringOrVecContent};
+use serde::Serialize;
+
+use crate::ResourceSource;
+
+const CHAT_TEMPLATE_NAME: &str = "chat_template";
+/// Placeholder of an image in the llama 3.2 vision prompts
+const IMAGE_TOKEN: &str = "<|image|>";
+
+/// Build the model prompt from chat messages.
+///
+/// The `chat_template` of the model's `tokenizer_config.json` is rendered with a Jinja engine when available,
+/// otherwise the built-in template is used.
+pub struct ChatTemplate {
+    env: Option<Environment<'static>>,
+    bos_token: String,
+    eos_token: String,
+    builtin: PromptTemplate,
+}
+
+#[derive(Serialize)]
+struct TemplateMessage<'a> {
+    role: &'a str,
+    content: TemplateContent<'a>,
+}
+
+/// The content of a message with images is a list of parts, as the chat templates of vision models expect
+#[derive(Serialize)]
+#[serde(untagged)]
+enum TemplateContent<'a> {
+    Text(String),
+    Parts(Vec<TemplatePart<'a>>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TemplatePart<'a> {
+    Text { text: &'a str },
+    Image,
+}
+
+impl<'a> TemplateContent<'a> {
+    fn new(message: &'a Message) -> Self {
+        match &message.content {
+            StringOrVecContent::Vec(parts) if parts.iter().any(|part| part.image_url.is_some()) => Self::Parts(
+                parts
+                    .iter()
+                    .filter_map(|part| match (&part.text, &part.image_url) {
+                        (_, Some(_)) => Some(TemplatePart::Image),
+                        (Some(text), None) => Some(TemplatePart::Text { text }),
+                        (None, None) => None,
+                    })
+                    .collect(),
+            ),
+            content => Self::Text(content.contents().join("\n")),
+        }
+    }
+}
+
+impl ChatTemplate {
+    pub fn builtin(builtin: PromptTemplate) -> Self {
+        Self {
+            env: None,
+            bos_token: String::new(),
+            eos_token: String::new(),
+            builtin,
+        }
+    }
+
+    /// Compile the Jinja `template`, falling back to `builtin` when it is invalid
+    pub fn new(template: &str, bos_token: &str, eos_token: &str, builtin: PromptTemplate) -> Self {
+        let mut env = Environment::new();
+        minijinja_contrib::add_to_environment(&mut env);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", raise_exception);
+        env.add_function("strftime_now", strftime_now);
+        // same whitespace handling as the HF transformers chat templates
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        if let Err(e) = env.add_template_owned(CHAT_TEMPLATE_NAME, template.to_string()) {
+            log::warn!("[ChatTemplate] invalid chat template, fallback to builtin {:?}: {e}", builtin);
+            return Self::builtin(builtin);
+        }
+        Self {
+            env: Some(env),
+            bos_token: bos_token.to_string(),
+            eos_token: eos_token.to_string(),
+            builtin,
+        }
+    }
+
+    /// Load the `chat_template` from a `tokenizer_config.json` file of the repo, falling back to `builtin` when there is none
+    pub async fn load(source: &ResourceSource, repo: &str, tokenizer_config: Option<&str>, builtin: PromptTemplate) -> Self {
+        let Some(filename) = tokenizer_config else {
+            return Self::builtin(builtin);
+        };
+        match Self::load_config(source, repo, filename).await {
+            Ok(Some((template, bos_token, eos_token))) => Self::new(&template, &bos_token, &eos_token, builtin),
+            Ok(None) => {
+                log::info!("[ChatTemplate] no chat template in {filename} of {repo}, use builtin {:?}", builtin);
+                Self::builtin(builtin)
+            }
+            Err(e) => {
+                log::warn!("[ChatTemplate] cannot load {filename} of {repo}, use builtin {:?}: {e}", builtin);
+                Self::builtin(builtin)
+            }
+        }
+    }
+
+    async fn load_config(source: &ResourceSource, repo: &str, filename: &str) -> Result<Option<(String, String, String)>> {
+        let path = source.get(repo, filename).await?;
+        let config: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?).map_err(candle_core::Error::wrap)?;
+        let template = match config.get("chat_template") {
+            Some(serde_json::Value::String(template)) => template.clone(),
+            // a list of named templates, e.g. `default` and `tool_use`
+            Some(serde_json::Value::Array(templates)) => {
+                let default = templates.iter().find(|t| t.get("name").and_then(|n| n.as_str()) == Some("default"));
+                match default.and_then(|t| t.get("template")).and_then(|t| t.as_str()) {
+                    Some(template) => template.to_string(),
+                    None => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some((template, special_token(&config, "bos_token"), special_token(&config, "eos_token"))))
+    }
+
+    pub fn render(&self, request: &ChatCompletionRequest) -> String {
+        if let Some(env) = &self.env {
+            match self.render_template(env, request) {
+                Ok(prompt) => return prompt,
+                Err(e) => log::warn!("[ChatTemplate] render chat template error, fallback to builtin {:?}: {e}", self.builtin),
+            }
+        }
+        build_prompt(self.builtin, request)
+    }
+
+    fn render_template(&self, env: &Environment<'static>, request: &ChatCompletionRequest) -> std::result::Result<String, Error> {
+        let messages = request
+            .messages
+            .iter()
+            .map(|m| TemplateMessage {
+                role: &m.role,
+                content: TemplateContent::new(m),
+            })
+            .collect::<Vec<_>>();
+        env.get_template(CHAT_TEMPLATE_NAME)?.render(context! {
+            messages => messages,
+            add_generation_prompt => true,
+            tools => Value::from(()),
+            bos_token => self.bos_token,
+            eos_token => self.eos_token,
+        })
+    }
+}
+
+/// Special tokens are either a plain string or an added token object with a `content` field
+fn special_token(config: &serde_json::Value, name: &str) -> String {
+    match config.get(name) {
+        Some(serde_json::Value::String(token)) => token.clone(),
+        Some(serde_json::Value::Object(token)) => token.get("content").and_then(|c| c.as_str()).unwrap_or_default().to_string(),
+        _ => String::new(),
+    }
+}
+
+fn raise_exception(msg: String) -> std::result::Result<String, Error> {
+    Err(Error::new(ErrorKind::InvalidOperation, msg))
+}
+
+fn strftime_now(format: String) -> String {
+    chrono::Local::now().format(&format).to_string()
+}
+
+/// Render chat messages into the model prompt with the given built-in template
+pub fn build_prompt(template: PromptTemplate, request: &ChatCompletionRequest) -> String {
+    match template {
+        PromptTemplate::Llama3 => build_llama3_prompt(request),
+        PromptTemplate::Phi3 => build_phi3_prompt(request),
+        PromptTemplate::ChatMl => build_chatml_prompt(request),
+        PromptTemplate::Gemma => build_gemma_prompt(request),
+        PromptTemplate::Mistral => build_mistral_prompt(request),
+    }
+}
+
+/// The images of a user message go before its text, like the llama 3.2 vision chat template
+fn build_llama3_prompt(request: &ChatCompletionRequest) -> String {
+    let mut prompt = String::from("<|begin_of_text|>");
+    for message in &request.messages {
+        let mut images = IMAGE_TOKEN.repeat(message.content.images().len());
+        let mut contents = message.content.contents();
+        if contents.is_empty() && !images.is_empty() {
+            contents.push("");
+        }
+        for content in contents {
+            match message.role.as_str() {
+                "system" => {
+                    prompt.push_str(&format!("<|start_header_id|>system<|end_header_id|>\n{content}<|eot_id|>"));
+                }
+                "user" => {
+                    prompt.push_str(&format!("<|start_header_id|>user<|end_header_id|>\n{}{content}<|eot_id|>", std::mem::take(&mut images)));
+                }
+                "assistant" => {
+                    prompt.push_str(&format!("<|start_header_id|>assistant<|end_header_id|>\n{content}<|eot_id|>"));
+                }
+                _ => {
+                    log::warn!("unsupported role: {}", message.role)
+                }
+            }
+        }
+    }
+    prompt.push_str("<|start_header_id|>assistant<|end_header_id|>");
+    prompt
+}
+
+fn build_phi3_prompt(request: &ChatCompletionRequest) -> String {
+    let mut prompt = String::new();
+    for message in &request.messages {
+        for content in message.content.contents() {
+            match message.role.as_str() {
+                "system" => {
+                    prompt.push_str(&format!("<|system|>\n{content}<|end|>"));
+                }
+                "user" => {
+                    prompt.push_str(&format!("<|user|>\n{content}<|end|>"));
+                }
+                "assistant" => {
+                    prompt.push_str(&format!("<|assistant|>\n{content}<|end|>"));
+                }
+                _ => {
+                    log::warn!("unsupported role: {}", message.role)
+                }
+            }
+        }
+    }
+    prompt.push_str("<|assistant|>\n");
+    prompt
+}
+
+fn build_chatml_prompt(request: &ChatCompletionRequest) -> String {
+    let mut prompt = String::new();
+    for message in &request.messages {
+        for content in message.content.contents() {
+            match message.role.as_str() {
+                "system" | "user" | "assistant" => {
+                    prompt.push_str(&format!("<|im_start|>{}\n{content}<|im_end|>\n", message.role));
+                }
+                _ => {
+                    log::warn!("unsupported role: {}", message.role)
+                }
+            }
+        }
+    }
+    prompt.push_str("<|im_start|>assistant\n");
+    prompt
+}
+
+/// Gemma has no system turn, the system messages go at the start of the next user turn
+fn build_gemma_prompt(request: &ChatCompletionRequest) -> String {
+    let mut prompt = String::from("<bos>");
+    let mut system = String::new();
+    for message in &request.messages {
+        for content in message.content.contents() {
+            match message.role.as_str() {
+                "system" => {
+                    system.push_str(&format!("{content}\n\n"));
+                }
+                "user" => {
+                    prompt.push_str(&format!("<start_of_turn>user\n{}{content}<end_of_turn>\n", std::mem::take(&mut system)));
+                }
+                "assistant" => {
+                    prompt.push_str(&format!("<start_of_turn>model\n{content}<end_of_turn>\n"));
+                }
+                _ => {
+                    log::warn!("unsupported role: {}", message.role)
+                }
+            }
+        }
+    }
+    prompt.push_str("<start_of_turn>model\n");
+    prompt
+}
+
+/// Mistral has no system turn either, the system messages go at the start of the next user turn
+fn build_mistral_prompt(request: &ChatCompletionRequest) -> String {
+    let mut prompt = String::from("<s>");
+    let mut system = String::new();
+    for message in &request.messages {
+        for content in message.content.contents() {
+            match message.role.as_str() {
+                "system" => {
+                    system.push_str(&format!("{content}\n\n"));
+                }
+                "user" => {
+                    prompt.push_str(&format!("[INST] {}{content} [/INST]", std::mem::take(&mut system)));
+                }
+                "assistant" => {
+                    prompt.push_str(&format!("{content}</s>"));
+                }
+                _ => {
+                    log::warn!("unsupported role: {}", message.role)
+                }
+            }
+        }
+    }
+    prompt
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::{ChatCompletionRequest, PromptTemplate};
+
+    use super::{build_prompt, ChatTemplate};
+
+    const LLAMA3_TEMPLATE: &str = "{{ bos_token }}{% for message in messages %}<|start_header_id|>{{ message['role'] }}<|end_header_id|>\n{{ message['content'] | trim }}<|eot_id|>{% endfor %}{% if add_generation_prompt %}<|start_header_id|>assistant<|end_header_id|>{% endif %}";
+
+    fn request() -> ChatCompletionRequest {
+        serde_json::from_str(r#"{"model": "test", "messages": [{"role": "system", "content": "be nice"}, {"role": "user", "content": [{"type": "text", "text": "hello"}]}]}"#).unwrap()
+    }
+
+    #[test]
+    fn render_chat_template() {
+        let template = ChatTemplate::new(LLAMA3_TEMPLATE, "<|begin_of_text|>", "<|eot_id|>", PromptTemplate::Llama3);
+        assert_eq!(template.render(&request()), build_prompt(PromptTemplate::Llama3, &request()));
+    }
+
+    #[test]
+    fn render_image_parts() {
+        let template = "{% for message in messages %}{% if message['content'] is string %}{{ message['content'] }}{% else %}{% for content in message['content'] %}{% if content['type'] == 'image' %}{{ '<|image|>' }}{% elif content['type'] == 'text' %}{{ content['text'] }}{% endif %}{% endfor %}{% endif %}|{% endfor %}";
+        let request: ChatCompletionRequest = serde_json::from_str(
+            r#"{"model": "test", "messages": [{"role": "system", "content": "be nice"}, {"role": "user", "content": [{"type": "text", "text": "what is"}, {"type": "image_url", "image_url": {"url": "data:image/png;base64,"}}]}]}"#,
+        )
+        .unwrap();
+        let template = ChatTemplate::new(template, "", "", PromptTemplate::Llama3);
+        assert_eq!(template.render(&request), "be nice|what is<|image|>|");
+        assert_eq!(
+            build_prompt(PromptTemplate::Llama3, &request),
+            "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\nbe nice<|eot_id|><|start_header_id|>user<|end_header_id|>\n<|image|>what is<|eot_id|><|start_header_id|>assistant<|end_header_id|>"
+        );
+    }
+
+    #[test]
+    fn fallback_to_builtin() {
+        let template = ChatTemplate::new("{% if %}", "", "", PromptTemplate::Phi3);
+        assert_eq!(template.render(&request()), "<|system|>\nbe nice<|end|><|user|>\nhello<|end|><|assistant|>\n");
+
+        let template = ChatTemplate::new("{{ raise_exception('unsupported') }}", "", "", PromptTemplate::Phi3);
+        assert_eq!(template.render(&request()), build_prompt(PromptTemplate::Phi3, &request()));
+    }
+
+    #[test]
+    fn builtin_chatml() {
+        assert_eq!(
+            build_prompt(PromptTemplate::ChatMl, &request()),
+            "<|im_start|>system\nbe nice<|im_end|>\n<|im_start|>user\nhello<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn builtin_gemma() {
+        assert_eq!(
+            build_prompt(PromptTemplate::Gemma, &request()),
+            "<bos><start_of_turn>user\nbe nice\n\nhello<end_of_turn>\n<start_of_turn>model\n"
+        );
+    }
+
+    #[test]
+    fn builtin_mistral() {
+        assert_eq!(build_prompt(PromptTemplate::Mistral, &request()), "<s>[INST] be nice\n\nhello [/INST]");
+    }
+}
@@ -0,0 +1,86 @@
+//! Qwen2 and Qwen2.5 are llama layers with biases on the query, key and value projections,
+//! so they run with the llama pre/layers/post and are sharded like llama.
+
+use candle_core::{bail, Result};
+
+use crate::llama::{
+    internal::{Config, LlamaEosToks},
+    LlamaLayersWorker, LlamaModel,
+};
+
+/// ChatML turns end with this token, which is not the eos token of every qwen2 config
+pub(crate) const IM_END_TOKEN: &str = "<|im_end|>";
+
+pub type Qwen2Model<W> = LlamaModel<W>;
+pub type Qwen2LayersWorker = LlamaLayersWorker;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Qwen2Config {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub max_position_embeddings: usize,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f32,
+    pub rms_norm_eps: f64,
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
+    pub bos_token_id: Option<u32>,
+    pub eos_token_id: Option<u32>,
+    #[serde(default)]
+    pub use_sliding_window: bool,
+}
+
+fn default_rope_theta() -> f32 {
+    1_000_000.0
+}
+
+impl Qwen2Config {
+    pub(crate) fn into_config(self, use_flash_attn: bool) -> Result<Config> {
+        if self.use_sliding_window {
+            bail!("sliding window attention of qwen2 is not supported")
+        }
+        Ok(Config {
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads,
+            use_flash_attn,
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+            bos_token_id: self.bos_token_id,
+            eos_token_id: self.eos_token_id.map(LlamaEosToks::Single),
+            rope_scaling: None,
+            max_position_embeddings: self.max_position_embeddings,
+            tie_word_embeddings: self.tie_word_embeddings,
+            attention_bias: true,
+            rope_interleaved: false,
+            rope_freq_factors: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Qwen2Config;
+
+    #[test]
+    fn qwen25_config() {
+        // config.json of Qwen2.5-1.5B-Instruct
+        let json = r#"{"architectures": ["Qwen2ForCausalLM"], "attention_dropout": 0.0, "bos_token_id": 151643, "eos_token_id": 151645, "hidden_act": "silu", "hidden_size": 1536, "initializer_range": 0.02, "intermediate_size": 8960, "max_position_embeddings": 32768, "max_window_layers": 21, "model_type": "qwen2", "num_attention_heads": 12, "num_hidden_layers": 28, "num_key_value_heads": 2, "rms_norm_eps": 1e-06, "rope_theta": 1000000.0, "sliding_window": 32768, "tie_word_embeddings": true, "torch_dtype": "bfloat16", "use_cache": true, "use_sliding_window": false, "vocab_size": 151936}"#;
+        let config = serde_json::from_str::<Qwen2Config>(json).unwrap().into_config(false).unwrap();
+        assert!(config.attention_bias);
+        assert!(config.tie_word_embeddings);
+        assert!(!config.rope_interleaved);
+        assert_eq!(config.num_key_value_heads, 2);
+        assert_eq!(config.rope_theta, 1_000_000.0);
+
+        let sliding = json.replace(r#""use_sliding_window": false"#, r#""use_sliding_window": true"#);
+        assert!(serde_json::from_str::<Qwen2Config>(&sliding).unwrap().into_config(false).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::path::{Path, PathBuf};
+
+use candle_core::Result;
+use hf_hub::{api::tokio::Api, Cache, Repo, RepoType};
+
+/// Where the files of a model are read from.
+///
+/// By default files are downloaded from the hf-hub repo (or reused from the hf-hub cache).
+/// With `local_dir` every file is read from that directory, and with `offline` the network is never used,
+/// only the local directory or the hf-hub cache.
+/// A filename which is an absolute path is always used as is.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceSource {
+    pub local_dir: Option<PathBuf>,
+    pub offline: bool,
+}
+
+impl ResourceSource {
+    pub fn local(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            local_dir: Some(dir.into()),
+            offline: true,
+        }
+    }
+
+    /// Resolve a single file of the repo, returning an error which names the missing file.
+    pub async fn get(&self, repo: &str, filename: &str) -> Result<PathBuf> {
+        let path = Path::new(filename);
+        if path.is_absolute() {
+            if path.is_file() {
+                return Ok(path.to_path_buf());
+            }
+            candle_core::bail!("model file {} not found", path.display())
+        }
+
+        if let Some(dir) = &self.local_dir {
+            let path = dir.join(filename);
+            if path.is_file() {
+                return Ok(path);
+            }
+            candle_core::bail!("model file {filename} not found in {}", dir.display())
+        }
+
+        let hub_repo = Repo::with_revision(repo.to_string(), RepoType::Model, "main".to_string());
+        if self.offline {
+            match Cache::default().repo(hub_repo).get(filename) {
+                Some(path) => Ok(path),
+                None => candle_core::bail!("model file {filename} of {repo} is not in the hf-hub cache and offline mode is enabled"),
+            }
+        } else {
+            let api = Api::new().map_err(candle_core::Error::wrap)?;
+            api.repo(hub_repo)
+                .get(filename)
+                .await
+                .map_err(|e| candle_core::Error::Msg(format!("cannot get model file {filename} of {repo}: {e}")))
+        }
+    }
+
+    /// Resolve the safetensors files of the repo.
+    /// If `filename` is a json index file, all files in its weight map are resolved, and every missing one is reported.
+    pub async fn get_safetensors(&self, repo: &str, filename: &str) -> Result<Vec<PathBuf>> {
+        if !filename.ends_with(".json") {
+            return Ok(vec![self.get(repo, filename).await?]);
+        }
+
+        let json_file = self.get(repo, filename).await?;
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&json_file)?).map_err(candle_core::Error::wrap)?;
+        let weight_map = match json.get("weight_map") {
+            None => candle_core::bail!("no weight map in {json_file:?}"),
+            Some(serde_json::Value::Object(map)) => map,
+            Some(_) => candle_core::bail!("weight map in {json_file:?} is not a map"),
+        };
+        let mut safetensors_files = weight_map.values().filter_map(|v| v.as_str()).collect::<Vec<_>>();
+        safetensors_files.sort();
+        safetensors_files.dedup();
+
+        // relative files in the index are next to the index itself
+        let index_dir = Path::new(filename).parent().filter(|p| !p.as_os_str().is_empty());
+        let mut result = vec![];
+        let mut missing = vec![];
+        for file in safetensors_files {
+            let file = match index_dir {
+                Some(dir) => dir.join(file).to_string_lossy().to_string(),
+                None => file.to_string(),
+            };
+            match self.get(repo, &file).await {
+                Ok(path) => result.push(path),
+                Err(e) => missing.push(e.to_string()),
+            }
+        }
+        if !missing.is_empty() {
+            candle_core::bail!("cannot resolve weights of {filename}: {}", missing.join(", "))
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,109 @@
+/// Find stop strings in streamed text.
+///
+/// A stop string can be split over many decoded chunks, so the text which may be the start of a stop string is held back
+/// until it either completes the stop string or cannot match anymore. The stop string itself is never emitted.
+pub struct StopMatcher {
+    stops: Vec<String>,
+    pending: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StopMatch {
+    /// Text which is safe to emit, can be empty
+    Continue(String),
+    /// A stop string is found, with the text before it
+    Stop(String),
+}
+
+impl StopMatcher {
+    pub fn new(stops: &[String]) -> Self {
+        Self {
+            stops: stops.iter().filter(|s| !s.is_empty()).cloned().collect(),
+            pending: String::new(),
+        }
+    }
+
+    pub fn push(&mut self, text: &str) -> StopMatch {
+        self.pending.push_str(text);
+        if let Some(pos) = self.stops.iter().filter_map(|stop| self.pending.find(stop.as_str())).min() {
+            let mut out = std::mem::take(&mut self.pending);
+            out.truncate(pos);
+            return StopMatch::Stop(out);
+        }
+
+        let hold = self.holdback_len();
+        let out = self.pending[..self.pending.len() - hold].to_string();
+        self.pending.drain(..self.pending.len() - hold);
+        StopMatch::Continue(out)
+    }
+
+    /// Take the held back text when the generation ends without a stop string
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Length of the longest suffix of the pending text which is a prefix of a stop string
+    fn holdback_len(&self) -> usize {
+        self.pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                let suffix = &self.pending[i..];
+                self.stops.iter().any(|stop| stop.starts_with(suffix))
+            })
+            .map(|i| self.pending.len() - i)
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StopMatch, StopMatcher};
+
+    fn matcher(stops: &[&str]) -> StopMatcher {
+        StopMatcher::new(&stops.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn no_stops() {
+        let mut matcher = matcher(&[]);
+        assert_eq!(matcher.push("hello"), StopMatch::Continue("hello".to_string()));
+        assert_eq!(matcher.flush(), "");
+    }
+
+    #[test]
+    fn stop_in_one_chunk() {
+        let mut matcher = matcher(&["###"]);
+        assert_eq!(matcher.push("hello ### world"), StopMatch::Stop("hello ".to_string()));
+    }
+
+    #[test]
+    fn stop_across_chunks() {
+        let mut matcher = matcher(&["</answer>"]);
+        assert_eq!(matcher.push("42 </"), StopMatch::Continue("42 ".to_string()));
+        assert_eq!(matcher.push("ans"), StopMatch::Continue("".to_string()));
+        assert_eq!(matcher.push("wer> tail"), StopMatch::Stop("".to_string()));
+    }
+
+    #[test]
+    fn partial_match_released() {
+        let mut matcher = matcher(&["</answer>"]);
+        assert_eq!(matcher.push("a </an"), StopMatch::Continue("a ".to_string()));
+        assert_eq!(matcher.push("d b"), StopMatch::Continue("</and b".to_string()));
+        assert_eq!(matcher.push("</a"), StopMatch::Continue("".to_string()));
+        assert_eq!(matcher.flush(), "</a");
+    }
+
+    #[test]
+    fn earliest_stop_wins() {
+        let mut matcher = matcher(&["world", "lo"]);
+        assert_eq!(matcher.push("hello world"), StopMatch::Stop("hel".to_string()));
+    }
+
+    #[test]
+    fn multi_byte_chars() {
+        let mut matcher = matcher(&["éa"]);
+        assert_eq!(matcher.push("cafe café"), StopMatch::Continue("cafe caf".to_string()));
+        assert_eq!(matcher.push("au"), StopMatch::Stop("".to_string()));
+    }
+}
@@ -0,0 +1,61 @@
+use candle_core::{Result, Tensor};
+use protocol::{ChatCfg, ChatEvent, FinishReason};
+use tokio::sync::mpsc::Sender;
+
+use crate::{chat_output::ChatOutput, constraint::TokenConstraint, conversation::ChatSteps, logits_processor::LogitsProcessor, utils::apply_penalties};
+
+/// Samples the generated tokens, with the penalties, constraint and stop conditions of the chat
+pub(crate) struct TokenSampler {
+    pub cfg: ChatCfg,
+    logits_processor: LogitsProcessor,
+    constraint: Option<TokenConstraint>,
+    pub output: ChatOutput,
+    eos_tokens: Vec<u32>,
+    prompt_len: usize,
+    pub generated: u32,
+}
+
+impl TokenSampler {
+    pub fn new<M: ChatSteps + ?Sized>(model: &M, cfg: ChatCfg, prompt_len: usize, tx: Sender<ChatEvent>) -> Result<Self> {
+        let eos_tokens = model.eos_tokens();
+        let constraint = match &cfg.constraint {
+            Some(constraint) => {
+                let eos_tokens = eos_tokens.iter().chain(&cfg.stop_token_ids).copied().collect();
+                Some(TokenConstraint::new(constraint, model.token_vocab(), eos_tokens)?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            logits_processor: LogitsProcessor::from_cfg(&cfg),
+            output: ChatOutput::new(model.tokenizer().clone(), &cfg, tx),
+            cfg,
+            constraint,
+            eos_tokens,
+            prompt_len,
+            generated: 0,
+        })
+    }
+
+    /// Sample the token following `tokens` from its logits and push it, returns the finish reason once the generation ends
+    pub async fn next(&mut self, logits: Tensor, tokens: &mut Vec<u32>) -> Result<Option<FinishReason>> {
+        let logits = apply_penalties(logits, &self.cfg, tokens, &tokens[self.prompt_len..])?;
+        let logits = match self.constraint.as_mut() {
+            Some(constraint) => constraint.mask_logits(&logits)?,
+            None => logits,
+        };
+        let (next_token, logprobs) = self.logits_processor.sample_with_logprobs(&logits, self.cfg.logprobs)?;
+        if let Some(constraint) = self.constraint.as_mut() {
+            constraint.advance(next_token)?;
+        }
+        self.generated += 1;
+        tokens.push(next_token);
+
+        if self.eos_tokens.contains(&next_token) {
+            return Ok(Some(FinishReason::Stop));
+        }
+        if let Some(reason) = self.output.push_token(next_token, logprobs).await? {
+            return Ok(Some(reason));
+        }
+        Ok((self.generated >= self.cfg.max_len).then_some(FinishReason::Length))
+    }
+}
@@ -1,4 +1,28 @@
+use std::collections::HashMap;
+
 use candle_core::{Result, Tensor};
+use protocol::ChatCfg;
+
+/// Apply the penalties and logit bias of the chat config.
+/// The repeat penalty is over the last tokens of `context`, the presence and frequency penalties are over the `generated` tokens.
+pub fn apply_penalties(logits: Tensor, cfg: &ChatCfg, context: &[u32], generated: &[u32]) -> Result<Tensor> {
+    let logits = if cfg.repeat_penalty == 1. {
+        logits
+    } else {
+        let start_at = context.len().saturating_sub(cfg.repeat_last_n);
+        apply_repeat_penalty(&logits, cfg.repeat_penalty, &context[start_at..])?
+    };
+    let logits = if cfg.presence_penalty == 0. && cfg.frequency_penalty == 0. {
+        logits
+    } else {
+        apply_presence_frequency_penalty(&logits, cfg.presence_penalty, cfg.frequency_penalty, generated)?
+    };
+    if cfg.logit_bias.is_empty() {
+        Ok(logits)
+    } else {
+        apply_logit_bias(&logits, &cfg.logit_bias)
+    }
+}
 
 pub fn apply_repeat_penalty(logits: &Tensor, penalty: f32, context: &[u32]) -> Result<Tensor> {
     let device = logits.device();
@@ -21,6 +45,35 @@ pub fn apply_repeat_penalty(logits: &Tensor, penalty: f32, context: &[u32]) -> R
     Tensor::from_vec(logits, logits_len, device)
 }
 
+/// OpenAI style penalties: `presence_penalty` once for every token in the context, plus `frequency_penalty` for each occurrence
+pub fn apply_presence_frequency_penalty(logits: &Tensor, presence_penalty: f32, frequency_penalty: f32, context: &[u32]) -> Result<Tensor> {
+    let device = logits.device();
+    let mut logits = logits.to_dtype(candle_core::DType::F32)?.to_vec1::<f32>()?;
+    let mut counts = HashMap::new();
+    for token_id in context {
+        *counts.entry(*token_id).or_insert(0u32) += 1;
+    }
+    for (token_id, count) in counts {
+        if let Some(logit) = logits.get_mut(token_id as usize) {
+            *logit -= presence_penalty + frequency_penalty * count as f32;
+        }
+    }
+    let logits_len = logits.len();
+    Tensor::from_vec(logits, logits_len, device)
+}
+
+pub fn apply_logit_bias(logits: &Tensor, logit_bias: &HashMap<u32, f32>) -> Result<Tensor> {
+    let device = logits.device();
+    let mut logits = logits.to_dtype(candle_core::DType::F32)?.to_vec1::<f32>()?;
+    for (token_id, bias) in logit_bias {
+        if let Some(logit) = logits.get_mut(*token_id as usize) {
+            *logit += bias;
+        }
+    }
+    let logits_len = logits.len();
+    Tensor::from_vec(logits, logits_len, device)
+}
+
 /// Repeats a key or value tensor for grouped query attention
 /// The input tensor should have a shape `(batch, num_kv_heads, seq_len, head_dim)`,
 pub fn repeat_kv(xs: Tensor, n_rep: usize) -> Result<Tensor> {
@@ -35,26 +88,26 @@ pub fn repeat_kv(xs: Tensor, n_rep: usize) -> Result<Tensor> {
     }
 }
 
-/// Loads the safetensors files for a model from the hub based on a json index file.
-pub async fn hub_load_safetensors(repo: &hf_hub::api::tokio::ApiRepo, json_file: &str) -> Result<Vec<std::path::PathBuf>> {
-    let json_file = repo.get(json_file).await.map_err(candle_core::Error::wrap)?;
-    let json_file = std::fs::File::open(json_file)?;
-    let json: serde_json::Value = serde_json::from_reader(&json_file).map_err(candle_core::Error::wrap)?;
-    let weight_map = match json.get("weight_map") {
-        None => candle_core::bail!("no weight map in {json_file:?}"),
-        Some(serde_json::Value::Object(map)) => map,
-        Some(_) => candle_core::bail!("weight map in {json_file:?} is not a map"),
-    };
-    let mut safetensors_files = std::collections::HashSet::new();
-    for value in weight_map.values() {
-        if let Some(file) = value.as_str() {
-            safetensors_files.insert(file.to_string());
-        }
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{Device, Tensor};
+
+    use super::{apply_logit_bias, apply_presence_frequency_penalty};
+
+    #[test]
+    fn presence_frequency_penalty() {
+        let logits = Tensor::new(&[1f32, 2., 3., 4.], &Device::Cpu).unwrap();
+        let logits = apply_presence_frequency_penalty(&logits, 0.5, 0.25, &[1, 3, 3, 7]).unwrap();
+        assert_eq!(logits.to_vec1::<f32>().unwrap(), vec![1., 1.25, 3., 3.]);
     }
-    let mut result = vec![];
-    for file in safetensors_files.iter() {
-        let file = repo.get(file).await.map_err(candle_core::Error::wrap)?;
-        result.push(file);
+
+    #[test]
+    fn logit_bias() {
+        let logits = Tensor::new(&[1f32, 2., 3., 4.], &Device::Cpu).unwrap();
+        let logit_bias = HashMap::from([(0, 100.), (2, -100.), (9, 1.)]);
+        let logits = apply_logit_bias(&logits, &logit_bias).unwrap();
+        assert_eq!(logits.to_vec1::<f32>().unwrap(), vec![101., 2., -97., 4.]);
     }
-    Ok(result)
 }
@@ -1,6 +1,7 @@
 use std::{
     io,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
 };
 
@@ -11,11 +12,14 @@ use poem::{
     Body, Error, IntoResponse, Response,
 };
 use protocol::Session;
-use protocol::{ChatCfg, ChatCompletionRequest, ModelList};
+use protocol::{BeamSearch, ChatCfg, ChatCompletionRequest, ChatEvent, Constraint, ContextOverflow, ModelList, OverflowPolicy, ResponseFormat, DEFAULT_ATTENTION_SINKS};
 use serde_json::json;
 use tokio::{
     io::AsyncRead,
-    sync::mpsc::{channel, Receiver, Sender},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        oneshot,
+    },
 };
 
 use crate::ModelStore;
@@ -79,11 +83,26 @@ impl AsyncRead for AsyncReadRx {
     }
 }
 
+/// Add the conversation id to a stream chunk of a conversation
+fn with_conversation(mut chunk: serde_json::Value, conversation_id: &Option<String>) -> serde_json::Value {
+    if let Some(conversation_id) = conversation_id {
+        chunk["conversation_id"] = json!(conversation_id);
+    }
+    chunk
+}
+
+/// Same limit as the OpenAI API
+const MAX_TOP_LOGPROBS: usize = 20;
+
 pub struct ChatStartRequest {
     pub session: Session,
+    /// the session is kept after the answer for the next turns of this conversation
+    pub conversation_id: Option<String>,
     pub cfg: ChatCfg,
     pub req: ChatCompletionRequest,
-    pub answer_tx: Sender<String>,
+    pub answer_tx: Sender<ChatEvent>,
+    /// the worker accepts the prompt before streaming the answer, or rejects it with the reason
+    pub accept_tx: oneshot::Sender<Result<(), String>>,
 }
 
 #[handler]
@@ -106,7 +125,7 @@ pub async fn get_model(Path(model_id): Path<String>, data: Data<&ModelStore>) ->
 }
 
 #[handler]
-pub async fn chat_completions(Json(req): Json<ChatCompletionRequest>, data: Data<&Sender<ChatStartRequest>>) -> impl IntoResponse {
+pub async fn chat_completions(Json(mut req): Json<ChatCompletionRequest>, data: Data<&Sender<ChatStartRequest>>) -> impl IntoResponse {
     let mut cfg = ChatCfg::default();
     if let Some(temperature) = req.temperature {
         cfg.temperature = temperature as f64;
@@ -114,29 +133,156 @@ pub async fn chat_completions(Json(req): Json<ChatCompletionRequest>, data: Data
     if let Some(max_tokens) = req.max_tokens {
         cfg.max_len = max_tokens as u32;
     }
+    if let Some(stop) = req.stop.take() {
+        cfg.stop = stop.into_vec();
+    }
+    if let Some(stop_token_ids) = req.stop_token_ids.take() {
+        cfg.stop_token_ids = stop_token_ids;
+    }
+    for (name, p) in [("min_p", req.min_p), ("typical_p", req.typical_p)] {
+        if p.is_some_and(|p| p <= 0. || p > 1.) {
+            return Response::builder().status(StatusCode::BAD_REQUEST).body(format!("{name} must be in (0, 1]"));
+        }
+    }
+    cfg.min_p = req.min_p;
+    cfg.typical_p = req.typical_p;
+    if let Some(presence_penalty) = req.presence_penalty {
+        cfg.presence_penalty = presence_penalty;
+    }
+    if let Some(frequency_penalty) = req.frequency_penalty {
+        cfg.frequency_penalty = frequency_penalty;
+    }
+    if let Some(logit_bias) = req.logit_bias.take() {
+        for (token, bias) in logit_bias {
+            match token.parse::<u32>() {
+                Ok(token) => cfg.logit_bias.insert(token, bias.clamp(-100., 100.)),
+                Err(_) => return Response::builder().status(StatusCode::BAD_REQUEST).body(format!("invalid token id in logit_bias: {token}")),
+            };
+        }
+    }
+    let mut constraints = vec![];
+    match req.response_format.take() {
+        Some(ResponseFormat::JsonObject) => constraints.push(Constraint::JsonSchema(json!({"type": "object"}))),
+        Some(ResponseFormat::JsonSchema { json_schema }) => constraints.push(Constraint::JsonSchema(json_schema.schema.unwrap_or(json!({})))),
+        Some(ResponseFormat::Text) | None => {}
+    }
+    if let Some(regex) = req.guided_regex.take() {
+        constraints.push(Constraint::Regex(regex));
+    }
+    if let Some(grammar) = req.guided_grammar.take() {
+        constraints.push(Constraint::Grammar(grammar));
+    }
+    if constraints.len() > 1 {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("only one of response_format, guided_regex and guided_grammar can be set");
+    }
+    cfg.constraint = constraints.pop();
+    match (req.logprobs, req.top_logprobs) {
+        (_, Some(top_logprobs)) if top_logprobs > MAX_TOP_LOGPROBS => {
+            return Response::builder().status(StatusCode::BAD_REQUEST).body(format!("top_logprobs must be at most {MAX_TOP_LOGPROBS}"));
+        }
+        (Some(true), top_logprobs) => cfg.logprobs = Some(top_logprobs.unwrap_or(0)),
+        (_, Some(_)) => {
+            return Response::builder().status(StatusCode::BAD_REQUEST).body("top_logprobs requires logprobs to be true");
+        }
+        _ => {}
+    }
+    cfg.overflow = match (req.context_overflow, req.attention_sinks) {
+        (None | Some(ContextOverflow::Error), None) => OverflowPolicy::Error,
+        (Some(ContextOverflow::Truncate), None) => OverflowPolicy::Truncate,
+        (Some(ContextOverflow::SlidingWindow), sinks) => OverflowPolicy::SlidingWindow {
+            sinks: sinks.unwrap_or(DEFAULT_ATTENTION_SINKS),
+        },
+        (_, Some(_)) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body("attention_sinks requires context_overflow to be sliding_window");
+        }
+    };
+    cfg.beam_search = match (req.beam_width, req.length_penalty) {
+        (Some(0), _) => return Response::builder().status(StatusCode::BAD_REQUEST).body("beam_width must be at least 1"),
+        (Some(width), length_penalty) => Some(BeamSearch {
+            width,
+            length_penalty: length_penalty.unwrap_or(BeamSearch::default().length_penalty),
+        }),
+        (None, None) => None,
+        (None, Some(_)) => return Response::builder().status(StatusCode::BAD_REQUEST).body("length_penalty requires beam_width"),
+    };
+    if cfg.beam_search.is_some() && (cfg.constraint.is_some() || cfg.logprobs.is_some()) {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("beam_width cannot be combined with response_format, guided_regex, guided_grammar or logprobs");
+    }
+    // the prompts only have image tokens in the user messages
+    if req.messages.iter().any(|message| message.role != "user" && !message.content.images().is_empty()) {
+        return Response::builder().status(StatusCode::BAD_REQUEST).body("image_url parts are only supported in user messages");
+    }
+    for image in req.images() {
+        match image.decode() {
+            Ok(image) => cfg.images.push(Arc::new(image)),
+            Err(e) => return Response::builder().status(StatusCode::BAD_REQUEST).body(e),
+        }
+    }
     let stream = req.stream.unwrap_or(false);
+    let conversation_id = match (req.conversation_id.take(), req.store) {
+        (Some(conversation_id), _) => Some(conversation_id),
+        (None, Some(true)) => Some(format!("conv-{:016x}", rand::random::<u64>())),
+        (None, _) => None,
+    };
 
     if stream {
         let session = Session::new();
         let (tx, mut rx) = channel(1);
         let (stream, stream_tx) = AsyncReadRx::new();
         let plain_text = req.plain_text;
-        if let Err(e) = data.0.send(ChatStartRequest { session, cfg, req, answer_tx: tx }).await {
+        let with_logprobs = cfg.logprobs.is_some();
+        let (accept_tx, accept_rx) = oneshot::channel();
+        let start = ChatStartRequest {
+            session,
+            conversation_id: conversation_id.clone(),
+            cfg,
+            req,
+            answer_tx: tx,
+            accept_tx,
+        };
+        if let Err(e) = data.0.send(start).await {
             return Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(format!("{e:?}"));
         }
+        match accept_rx.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Response::builder().status(StatusCode::BAD_REQUEST).body(e),
+            Err(_) => return Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body("model not available"),
+        }
+        let header_conversation_id = conversation_id.clone();
         tokio::spawn(async move {
-            while let Some(out) = rx.recv().await {
-                let response = match plain_text {
-                    Some(false) | None => json!({
-                        "choices": [
-                            {
-                                "delta": {"content": out},
-                                "index": 0
-                            }
-                        ]
-                    })
+            while let Some(event) = rx.recv().await {
+                let response = match (event, plain_text) {
+                    (ChatEvent::Delta { text, logprobs }, Some(false) | None) => {
+                        let mut choice = json!({
+                            "delta": {"content": text},
+                            "index": 0
+                        });
+                        if with_logprobs {
+                            choice["logprobs"] = json!({ "content": logprobs });
+                        }
+                        with_conversation(json!({ "choices": [choice] }), &conversation_id).to_string()
+                    }
+                    (ChatEvent::Delta { text, .. }, Some(true)) => text,
+                    (ChatEvent::Finish(reason), Some(false) | None) => with_conversation(
+                        json!({
+                            "choices": [
+                                {
+                                    "delta": {},
+                                    "index": 0,
+                                    "finish_reason": reason.as_str()
+                                }
+                            ]
+                        }),
+                        &conversation_id,
+                    )
                     .to_string(),
-                    Some(true) => out,
+                    (ChatEvent::Finish(_), Some(true)) => break,
                 };
                 if let Err(e) = stream_tx.send(response).await {
                     log::error!("error sending message: {}", e);
@@ -147,11 +293,14 @@ pub async fn chat_completions(Json(req): Json<ChatCompletionRequest>, data: Data
 
         let body = poem::Body::from_async_read(stream);
 
-        Response::builder()
+        let mut response = Response::builder()
             .header("Content-Type", "text/event-stream")
             .header("Cache-Control", "no-cache")
-            .header("Connection", "keep-alive")
-            .body(body)
+            .header("Connection", "keep-alive");
+        if let Some(conversation_id) = header_conversation_id {
+            response = response.header("X-Conversation-Id", conversation_id);
+        }
+        response.body(body)
     } else {
         Response::builder().status(StatusCode::BAD_REQUEST).body("Only support stream")
     }
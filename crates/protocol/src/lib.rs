@@ -1,10 +1,12 @@
-use model_router::{LayerRemoteInfo, RouteSync};
+use model_router::{ExpertShard, LayerRemoteInfo, RouteSync};
 use std::fmt::Display;
 use std::ops::Deref;
 
+mod manifest;
 mod model;
 mod openai;
 
+pub use manifest::*;
 pub use model::*;
 pub use openai::*;
 
@@ -61,6 +63,14 @@ impl From<RouteSync> for worker::event::SyncReq {
                     },
                 })
                 .collect::<Vec<_>>(),
+            experts: value.experts.map(|(shard, info)| worker::event::sync_req::ExpertShard {
+                layers_from: shard.layers.start,
+                layers_to: shard.layers.end,
+                experts_from: shard.experts.start,
+                experts_to: shard.experts.end,
+                cost: info.cost,
+                last_updated: info.last_updated,
+            }),
         }
     }
 }
@@ -82,6 +92,18 @@ impl From<worker::event::SyncReq> for RouteSync {
                     }
                 })
                 .collect::<Vec<_>>(),
+            experts: value.experts.map(|shard| {
+                (
+                    ExpertShard {
+                        layers: shard.layers_from..shard.layers_to,
+                        experts: shard.experts_from..shard.experts_to,
+                    },
+                    LayerRemoteInfo {
+                        cost: shard.cost,
+                        last_updated: shard.last_updated,
+                    },
+                )
+            }),
         }
     }
 }
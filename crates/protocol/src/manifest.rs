@@ -0,0 +1,232 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+const BUILTIN_MANIFESTS: &str = include_str!("../manifests/models.json");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelArchitecture {
+    Llama,
+    Phi3,
+    Qwen2,
+    Gemma2,
+    /// llama with mixture-of-experts layers, the experts may be sharded across nodes
+    Mixtral,
+    /// llama 3.2 vision, llama with a vision model and cross-attention layers over the images
+    Mllama,
+    Fake,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelDType {
+    #[default]
+    F16,
+    Bf16,
+    F32,
+}
+
+/// Built-in prompt formats, used for rendering chat messages into the model prompt
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptTemplate {
+    #[default]
+    Llama3,
+    Phi3,
+    /// `<|im_start|>` and `<|im_end|>` turns of qwen2
+    ChatMl,
+    /// `<start_of_turn>` and `<end_of_turn>` turns of gemma, without a system turn
+    Gemma,
+    /// `[INST]` and `[/INST]` turns of mistral, without a system turn
+    Mistral,
+}
+
+/// Describe everything needed for serving a model, so adding a model is a config change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelManifest {
+    pub id: String,
+    pub architecture: ModelArchitecture,
+    #[serde(default)]
+    pub owned_by: String,
+    /// hf-hub repo of the weights
+    #[serde(default)]
+    pub repo: String,
+    /// weights file: safetensors, safetensors index json or gguf
+    #[serde(default)]
+    pub weights: String,
+    #[serde(default)]
+    pub config: Option<String>,
+    /// hf-hub repo of the tokenizer, default to `repo`
+    #[serde(default)]
+    pub tokenizer_repo: Option<String>,
+    #[serde(default = "default_tokenizer")]
+    pub tokenizer: String,
+    /// tokenizer config with the jinja `chat_template`, in the tokenizer repo
+    #[serde(default = "default_tokenizer_config")]
+    pub tokenizer_config: Option<String>,
+    #[serde(default)]
+    pub dtype: ModelDType,
+    /// number of layers, read from the model config when omitted
+    #[serde(default)]
+    pub layers: Option<u32>,
+    /// estimated memory in GB for the whole model
+    #[serde(default)]
+    pub memory: u32,
+    /// built-in prompt format, used when the model has no chat template
+    #[serde(default)]
+    pub prompt_template: PromptTemplate,
+    /// hidden models are not listed by the registry, e.g. for testing
+    #[serde(default)]
+    pub hidden: bool,
+    /// small llama model run by the gateway for speculative decoding
+    #[serde(default)]
+    pub draft: Option<DraftManifest>,
+}
+
+/// Draft model proposing the next tokens, which the layers chain verifies in one forward.
+/// It must have the same tokenizer as the model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DraftManifest {
+    /// hf-hub repo of the weights
+    pub repo: String,
+    #[serde(default = "default_draft_weights")]
+    pub weights: String,
+    #[serde(default)]
+    pub config: Option<String>,
+    #[serde(default)]
+    pub dtype: ModelDType,
+    /// tokens proposed at every step
+    #[serde(default = "default_draft_tokens")]
+    pub tokens: u32,
+}
+
+fn default_tokenizer() -> String {
+    "tokenizer.json".to_string()
+}
+
+fn default_tokenizer_config() -> Option<String> {
+    Some("tokenizer_config.json".to_string())
+}
+
+fn default_draft_weights() -> String {
+    "model.safetensors".to_string()
+}
+
+fn default_draft_tokens() -> u32 {
+    4
+}
+
+impl ModelManifest {
+    pub fn tokenizer_repo(&self) -> &str {
+        self.tokenizer_repo.as_deref().unwrap_or(&self.repo)
+    }
+
+    /// Manifest of the draft model, with the tokenizer and prompt of this model.
+    /// The draft of a qwen2 model is a smaller qwen2, of any other model a llama
+    pub fn draft_manifest(&self) -> Option<ModelManifest> {
+        let draft = self.draft.as_ref()?;
+        let architecture = match self.architecture {
+            ModelArchitecture::Qwen2 => ModelArchitecture::Qwen2,
+            _ => ModelArchitecture::Llama,
+        };
+        Some(ModelManifest {
+            id: format!("{}-draft", self.id),
+            architecture,
+            repo: draft.repo.clone(),
+            weights: draft.weights.clone(),
+            config: draft.config.clone(),
+            tokenizer_repo: Some(self.tokenizer_repo().to_string()),
+            dtype: draft.dtype,
+            layers: None,
+            memory: 0,
+            hidden: true,
+            draft: None,
+            ..self.clone()
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelManifests {
+    models: Vec<ModelManifest>,
+}
+
+impl ModelManifests {
+    pub fn builtin() -> Self {
+        Self::from_json(BUILTIN_MANIFESTS).expect("Should parse builtin manifests")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let models: Vec<ModelManifest> = serde_json::from_str(json).map_err(|e| format!("invalid model manifests: {e}"))?;
+        Ok(Self { models })
+    }
+
+    /// Load manifests from a json file, or the builtin list when no file is given
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        match path {
+            Some(path) => {
+                let json = std::fs::read_to_string(path).map_err(|e| format!("cannot read model manifests {}: {e}", path.display()))?;
+                Self::from_json(&json)
+            }
+            None => Ok(Self::builtin()),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ModelManifest> {
+        self.models.iter().find(|m| m.id == id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ModelManifest> {
+        self.models.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_manifests() {
+        let manifests = ModelManifests::builtin();
+        let phi3 = manifests.get("phi3").expect("Should have phi3");
+        assert_eq!(phi3.architecture, ModelArchitecture::Phi3);
+        assert_eq!(phi3.tokenizer_repo(), "microsoft/Phi-3-mini-4k-instruct");
+        assert_eq!(phi3.layers, Some(32));
+
+        let llama = manifests.get("llama32-1b").expect("Should have llama32-1b");
+        assert_eq!(llama.tokenizer_repo(), "unsloth/Llama-3.2-1B-Instruct");
+        assert_eq!(llama.dtype, ModelDType::F16);
+        assert_eq!(llama.prompt_template, PromptTemplate::Llama3);
+        assert_eq!(llama.draft, None);
+
+        let qwen = manifests.get("qwen25-1.5b").expect("Should have qwen25-1.5b");
+        assert_eq!(qwen.architecture, ModelArchitecture::Qwen2);
+        assert_eq!(qwen.prompt_template, PromptTemplate::ChatMl);
+
+        let gemma = manifests.get("gemma2-9b").expect("Should have gemma2-9b");
+        assert_eq!(gemma.architecture, ModelArchitecture::Gemma2);
+        assert_eq!(gemma.prompt_template, PromptTemplate::Gemma);
+
+        let mixtral = manifests.get("mixtral-8x7b").expect("Should have mixtral-8x7b");
+        assert_eq!(mixtral.architecture, ModelArchitecture::Mixtral);
+        assert_eq!(mixtral.prompt_template, PromptTemplate::Mistral);
+
+        let vision = manifests.get("llama32-vision-11b").expect("Should have llama32-vision-11b");
+        assert_eq!(vision.architecture, ModelArchitecture::Mllama);
+    }
+
+    #[test]
+    fn draft_manifest() {
+        let json = r#"[{"id": "llama31-8b", "architecture": "llama", "repo": "unsloth/Meta-Llama-3.1-8B-Instruct", "weights": "model.safetensors.index.json", "draft": {"repo": "unsloth/Llama-3.2-1B-Instruct"}}]"#;
+        let manifests = ModelManifests::from_json(json).expect("Should parse");
+        let llama = manifests.get("llama31-8b").expect("Should have llama31-8b");
+        assert_eq!(llama.draft.as_ref().map(|d| d.tokens), Some(4));
+
+        let draft = llama.draft_manifest().expect("Should have draft");
+        assert_eq!(draft.repo, "unsloth/Llama-3.2-1B-Instruct");
+        assert_eq!(draft.weights, "model.safetensors");
+        assert_eq!(draft.tokenizer_repo(), "unsloth/Meta-Llama-3.1-8B-Instruct");
+        assert_eq!(draft.draft, None);
+    }
+}
@@ -1,12 +1,42 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{llm::Overflow, TokenLogprob};
+
 #[derive(Debug, Clone)]
 pub struct ChatCfg {
     pub seed: u64,
     pub temperature: f64,
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
+    /// min-p sampling, takes precedence over top-k/top-p
+    pub min_p: Option<f64>,
+    /// locally typical sampling, takes precedence over top-k/top-p
+    pub typical_p: Option<f64>,
     pub max_len: u32,
+    /// prompt tokens sent through the layers chain in one forward, bigger chunks need less round trips but bigger messages
+    pub prefill_chunk_size: usize,
     pub repeat_penalty: f32,
     pub repeat_last_n: usize,
+    /// subtracted once from the logit of every token already generated
+    pub presence_penalty: f32,
+    /// subtracted from the logit of every token for each time it was already generated
+    pub frequency_penalty: f32,
+    /// added to the logit of the token
+    pub logit_bias: HashMap<u32, f32>,
+    /// only generate output matching this constraint
+    pub constraint: Option<Constraint>,
+    /// generation stops before any of these strings, which are not emitted
+    pub stop: Vec<String>,
+    /// generation stops at any of these tokens, in addition to the model EOS tokens
+    pub stop_token_ids: Vec<u32>,
+    /// return the logprob of each generated token with this many most likely alternatives
+    pub logprobs: Option<usize>,
+    /// what the session does when its context outgrows the context window of the model
+    pub overflow: OverflowPolicy,
+    /// answer with the most likely of several beams instead of sampling
+    pub beam_search: Option<BeamSearch>,
+    /// encoded images of the prompt, in the order of the prompt, only vision models accept them
+    pub images: Vec<Arc<Vec<u8>>>,
 }
 
 impl Default for ChatCfg {
@@ -16,9 +46,117 @@ impl Default for ChatCfg {
             temperature: 0.8,
             top_k: None,
             top_p: None,
+            min_p: None,
+            typical_p: None,
             max_len: 1024,
+            prefill_chunk_size: 32,
             repeat_penalty: 1.1,
             repeat_last_n: 128,
+            presence_penalty: 0.,
+            frequency_penalty: 0.,
+            logit_bias: HashMap::new(),
+            constraint: None,
+            stop: vec![],
+            stop_token_ids: vec![],
+            logprobs: None,
+            overflow: OverflowPolicy::default(),
+            beam_search: None,
+            images: vec![],
+        }
+    }
+}
+
+/// Beam search keeping the `width` most likely answers at every step, each beam has its own kv caches on every hop
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeamSearch {
+    pub width: u32,
+    /// the score of a finished answer is its logprob divided by its length to this power, above 0 favors longer answers
+    pub length_penalty: f32,
+}
+
+impl Default for BeamSearch {
+    fn default() -> Self {
+        Self { width: 4, length_penalty: 1. }
+    }
+}
+
+/// Attention sinks kept by a sliding window when the request does not choose, as in StreamingLLM
+pub const DEFAULT_ATTENTION_SINKS: u32 = 4;
+
+/// Handling of a context longer than the context window of the model
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// a prompt longer than the window is rejected, the generation stops at the end of the window
+    #[default]
+    Error,
+    /// the oldest tokens are dropped
+    Truncate,
+    /// the first `sinks` tokens are kept as attention sinks, the oldest ones after them are dropped
+    SlidingWindow { sinks: u32 },
+}
+
+impl OverflowPolicy {
+    /// Tokens always kept at the start of the context
+    pub fn sinks(&self) -> usize {
+        match self {
+            OverflowPolicy::SlidingWindow { sinks } => *sinks as usize,
+            OverflowPolicy::Error | OverflowPolicy::Truncate => 0,
         }
     }
+
+    /// The policy and attention sinks fields of a `StartReq`
+    pub fn to_proto(self) -> (Overflow, u32) {
+        match self {
+            OverflowPolicy::Error => (Overflow::Error, 0),
+            OverflowPolicy::Truncate => (Overflow::Truncate, 0),
+            OverflowPolicy::SlidingWindow { sinks } => (Overflow::SlidingWindow, sinks),
+        }
+    }
+
+    pub fn from_proto(overflow: i32, sinks: u32) -> Self {
+        match Overflow::try_from(overflow) {
+            Ok(Overflow::Truncate) => OverflowPolicy::Truncate,
+            Ok(Overflow::SlidingWindow) => OverflowPolicy::SlidingWindow { sinks },
+            Ok(Overflow::Error) | Err(_) => OverflowPolicy::Error,
+        }
+    }
+}
+
+/// Constrained decoding, the generated text must be matched by the constraint
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constraint {
+    JsonSchema(serde_json::Value),
+    Regex(String),
+    /// GBNF grammar, starting from the `root` rule
+    Grammar(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// EOS token, stop token or stop string
+    Stop,
+    /// reached `max_len`
+    Length,
+    Error,
+}
+
+impl FinishReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+            FinishReason::Error => "error",
+        }
+    }
+}
+
+/// Output of a chat session, streamed to the client
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatEvent {
+    /// generated text, with the logprobs of its tokens when requested
+    Delta {
+        text: String,
+        logprobs: Vec<TokenLogprob>,
+    },
+    Finish(FinishReason),
 }
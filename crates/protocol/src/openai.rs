@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -8,10 +11,35 @@ pub enum StringOrVecContent {
 }
 
 impl StringOrVecContent {
+    /// Text parts of the content, the image parts are in `images`
     pub fn contents(&self) -> Vec<&str> {
         match self {
             StringOrVecContent::String(c) => vec![c],
-            StringOrVecContent::Vec(vec) => vec.iter().map(|c| c.text.as_str()).collect::<Vec<_>>(),
+            StringOrVecContent::Vec(vec) => vec.iter().filter_map(|c| c.text.as_deref()).collect::<Vec<_>>(),
+        }
+    }
+
+    /// Image parts of the content, in order
+    pub fn images(&self) -> Vec<&ImageUrl> {
+        match self {
+            StringOrVecContent::String(_) => vec![],
+            StringOrVecContent::Vec(vec) => vec.iter().filter_map(|c| c.image_url.as_ref()).collect::<Vec<_>>(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum StringOrVec {
+    String(String),
+    Vec(Vec<String>),
+}
+
+impl StringOrVec {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            StringOrVec::String(s) => vec![s],
+            StringOrVec::Vec(vec) => vec,
         }
     }
 }
@@ -20,7 +48,31 @@ impl StringOrVecContent {
 pub struct MessageContent {
     #[serde(rename = "type")]
     pub _type: String,
-    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<ImageUrl>,
+}
+
+/// Image of an `image_url` part, only base64 data URIs are supported
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImageUrl {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl ImageUrl {
+    /// Bytes of the encoded image of a `data:image/...;base64,` URI
+    pub fn decode(&self) -> Result<Vec<u8>, String> {
+        let Some((media_type, data)) = self.url.strip_prefix("data:").and_then(|uri| uri.split_once(',')) else {
+            return Err("only data URI images are supported".to_string());
+        };
+        match media_type.strip_suffix(";base64") {
+            Some(media_type) if media_type.starts_with("image/") => BASE64_STANDARD.decode(data.trim()).map_err(|e| format!("invalid base64 image: {e}")),
+            _ => Err(format!("unsupported data URI {media_type}, only base64 images are supported")),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -37,6 +89,78 @@ pub struct ChatCompletionRequest {
     pub max_tokens: Option<i32>,
     pub stream: Option<bool>,
     pub plain_text: Option<bool>,
+    pub stop: Option<StringOrVec>,
+    pub stop_token_ids: Option<Vec<u32>>,
+    pub logprobs: Option<bool>,
+    pub top_logprobs: Option<usize>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    /// token id (as a string) to bias added to its logit
+    pub logit_bias: Option<HashMap<String, f32>>,
+    pub response_format: Option<ResponseFormat>,
+    /// only generate text matching this regex
+    pub guided_regex: Option<String>,
+    /// only generate text matching this GBNF grammar
+    pub guided_grammar: Option<String>,
+    /// start a conversation, the answer carries its `conversation_id`
+    pub store: Option<bool>,
+    /// continue a conversation, the layers chain still has the previous turns in its kv caches
+    pub conversation_id: Option<String>,
+    /// what to do when the context outgrows the context window of the model, `error` by default
+    pub context_overflow: Option<ContextOverflow>,
+    /// tokens kept at the start of the context by `sliding_window`
+    pub attention_sinks: Option<u32>,
+    /// answer with beam search over this many beams instead of sampling
+    pub beam_width: Option<u32>,
+    /// exponent of the answer length dividing the beam scores, 1 by default
+    pub length_penalty: Option<f32>,
+}
+
+impl ChatCompletionRequest {
+    /// Images of all the messages, in the order of the prompt
+    pub fn images(&self) -> Vec<&ImageUrl> {
+        self.messages.iter().flat_map(|message| message.content.images()).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextOverflow {
+    Error,
+    Truncate,
+    SlidingWindow,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JsonSchemaFormat {
+    pub name: Option<String>,
+    pub schema: Option<serde_json::Value>,
+    pub strict: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Vec<u8>,
+    pub top_logprobs: Vec<TopLogprob>,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -52,3 +176,23 @@ pub struct ModelList {
     pub object: String,
     pub data: Vec<Model>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ChatCompletionRequest;
+
+    #[test]
+    fn image_url_parts() {
+        let json =
+            r#"{"model": "test", "messages": [{"role": "user", "content": [{"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0K"}}, {"type": "text", "text": "what is it?"}]}]}"#;
+        let req = serde_json::from_str::<ChatCompletionRequest>(json).unwrap();
+        assert_eq!(req.messages[0].content.contents(), vec!["what is it?"]);
+        let images = req.images();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].decode().unwrap(), vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a]);
+
+        let json = json.replace("data:image/png;base64,iVBORw0K", "https://example.com/cat.png");
+        let req = serde_json::from_str::<ChatCompletionRequest>(&json).unwrap();
+        assert!(req.images()[0].decode().is_err());
+    }
+}
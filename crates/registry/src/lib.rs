@@ -1,3 +1,4 @@
+use protocol::{ModelManifest, ModelManifests};
 use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "client")]
@@ -21,31 +22,28 @@ pub struct ModelDistribution {
     pub layers: Vec<usize>,
 }
 
-#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ModelInfo {
-    pub id: &'static str,
+    pub id: String,
     pub layers: usize,
     pub memory: usize,
 }
 
-pub const SUPPORTED_MODELS: [ModelInfo; 3] = [
-    ModelInfo {
-        id: "llama32-1b",
-        layers: 16,
-        memory: 3,
-    },
-    ModelInfo {
-        id: "llama32-3b",
-        layers: 28,
-        memory: 8,
-    },
-    ModelInfo {
-        id: "phi3",
-        layers: 32,
-        memory: 4,
-    },
-];
-
-pub fn get_model_info(id: &str) -> Option<&ModelInfo> {
-    SUPPORTED_MODELS.iter().find(|m| m.id == id)
-}
\ No newline at end of file
+impl TryFrom<&ModelManifest> for ModelInfo {
+    type Error = String;
+
+    /// The registry can not read the model config, so the manifest must declare the layers
+    fn try_from(manifest: &ModelManifest) -> Result<Self, Self::Error> {
+        let layers = manifest.layers.ok_or_else(|| format!("model {} manifest has no layers", manifest.id))?;
+        Ok(Self {
+            id: manifest.id.clone(),
+            layers: layers as usize,
+            memory: manifest.memory as usize,
+        })
+    }
+}
+
+/// Models which are listed by the registry, hidden models are skipped
+pub fn supported_models(manifests: &ModelManifests) -> Result<Vec<ModelInfo>, String> {
+    manifests.iter().filter(|m| !m.hidden).map(ModelInfo::try_from).collect()
+}
@@ -8,7 +8,7 @@ use poem::{
 };
 use protobuf_stream::ProtobufStream;
 use serde::Serialize;
-use std::{collections::HashMap, net::SocketAddr};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 mod protobuf_stream;
@@ -16,7 +16,7 @@ mod session_manager;
 
 use session_manager::{NodeInfo, SessionManager};
 
-use crate::{get_model_info, ModelDistribution, ModelId, ModelInfo, SUPPORTED_MODELS};
+use crate::{ModelDistribution, ModelId, ModelInfo};
 
 enum StreamEvent {
     Start(ModelId, NodeId, Sender<protocol::registry::to_worker::Event>),
@@ -28,6 +28,7 @@ enum StreamEvent {
 
 pub struct RegistryServer {
     models: HashMap<ModelId, SessionManager>,
+    models_info: Arc<Vec<ModelInfo>>,
     stream_rx: Receiver<StreamEvent>,
     streams_tx: HashMap<(ModelId, NodeId), Sender<protocol::registry::to_worker::Event>>,
 }
@@ -47,15 +48,17 @@ struct ModelSwarmHealth {
 }
 
 impl RegistryServer {
-    pub fn new(http_addr: SocketAddr) -> Self {
+    pub fn new(http_addr: SocketAddr, models_info: Vec<ModelInfo>) -> Self {
         let (stream_tx, stream_rx) = channel(10);
+        let models_info = Arc::new(models_info);
+        let list_models_info = models_info.clone();
         tokio::spawn(async move {
             log::info!("[RegistryServer] listen on ws://{http_addr}");
             let app = Route::new()
                 .at("/api/:model/distribution", get(distribution.data(stream_tx.clone())))
                 .at("/ws/:model/:node", get(ws.data(stream_tx.clone())))
                 .at("/api/health", get(health.data(stream_tx)))
-                .at("/api/models", get(list_models))
+                .at("/api/models", get(list_models.data(list_models_info)))
                 .with(Cors::new());
 
             Server::new(TcpListener::bind(http_addr)).run(app).await
@@ -63,6 +66,7 @@ impl RegistryServer {
 
         Self {
             models: Default::default(),
+            models_info,
             stream_rx,
             streams_tx: HashMap::new(),
         }
@@ -119,7 +123,7 @@ impl RegistryServer {
                                 info: info.clone(),
                             })
                             .collect();
-                        let model_info = get_model_info(&model.0);
+                        let model_info = self.models_info.iter().find(|m| m.id == model.0);
                         if model_info.is_none() {
                             log::warn!("[RegistryServer] Unexpected model, Mode {} not found", &model.0);
                         }
@@ -166,8 +170,8 @@ async fn health(stream_tx: Data<&Sender<StreamEvent>>) -> impl IntoResponse {
 }
 
 #[handler]
-async fn list_models() -> impl IntoResponse {
-    Json(SUPPORTED_MODELS).into_response()
+async fn list_models(models_info: Data<&Arc<Vec<ModelInfo>>>) -> impl IntoResponse {
+    Json(models_info.as_slice()).into_response()
 }
 
 #[handler]
@@ -17,30 +17,33 @@ use tokio::time::Interval;
 
 use crate::{rpc::RpcClientRx, ServiceHandler};
 
-pub struct WorkerCommunication<const MODEL_LAYERS: usize> {
+pub struct WorkerCommunication {
     registry_client: RegistryClient,
-    router: Arc<RwLock<RouteTable<NodeId, MODEL_LAYERS>>>,
+    router: Arc<RwLock<RouteTable<NodeId>>>,
     network: NetworkNode<protocol::worker::Event>,
     ticker: Interval,
-    rpc_handler: Arc<dyn ServiceHandler<MODEL_LAYERS>>,
+    rpc_handler: Arc<dyn ServiceHandler>,
     rpc_rx: RpcClientRx,
     res_tx: Sender<(NodeId, RpcRes)>,
     res_rx: Receiver<(NodeId, RpcRes)>,
 }
 
-impl<const MODEL_LAYERS: usize> WorkerCommunication<MODEL_LAYERS> {
+impl WorkerCommunication {
     // TODO make layers_worker generic
     pub async fn new(
         registry_endpoint: &str,
         model: &str,
         node_id: &str,
         range: Range<u32>,
-        router: Arc<RwLock<RouteTable<NodeId, MODEL_LAYERS>>>,
+        router: Arc<RwLock<RouteTable<NodeId>>>,
         rpc_rx: RpcClientRx,
-        rpc_handler: Arc<dyn ServiceHandler<MODEL_LAYERS>>,
+        rpc_handler: Arc<dyn ServiceHandler>,
         stun_servers: Vec<SocketAddr>,
     ) -> Self {
-        log::info!("[WorkerComunication] start with node {node_id} with model {model}, layers [{range:?}] / total {MODEL_LAYERS}");
+        log::info!(
+            "[WorkerComunication] start with node {node_id} with model {model}, layers [{range:?}] / total {}",
+            router.read().model_layers()
+        );
         let node_id = NodeId(node_id.to_string());
         let mut registry_client = RegistryClient::new(registry_endpoint, model, node_id.clone()).await;
         registry_client.update_layer(range.clone());
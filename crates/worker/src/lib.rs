@@ -1,5 +1,6 @@
 mod communication;
 mod model_service;
+mod remote_experts;
 mod rpc;
 mod virtual_model_layers;
 
@@ -16,36 +17,50 @@ use protocol::{
     registry::to_registry::Stats,
     worker::event::{RpcReq, RpcRes},
 };
+use remote_experts::RpcRemoteExperts;
 use rpc::create_rpc;
 use spin::RwLock;
 use usage_service::WorkerUsageService;
 pub use virtual_model_layers::*;
 
 #[async_trait::async_trait]
-pub trait ServiceHandler<const MODEL_LAYERS: usize>: Send + Sync + 'static {
+pub trait ServiceHandler: Send + Sync + 'static {
     fn tick(&self);
     fn sessions(&self) -> Vec<u64>;
     fn stats(&self) -> Stats;
     async fn on_req(&self, from: NodeId, req: RpcReq) -> RpcRes;
 }
 
-pub struct WorkerRunner<const MODEL_LAYERS: usize> {
-    communication: WorkerCommunication<MODEL_LAYERS>,
+pub struct WorkerRunner {
+    communication: WorkerCommunication,
 }
 
-impl<const MODEL_LAYERS: usize> WorkerRunner<MODEL_LAYERS> {
+impl WorkerRunner {
     pub async fn new<LW: ModelLayersWorker<(Tensor, u32)>>(
         registry_endpoint: &str,
         model: &str,
+        model_layers: u32,
         node_id: &str,
         range: Range<u32>,
         layers: LW,
         device: Device,
         stun_servers: Vec<SocketAddr>,
         usage_service: Arc<dyn WorkerUsageService>,
-    ) -> (Self, VirtualModelLayers<LW, MODEL_LAYERS>) {
-        let router = Arc::new(RwLock::new(RouteTable::new(range.clone())));
+    ) -> (Self, VirtualModelLayers<LW>) {
+        let mut table = RouteTable::new(model_layers, range.clone());
+        // the experts missing on this node are sent to their owners
+        if let Some((num_experts, experts)) = layers.local_experts() {
+            table = table.with_experts(num_experts, experts);
+        }
+        let router = Arc::new(RwLock::new(table));
         let (rpc_client, rpc_rx) = create_rpc();
+        if layers.local_experts().is_some() {
+            layers.set_remote_experts(Arc::new(RpcRemoteExperts {
+                rpc: rpc_client.clone(),
+                router: router.clone(),
+                device: device.clone(),
+            }));
+        }
         let model_service = Arc::new(ModelService::new(layers, device.clone(), rpc_client.clone(), router.clone(), usage_service));
         let communication = WorkerCommunication::new(registry_endpoint, model, node_id, range, router, rpc_rx, model_service.clone(), stun_servers).await;
         (Self { communication }, VirtualModelLayers { device, model_service })
@@ -1,18 +1,19 @@
-use std::{io::Read, ops::Range, sync::Arc, time::Instant};
+use std::{collections::BTreeMap, io::Read, ops::Range, sync::Arc, time::Instant};
 
 use candle_core::{Device, Tensor};
+use futures_util::future::join_all;
 use model_router::RouteTable;
-use models::{remote::TensorBuf, ModelLayersWorker};
+use models::{remote::TensorBuf, CrossAttentionStates, ImageSpan, ModelLayersWorker};
 use p2p_network::addr::NodeId;
 use prost::Message;
 use protocol::{
     llm::*,
     registry::to_registry::Stats,
     worker::event::{RpcReq, RpcRes},
-    ChatCfg, Session,
+    ChatCfg, OverflowPolicy, Session,
 };
 use spin::RwLock;
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, watch};
 use usage_service::WorkerUsageService;
 use utils::shared_map::SharedHashMap;
 
@@ -23,6 +24,12 @@ struct SessionContainer {
     chat_id: u64,
     local: Option<Range<u32>>,
     remote: Option<(NodeId, Session)>,
+    /// first layer of the next hops, the chain runs the layers in order
+    next_layer: u32,
+    /// index_pos of the next forward to run on the local layers, the chunks of a prefill can arrive in any order
+    next_pos: Arc<watch::Sender<u32>>,
+    /// forked from a session of the chat, the usage service only knows that one
+    forked: bool,
 }
 
 pub enum WorkerEvent {
@@ -36,19 +43,19 @@ pub struct WorkerEventWithResp {
     pub resp: Option<oneshot::Sender<bool>>,
 }
 
-pub struct ModelService<LW, const MODEL_LAYERS: usize> {
+pub struct ModelService<LW> {
     device: Device,
     layers: LW,
     rpc: RpcClientTx,
     sessions: SharedHashMap<Session, SessionContainer>,
-    router: Arc<RwLock<RouteTable<NodeId, MODEL_LAYERS>>>,
+    router: Arc<RwLock<RouteTable<NodeId>>>,
     usage_service: Arc<dyn WorkerUsageService>,
     stats: RwLock<Stats>,
     last_updated_stats: RwLock<(Instant, Stats)>,
 }
 
-impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_LAYERS: usize> ModelService<LW, MODEL_LAYERS> {
-    pub fn new(layers: LW, device: Device, rpc: RpcClientTx, router: Arc<RwLock<RouteTable<NodeId, MODEL_LAYERS>>>, usage_service: Arc<dyn WorkerUsageService>) -> Self {
+impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static> ModelService<LW> {
+    pub fn new(layers: LW, device: Device, rpc: RpcClientTx, router: Arc<RwLock<RouteTable<NodeId>>>, usage_service: Arc<dyn WorkerUsageService>) -> Self {
         Self {
             layers,
             device,
@@ -63,7 +70,7 @@ impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_L
 }
 
 #[async_trait::async_trait]
-impl<LW: ModelLayersWorker<(Tensor, u32)>, const MODEL_LAYERS: usize> ServiceHandler<MODEL_LAYERS> for ModelService<LW, MODEL_LAYERS> {
+impl<LW: ModelLayersWorker<(Tensor, u32)>> ServiceHandler for ModelService<LW> {
     fn tick(&self) {
         let mut last_updated_stats = self.last_updated_stats.write();
         let milis = (*last_updated_stats).0.elapsed().as_millis() as u64;
@@ -84,7 +91,14 @@ impl<LW: ModelLayersWorker<(Tensor, u32)>, const MODEL_LAYERS: usize> ServiceHan
     }
 
     fn stats(&self) -> Stats {
-        self.stats.read().clone()
+        let kv = self.layers.kv_usage();
+        Stats {
+            kv_used_bytes: kv.used_bytes,
+            kv_max_bytes: kv.max_bytes,
+            kv_sessions: kv.sessions,
+            kv_evictions: kv.evictions,
+            ..*self.stats.read()
+        }
     }
 
     async fn on_req(&self, _from: NodeId, req: RpcReq) -> RpcRes {
@@ -106,6 +120,48 @@ impl<LW: ModelLayersWorker<(Tensor, u32)>, const MODEL_LAYERS: usize> ServiceHan
                 self.more_net_out(payload.len());
                 RpcRes { seq: req.seq, success: true, payload }
             }
+            "FORWARD_BATCH" => {
+                self.more_net_in(req.payload.len());
+                let forward_req = ForwardBatchReq::decode(req.payload.as_slice()).unwrap();
+                let res = self.forward_batch(forward_req).await;
+                let mut payload = Vec::new();
+                res.encode(&mut payload).unwrap();
+
+                self.more_net_out(payload.len());
+                RpcRes { seq: req.seq, success: true, payload }
+            }
+            "ROLLBACK" => {
+                let rollback_req = RollbackReq::decode(req.payload.as_slice()).unwrap();
+                let res = self.rollback(rollback_req).await;
+                let mut payload = Vec::new();
+                res.encode(&mut payload).unwrap();
+                RpcRes { seq: req.seq, success: true, payload }
+            }
+            "CROSS_ATTENTION" => {
+                self.more_net_in(req.payload.len());
+                let cross_req = CrossAttentionReq::decode(req.payload.as_slice()).unwrap();
+                let res = self.cross_attention(cross_req).await;
+                let mut payload = Vec::new();
+                res.encode(&mut payload).unwrap();
+                RpcRes { seq: req.seq, success: true, payload }
+            }
+            "FORK" => {
+                let fork_req = ForkReq::decode(req.payload.as_slice()).unwrap();
+                let res = self.fork(fork_req).await;
+                let mut payload = Vec::new();
+                res.encode(&mut payload).unwrap();
+                RpcRes { seq: req.seq, success: true, payload }
+            }
+            "EXPERT_FORWARD" => {
+                self.more_net_in(req.payload.len());
+                let expert_req = ExpertForwardReq::decode(req.payload.as_slice()).unwrap();
+                let res = self.forward_experts(expert_req).await;
+                let mut payload = Vec::new();
+                res.encode(&mut payload).unwrap();
+
+                self.more_net_out(payload.len());
+                RpcRes { seq: req.seq, success: true, payload }
+            }
             "END" => {
                 let end_req = EndReq::decode(req.payload.as_slice()).unwrap();
                 let res = self.end(end_req).await;
@@ -122,7 +178,7 @@ impl<LW: ModelLayersWorker<(Tensor, u32)>, const MODEL_LAYERS: usize> ServiceHan
     }
 }
 
-impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_LAYERS: usize> ModelService<LW, MODEL_LAYERS> {
+impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static> ModelService<LW> {
     pub async fn start(&self, req: StartReq) -> StartRes {
         if let Ok(req) = self.usage_service.pre_start(req.clone()).await {
             log::info!("[ModelService] chat {} start session {} with from_layer {}", req.chat_id, req.session, req.from_layer);
@@ -132,25 +188,40 @@ impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_L
                 route
             } else {
                 log::warn!("[ModelService] chat {} start session {} with from_layer {} but no route", req.chat_id, req.session, req.from_layer);
-                return StartRes { success: false, metadata: vec![] };
+                return StartRes { success: false, ..Default::default() };
             };
 
+            let next_pos = Arc::new(watch::channel(0).0);
             self.sessions.insert(
                 Session(req.session),
                 SessionContainer {
                     chat_id: req.chat_id,
                     local: route.local.clone(),
                     remote: route.remote.as_ref().map(|(d, ..)| (d.clone(), remote_session.clone())),
+                    next_layer: route.remote.as_ref().map_or(u32::MAX, |(_, layers, ..)| layers.start),
+                    next_pos: next_pos.clone(),
+                    forked: false,
                 },
             );
 
-            if let Some(layers) = route.local {
+            let local_cached = if let Some(layers) = route.local {
                 log::info!("[ModelService] start session {} with local layers {layers:?}", req.session);
-                // TODO: Handle Chat Config properly
-                if let Err(_e) = self.layers.start(Session(req.session), ChatCfg::default()).await {
-                    return StartRes { success: false, metadata: vec![] };
+                // the hops only need the settings of their kv caches, the sampling is done by the gateway
+                let cfg = ChatCfg {
+                    overflow: OverflowPolicy::from_proto(req.overflow, req.attention_sinks),
+                    ..Default::default()
+                };
+                match self.layers.start_with_prefix(Session(req.session), cfg, req.prefixes.clone()).await {
+                    Ok(cached) => {
+                        // the first forward starts after the cached prefix
+                        next_pos.send_replace(cached);
+                        Some(cached)
+                    }
+                    Err(_e) => return StartRes { success: false, ..Default::default() },
                 }
-            }
+            } else {
+                None
+            };
 
             let res = if let Some((dest, layers, _, _)) = &route.remote {
                 log::info!(
@@ -170,6 +241,9 @@ impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_L
                             metadata: req.metadata.clone(),
                             chain_index: req.chain_index + 1,
                             max_tokens: req.max_tokens,
+                            prefixes: req.prefixes.clone(),
+                            overflow: req.overflow,
+                            attention_sinks: req.attention_sinks,
                         },
                     )
                     .await
@@ -178,6 +252,11 @@ impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_L
                         metadata: req.metadata.clone(),
                         ..Default::default()
                     });
+                // a prefix is skipped only if every hop of the chain has it
+                let res = StartRes {
+                    cached_prefix_len: local_cached.map_or(res.cached_prefix_len, |cached| cached.min(res.cached_prefix_len)),
+                    ..res
+                };
                 log::info!(
                     "[ModelService] start session {} with remote {dest:?} layers {layers:?}, remote session {} done",
                     req.session,
@@ -188,13 +267,38 @@ impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_L
                 StartRes {
                     success: true,
                     metadata: req.metadata.clone(),
+                    cached_prefix_len: local_cached.unwrap_or(0),
                 }
             };
             log::info!("[ModelService] chat {} start session {} with from_layer {} done", req.chat_id, req.session, req.from_layer);
             self.usage_service.post_start(req, res).await
         } else {
             log::warn!("[ModelService] chat {} start session {} failed to pre_start", req.chat_id, req.session.clone());
-            return StartRes { success: false, metadata: vec![] };
+            return StartRes { success: false, ..Default::default() };
+        }
+    }
+
+    /// Run the local experts of a layer for the hop which runs the layer
+    pub async fn forward_experts(&self, req: ExpertForwardReq) -> ExpertForwardRes {
+        log::debug!("[ModelService] forward {} inputs to experts of layer {}", req.inputs.len(), req.layer);
+        let inputs = req
+            .inputs
+            .into_iter()
+            .map(|input| Ok((input.expert, TensorBuf::try_from(input.hidden).map_err(candle_core::Error::wrap)?.to_tensor(&self.device)?)))
+            .collect::<candle_core::Result<Vec<_>>>();
+        let outputs = match inputs {
+            Ok(inputs) => self.layers.forward_experts(req.layer, inputs).await,
+            Err(e) => Err(e),
+        };
+        match outputs {
+            Ok(outputs) => ExpertForwardRes {
+                success: true,
+                outputs: outputs.into_iter().map(|output| TensorBuf::from(output).to_vec()).collect(),
+            },
+            Err(e) => {
+                log::error!("[ModelService] forward experts of layer {} error {e:?}", req.layer);
+                ExpertForwardRes::default()
+            }
         }
     }
 
@@ -203,9 +307,21 @@ impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_L
             if let Ok(req) = self.usage_service.pre_forward(container.chat_id, req.clone()).await {
                 log::info!("[ModelService] session {} forward step {} processing ...", req.session, req.step);
                 let embedding = if let Some(layers) = container.local {
+                    if container.next_pos.subscribe().wait_for(|pos| *pos >= req.index_pos).await.is_err() || !self.sessions.contains_key(&Session(req.session)) {
+                        log::warn!("[ModelService] session {} ended while forward at {} waiting", req.session, req.index_pos);
+                        return ForwardRes { success: false, ..Default::default() };
+                    }
                     log::info!("[ModelService] session {} forward step {} local {layers:?} layers ...", req.session, req.step);
                     let embedding = TensorBuf::try_from(req.embedding.clone()).unwrap().to_tensor(&self.device).unwrap();
-                    let (embedding, _) = self.layers.forward(Session(req.session), req.step, (embedding, req.seq_len), req.index_pos).await.unwrap();
+                    let embedding = match self.layers.forward(Session(req.session), req.step, (embedding, req.seq_len), req.index_pos).await {
+                        Ok((embedding, _)) => embedding,
+                        Err(e) => {
+                            // the kv caches of an evicted session are gone, the chat fails at this step
+                            log::warn!("[ModelService] session {} forward step {} local {layers:?} layers error {e}", req.session, req.step);
+                            return ForwardRes { success: false, ..Default::default() };
+                        }
+                    };
+                    container.next_pos.send_replace(req.index_pos + req.seq_len);
                     self.more_token_out();
                     log::info!("[ModelService] session {} forward step {} local {layers:?} layers done", req.session, req.step);
                     TensorBuf::from(embedding).to_vec()
@@ -271,42 +387,310 @@ impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_L
         res
     }
 
-    pub async fn end(&self, req: EndReq) -> EndRes {
-        if let Some(container) = self.sessions.remove(&Session(req.session)) {
-            if let Ok(req) = self.usage_service.pre_end(container.chat_id, req.clone()).await {
-                log::warn!("[ModelService] session {} ending ...", req.session);
-                if let Some(layers) = container.local {
-                    log::warn!("[ModelService] session {} end local {layers:?} layers", req.session);
-                    self.layers.finish(Session(req.session)).await;
+    /// Forward the steps of several sessions, the local layers run them together and each next hop gets one batched request
+    pub async fn forward_batch(&self, req: ForwardBatchReq) -> ForwardBatchRes {
+        let failed = || ForwardRes { success: false, ..Default::default() };
+        let mut results = vec![None; req.items.len()];
+        let mut ready = vec![];
+        for (i, item) in req.items.into_iter().enumerate() {
+            let session = item.session;
+            let Some(container) = self.sessions.get_clone(&Session(session)) else {
+                log::warn!("[ModelService] forward batch session {session} but not found");
+                results[i] = Some(failed());
+                continue;
+            };
+            match self.usage_service.pre_forward(container.chat_id, item).await {
+                Ok(item) => ready.push((i, container, item)),
+                Err(_) => {
+                    log::warn!("[ModelService] session {session} failed to pre_forward");
+                    results[i] = Some(failed());
+                }
+            }
+        }
+        log::info!("[ModelService] forward batch of {} sessions processing ...", ready.len());
+
+        // output embedding of each ready session, None once it failed
+        let mut embeddings = ready.iter().map(|(_, _, item)| Some(item.embedding.clone())).collect::<Vec<_>>();
+        let mut groups = BTreeMap::<u32, Vec<usize>>::new();
+        for (j, (_, container, item)) in ready.iter().enumerate() {
+            if container.local.is_some() {
+                groups.entry(item.seq_len).or_default().push(j);
+            }
+        }
+        for group in groups.into_values() {
+            let mut batch = vec![];
+            let mut batch_idx = vec![];
+            for j in group {
+                let (_, container, item) = &ready[j];
+                if container.next_pos.subscribe().wait_for(|pos| *pos >= item.index_pos).await.is_err() || !self.sessions.contains_key(&Session(item.session)) {
+                    log::warn!("[ModelService] session {} ended while forward at {} waiting", item.session, item.index_pos);
+                    embeddings[j] = None;
+                    continue;
+                }
+                match TensorBuf::try_from(item.embedding.clone())
+                    .map_err(candle_core::Error::wrap)
+                    .and_then(|buf| buf.to_tensor(&self.device))
+                {
+                    Ok(embedding) => {
+                        batch.push((Session(item.session), item.step, (embedding, item.seq_len), item.index_pos));
+                        batch_idx.push(j);
+                    }
+                    Err(e) => {
+                        log::warn!("[ModelService] session {} forward with invalid embedding {e}", item.session);
+                        embeddings[j] = None;
+                    }
+                }
+            }
+            for (j, output) in batch_idx.into_iter().zip(self.layers.forward_batch(batch).await) {
+                let (_, container, item) = &ready[j];
+                match output {
+                    Ok((embedding, _)) => {
+                        container.next_pos.send_replace(item.index_pos + item.seq_len);
+                        self.more_token_out();
+                        embeddings[j] = Some(TensorBuf::from(embedding).to_vec());
+                    }
+                    Err(e) => {
+                        log::warn!("[ModelService] session {} forward local layers error {e}", item.session);
+                        embeddings[j] = None;
+                    }
                 }
+            }
+        }
 
-                let res = if let Some((dest, remote_session)) = &container.remote {
-                    log::info!("[ModelService] session {} end remote {dest:?} with remote session {}", req.session, remote_session.0);
-                    let res = self
-                        .rpc
-                        .request(
-                            dest.clone(),
-                            "END",
-                            EndReq {
-                                session: remote_session.0,
-                                metadata: req.metadata.clone(),
-                                chain_index: req.chain_index + 1,
-                            },
-                        )
-                        .await
-                        .unwrap_or(EndRes {
-                            success: false,
-                            metadata: req.metadata.clone(),
-                            ..Default::default()
-                        });
-                    log::info!("[ModelService] session {} end remote {dest:?} with remote session {} done", req.session, remote_session.0);
-                    res
-                } else {
-                    EndRes {
-                        success: true,
-                        metadata: req.metadata.clone(),
+        // one request per next hop, all sent together
+        let mut hops = Vec::<(NodeId, Vec<usize>)>::new();
+        for (j, (_, container, _)) in ready.iter().enumerate() {
+            if let (Some((dest, _)), Some(_)) = (&container.remote, &embeddings[j]) {
+                match hops.iter_mut().find(|(d, _)| d == dest) {
+                    Some((_, idxs)) => idxs.push(j),
+                    None => hops.push((dest.clone(), vec![j])),
+                }
+            }
+        }
+        let requests = hops.iter().map(|(dest, idxs)| {
+            let items = idxs
+                .iter()
+                .map(|j| {
+                    let (_, container, item) = &ready[*j];
+                    let (_, remote_session) = container.remote.as_ref().expect("should have remote");
+                    ForwardReq {
+                        session: remote_session.0,
+                        embedding: embeddings[*j].clone().unwrap_or_default(),
+                        step: item.step,
+                        seq_len: item.seq_len,
+                        index_pos: item.index_pos,
+                        metadata: item.metadata.clone(),
+                        chain_index: item.chain_index + 1,
                     }
-                };
+                })
+                .collect::<Vec<_>>();
+            self.more_net_out(items.iter().map(|item| item.embedding.len()).sum());
+            log::info!("[ModelService] forward batch of {} sessions to remote {dest:?}", items.len());
+            self.rpc.request::<_, ForwardBatchRes>(
+                dest.clone(),
+                "FORWARD_BATCH",
+                ForwardBatchReq {
+                    items,
+                    chain_index: req.chain_index + 1,
+                },
+            )
+        });
+        let mut remote_results = vec![None; ready.len()];
+        for ((dest, idxs), res) in hops.iter().zip(join_all(requests).await) {
+            match res {
+                Ok(res) if res.items.len() == idxs.len() => {
+                    for (j, res) in idxs.iter().zip(res.items) {
+                        self.more_net_in(res.embedding.len());
+                        remote_results[*j] = Some(res);
+                    }
+                }
+                _ => log::warn!("[ModelService] forward batch to remote {dest:?} failed"),
+            }
+        }
+
+        for (j, ((i, container, item), embedding)) in ready.into_iter().zip(embeddings).enumerate() {
+            let res = match (embedding, &container.remote) {
+                (None, _) => failed(),
+                (Some(_), Some(_)) => remote_results[j].take().unwrap_or_else(failed),
+                (Some(embedding), None) => ForwardRes {
+                    success: true,
+                    embedding,
+                    metadata: item.metadata.clone(),
+                },
+            };
+            results[i] = Some(self.usage_service.post_forward(container.chat_id, item, res).await);
+        }
+        log::info!("[ModelService] forward batch done");
+        ForwardBatchRes {
+            items: results.into_iter().map(|res| res.unwrap_or_else(failed)).collect(),
+        }
+    }
+
+    pub async fn rollback(&self, req: RollbackReq) -> RollbackRes {
+        let Some(container) = self.sessions.get_clone(&Session(req.session)) else {
+            log::warn!("[ModelService] rollback session {} but not found", req.session);
+            return RollbackRes { success: false, metadata: vec![] };
+        };
+        if let Some(layers) = &container.local {
+            if let Err(e) = self.layers.rollback(Session(req.session), req.count).await {
+                log::warn!("[ModelService] session {} rollback {} positions local {layers:?} layers error {e}", req.session, req.count);
+                return RollbackRes { success: false, metadata: vec![] };
+            }
+            container.next_pos.send_modify(|pos| *pos = pos.saturating_sub(req.count));
+        }
+
+        if let Some((dest, remote_session)) = &container.remote {
+            log::info!(
+                "[ModelService] session {} rollback {} positions remote {dest:?} with remote session {}",
+                req.session,
+                req.count,
+                remote_session.0
+            );
+            self.rpc
+                .request(
+                    dest.clone(),
+                    "ROLLBACK",
+                    RollbackReq {
+                        session: remote_session.0,
+                        count: req.count,
+                        metadata: req.metadata.clone(),
+                        chain_index: req.chain_index + 1,
+                    },
+                )
+                .await
+                .unwrap_or(RollbackRes {
+                    success: false,
+                    metadata: req.metadata.clone(),
+                })
+        } else {
+            RollbackRes {
+                success: true,
+                metadata: req.metadata.clone(),
+            }
+        }
+    }
+
+    /// Give the cross-attention states of the images of the session to the local layers then to the next hops,
+    /// only the ones running cross-attention layers get them
+    pub async fn cross_attention(&self, req: CrossAttentionReq) -> CrossAttentionRes {
+        let Some(container) = self.sessions.get_clone(&Session(req.session)) else {
+            log::warn!("[ModelService] cross attention of session {} but not found", req.session);
+            return CrossAttentionRes { success: false, metadata: vec![] };
+        };
+        let cross_layers = self.layers.cross_attention_layers();
+        let local = container.local.as_ref().filter(|layers| cross_layers.iter().any(|layer| layers.contains(layer)));
+        let remote = container.remote.as_ref().filter(|_| cross_layers.iter().any(|layer| *layer >= container.next_layer));
+        if let Some(layers) = local {
+            let states = TensorBuf::try_from(req.states.clone()).map_err(candle_core::Error::wrap).and_then(|buf| buf.to_tensor(&self.device));
+            let res = match states {
+                Ok(states) => {
+                    let images = req.images.iter().map(ImageSpan::from_proto).collect();
+                    self.layers.set_cross_attention(Session(req.session), CrossAttentionStates { states, images }).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                log::warn!(
+                    "[ModelService] session {} cross attention of {} images local {layers:?} layers error {e}",
+                    req.session,
+                    req.images.len()
+                );
+                return CrossAttentionRes { success: false, metadata: vec![] };
+            }
+        }
+
+        if let Some((dest, remote_session)) = remote {
+            log::info!(
+                "[ModelService] session {} cross attention of {} images remote {dest:?} with remote session {}",
+                req.session,
+                req.images.len(),
+                remote_session.0
+            );
+            self.more_net_out(req.states.len());
+            self.rpc
+                .request(
+                    dest.clone(),
+                    "CROSS_ATTENTION",
+                    CrossAttentionReq {
+                        session: remote_session.0,
+                        chain_index: req.chain_index + 1,
+                        ..req.clone()
+                    },
+                )
+                .await
+                .unwrap_or(CrossAttentionRes {
+                    success: false,
+                    metadata: req.metadata.clone(),
+                })
+        } else {
+            CrossAttentionRes {
+                success: true,
+                metadata: req.metadata.clone(),
+            }
+        }
+    }
+
+    pub async fn fork(&self, req: ForkReq) -> ForkRes {
+        let Some(container) = self.sessions.get_clone(&Session(req.session)) else {
+            log::warn!("[ModelService] fork session {} but not found", req.session);
+            return ForkRes { success: false, metadata: vec![] };
+        };
+        if let Some(layers) = &container.local {
+            if let Err(e) = self.layers.fork(Session(req.session), Session(req.forked)).await {
+                log::warn!("[ModelService] session {} fork {} local {layers:?} layers error {e}", req.session, req.forked);
+                return ForkRes { success: false, metadata: vec![] };
+            }
+        }
+
+        // the forked session has its own session on the next hop
+        let remote = container.remote.as_ref().map(|(dest, _)| (dest.clone(), Session::new()));
+        self.sessions.insert(
+            Session(req.forked),
+            SessionContainer {
+                chat_id: container.chat_id,
+                local: container.local.clone(),
+                remote: remote.clone(),
+                next_layer: container.next_layer,
+                next_pos: Arc::new(watch::channel(*container.next_pos.borrow()).0),
+                forked: true,
+            },
+        );
+        if let (Some((dest, remote_session)), Some((_, remote_forked))) = (&container.remote, remote) {
+            log::info!("[ModelService] session {} fork {} remote {dest:?} with remote session {}", req.session, req.forked, remote_session.0);
+            self.rpc
+                .request(
+                    dest.clone(),
+                    "FORK",
+                    ForkReq {
+                        session: remote_session.0,
+                        forked: remote_forked.0,
+                        metadata: req.metadata.clone(),
+                        chain_index: req.chain_index + 1,
+                    },
+                )
+                .await
+                .unwrap_or(ForkRes {
+                    success: false,
+                    metadata: req.metadata.clone(),
+                })
+        } else {
+            ForkRes {
+                success: true,
+                metadata: req.metadata.clone(),
+            }
+        }
+    }
+
+    pub async fn end(&self, req: EndReq) -> EndRes {
+        if let Some(container) = self.sessions.remove(&Session(req.session)) {
+            // wake up the forwards still waiting for their turn, they will see the session is gone
+            container.next_pos.send_replace(u32::MAX);
+            if container.forked {
+                // a forked session never went through the usage service, only the session it was forked from
+                return self.end_chain(&container, &req).await;
+            }
+            if let Ok(req) = self.usage_service.pre_end(container.chat_id, req.clone()).await {
+                let res = self.end_chain(&container, &req).await;
                 self.usage_service.post_end(container.chat_id, req, res).await
             } else {
                 log::warn!("[ModelService] session {} failed to pre_end", req.session);
@@ -318,6 +702,43 @@ impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_L
         }
     }
 
+    /// Finish the session on the local layers then end it on the next hops
+    async fn end_chain(&self, container: &SessionContainer, req: &EndReq) -> EndRes {
+        log::warn!("[ModelService] session {} ending ...", req.session);
+        if let Some(layers) = &container.local {
+            log::warn!("[ModelService] session {} end local {layers:?} layers", req.session);
+            self.layers.finish(Session(req.session)).await;
+        }
+
+        if let Some((dest, remote_session)) = &container.remote {
+            log::info!("[ModelService] session {} end remote {dest:?} with remote session {}", req.session, remote_session.0);
+            let res = self
+                .rpc
+                .request(
+                    dest.clone(),
+                    "END",
+                    EndReq {
+                        session: remote_session.0,
+                        metadata: req.metadata.clone(),
+                        chain_index: req.chain_index + 1,
+                    },
+                )
+                .await
+                .unwrap_or(EndRes {
+                    success: false,
+                    metadata: req.metadata.clone(),
+                    ..Default::default()
+                });
+            log::info!("[ModelService] session {} end remote {dest:?} with remote session {} done", req.session, remote_session.0);
+            res
+        } else {
+            EndRes {
+                success: true,
+                metadata: req.metadata.clone(),
+            }
+        }
+    }
+
     fn more_net_in(&self, bytes: usize) {
         self.stats.write().network_in_bytes += bytes as u64;
     }
@@ -335,3 +756,143 @@ impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_L
         self.stats.write().token_out_sum += 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{DType, Device, Result, Tensor};
+    use model_router::RouteTable;
+    use models::{remote::TensorBuf, CrossAttentionStates, ModelLayersWorker};
+    use p2p_network::addr::NodeId;
+    use protocol::{
+        llm::{CrossAttentionReq, ForwardBatchReq, ForwardReq, StartReq},
+        ChatCfg, Session,
+    };
+    use spin::{Mutex, RwLock};
+    use usage_service::PassthroughUsageService;
+
+    use super::ModelService;
+    use crate::{rpc::create_rpc, ServiceHandler};
+
+    /// Layers adding 1 to the embeddings, records the sessions of each batch and the ones given cross-attention states
+    #[derive(Default)]
+    struct RecordLayers {
+        cross_attention_layers: Vec<u32>,
+        batches: Mutex<Vec<Vec<Session>>>,
+        cross_attention: Mutex<Vec<Session>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ModelLayersWorker<(Tensor, u32)> for RecordLayers {
+        async fn start(&self, _session: Session, _cfg: ChatCfg) -> Result<()> {
+            Ok(())
+        }
+
+        async fn forward(&self, session: Session, step: u32, xs: (Tensor, u32), index_pos: u32) -> Result<(Tensor, u32)> {
+            self.forward_batch(vec![(session, step, xs, index_pos)]).await.remove(0)
+        }
+
+        async fn forward_batch(&self, batch: Vec<(Session, u32, (Tensor, u32), u32)>) -> Vec<Result<(Tensor, u32)>> {
+            self.batches.lock().push(batch.iter().map(|(session, ..)| *session).collect());
+            batch.into_iter().map(|(_, _, (xs, seq_len), _)| Ok(((xs + 1.)?, seq_len))).collect()
+        }
+
+        async fn finish(&self, _session: Session) {}
+
+        async fn set_cross_attention(&self, session: Session, _states: CrossAttentionStates) -> Result<()> {
+            self.cross_attention.lock().push(session);
+            Ok(())
+        }
+
+        fn cross_attention_layers(&self) -> &[u32] {
+            &self.cross_attention_layers
+        }
+    }
+
+    type Hop = Arc<ModelService<RecordLayers>>;
+
+    /// Two hops of a model of 4 layers, the first one runs the layers 0..2 and sends the others to the second one
+    fn chain(cross_attention_layers: &[u32]) -> (Hop, Hop) {
+        let hop = |rpc, router| {
+            let layers = RecordLayers {
+                cross_attention_layers: cross_attention_layers.to_vec(),
+                ..Default::default()
+            };
+            Arc::new(ModelService::new(layers, Device::Cpu, rpc, Arc::new(RwLock::new(router)), Arc::new(PassthroughUsageService)))
+        };
+        let second_router = RouteTable::new(4, 2..4);
+        let mut first_router = RouteTable::new(4, 0..2);
+        first_router.apply_sync(NodeId("second".to_string()), 1, second_router.create_sync(0));
+
+        let (first_rpc, mut first_rx) = create_rpc();
+        let first = hop(first_rpc, first_router);
+        let second = hop(create_rpc().0, second_router);
+        let next = second.clone();
+        tokio::spawn(async move {
+            while let Some((_, req)) = first_rx.recv().await {
+                let res = next.on_req(NodeId("first".to_string()), req).await;
+                first_rx.on_res(res);
+            }
+        });
+        (first, second)
+    }
+
+    async fn start(first: &Hop) -> Session {
+        let session = Session::new();
+        let req = StartReq {
+            session: session.0,
+            chat_id: session.0,
+            ..Default::default()
+        };
+        assert!(first.start(req).await.success);
+        session
+    }
+
+    #[tokio::test]
+    async fn cross_attention_only_to_hops_with_cross_attention_layers() {
+        for (cross_attention_layers, first_gets, second_gets) in [(vec![1], 1, 0), (vec![3], 0, 1), (vec![1, 2], 1, 1)] {
+            let (first, second) = chain(&cross_attention_layers);
+            let session = start(&first).await;
+            let states = Tensor::zeros((2, 4), DType::F32, &Device::Cpu).unwrap();
+            let req = CrossAttentionReq {
+                session: session.0,
+                states: TensorBuf::from(states).to_vec(),
+                ..Default::default()
+            };
+            assert!(first.cross_attention(req).await.success);
+            assert_eq!(first.layers.cross_attention.lock().len(), first_gets, "{cross_attention_layers:?}");
+            assert_eq!(second.layers.cross_attention.lock().len(), second_gets, "{cross_attention_layers:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn forward_batch_through_the_chain() {
+        let (first, second) = chain(&[]);
+        let sessions = [start(&first).await, start(&first).await, Session::new()];
+        let inputs = [1f32, 5., 9.].map(|v| Tensor::full(v, (1, 4), &Device::Cpu).unwrap());
+        let items = sessions
+            .iter()
+            .zip(&inputs)
+            .map(|(session, xs)| ForwardReq {
+                session: session.0,
+                embedding: TensorBuf::from(xs.clone()).to_vec(),
+                seq_len: 1,
+                ..Default::default()
+            })
+            .collect();
+        let res = first.forward_batch(ForwardBatchReq { items, chain_index: 0 }).await;
+
+        // the unknown session fails at its own index, the others run both hops
+        assert_eq!(res.items.iter().map(|item| item.success).collect::<Vec<_>>(), [true, true, false]);
+        for (item, xs) in res.items.iter().zip(&inputs).take(2) {
+            let output = TensorBuf::try_from(item.embedding.clone()).unwrap().to_tensor(&Device::Cpu).unwrap();
+            let expected = (xs + 2.).unwrap();
+            assert_eq!(output.to_vec2::<f32>().unwrap(), expected.to_vec2::<f32>().unwrap());
+        }
+        assert_eq!(*first.layers.batches.lock(), [sessions[..2].to_vec()]);
+        let second_batches = second.layers.batches.lock();
+        assert_eq!(second_batches.len(), 1);
+        assert_eq!(second_batches[0].len(), 2);
+    }
+}
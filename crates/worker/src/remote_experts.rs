@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use candle_core::{bail, Device, Result, Tensor};
+use futures_util::future::join_all;
+use model_router::RouteTable;
+use models::{remote::TensorBuf, RemoteExperts};
+use p2p_network::addr::NodeId;
+use protocol::llm::{ExpertForwardReq, ExpertForwardRes, ExpertInput};
+use spin::RwLock;
+
+use crate::rpc::RpcClientTx;
+
+/// Send the inputs of the experts which are not local to their owners, one request per owner
+pub struct RpcRemoteExperts {
+    pub rpc: RpcClientTx,
+    pub router: Arc<RwLock<RouteTable<NodeId>>>,
+    pub device: Device,
+}
+
+#[async_trait::async_trait]
+impl RemoteExperts for RpcRemoteExperts {
+    async fn forward_experts(&self, layer: u32, inputs: Vec<(u32, Tensor)>) -> Result<Vec<Tensor>> {
+        let experts = inputs.iter().map(|(expert, _)| *expert).collect::<Vec<_>>();
+        let Some(paths) = self.router.read().select_experts(layer, &experts) else {
+            bail!("no owner for some of the experts {experts:?} of layer {layer}")
+        };
+
+        let mut inputs = inputs.into_iter().map(Some).collect::<Vec<_>>();
+        let mut hops = vec![];
+        let mut reqs = vec![];
+        for path in paths {
+            let Some(dest) = path.node else { bail!("experts {:?} of layer {layer} are local", path.experts) };
+            let mut idxs = vec![];
+            let mut items = vec![];
+            for expert in path.experts {
+                let Some(idx) = inputs.iter().position(|input| input.as_ref().is_some_and(|(e, _)| *e == expert)) else {
+                    bail!("expert {expert} of layer {layer} selected twice")
+                };
+                let (expert, hidden) = inputs[idx].take().expect("Should have input");
+                idxs.push(idx);
+                items.push(ExpertInput {
+                    expert,
+                    hidden: TensorBuf::from(hidden).to_vec(),
+                });
+            }
+            hops.push((dest, idxs));
+            reqs.push(ExpertForwardReq { layer, inputs: items });
+        }
+
+        let requests = hops.iter().zip(reqs).map(|((dest, _), req)| {
+            log::debug!("[RpcRemoteExperts] forward {} experts of layer {layer} to remote {dest:?}", req.inputs.len());
+            self.rpc.request::<_, ExpertForwardRes>(dest.clone(), "EXPERT_FORWARD", req)
+        });
+        let mut outputs = vec![None; experts.len()];
+        for ((dest, idxs), res) in hops.iter().zip(join_all(requests).await) {
+            match res {
+                Ok(res) if res.success && res.outputs.len() == idxs.len() => {
+                    for (idx, output) in idxs.iter().zip(res.outputs) {
+                        outputs[*idx] = Some(TensorBuf::try_from(output).map_err(candle_core::Error::wrap)?.to_tensor(&self.device)?);
+                    }
+                }
+                Ok(_) => bail!("remote {dest:?} failed to forward experts of layer {layer}"),
+                Err(e) => bail!("remote {dest:?} forward experts of layer {layer} error {e}"),
+            }
+        }
+        Ok(outputs.into_iter().flatten().collect())
+    }
+}
@@ -1,22 +1,27 @@
 use std::sync::Arc;
 
 use candle_core::{Device, Result, Tensor};
-use models::{remote::TensorBuf, ModelLayersWorker};
+use models::{remote::TensorBuf, CrossAttentionStates, ModelLayersWorker};
 use protocol::{
-    llm::{EndReq, ForwardReq, StartReq},
+    llm::{CrossAttentionReq, EndReq, ForkReq, ForwardBatchReq, ForwardReq, PrefixKey, RollbackReq, StartReq},
     ChatCfg, Session,
 };
 
 use crate::model_service::ModelService;
 
-pub struct VirtualModelLayers<LW, const MODEL_LAYERS: usize> {
+pub struct VirtualModelLayers<LW> {
     pub device: Device,
-    pub model_service: Arc<ModelService<LW, MODEL_LAYERS>>,
+    pub model_service: Arc<ModelService<LW>>,
 }
 
 #[async_trait::async_trait]
-impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_LAYERS: usize> ModelLayersWorker<(Tensor, u32)> for VirtualModelLayers<LW, MODEL_LAYERS> {
+impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static> ModelLayersWorker<(Tensor, u32)> for VirtualModelLayers<LW> {
     async fn start(&self, session: Session, config: ChatCfg) -> Result<()> {
+        self.start_with_prefix(session, config, vec![]).await.map(|_| ())
+    }
+
+    async fn start_with_prefix(&self, session: Session, config: ChatCfg, prefixes: Vec<PrefixKey>) -> Result<u32> {
+        let (overflow, attention_sinks) = config.overflow.to_proto();
         let res = self
             .model_service
             .start(StartReq {
@@ -26,12 +31,15 @@ impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_L
                 metadata: vec![],
                 chain_index: 0,
                 max_tokens: config.max_len,
+                prefixes,
+                overflow: overflow.into(),
+                attention_sinks,
             })
             .await;
         if res.success {
-            Ok(())
+            Ok(res.cached_prefix_len)
         } else {
-            Err(std::io::Error::new(std::io::ErrorKind::Other, "Worker Start Error").into())
+            Err(std::io::Error::other("Worker Start Error").into())
         }
     }
 
@@ -53,7 +61,88 @@ impl<LW: ModelLayersWorker<(Tensor, u32)> + Send + Sync + 'static, const MODEL_L
             let res_tensor = TensorBuf::try_from(res.embedding).unwrap().to_tensor(&self.device)?;
             Ok((res_tensor, seq_len))
         } else {
-            Err(std::io::Error::new(std::io::ErrorKind::Other, "RpcError").into())
+            Err(std::io::Error::other("RpcError").into())
+        }
+    }
+
+    async fn forward_batch(&self, batch: Vec<(Session, u32, (Tensor, u32), u32)>) -> Vec<Result<(Tensor, u32)>> {
+        let seq_lens = batch.iter().map(|(_, _, (_, seq_len), _)| *seq_len).collect::<Vec<_>>();
+        let items = batch
+            .into_iter()
+            .map(|(session, step, (tensor, seq_len), index_pos)| ForwardReq {
+                session: session.0,
+                embedding: TensorBuf::from(tensor).to_vec(),
+                step,
+                seq_len,
+                index_pos,
+                metadata: vec![],
+                chain_index: 0,
+            })
+            .collect();
+        let res = self.model_service.forward_batch(ForwardBatchReq { items, chain_index: 0 }).await;
+        res.items
+            .into_iter()
+            .zip(seq_lens)
+            .map(|(res, seq_len)| {
+                if res.success {
+                    let res_tensor = TensorBuf::try_from(res.embedding).map_err(candle_core::Error::wrap)?.to_tensor(&self.device)?;
+                    Ok((res_tensor, seq_len))
+                } else {
+                    Err(std::io::Error::other("RpcError").into())
+                }
+            })
+            .collect()
+    }
+
+    async fn rollback(&self, session: Session, count: u32) -> Result<()> {
+        let res = self
+            .model_service
+            .rollback(RollbackReq {
+                session: session.0,
+                count,
+                metadata: vec![],
+                chain_index: 0,
+            })
+            .await;
+        if res.success {
+            Ok(())
+        } else {
+            Err(std::io::Error::other("Worker Rollback Error").into())
+        }
+    }
+
+    async fn fork(&self, session: Session, forked: Session) -> Result<()> {
+        let res = self
+            .model_service
+            .fork(ForkReq {
+                session: session.0,
+                forked: forked.0,
+                metadata: vec![],
+                chain_index: 0,
+            })
+            .await;
+        if res.success {
+            Ok(())
+        } else {
+            Err(std::io::Error::other("Worker Fork Error").into())
+        }
+    }
+
+    async fn set_cross_attention(&self, session: Session, states: CrossAttentionStates) -> Result<()> {
+        let res = self
+            .model_service
+            .cross_attention(CrossAttentionReq {
+                session: session.0,
+                states: TensorBuf::from(states.states).to_vec(),
+                images: states.images.iter().map(|image| image.to_proto()).collect(),
+                metadata: vec![],
+                chain_index: 0,
+            })
+            .await;
+        if res.success {
+            Ok(())
+        } else {
+            Err(std::io::Error::other("Worker Cross Attention Error").into())
         }
     }
 